@@ -0,0 +1,19 @@
+#!/bin/sh
+# Downloads the Lua libraries listed in benches/fixtures/README.md, at the
+# pinned releases, along with their licenses. benches/parse.rs parses every
+# benches/fixtures/*.lua it finds.
+set -e
+cd "$(dirname "$0")/fixtures"
+
+fetch() {
+    curl -fsSL "https://raw.githubusercontent.com/$1/$2/$3" -o "$4"
+}
+
+fetch rxi/json.lua v0.1.2 json.lua json.lua
+fetch rxi/json.lua v0.1.2 LICENSE json.LICENSE
+fetch rxi/lume v2.3.0 lume.lua lume.lua
+fetch rxi/lume v2.3.0 LICENSE lume.LICENSE
+fetch kikito/inspect.lua v3.1.3 inspect.lua inspect.lua
+fetch kikito/inspect.lua v3.1.3 MIT-LICENSE.txt inspect.LICENSE
+fetch kikito/middleclass v4.1.1 middleclass.lua middleclass.lua
+fetch kikito/middleclass v4.1.1 MIT-LICENSE.txt middleclass.LICENSE
@@ -6,53 +6,33 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-
-#![feature(test)]
-
-extern crate test;
+#[macro_use]
+extern crate criterion;
 extern crate nom_lua;
 
-use test::Bencher;
+use criterion::{black_box, Criterion};
 use nom_lua::number;
 
-#[bench]
-fn parse_hex_int(b: &mut Bencher) {
-    let ints: Vec<String> =
-        test::black_box((0..32)
-                        .map(|i| format!("0x{:X}", i).to_string())
-                        .collect());
-
-    b.iter(|| {
+fn bench_numbers(c: &mut Criterion, name: &str, ints: Vec<String>) {
+    let ints = black_box(ints);
+    c.bench_function(name, |b| b.iter(|| {
         for i in &ints {
-            number::parse_number(i.as_bytes());
+            black_box(number::parse_number(i.as_bytes()));
         }
-    });
+    }));
 }
 
-#[bench]
-fn parse_int(b: &mut Bencher) {
-    let ints: Vec<String> =
-        test::black_box((0..32)
-                        .map(|i| format!("{}", i).to_string())
-                        .collect());
-
-    b.iter(|| {
-        for i in &ints {
-            number::parse_number(i.as_bytes());
-        }
-    });
+fn parse_hex_int(c: &mut Criterion) {
+    bench_numbers(c, "parse_hex_int", (0..32).map(|i| format!("0x{:X}", i)).collect());
 }
 
-#[bench]
-fn parse_float(b: &mut Bencher) {
-    let ints: Vec<String> =
-        test::black_box((0..32)
-                        .map(|i| format!("{}", i as f32).to_string())
-                        .collect());
+fn parse_int(c: &mut Criterion) {
+    bench_numbers(c, "parse_int", (0..32).map(|i| format!("{}", i)).collect());
+}
 
-    b.iter(|| {
-        for i in &ints {
-            number::parse_number(i.as_bytes());
-        }
-    });
+fn parse_float(c: &mut Criterion) {
+    bench_numbers(c, "parse_float", (0..32).map(|i| format!("{}", i as f32)).collect());
 }
+
+criterion_group!(benches, parse_hex_int, parse_int, parse_float);
+criterion_main!(benches);
@@ -0,0 +1,171 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Throughput of the parser over larger, generated workloads
+//!
+//! Every benchmark reports its throughput in bytes, so the numbers can be
+//! compared across workloads and between runs. Besides the generated
+//! workloads, every `benches/fixtures/*.lua` is parsed as a whole file, see
+//! `benches/fixtures/README.md` for where they come from.
+
+#[macro_use]
+extern crate criterion;
+extern crate nom_lua;
+
+use std::fs;
+use std::path::Path;
+
+use criterion::{black_box, Criterion, Throughput};
+use nom_lua::{IResult, ParserConfig};
+use nom_lua::function::parse_block;
+
+/// A data file made of a single big table literal, with nested records
+fn table_literal(records: usize) -> String {
+    let mut s = String::from("{\n");
+    for i in 0..records {
+        s.push_str(&format!(
+            "  {{ id = {}, price = {}.{}e1, ratio = 0x{:X},\n    \
+             tags = {{ {}, [{}] = nil; ... }}, active = {}, extra = {{}} }};\n",
+            i, i, i % 10, i, i * 3, i, i % 2 == 0));
+    }
+    s.push_str("  nil\n}");
+    s
+}
+
+/// `depth` levels of parentheses, each with an operator chain inside
+fn nested_expression(depth: usize) -> String {
+    let mut s = String::new();
+    for i in 0..depth {
+        s.push_str(&format!("({} + ", i));
+    }
+    s.push('1');
+    for i in 0..depth {
+        s.push_str(&format!(" * {} - 0x{:x} // 2)", i, i));
+    }
+    s
+}
+
+/// `depth` levels of nested table constructors
+fn nested_tables(depth: usize) -> String {
+    let mut s = String::new();
+    for i in 0..depth {
+        s.push_str(&format!("{{ k{} = ", i));
+    }
+    s.push_str("nil");
+    for _ in 0..depth {
+        s.push_str(", true }");
+    }
+    s
+}
+
+/// A long block of the statements real code is made of: locals, calls,
+/// assignments, branches, loops, functions and comments
+fn statements(count: usize) -> String {
+    let mut s = String::from("local t, acc = {}, 0\n");
+    for i in 0..count {
+        s.push_str(&format!("\
+-- step {i}
+local v{i} = {i} * 2 + #t - math.floor(acc / 3)
+if v{i} > 10 and not t[{i}] then
+  print(\"big\", v{i})
+elseif v{i} == 0 then
+  t.zero = (t.zero or 0) + 1
+else
+  acc = acc .. \"\"
+end
+for j = 1, v{i} % 8, 2 do
+  acc = acc + t[j] or j
+end
+for k, v in pairs(t) do
+  t[k] = v ~= nil and v or {{}}
+end
+local function f{i}(a, b, ...)
+  local c = a or b
+  while c and c.next do c = c.next end
+  return c:method(...), select(\"#\", ...)
+end
+t.handlers[\"f{i}\"] = f{i}
+string.format(\"%d: %s\", {i}, [[long
+string]])
+", i = i));
+    }
+    s.push_str("return t, acc");
+    s
+}
+
+fn bench_throughput<F>(c: &mut Criterion, name: &str, input: &str, parser: F)
+    where F: Fn(&[u8]) -> IResult<&[u8], nom_lua::ASTNode>
+{
+    match parser(input.as_bytes()) {
+        IResult::Done(&[], _) => {},
+        _ => panic!("benchmark input for {} does not parse", name),
+    }
+
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Bytes(input.len() as u64));
+    group.bench_function("parse", |b| b.iter(|| parser(black_box(input.as_bytes()))));
+    group.finish();
+}
+
+fn parse_table_literal(c: &mut Criterion) {
+    bench_throughput(c, "table_literal", &table_literal(2000), nom_lua::parse_chunk);
+}
+
+fn parse_nested_expression(c: &mut Criterion) {
+    bench_throughput(c, "nested_expression", &nested_expression(48), nom_lua::parse_chunk);
+}
+
+fn parse_nested_tables(c: &mut Criterion) {
+    bench_throughput(c, "nested_tables", &nested_tables(48), nom_lua::parse_chunk);
+}
+
+fn parse_statements(c: &mut Criterion) {
+    bench_throughput(c, "statements", &statements(1000), parse_block);
+}
+
+/// The Lua files in `benches/fixtures`, by file name
+fn fixtures() -> Vec<(String, Vec<u8>)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches").join("fixtures");
+    let mut files: Vec<_> = fs::read_dir(&dir)
+        .map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+        .unwrap_or_default();
+    files.retain(|path| path.extension().is_some_and(|e| e == "lua"));
+    files.sort();
+    files.into_iter()
+        .map(|path| {
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            let source = fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+            (name, source)
+        })
+        .collect()
+}
+
+fn parse_fixtures(c: &mut Criterion) {
+    let fixtures = fixtures();
+    if fixtures.is_empty() {
+        println!("no fixtures in benches/fixtures, run benches/fetch-fixtures.sh to download them");
+    }
+    let config = ParserConfig::default();
+    for (name, source) in &fixtures {
+        if let Err(e) = nom_lua::parse_block_with(source, &config) {
+            panic!("fixture {}.lua does not parse: {}", name, e);
+        }
+        let mut group = c.benchmark_group(format!("fixture_{}", name));
+        group.throughput(Throughput::Bytes(source.len() as u64));
+        group.bench_function("parse", |b| b.iter(|| nom_lua::parse_block_with(black_box(source), &config)));
+        group.finish();
+    }
+}
+
+criterion_group!(benches,
+                 parse_table_literal,
+                 parse_nested_expression,
+                 parse_nested_tables,
+                 parse_statements,
+                 parse_fixtures);
+criterion_main!(benches);
@@ -0,0 +1,52 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Lists a precompiled chunk like `luac -l -l`, or a source file after
+//! compiling it. `-l` alone leaves out the constants, locals and upvalues.
+//!
+//! ```text
+//! cargo run --example luac -- [-l] file.luac
+//! ```
+
+extern crate nom_lua;
+
+use std::env;
+use std::fs;
+use std::process;
+
+use nom_lua::bytecode::{compile, listing, undump, Chunk};
+use nom_lua::{parse_block_with, ParserConfig};
+
+fn load(path: &str) -> Result<Chunk, String> {
+    let data = fs::read(path).map_err(|e| format!("cannot open {}: {}", path, e))?;
+    if data.starts_with(b"\x1bLua") {
+        return undump(&data).map_err(|e| format!("{}: {}", path, e));
+    }
+    let block = parse_block_with(&data, &ParserConfig::default()).map_err(|e| format!("{}: {}", path, e))?;
+    let proto = compile(&block, &data, &format!("@{}", path)).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(Chunk::new(proto))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let full = !args.iter().any(|a| a == "-l");
+    let files: Vec<&String> = args.iter().filter(|a| !a.starts_with('-')).collect();
+    if files.is_empty() {
+        eprintln!("usage: luac [-l] file...");
+        process::exit(1);
+    }
+    for path in files {
+        match load(path) {
+            Ok(chunk) => print!("{}", listing(&chunk, full)),
+            Err(e) => {
+                eprintln!("luac: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+}
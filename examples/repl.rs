@@ -6,24 +6,306 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+//! Reads Lua and shows what the parser makes of it
+//!
+//! ```text
+//! cargo run --example repl --features graphviz
+//! ```
+//!
+//! Each input is parsed as a chunk, or as an expression when it is not one,
+//! and shown as an S-expression. Input that stops inside a block, a
+//! bracket or a long string is continued on the next line. Commands start
+//! with `:`:
+//!
+//! - `:ast`, `:tokens`, `:dot` and `:eval` show the tree, the tokens, the
+//!   Graphviz graph or the values of the inputs that follow. `:eval` keeps
+//!   globals from one input to the next and has a `print`.
+//! - Followed by code, as in `:tokens a..b`, they only apply to that code.
+//! - `:history` lists the previous inputs, `:history 3` runs the third one
+//!   again. `:quit` exits.
+//!
+//! A command drops any unfinished input. Inputs are saved to
+//! `~/.nom_lua_history`, or to the file in `NOM_LUA_HISTORY`, and listed
+//! again by `:history` in the next session. Lines are read as they come,
+//! without line editing, so the arrow keys do not recall inputs.
+//!
+//! The interpreter borrows the trees it runs for as long as it lives, so
+//! `:eval` does not keep one running. It keeps the sources it ran instead,
+//! and runs them again quietly before each new input to rebuild the
+//! globals.
+
 extern crate nom_lua;
 
-fn exec_repl() {
-    use std::io::{BufRead, Write};
-    let stdin = std::io::stdin();
-    let stdout = std::io::stdout();
-    for line in stdin.lock().lines() {
-        if let nom_lua::IResult::Done(_, string) =
-            nom_lua::parse_chunk(line.expect("Failed to read line").as_bytes()) {
-            println!("EVAL: {}", string);
-        } else {
-            println!("ERROR: Parse Error");
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use nom_lua::eval::{Interpreter, Value};
+use nom_lua::span::{self, is_incomplete, Span};
+use nom_lua::{parse_block_with, parse_chunk_with, sexp, ASTNode, Error, ParserConfig};
+
+const HELP: &str = "\
+:ast        show the tree of each input, the default
+:tokens     list the tokens of each input
+:dot        print the Graphviz graph of each input
+:eval       run each input and print the values it returns
+:history    list the previous inputs, :history <n> runs input n again
+:quit       exit
+Followed by code, :ast, :tokens, :dot and :eval only apply to that code.";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Ast,
+    Tokens,
+    Dot,
+    Eval,
+}
+
+fn history_file() -> Option<PathBuf> {
+    match env::var_os("NOM_LUA_HISTORY") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".nom_lua_history")),
+    }
+}
+
+// One input per line in the history file
+fn escape(input: &str) -> String {
+    input.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(line: &str) -> String {
+    let mut input = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (c, c == '\\') {
+            (_, true) => match chars.next() {
+                Some('n') => input.push('\n'),
+                Some(other) => input.push(other),
+                None => input.push('\\'),
+            },
+            (c, false) => input.push(c),
         }
-        print!("> ");
-        stdout.lock().flush().expect("Failed to flush");
     }
+    input
+}
+
+fn describe(source: &str, error: Error) -> String {
+    match error {
+        Error::Syntax(offset) => {
+            let (line, column) = Span { start: offset, end: offset }.line_column(source.as_bytes());
+            format!("syntax error at {}:{}", line, column)
+        }
+        Error::Invalid(offset, message) => {
+            let (line, column) = Span { start: offset, end: offset }.line_column(source.as_bytes());
+            format!("{} at {}:{}", message, line, column)
+        }
+        error => error.to_string(),
+    }
+}
+
+struct Repl {
+    mode: Mode,
+    config: ParserConfig,
+    // The sources `:eval` ran, in order
+    evaluated: Vec<String>,
+    history: Vec<String>,
+}
+
+// An interpreter with a `print`, which prints nothing while `quiet` is set
+fn interpreter<'a>(quiet: &Rc<Cell<bool>>) -> Interpreter<'a> {
+    let mut lua = Interpreter::new();
+    let quiet = quiet.clone();
+    lua.set_global("print", Value::native(move |_, args| {
+        if !quiet.get() {
+            let values: Vec<_> = args.iter().map(|v| v.to_string()).collect();
+            println!("{}", values.join("\t"));
+        }
+        Ok(vec![])
+    }));
+    lua
+}
+
+impl Repl {
+    fn new() -> Repl {
+        let history = history_file()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map_or_else(Vec::new, |text| text.lines().map(unescape).collect());
+        Repl { mode: Mode::Ast, config: ParserConfig::default(), evaluated: Vec::new(), history }
+    }
+
+    fn remember(&mut self, input: &str) {
+        self.history.push(input.to_string());
+        if let Some(path) = history_file() {
+            let file = OpenOptions::new().create(true).append(true).open(path);
+            // A history that can not be written is not worth stopping for
+            let _ = file.and_then(|mut file| writeln!(file, "{}", escape(input)));
+        }
+    }
+
+    // Parses `source` as a chunk, or as an expression when it is not one
+    fn parse(&self, source: &str) -> Result<ASTNode, String> {
+        parse_block_with(source.as_bytes(), &self.config)
+            .or_else(|e| parse_chunk_with(source.as_bytes(), &self.config).map_err(|_| describe(source, e)))
+    }
+
+    // The values returned by `source`, after the earlier inputs have run
+    fn eval(&mut self, source: &str) -> Result<Vec<String>, String> {
+        // As in `lua`, an expression is run for its value
+        let expression = format!("return {}", source);
+        let source = match parse_block_with(expression.as_bytes(), &self.config) {
+            Ok(_) => expression,
+            Err(_) => match parse_block_with(source.as_bytes(), &self.config) {
+                Ok(_) => source.to_string(),
+                Err(e) => return Err(describe(source, e)),
+            },
+        };
+        self.evaluated.push(source);
+        let blocks = self.evaluated.iter()
+            .map(|source| parse_block_with(source.as_bytes(), &self.config))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        let quiet = Rc::new(Cell::new(true));
+        let mut lua = interpreter(&quiet);
+        let (last, earlier) = blocks.split_last().unwrap();
+        for block in earlier {
+            // Errors were reported when the input was first run
+            let _ = lua.exec(block);
+        }
+        quiet.set(false);
+        let values = match lua.exec(last) {
+            Ok(values) => Ok(values.iter().map(|v| format!("{:?}", v)).collect()),
+            Err(e) => Err(e.to_string()),
+        };
+        values
+    }
+
+    fn show(&mut self, mode: Mode, source: &str) -> Result<(), String> {
+        match mode {
+            Mode::Ast => print!("{}", sexp::print(&self.parse(source)?)),
+            Mode::Tokens => for token in span::tokens(source.as_bytes()) {
+                let (line, column) = token.span.line_column(source.as_bytes());
+                println!("{}:{} {} {}", line, column, token.kind, &source[token.span.start..token.span.end]);
+            },
+            Mode::Dot => dot(&self.parse(source)?)?,
+            Mode::Eval => {
+                let values = self.eval(source)?;
+                if !values.is_empty() {
+                    println!("{}", values.join("\t"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "graphviz")]
+fn dot(ast: &ASTNode) -> Result<(), String> {
+    use nom_lua::graphviz::{render, DotConfig};
+    render(ast, &DotConfig { cluster_functions: true }, &mut io::stdout()).map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "graphviz"))]
+fn dot(_: &ASTNode) -> Result<(), String> {
+    Err(":dot needs the graphviz feature".to_string())
 }
 
 fn main() {
-    exec_repl();
+    let mut repl = Repl::new();
+    let stdin = io::stdin();
+    let interactive = stdin.is_terminal();
+    let mut lines = stdin.lock().lines();
+    // The lines of an input recalled from the history, read before stdin
+    let mut recalled = VecDeque::new();
+    let mut pending = String::new();
+    // The mode of a command given with code, for that code only
+    let mut once = None;
+    loop {
+        let line = match recalled.pop_front() {
+            Some(line) => line,
+            None => {
+                if interactive {
+                    print!("{}", if pending.is_empty() { "> " } else { ">> " });
+                    io::stdout().flush().expect("Failed to flush");
+                }
+                match lines.next() {
+                    Some(line) => line.expect("Failed to read line"),
+                    None => break,
+                }
+            }
+        };
+        if line.starts_with(':') && line[1..].starts_with(|c: char| c.is_ascii_alphabetic()) {
+            pending.clear();
+            let (command, code) = match line[1..].find(' ') {
+                Some(space) => (&line[1..space + 1], line[space + 1..].trim()),
+                None => (&line[1..], ""),
+            };
+            let mode = match command {
+                "ast" => Mode::Ast,
+                "tokens" => Mode::Tokens,
+                "dot" => Mode::Dot,
+                "eval" => Mode::Eval,
+                "history" if code.is_empty() => {
+                    for (i, input) in repl.history.iter().enumerate() {
+                        println!("{:4}  {}", i + 1, input.replace('\n', "\n      "));
+                    }
+                    continue;
+                }
+                "history" => {
+                    let n = code.parse::<usize>().ok().and_then(|n| n.checked_sub(1));
+                    match n.and_then(|n| repl.history.get(n)) {
+                        Some(input) => {
+                            println!("{}", input);
+                            recalled.extend(input.lines().map(String::from));
+                        }
+                        None => println!("no input {} in the history", code),
+                    }
+                    continue;
+                }
+                "help" => {
+                    println!("{}", HELP);
+                    continue;
+                }
+                "quit" | "q" => break,
+                _ => {
+                    println!("unknown command :{}, :help lists them", command);
+                    continue;
+                }
+            };
+            if code.is_empty() {
+                repl.mode = mode;
+                continue;
+            }
+            once = Some((mode, command.to_string()));
+            pending.push_str(code);
+        } else {
+            pending.push_str(&line);
+        }
+        pending.push('\n');
+        if is_incomplete(pending.as_bytes()) {
+            continue;
+        }
+        let source = pending.trim_end().to_string();
+        pending.clear();
+        let (mode, command) = match once.take() {
+            Some((mode, command)) => (mode, Some(command)),
+            None => (repl.mode, None),
+        };
+        if source.trim().is_empty() {
+            continue;
+        }
+        repl.remember(&match command {
+            Some(command) => format!(":{} {}", command, source),
+            None => source.clone(),
+        });
+        if let Err(e) = repl.show(mode, &source) {
+            println!("{}", e);
+        }
+    }
+    if interactive {
+        println!();
+    }
 }
@@ -6,12 +6,13 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-extern crate nom_lua;
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use nom_lua::IResult;
 
-pub fn main() {
-    use std::fs::File;
-    let mut f = File::create("example1.dot").unwrap();
-    let ast = nom_lua::parse_string("10 / 20 * 30".as_bytes()).unwrap();
-    println!("{}", ast);
-    ast.graphviz_render(&mut f);
-}
+// A parser must never panic, and when it succeeds it must have consumed input
+fuzz_target!(|data: &[u8]| {
+    if let IResult::Done(rest, _) = nom_lua::parse_chunk(data) {
+        assert!(rest.len() < data.len(), "parsed without consuming any input");
+    }
+});
@@ -0,0 +1,18 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use nom_lua::IResult;
+
+// A parser must never panic, and when it succeeds it must have consumed input
+fuzz_target!(|data: &[u8]| {
+    if let IResult::Done(rest, _) = nom_lua::number::parse_number(data) {
+        assert!(rest.len() < data.len(), "parsed without consuming any input");
+    }
+});
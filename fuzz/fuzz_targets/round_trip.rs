@@ -0,0 +1,22 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use nom_lua::{parse_block_with, printer, ParserConfig};
+
+// A block that parses must print as source that parses to the same tree
+fuzz_target!(|data: &[u8]| {
+    let config = ParserConfig::default();
+    if let Ok(ast) = parse_block_with(data, &config) {
+        let printed = printer::print(&ast);
+        let reparsed = parse_block_with(printed.as_bytes(), &config)
+            .unwrap_or_else(|e| panic!("{} does not parse: {}", printed, e));
+        assert_eq!(reparsed, ast, "{}", printed);
+    }
+});
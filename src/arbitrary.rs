@@ -0,0 +1,564 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Random syntactically valid trees for quickcheck
+//!
+//! `ASTNode::arbitrary` generates a chunk, `Exp` an expression and `Stat` a
+//! statement. A tree has about `g.size()` nodes at most, is the tree the
+//! parser reads from its `printer::print`, and passes `validate`: `break`
+//! only appears in loops and `...` only in vararg functions. Names are
+//! identifiers that are not keywords.
+//!
+//! Shrinking removes statements and list items, replaces a statement by
+//! the statements of its blocks and an expression by one of its operands or
+//! by a smaller literal, and keeps only the trees that are still valid.
+//!
+//! ```rust,ignore
+//! quickcheck! {
+//!     fn prints_and_parses(ast: ASTNode) -> bool {
+//!         parse_block_with(printer::print(&ast).as_bytes(), &ParserConfig::default()) == Ok(ast)
+//!     }
+//! }
+//! ```
+
+use std::string::String;
+
+use quickcheck::{Arbitrary, Gen};
+
+use arena::Arena;
+use ast::ASTNode;
+use ast::ASTNode::*;
+use config::{LuaVersion, ParserConfig};
+use op::{make_binop, BinOp};
+use printer::{self, is_name};
+use validate::validate;
+use {parse_block_with, parse_chunk_with};
+
+/// An expression
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exp(pub ASTNode);
+
+/// A statement outside of any loop
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stat(pub ASTNode);
+
+const FIRST: &[char] = &['a', 'b', 'd', 'e', 'f', 'i', 'n', 'o', 'r', 't', 'x', '_'];
+const REST: &[char] = &['a', 'd', 'e', 'f', 'i', 'l', 'n', 'o', 'r', 's', 't', '0', '1', '_'];
+const CHARS: &[char] = &['a', 'z', ' ', '"', '\'', '\\', '\n', '\r', '\t', '\0', '\u{7f}', 'é', '\u{10ffff}'];
+
+const BINOPS: &[BinOp] = &[
+    BinOp::Exp, BinOp::Mul, BinOp::Div, BinOp::FDiv, BinOp::Mod, BinOp::Add, BinOp::Sub,
+    BinOp::Concat, BinOp::Lsh, BinOp::Rsh, BinOp::BitAnd, BinOp::BitXor, BinOp::BitOr,
+    BinOp::Lt, BinOp::Gt, BinOp::Le, BinOp::Ge, BinOp::Ne, BinOp::Eq, BinOp::And, BinOp::Or,
+];
+
+fn config() -> ParserConfig {
+    ParserConfig { version: LuaVersion::Lua53, ..ParserConfig::default() }
+}
+
+// What the code being generated may use
+#[derive(Clone, Copy)]
+struct Scope {
+    in_loop: bool,
+    vararg: bool,
+}
+
+// How deep the generated nodes nest, well within `DEFAULT_MAX_DEPTH`
+const MAX_NESTING: usize = 12;
+
+// Generates nodes until `budget` runs out, then only leaves
+struct Generator<'a, G: 'a> {
+    g: &'a mut G,
+    budget: usize,
+    nesting: usize,
+}
+
+impl<'a, G: Gen> Generator<'a, G> {
+    fn new(g: &'a mut G) -> Generator<'a, G> {
+        let budget = g.gen_range(0, g.size() + 1);
+        Generator { g, budget, nesting: 0 }
+    }
+
+    // Spends one node of the budget, if any is left
+    fn grow(&mut self) -> bool {
+        if self.budget == 0 || self.nesting >= MAX_NESTING {
+            return false;
+        }
+        self.budget -= 1;
+        true
+    }
+
+    fn nested<T, F: FnOnce(&mut Self) -> T>(&mut self, f: F) -> T {
+        self.nesting += 1;
+        let node = f(self);
+        self.nesting -= 1;
+        node
+    }
+
+    // Up to `max` items, fewer once the budget is spent
+    fn count(&mut self, min: usize, max: usize) -> usize {
+        if self.budget == 0 {
+            min
+        } else {
+            self.g.gen_range(min, max + 1)
+        }
+    }
+
+    fn name(&mut self) -> ASTNode {
+        loop {
+            let len = self.g.gen_range(1, 5);
+            let mut name = self.g.choose(FIRST).unwrap().to_string();
+            for _ in 1..len {
+                name.push(*self.g.choose(REST).unwrap());
+            }
+            if is_name(&name) {
+                return Name(name);
+            }
+        }
+    }
+
+    // Strings are bytes, some of them not UTF-8
+    fn string(&mut self) -> Vec<u8> {
+        let len = self.g.gen_range(0, 6);
+        let mut out = Vec::new();
+        for _ in 0..len {
+            match self.g.gen_range(0, 4) {
+                0 => {
+                    let c = *self.g.choose(CHARS).unwrap();
+                    out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                1 => out.push(self.g.gen_range(0x80, 0x100u16) as u8),
+                _ => out.push(self.g.gen_range(b' ', b'~' + 1)),
+            }
+        }
+        out
+    }
+
+    fn leaf(&mut self, scope: Scope) -> ASTNode {
+        match self.g.gen_range(0, if scope.vararg { 8 } else { 7 }) {
+            0 => Nil,
+            1 => Bool(self.g.gen()),
+            2 => Integer(self.g.gen_range(0, 1 << 16)),
+            3 => Integer(self.g.gen_range(0, i64::MAX)),
+            4 => Float(self.g.gen_range(0, 1 << 16) as f64 / 16.0),
+            5 => String(self.string()),
+            6 => PrefixExp(Box::new(Var(Box::new(self.name())))),
+            _ => VarArg,
+        }
+    }
+
+    fn exp(&mut self, scope: Scope) -> ASTNode {
+        self.nested(|this| this.compound_exp(scope))
+    }
+
+    fn compound_exp(&mut self, scope: Scope) -> ASTNode {
+        if !self.grow() {
+            return self.leaf(scope);
+        }
+        match self.g.gen_range(0, 9) {
+            0 | 1 => {
+                let op = *self.g.choose(BINOPS).unwrap();
+                let (a, b) = (self.exp(scope), self.exp(scope));
+                make_binop(op, a, b)
+            }
+            2 => {
+                let a = Box::new(self.exp(scope));
+                match self.g.gen_range(0, 4) {
+                    0 => Not(a),
+                    1 => UMin(a),
+                    2 => Len(a),
+                    _ => BinNot(a),
+                }
+            }
+            3 => PrefixExp(Box::new(self.exp(scope))),
+            4 => PrefixExp(Box::new(self.var(scope))),
+            5 => PrefixExp(Box::new(self.call(scope))),
+            6 => self.table(scope),
+            7 => Function(Box::new(self.body())),
+            _ => self.leaf(scope),
+        }
+    }
+
+    fn exps(&mut self, scope: Scope, min: usize, max: usize) -> ASTNode {
+        let len = self.count(min, max);
+        ExpList((0..len).map(|_| self.exp(scope)).collect())
+    }
+
+    fn prefix(&mut self, scope: Scope) -> ASTNode {
+        if self.grow() && self.g.gen() {
+            PrefixExp(Box::new(self.var(scope)))
+        } else {
+            PrefixExp(Box::new(Var(Box::new(self.name()))))
+        }
+    }
+
+    fn var(&mut self, scope: Scope) -> ASTNode {
+        if !self.grow() {
+            return Var(Box::new(self.name()));
+        }
+        match self.g.gen_range(0, 3) {
+            0 => Var(Box::new(self.name())),
+            1 => VarListAccess(Box::new(self.prefix(scope)), Box::new(self.name())),
+            _ => VarPrefixed(Box::new(self.prefix(scope)), Box::new(self.exp(scope))),
+        }
+    }
+
+    fn call(&mut self, scope: Scope) -> ASTNode {
+        let prefix = Box::new(self.prefix(scope));
+        let args = match self.g.gen_range(0, 4) {
+            0 => None,
+            1 => Some(ExpList(vec![String(self.string())])),
+            2 => Some(ExpList(vec![self.table(scope)])),
+            _ => Some(self.exps(scope, 1, 3)),
+        };
+        if self.g.gen_weighted_bool(3) {
+            MethodCall(prefix, Box::new(self.name()), Box::new(args))
+        } else {
+            FunctionCall(prefix, Box::new(args))
+        }
+    }
+
+    fn table(&mut self, scope: Scope) -> ASTNode {
+        let len = self.count(0, 3);
+        let fields: Vec<_> = (0..len).map(|_| match self.g.gen_range(0, 3) {
+            0 => FieldSingle(Box::new(self.exp(scope))),
+            1 => FieldAssign(Box::new(self.name()), Box::new(self.exp(scope))),
+            _ => FieldAssign(Box::new(self.exp(scope)), Box::new(self.exp(scope))),
+        }).collect();
+        TableConstructor(Box::new(if fields.is_empty() { None } else { Some(FieldList(fields)) }))
+    }
+
+    fn names(&mut self, min: usize, max: usize) -> ASTNode {
+        let len = self.count(min, max);
+        NameList((0..len).map(|_| (self.name(), None)).collect())
+    }
+
+    fn body(&mut self) -> ASTNode {
+        let vararg = self.g.gen();
+        let params = if self.g.gen() { Some(self.names(1, 3)) } else { None };
+        let block = self.block(Scope { in_loop: false, vararg });
+        FunctionBody(Box::new(Some(ParameterList(Box::new(params), vararg))), Box::new(block))
+    }
+
+    fn block(&mut self, scope: Scope) -> ASTNode {
+        let len = self.count(0, 4);
+        let stats = (0..len).map(|_| self.stat(scope)).collect();
+        let ret = if self.g.gen_weighted_bool(4) {
+            let exps = if self.g.gen() { Some(self.exps(scope, 1, 2)) } else { None };
+            Some(RetStat(Box::new(exps)))
+        } else {
+            None
+        };
+        Block(stats, Box::new(ret))
+    }
+
+    fn stat(&mut self, scope: Scope) -> ASTNode {
+        self.nested(|this| this.compound_stat(scope))
+    }
+
+    fn compound_stat(&mut self, scope: Scope) -> ASTNode {
+        if !self.grow() {
+            return match self.g.gen_range(0, if scope.in_loop { 3 } else { 2 }) {
+                0 => EmptyStatement,
+                1 => Local(Box::new(self.names(1, 1)), Box::new(None)),
+                _ => Break,
+            };
+        }
+        let looped = Scope { in_loop: true, ..scope };
+        match self.g.gen_range(0, if scope.in_loop { 13 } else { 12 }) {
+            0 | 1 => {
+                let len = self.g.gen_range(1, 3);
+                let vars = VarList((0..len).map(|_| self.var(scope)).collect());
+                Assign(Box::new(vars), Box::new(self.exps(scope, 1, 2)))
+            }
+            2 => {
+                let values = if self.g.gen() { Some(self.exps(scope, 1, 2)) } else { None };
+                Local(Box::new(self.names(1, 2)), Box::new(values))
+            }
+            3 => self.call(scope),
+            4 => Do(Box::new(self.block(scope))),
+            5 => While(Box::new(self.exp(scope)), Box::new(self.block(looped))),
+            6 => Repeat(Box::new(self.block(looped)), Box::new(self.exp(scope))),
+            7 => {
+                let cond = Box::new(self.exp(scope));
+                let then = Box::new(self.block(scope));
+                let otherwise = match self.g.gen_range(0, 3) {
+                    0 => None,
+                    1 => Some(self.block(scope)),
+                    // elseif
+                    _ => Some(If(Box::new(self.exp(scope)), Box::new(self.block(scope)), Box::new(None))),
+                };
+                If(cond, then, Box::new(otherwise))
+            }
+            8 => {
+                let step = if self.g.gen() { Some(self.exp(scope)) } else { None };
+                NumericFor(Box::new(self.name()), Box::new(self.exp(scope)), Box::new(self.exp(scope)),
+                           Box::new(step), Box::new(self.block(looped)))
+            }
+            9 => GenericFor(Box::new(self.names(1, 2)), Box::new(self.exps(scope, 1, 2)),
+                            Box::new(self.block(looped))),
+            10 => {
+                let len = self.g.gen_range(0, 3);
+                let fields = if len == 0 { None } else { Some((0..len).map(|_| self.name()).collect()) };
+                let method = if self.g.gen() { Some(Box::new(self.name())) } else { None };
+                let name = FunctionName(Box::new(self.name()), fields, method);
+                FunctionStat(Box::new(name), Box::new(self.body()))
+            }
+            11 => NamedFunction(Box::new(self.name()), Box::new(self.body())),
+            _ => Break,
+        }
+    }
+}
+
+// The chunk `ast` is printed as, read back
+fn reparse_block(ast: &ASTNode) -> Option<ASTNode> {
+    parse_block_with(printer::print(ast).as_bytes(), &config()).ok()
+}
+
+fn reparse_exp(ast: &ASTNode) -> Option<ASTNode> {
+    parse_chunk_with(printer::print(ast).as_bytes(), &config()).ok()
+}
+
+fn reparse_stat(ast: &ASTNode) -> Option<ASTNode> {
+    match reparse_block(&Block(vec![ast.clone()], Box::new(None))) {
+        Some(Block(ref mut stats, _)) if stats.len() == 1 => stats.pop(),
+        _ => None,
+    }
+}
+
+// Whether `ast` is a chunk `luac` accepts and reads back unchanged
+fn valid_block(ast: &ASTNode) -> bool {
+    let source = printer::print(ast);
+    match parse_block_with(source.as_bytes(), &config()) {
+        Ok(ref back) if back == ast => {
+            validate(source.as_bytes(), &Arena::from_ast(ast), LuaVersion::Lua53).is_empty()
+        }
+        _ => false,
+    }
+}
+
+fn valid_exp(ast: &ASTNode) -> bool {
+    let ret = RetStat(Box::new(Some(ExpList(vec![ast.clone()]))));
+    reparse_exp(ast).as_ref() == Some(ast) && valid_block(&Block(vec![], Box::new(Some(ret))))
+}
+
+fn valid_stat(ast: &ASTNode) -> bool {
+    valid_block(&Block(vec![ast.clone()], Box::new(None)))
+}
+
+fn is_exp(node: &ASTNode) -> bool {
+    match *node {
+        Nil | Bool(_) | Integer(_) | Float(_) | String(_) | VarArg | PrefixExp(_) |
+        TableConstructor(_) | Function(_) | Not(_) | UMin(_) | Len(_) | BinNot(_) => true,
+        _ => node.as_binop().is_some(),
+    }
+}
+
+// Calls `f` on the node at pre-order position `index`, counting it down
+fn visit<F: FnMut(&mut ASTNode)>(node: &mut ASTNode, index: &mut usize, f: &mut F) -> bool {
+    if *index == 0 {
+        f(node);
+        return true;
+    }
+    *index -= 1;
+    node.children_mut().into_iter().any(|child| visit(child, index, f))
+}
+
+// The nodes that can replace `node` in a smaller tree
+fn replacements(node: &ASTNode) -> Vec<ASTNode> {
+    let mut out = Vec::new();
+    match *node {
+        Block(ref stats, ref ret) => {
+            for i in 0..stats.len() {
+                let mut rest = stats.clone();
+                let mut stat = rest.remove(i);
+                out.push(Block(rest.clone(), ret.clone()));
+                for child in stat.children_mut() {
+                    if let Block(ref inner, _) = *child {
+                        let mut spliced = rest.clone();
+                        spliced.splice(i..i, inner.iter().cloned());
+                        out.push(Block(spliced, ret.clone()));
+                    }
+                }
+            }
+            if ret.is_some() {
+                out.push(Block(stats.clone(), Box::new(None)));
+            }
+        }
+        ExpList(ref items) | VarList(ref items) | FieldList(ref items) if items.len() > 1 => {
+            for i in 0..items.len() {
+                let mut items = items.clone();
+                items.remove(i);
+                out.push(match *node {
+                    ExpList(_) => ExpList(items),
+                    VarList(_) => VarList(items),
+                    _ => FieldList(items),
+                });
+            }
+        }
+        NameList(ref names) if names.len() > 1 => {
+            for i in 0..names.len() {
+                let mut names = names.clone();
+                names.remove(i);
+                out.push(NameList(names));
+            }
+        }
+        Integer(n) => out.extend(n.shrink().map(Integer)),
+        Float(f) if f != 0.0 => out.push(Float(0.0)),
+        String(ref s) => out.extend(s.shrink().map(String)),
+        _ if is_exp(node) => {
+            let mut copy = node.clone();
+            out.extend(copy.children_mut().into_iter().filter(|c| is_exp(c)).map(|c| c.clone()));
+            if *node != Nil {
+                out.push(Nil);
+            }
+        }
+        _ => {}
+    }
+    out
+}
+
+fn size(node: &mut ASTNode) -> usize {
+    1 + node.children_mut().into_iter().map(size).sum::<usize>()
+}
+
+// Trees one step smaller than `ast`, read back through `reparse` and kept
+// when `valid`
+fn shrink(ast: &ASTNode, reparse: fn(&ASTNode) -> Option<ASTNode>, valid: fn(&ASTNode) -> bool)
+          -> Box<dyn Iterator<Item = ASTNode>> {
+    let ast = ast.clone();
+    let nodes = size(&mut ast.clone());
+    Box::new((0..nodes).flat_map(move |index| {
+        let mut edits = Vec::new();
+        visit(&mut ast.clone(), &mut { index }, &mut |node| edits = replacements(node));
+        let ast = ast.clone();
+        edits.into_iter().map(move |edit| {
+            let mut tree = ast.clone();
+            visit(&mut tree, &mut { index }, &mut |node| *node = edit.clone());
+            tree
+        })
+    }).filter_map(move |tree| reparse(&tree)).filter(valid))
+}
+
+/// Generates a chunk
+impl Arbitrary for ASTNode {
+    fn arbitrary<G: Gen>(g: &mut G) -> ASTNode {
+        let ast = Generator::new(g).block(Scope { in_loop: false, vararg: true });
+        reparse_block(&ast).unwrap_or(ast)
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = ASTNode>> {
+        shrink(self, reparse_block, valid_block)
+    }
+}
+
+impl Arbitrary for Exp {
+    fn arbitrary<G: Gen>(g: &mut G) -> Exp {
+        let ast = Generator::new(g).exp(Scope { in_loop: false, vararg: true });
+        Exp(reparse_exp(&ast).unwrap_or(ast))
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Exp>> {
+        Box::new(shrink(&self.0, reparse_exp, valid_exp).map(Exp))
+    }
+}
+
+impl Arbitrary for Stat {
+    fn arbitrary<G: Gen>(g: &mut G) -> Stat {
+        let ast = Generator::new(g).stat(Scope { in_loop: false, vararg: true });
+        Stat(reparse_stat(&ast).unwrap_or(ast))
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Stat>> {
+        Box::new(shrink(&self.0, reparse_stat, valid_stat).map(Stat))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::{Rng, StdGen};
+
+    // xorshift, so that the tests see the same trees on every run
+    struct Seeded(u64);
+
+    impl Rng for Seeded {
+        fn next_u32(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 32) as u32
+        }
+    }
+
+    fn generator(size: usize) -> StdGen<Seeded> {
+        StdGen::new(Seeded(0x2545_f491_4f6c_dd1d), size)
+    }
+
+    quickcheck! {
+        fn quickcheck_chunks_are_valid(ast: ASTNode) -> bool {
+            valid_block(&ast)
+        }
+
+        fn quickcheck_expressions_are_valid(exp: Exp) -> bool {
+            valid_exp(&exp.0)
+        }
+
+        fn quickcheck_statements_are_valid(stat: Stat) -> bool {
+            valid_stat(&stat.0)
+        }
+
+        fn quickcheck_shrinking_keeps_chunks_valid(ast: ASTNode) -> bool {
+            ast.shrink().take(20).all(|smaller| valid_block(&smaller))
+        }
+    }
+
+    #[test]
+    fn size_bounds_the_trees() {
+        let mut g = generator(0);
+        for _ in 0..20 {
+            match ASTNode::arbitrary(&mut g) {
+                Block(ref stats, _) => assert!(stats.is_empty()),
+                ast => panic!("{:?}", ast),
+            }
+        }
+        let mut g = generator(30);
+        for _ in 0..20 {
+            let ast = ASTNode::arbitrary(&mut g);
+            assert!(Arena::from_ast(&ast).len() < 400, "{:#?}", ast);
+        }
+    }
+
+    #[test]
+    fn names_are_not_keywords() {
+        let mut g = generator(10);
+        let mut generator = Generator::new(&mut g);
+        for _ in 0..1000 {
+            match generator.name() {
+                Name(ref name) => assert!(is_name(name), "{}", name),
+                name => panic!("{:?}", name),
+            }
+        }
+    }
+
+    #[test]
+    fn shrinking() {
+        let ast = parse_block_with(b"while x do if y then break end f(1 + 2, ...) end return {a = 'bc'}",
+                                   &config()).unwrap();
+        let smaller: Vec<_> = ast.shrink().collect();
+        assert!(smaller.contains(&parse_block_with(b"return {a = 'bc'}", &config()).unwrap()));
+        assert!(smaller.iter().all(valid_block));
+        // hoisting the `break` out of its loop is not valid
+        assert!(!smaller.contains(&parse_block_with(b"if y then break end return {a = 'bc'}", &config()).unwrap()));
+
+        let exp = Exp(parse_chunk_with(b"-(x + 20)", &config()).unwrap());
+        let smaller: Vec<_> = exp.shrink().map(|e| printer::print(&e.0)).collect();
+        assert!(smaller.contains(&"(x + 20)".to_string()), "{:?}", smaller);
+        assert!(smaller.contains(&"-(x + 10)".to_string()), "{:?}", smaller);
+    }
+}
@@ -0,0 +1,763 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A flat representation of the AST
+//!
+//! Every node of an `ASTNode` tree is stored once in a `Vec` and addressed by
+//! a `NodeId`. Nodes keep a link to their parent and an ordered list of their
+//! children, so a tree can be walked in both directions without cloning any
+//! subtree. Analyses can attach their own data to nodes with a `SideTable`.
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::ops::Index;
+use std::slice;
+
+use ast::{ASTNode, Attrib};
+use op::BinOp;
+
+#[cfg(feature="graphviz")]
+use dot;
+#[cfg(feature="graphviz")]
+use graphviz;
+#[cfg(feature="graphviz")]
+use std::borrow::Cow;
+
+/// Identifies a node inside an `Arena`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(usize);
+
+impl NodeId {
+    /// The position of this node in the arena, nodes are numbered in pre-order
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// The kind of a node, along with the value of leaf nodes
+///
+/// This mirrors `ASTNode`, with the children moved out into the arena.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NodeKind {
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+    String(Vec<u8>),
+    Label(String),
+    Name(String),
+    Paren,
+    Block,
+    EmptyStatement,
+    Break,
+    Goto,
+    RetStat,
+    Assign,
+    Do,
+    While,
+    Repeat,
+    If,
+    NumericFor,
+    GenericFor,
+    FunctionStat,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Exp,
+    FDiv,
+    Mod,
+    And,
+    Or,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    BitOr,
+    BitAnd,
+    BitXor,
+    Rsh,
+    Lsh,
+    BinNot,
+    Not,
+    Len,
+    UMin,
+    Concat,
+    PrefixExp,
+    FunctionCall,
+    MethodCall,
+    Nil,
+    VarArg,
+    TableConstructor,
+    Function,
+    FunctionBody,
+    FunctionName,
+    NamedFunction,
+    ExpList,
+    VarList,
+    /// Holds the attribute of each name
+    NameList(Vec<Option<Attrib>>),
+    FieldList,
+    /// Holds whether the parameter list is vararg
+    ParameterList(bool),
+    FieldSingle,
+    FieldAssign,
+    Local,
+    Var,
+    VarPrefixed,
+    VarListAccess,
+    #[cfg(feature="luau")]
+    Typed,
+    #[cfg(feature="luau")]
+    TypedFunctionBody,
+    /// Holds whether the alias is exported
+    #[cfg(feature="luau")]
+    TypeAlias(bool),
+    #[cfg(feature="luau")]
+    CompoundAssign(BinOp),
+    #[cfg(feature="luau")]
+    Continue,
+    #[cfg(feature="luau")]
+    IfExp,
+    #[cfg(feature="luau")]
+    TypeName(String),
+    #[cfg(feature="luau")]
+    TypeOptional,
+    #[cfg(feature="luau")]
+    TypeUnion,
+    #[cfg(feature="luau")]
+    TypeIntersection,
+    #[cfg(feature="luau")]
+    TypeFunction,
+    #[cfg(feature="luau")]
+    TypePack,
+    #[cfg(feature="luau")]
+    TypeTable,
+    #[cfg(feature="luau")]
+    TypeField,
+    #[cfg(feature="luau")]
+    TypeIndexer,
+    #[cfg(feature="luau")]
+    TypeOf,
+    #[cfg(feature="luau")]
+    TypeVariadic,
+}
+
+impl<'a> From<&'a ASTNode> for NodeKind {
+    fn from(node: &'a ASTNode) -> NodeKind {
+        use ast::ASTNode::*;
+        match *node {
+            Integer(a) => NodeKind::Integer(a),
+            Float(a) => NodeKind::Float(a),
+            Bool(a) => NodeKind::Bool(a),
+            String(ref a) => NodeKind::String(a.clone()),
+            Label(ref a) => NodeKind::Label(a.clone()),
+            Name(ref a) => NodeKind::Name(a.clone()),
+            Paren(_) => NodeKind::Paren,
+            Block(_, _) => NodeKind::Block,
+            EmptyStatement => NodeKind::EmptyStatement,
+            Break => NodeKind::Break,
+            Goto(_) => NodeKind::Goto,
+            RetStat(_) => NodeKind::RetStat,
+            Assign(_, _) => NodeKind::Assign,
+            Do(_) => NodeKind::Do,
+            While(_, _) => NodeKind::While,
+            Repeat(_, _) => NodeKind::Repeat,
+            If(_, _, _) => NodeKind::If,
+            NumericFor(_, _, _, _, _) => NodeKind::NumericFor,
+            GenericFor(_, _, _) => NodeKind::GenericFor,
+            FunctionStat(_, _) => NodeKind::FunctionStat,
+            Add(_, _) => NodeKind::Add,
+            Sub(_, _) => NodeKind::Sub,
+            Mul(_, _) => NodeKind::Mul,
+            Div(_, _) => NodeKind::Div,
+            Exp(_, _) => NodeKind::Exp,
+            FDiv(_, _) => NodeKind::FDiv,
+            Mod(_, _) => NodeKind::Mod,
+            And(_, _) => NodeKind::And,
+            Or(_, _) => NodeKind::Or,
+            Lt(_, _) => NodeKind::Lt,
+            Le(_, _) => NodeKind::Le,
+            Gt(_, _) => NodeKind::Gt,
+            Ge(_, _) => NodeKind::Ge,
+            Eq(_, _) => NodeKind::Eq,
+            Ne(_, _) => NodeKind::Ne,
+            BitOr(_, _) => NodeKind::BitOr,
+            BitAnd(_, _) => NodeKind::BitAnd,
+            BitXor(_, _) => NodeKind::BitXor,
+            Rsh(_, _) => NodeKind::Rsh,
+            Lsh(_, _) => NodeKind::Lsh,
+            BinNot(_) => NodeKind::BinNot,
+            Not(_) => NodeKind::Not,
+            Len(_) => NodeKind::Len,
+            UMin(_) => NodeKind::UMin,
+            Concat(_, _) => NodeKind::Concat,
+            PrefixExp(_) => NodeKind::PrefixExp,
+            FunctionCall(_, _) => NodeKind::FunctionCall,
+            MethodCall(_, _, _) => NodeKind::MethodCall,
+            Nil => NodeKind::Nil,
+            VarArg => NodeKind::VarArg,
+            TableConstructor(_) => NodeKind::TableConstructor,
+            Function(_) => NodeKind::Function,
+            FunctionBody(_, _) => NodeKind::FunctionBody,
+            FunctionName(_, _, _) => NodeKind::FunctionName,
+            NamedFunction(_, _) => NodeKind::NamedFunction,
+            ExpList(_) => NodeKind::ExpList,
+            VarList(_) => NodeKind::VarList,
+            NameList(ref a) => NodeKind::NameList(a.iter().map(|&(_, attrib)| attrib).collect()),
+            FieldList(_) => NodeKind::FieldList,
+            ParameterList(_, va) => NodeKind::ParameterList(va),
+            FieldSingle(_) => NodeKind::FieldSingle,
+            FieldAssign(_, _) => NodeKind::FieldAssign,
+            Local(_, _) => NodeKind::Local,
+            Var(_) => NodeKind::Var,
+            VarPrefixed(_, _) => NodeKind::VarPrefixed,
+            VarListAccess(_, _) => NodeKind::VarListAccess,
+            #[cfg(feature="luau")]
+            Typed(_, _) => NodeKind::Typed,
+            #[cfg(feature="luau")]
+            TypedFunctionBody(_, _, _) => NodeKind::TypedFunctionBody,
+            #[cfg(feature="luau")]
+            TypeAlias(export, _, _, _) => NodeKind::TypeAlias(export),
+            #[cfg(feature="luau")]
+            CompoundAssign(op, _, _) => NodeKind::CompoundAssign(op),
+            #[cfg(feature="luau")]
+            Continue => NodeKind::Continue,
+            #[cfg(feature="luau")]
+            IfExp(_, _, _) => NodeKind::IfExp,
+            #[cfg(feature="luau")]
+            TypeName(ref a, _) => NodeKind::TypeName(a.clone()),
+            #[cfg(feature="luau")]
+            TypeOptional(_) => NodeKind::TypeOptional,
+            #[cfg(feature="luau")]
+            TypeUnion(_) => NodeKind::TypeUnion,
+            #[cfg(feature="luau")]
+            TypeIntersection(_) => NodeKind::TypeIntersection,
+            #[cfg(feature="luau")]
+            TypeFunction(_, _) => NodeKind::TypeFunction,
+            #[cfg(feature="luau")]
+            TypePack(_) => NodeKind::TypePack,
+            #[cfg(feature="luau")]
+            TypeTable(_) => NodeKind::TypeTable,
+            #[cfg(feature="luau")]
+            TypeField(_, _) => NodeKind::TypeField,
+            #[cfg(feature="luau")]
+            TypeIndexer(_, _) => NodeKind::TypeIndexer,
+            #[cfg(feature="luau")]
+            TypeOf(_) => NodeKind::TypeOf,
+            #[cfg(feature="luau")]
+            TypeVariadic(_) => NodeKind::TypeVariadic,
+        }
+    }
+}
+
+impl NodeKind {
+    /// The operator of a binary operation
+    pub fn binop(&self) -> Option<BinOp> {
+        Some(match *self {
+            NodeKind::Exp => BinOp::Exp,
+            NodeKind::Mul => BinOp::Mul,
+            NodeKind::Div => BinOp::Div,
+            NodeKind::FDiv => BinOp::FDiv,
+            NodeKind::Mod => BinOp::Mod,
+            NodeKind::Add => BinOp::Add,
+            NodeKind::Sub => BinOp::Sub,
+            NodeKind::Concat => BinOp::Concat,
+            NodeKind::Lsh => BinOp::Lsh,
+            NodeKind::Rsh => BinOp::Rsh,
+            NodeKind::BitAnd => BinOp::BitAnd,
+            NodeKind::BitXor => BinOp::BitXor,
+            NodeKind::BitOr => BinOp::BitOr,
+            NodeKind::Lt => BinOp::Lt,
+            NodeKind::Gt => BinOp::Gt,
+            NodeKind::Le => BinOp::Le,
+            NodeKind::Ge => BinOp::Ge,
+            NodeKind::Ne => BinOp::Ne,
+            NodeKind::Eq => BinOp::Eq,
+            NodeKind::And => BinOp::And,
+            NodeKind::Or => BinOp::Or,
+            _ => return None,
+        })
+    }
+}
+
+impl Display for NodeKind {
+    fn fmt(&self, format: &mut Formatter) -> fmt::Result {
+        match *self {
+            NodeKind::Integer(a) => write!(format, "Integer_{}_", a),
+            NodeKind::Float(a) => write!(format, "Float_{}_", a),
+            NodeKind::Bool(a) => write!(format, "Bool_{}_", a),
+            NodeKind::String(ref a) => write!(format, "String_{}_", ::std::string::String::from_utf8_lossy(a)),
+            NodeKind::Label(ref a) => write!(format, "Label_{}_", a),
+            NodeKind::Name(ref a) => write!(format, "Name_{}_", a),
+            NodeKind::NameList(_) => write!(format, "NameList"),
+            #[cfg(feature="luau")]
+            NodeKind::TypeAlias(_) => write!(format, "TypeAlias"),
+            #[cfg(feature="luau")]
+            NodeKind::CompoundAssign(_) => write!(format, "CompoundAssign"),
+            #[cfg(feature="luau")]
+            NodeKind::TypeName(ref a) => write!(format, "TypeName_{}_", a),
+            NodeKind::ParameterList(_) => write!(format, "ParameterList"),
+            ref kind => write!(format, "{:?}", kind),
+        }
+    }
+}
+
+/// A node stored in an `Arena`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Node {
+    pub kind: NodeKind,
+    pub parent: Option<NodeId>,
+    /// The children of this node in source order, paired with the name of
+    /// the field they were stored in. See `ASTNode::children`.
+    pub children: Vec<(&'static str, NodeId)>,
+}
+
+/// An AST stored as a flat list of nodes
+#[derive(Clone, Debug, PartialEq)]
+pub struct Arena {
+    nodes: Vec<Node>,
+}
+
+impl Arena {
+    /// Flattens `ast` into a new arena, its root gets the first `NodeId`
+    pub fn from_ast(ast: &ASTNode) -> Arena {
+        let mut arena = Arena { nodes: Vec::new() };
+        arena.insert(ast, None);
+        arena
+    }
+
+    fn insert(&mut self, ast: &ASTNode, parent: Option<NodeId>) -> NodeId {
+        let id = NodeId(self.nodes.len());
+        self.nodes.push(Node {
+            kind: NodeKind::from(ast),
+            parent,
+            children: Vec::new(),
+        });
+        for (field, child) in ast.children() {
+            let child_id = self.insert(child, Some(id));
+            self.nodes[id.0].children.push((field, child_id));
+        }
+        id
+    }
+
+    pub fn root(&self) -> NodeId {
+        NodeId(0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn get(&self, id: NodeId) -> Option<&Node> {
+        self.nodes.get(id.0)
+    }
+
+    pub fn kind(&self, id: NodeId) -> &NodeKind {
+        &self[id].kind
+    }
+
+    pub fn parent(&self, id: NodeId) -> Option<NodeId> {
+        self[id].parent
+    }
+
+    /// Iterates over the ids of the children of `id`, in source order
+    pub fn children(&self, id: NodeId) -> Children<'_> {
+        Children { inner: self[id].children.iter() }
+    }
+
+    /// Returns the first child of `id` stored in `field`
+    pub fn child(&self, id: NodeId, field: &str) -> Option<NodeId> {
+        self[id].children.iter()
+            .find(|&&(f, _)| f == field)
+            .map(|&(_, c)| c)
+    }
+
+    /// Iterates over the parents of `id`, starting with its direct parent
+    pub fn ancestors(&self, id: NodeId) -> Ancestors<'_> {
+        Ancestors { arena: self, next: self.parent(id) }
+    }
+
+    /// Iterates over every id in the arena, parents always come before
+    /// their children
+    pub fn ids(&self) -> Ids {
+        Ids { next: 0, len: self.nodes.len() }
+    }
+
+    /// Rebuilds the `ASTNode` tree rooted at `id`
+    pub fn to_ast(&self, id: NodeId) -> ASTNode {
+        use ast::ASTNode::*;
+        let child = |field| Box::new(self.to_ast(self.child(id, field).unwrap()));
+        let opt_child = |field| Box::new(self.child(id, field).map(|c| self.to_ast(c)));
+        let list = |field| self[id].children.iter()
+            .filter(|&&(f, _)| f == field)
+            .map(|&(_, c)| self.to_ast(c))
+            .collect::<Vec<_>>();
+
+        match *self.kind(id) {
+            NodeKind::Integer(a) => Integer(a),
+            NodeKind::Float(a) => Float(a),
+            NodeKind::Bool(a) => Bool(a),
+            NodeKind::String(ref a) => String(a.clone()),
+            NodeKind::Label(ref a) => Label(a.clone()),
+            NodeKind::Name(ref a) => Name(a.clone()),
+            NodeKind::Paren => Paren(child("exp")),
+            NodeKind::Block => Block(list("stat"), opt_child("ret")),
+            NodeKind::EmptyStatement => EmptyStatement,
+            NodeKind::Break => Break,
+            NodeKind::Goto => Goto(child("label")),
+            NodeKind::RetStat => RetStat(opt_child("explist")),
+            NodeKind::Assign => Assign(child("vars"), child("exps")),
+            NodeKind::Do => Do(child("block")),
+            NodeKind::While => While(child("cond"), child("block")),
+            NodeKind::Repeat => Repeat(child("block"), child("cond")),
+            NodeKind::If => If(child("cond"), child("block"), opt_child("else")),
+            NodeKind::NumericFor => NumericFor(child("var"), child("start"), child("limit"),
+                                               opt_child("step"), child("block")),
+            NodeKind::GenericFor => GenericFor(child("names"), child("exps"), child("block")),
+            NodeKind::FunctionStat => FunctionStat(child("name"), child("body")),
+            NodeKind::Add => Add(child("left"), child("right")),
+            NodeKind::Sub => Sub(child("left"), child("right")),
+            NodeKind::Mul => Mul(child("left"), child("right")),
+            NodeKind::Div => Div(child("left"), child("right")),
+            NodeKind::Exp => Exp(child("left"), child("right")),
+            NodeKind::FDiv => FDiv(child("left"), child("right")),
+            NodeKind::Mod => Mod(child("left"), child("right")),
+            NodeKind::And => And(child("left"), child("right")),
+            NodeKind::Or => Or(child("left"), child("right")),
+            NodeKind::Lt => Lt(child("left"), child("right")),
+            NodeKind::Le => Le(child("left"), child("right")),
+            NodeKind::Gt => Gt(child("left"), child("right")),
+            NodeKind::Ge => Ge(child("left"), child("right")),
+            NodeKind::Eq => Eq(child("left"), child("right")),
+            NodeKind::Ne => Ne(child("left"), child("right")),
+            NodeKind::BitOr => BitOr(child("left"), child("right")),
+            NodeKind::BitAnd => BitAnd(child("left"), child("right")),
+            NodeKind::BitXor => BitXor(child("left"), child("right")),
+            NodeKind::Rsh => Rsh(child("left"), child("right")),
+            NodeKind::Lsh => Lsh(child("left"), child("right")),
+            NodeKind::BinNot => BinNot(child("operand")),
+            NodeKind::Not => Not(child("operand")),
+            NodeKind::Len => Len(child("operand")),
+            NodeKind::UMin => UMin(child("operand")),
+            NodeKind::Concat => Concat(child("left"), child("right")),
+            NodeKind::PrefixExp => PrefixExp(child("exp")),
+            NodeKind::FunctionCall => FunctionCall(child("prefix"), opt_child("args")),
+            NodeKind::MethodCall => MethodCall(child("prefix"), child("name"), opt_child("args")),
+            NodeKind::Nil => Nil,
+            NodeKind::VarArg => VarArg,
+            NodeKind::TableConstructor => TableConstructor(opt_child("fields")),
+            NodeKind::Function => Function(child("body")),
+            NodeKind::FunctionBody => FunctionBody(opt_child("params"), child("block")),
+            NodeKind::FunctionName => {
+                let fields = list("field");
+                FunctionName(child("name"),
+                             if fields.is_empty() { None } else { Some(fields) },
+                             self.child(id, "method").map(|c| Box::new(self.to_ast(c))))
+            },
+            NodeKind::NamedFunction => NamedFunction(child("name"), child("body")),
+            NodeKind::ExpList => ExpList(list("item")),
+            NodeKind::VarList => VarList(list("item")),
+            NodeKind::NameList(ref attribs) =>
+                NameList(list("item").into_iter().zip(attribs.iter().cloned()).collect()),
+            NodeKind::FieldList => FieldList(list("item")),
+            NodeKind::ParameterList(va) => ParameterList(opt_child("names"), va),
+            NodeKind::FieldSingle => FieldSingle(child("value")),
+            NodeKind::FieldAssign => FieldAssign(child("key"), child("value")),
+            NodeKind::Local => Local(child("names"), opt_child("values")),
+            NodeKind::Var => Var(child("name")),
+            NodeKind::VarPrefixed => VarPrefixed(child("prefix"), child("index")),
+            NodeKind::VarListAccess => VarListAccess(child("prefix"), child("name")),
+            #[cfg(feature="luau")]
+            NodeKind::Typed => Typed(child("name"), child("type")),
+            #[cfg(feature="luau")]
+            NodeKind::TypedFunctionBody =>
+                TypedFunctionBody(opt_child("params"), child("returns"), child("block")),
+            #[cfg(feature="luau")]
+            NodeKind::TypeAlias(export) =>
+                TypeAlias(export, child("name"), list("generic"), child("type")),
+            #[cfg(feature="luau")]
+            NodeKind::CompoundAssign(op) => CompoundAssign(op, child("var"), child("exp")),
+            #[cfg(feature="luau")]
+            NodeKind::Continue => Continue,
+            #[cfg(feature="luau")]
+            NodeKind::IfExp => IfExp(child("cond"), child("then"), child("else")),
+            #[cfg(feature="luau")]
+            NodeKind::TypeName(ref a) => TypeName(a.clone(), list("arg")),
+            #[cfg(feature="luau")]
+            NodeKind::TypeOptional => TypeOptional(child("type")),
+            #[cfg(feature="luau")]
+            NodeKind::TypeUnion => TypeUnion(list("item")),
+            #[cfg(feature="luau")]
+            NodeKind::TypeIntersection => TypeIntersection(list("item")),
+            #[cfg(feature="luau")]
+            NodeKind::TypeFunction => TypeFunction(list("param"), child("returns")),
+            #[cfg(feature="luau")]
+            NodeKind::TypePack => TypePack(list("item")),
+            #[cfg(feature="luau")]
+            NodeKind::TypeTable => TypeTable(list("item")),
+            #[cfg(feature="luau")]
+            NodeKind::TypeField => TypeField(child("name"), child("type")),
+            #[cfg(feature="luau")]
+            NodeKind::TypeIndexer => TypeIndexer(child("key"), child("value")),
+            #[cfg(feature="luau")]
+            NodeKind::TypeOf => TypeOf(child("exp")),
+            #[cfg(feature="luau")]
+            NodeKind::TypeVariadic => TypeVariadic(child("type")),
+        }
+    }
+}
+
+impl Index<NodeId> for Arena {
+    type Output = Node;
+
+    fn index(&self, id: NodeId) -> &Node {
+        &self.nodes[id.0]
+    }
+}
+
+impl<'a> From<&'a ASTNode> for Arena {
+    fn from(ast: &'a ASTNode) -> Arena {
+        Arena::from_ast(ast)
+    }
+}
+
+pub struct Children<'a> {
+    inner: slice::Iter<'a, (&'static str, NodeId)>,
+}
+
+impl<'a> Iterator for Children<'a> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        self.inner.next().map(|&(_, id)| id)
+    }
+}
+
+pub struct Ancestors<'a> {
+    arena: &'a Arena,
+    next: Option<NodeId>,
+}
+
+impl<'a> Iterator for Ancestors<'a> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        let current = self.next;
+        self.next = current.and_then(|id| self.arena.parent(id));
+        current
+    }
+}
+
+pub struct Ids {
+    next: usize,
+    len: usize,
+}
+
+impl Iterator for Ids {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        if self.next < self.len {
+            self.next += 1;
+            Some(NodeId(self.next - 1))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(feature="graphviz")]
+type Edge = (NodeId, NodeId);
+
+#[cfg(feature="graphviz")]
+impl<'a> dot::Labeller<'a, NodeId, Edge> for Arena {
+    fn graph_id(&'a self) -> dot::Id<'a> {
+        dot::Id::new("AST").unwrap()
+    }
+
+    fn node_id(&'a self, node: &NodeId) -> dot::Id<'a> {
+        dot::Id::new(format!("N{}", node.0)).unwrap()
+    }
+
+    // EscStr leaves backslashes to Graphviz, which reads `\\` as one
+    fn node_label(&'a self, node: &NodeId) -> dot::LabelText<'a> {
+        dot::LabelText::EscStr(graphviz::label(self.kind(*node)).replace('\\', "\\\\").into())
+    }
+
+    fn edge_label(&'a self, edge: &Edge) -> dot::LabelText<'a> {
+        let field = self[edge.0].children.iter()
+            .find(|&&(_, c)| c == edge.1)
+            .map_or("", |&(f, _)| f);
+        dot::LabelText::LabelStr(graphviz::edge_label(self, edge.0, field, edge.1).into())
+    }
+}
+
+#[cfg(feature="graphviz")]
+impl<'a> dot::GraphWalk<'a, NodeId, Edge> for Arena {
+    fn nodes(&'a self) -> dot::Nodes<'a, NodeId> {
+        Cow::Owned(self.ids().collect())
+    }
+
+    fn edges(&'a self) -> dot::Edges<'a, Edge> {
+        Cow::Owned(self.ids()
+                   .flat_map(|id| self.children(id).map(move |c| (id, c)))
+                   .collect())
+    }
+
+    fn source(&self, e: &Edge) -> NodeId { e.0 }
+
+    fn target(&self, e: &Edge) -> NodeId { e.1 }
+}
+
+/// Data attached to the nodes of an `Arena`, keyed by `NodeId`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SideTable<T> {
+    entries: Vec<Option<T>>,
+}
+
+impl<T> SideTable<T> {
+    pub fn new() -> SideTable<T> {
+        SideTable { entries: Vec::new() }
+    }
+
+    /// Sets the value for `id`, returning the previous one if any
+    pub fn insert(&mut self, id: NodeId, value: T) -> Option<T> {
+        if id.0 >= self.entries.len() {
+            let len = id.0 + 1;
+            self.entries.resize_with(len, || None);
+        }
+        self.entries[id.0].replace(value)
+    }
+
+    pub fn get(&self, id: NodeId) -> Option<&T> {
+        self.entries.get(id.0).and_then(|e| e.as_ref())
+    }
+
+    pub fn get_mut(&mut self, id: NodeId) -> Option<&mut T> {
+        self.entries.get_mut(id.0).and_then(|e| e.as_mut())
+    }
+
+    pub fn remove(&mut self, id: NodeId) -> Option<T> {
+        self.entries.get_mut(id.0).and_then(|e| e.take())
+    }
+
+    /// Iterates over the nodes that have a value, in `NodeId` order
+    pub fn iter(&self) -> impl Iterator<Item = (NodeId, &T)> {
+        self.entries.iter()
+            .enumerate()
+            .filter_map(|(i, e)| e.as_ref().map(|e| (NodeId(i), e)))
+    }
+}
+
+impl<T> Default for SideTable<T> {
+    fn default() -> SideTable<T> {
+        SideTable::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast::ASTNode::*;
+    use exp::parse_exp;
+    use function::parse_block;
+
+    fn add(left: ASTNode, right: ASTNode) -> ASTNode {
+        astb!(Add, left, right)
+    }
+
+    #[test]
+    fn arena_preorder() {
+        let a = Arena::from_ast(&add(ast!(Integer, 1), ast!(Integer, 2)));
+        assert_eq!(a.len(), 3);
+        assert_eq!(*a.kind(a.root()), NodeKind::Add);
+        let children: Vec<_> = a.children(a.root()).collect();
+        assert_eq!(children, vec![NodeId(1), NodeId(2)]);
+        assert_eq!(*a.kind(children[0]), NodeKind::Integer(1));
+        assert_eq!(*a.kind(children[1]), NodeKind::Integer(2));
+        assert_eq!(a.child(a.root(), "right"), Some(NodeId(2)));
+    }
+
+    #[test]
+    fn arena_parents() {
+        let a = Arena::from_ast(&add(ast!(Integer, 1), astb!(Mul, ast!(Integer, 2), ast!(Integer, 3))));
+        assert_eq!(a.parent(a.root()), None);
+        for id in a.ids().skip(1) {
+            let parent = a.parent(id).unwrap();
+            assert!(a.children(parent).any(|c| c == id));
+        }
+        let leaf = a.ids().last().unwrap();
+        let ancestors: Vec<_> = a.ancestors(leaf).collect();
+        assert_eq!(ancestors, vec![NodeId(2), a.root()]);
+    }
+
+    #[test]
+    fn arena_identical_leaves_are_distinct() {
+        let a = Arena::from_ast(&add(ast!(Integer, 1), ast!(Integer, 1)));
+        let children: Vec<_> = a.children(a.root()).collect();
+        assert_ne!(children[0], children[1]);
+        assert_eq!(a.kind(children[0]), a.kind(children[1]));
+    }
+
+    #[test]
+    fn arena_roundtrip() {
+        for input in &["1 + 2 * 3", "function (a, b, ...) ; end"] {
+            let ast = parse_exp(input.as_bytes()).unwrap().1;
+            assert_eq!(Arena::from_ast(&ast).to_ast(NodeId(0)), ast);
+        }
+
+        let ast = parse_block(b"::a:: goto a ; return 1, 2").unwrap().1;
+        assert_eq!(Arena::from_ast(&ast).to_ast(NodeId(0)), ast);
+
+        let ast = ast!(TableConstructor, Box::new(Some(ast!(FieldList, vec![
+            astb!(FieldAssign, ast!(Name, "a".into()), ast!(Nil)),
+            astb!(FieldSingle, ast!(Bool, true))
+        ]))));
+        assert_eq!(Arena::from_ast(&ast).to_ast(NodeId(0)), ast);
+
+        let ast = astb!(Local, ast!(NameList, vec![
+            (ast!(Name, "a".into()), Some(Attrib::Close)),
+            (ast!(Name, "b".into()), None)
+        ]), Some(ast!(ExpList, vec![ast!(Nil)])));
+        assert_eq!(Arena::from_ast(&ast).to_ast(NodeId(0)), ast);
+
+        let ast = ast!(FunctionName,
+                       Box::new(ast!(Name, "a".into())),
+                       Some(vec![ast!(Name, "b".into())]),
+                       Some(Box::new(ast!(Name, "c".into()))));
+        assert_eq!(Arena::from_ast(&ast).to_ast(NodeId(0)), ast);
+    }
+
+    #[cfg(feature="luau")]
+    #[test]
+    fn arena_roundtrip_luau() {
+        let input = "export type T<K> = { [K]: (number?, ...string) -> () | A & B }
+                     local f: typeof(g) continue a ..= if b then c else d";
+        let ast = parse_block(input.as_bytes()).unwrap().1;
+        assert_eq!(Arena::from_ast(&ast).to_ast(NodeId(0)), ast);
+    }
+
+    #[test]
+    fn side_table() {
+        let a = Arena::from_ast(&add(ast!(Integer, 1), ast!(Integer, 2)));
+        let mut depth = SideTable::new();
+        for id in a.ids() {
+            depth.insert(id, a.ancestors(id).count());
+        }
+        assert_eq!(depth.get(a.root()), Some(&0));
+        assert_eq!(depth.get(NodeId(2)), Some(&1));
+        assert_eq!(depth.get(NodeId(3)), None);
+        assert_eq!(depth.iter().count(), 3);
+    }
+}
@@ -7,15 +7,41 @@
 // except according to those terms.
 
 use std::fmt;
+use std::mem;
 use std::fmt::{Debug, Display, Formatter};
 
+use op::BinOp;
+use sexp;
+
+/// A Lua 5.4 attribute of a local variable
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+pub enum Attrib {
+    /// `<const>`, the variable can not be assigned to
+    Const,
+    /// `<close>`, the value is closed when the variable goes out of scope
+    Close,
+}
+
+impl Display for Attrib {
+    fn fmt(&self, format: &mut Formatter) -> fmt::Result {
+        match *self {
+            Attrib::Const => write!(format, "<const>"),
+            Attrib::Close => write!(format, "<close>"),
+        }
+    }
+}
+
 #[derive(Clone, PartialEq)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature="serde", serde(tag = "type", content = "value"))]
 pub enum ASTNode {
     // TODO: Should this be u64?
     Integer(i64),
-    Float(f64),
+    Float(#[cfg_attr(feature="serde", serde(with = "::json::float"))] f64),
     Bool(bool),
-    String(String),
+    /// The bytes of a string literal, which need not be UTF-8
+    String(#[cfg_attr(feature="serde", serde(with = "::json::bytes"))] Vec<u8>),
     Label(String),
     Name(String),
     Paren(Box<ASTNode>),
@@ -27,6 +53,24 @@ pub enum ASTNode {
     Break,
     Goto(Box<ASTNode>),
     RetStat(Box<Option<ASTNode>>),
+    /// Takes a VarList and an ExpList
+    Assign(Box<ASTNode>, Box<ASTNode>),
+    /// Takes a Block
+    Do(Box<ASTNode>),
+    /// Takes a condition and a Block
+    While(Box<ASTNode>, Box<ASTNode>),
+    /// Takes a Block and a condition, which can see the locals of the Block
+    Repeat(Box<ASTNode>, Box<ASTNode>),
+    /// Takes a condition, a Block and the else branch, which is a Block or
+    /// an If for elseif
+    If(Box<ASTNode>, Box<ASTNode>, Box<Option<ASTNode>>),
+    /// Takes the Name of the variable, the start, the limit, the step and a
+    /// Block
+    NumericFor(Box<ASTNode>, Box<ASTNode>, Box<ASTNode>, Box<Option<ASTNode>>, Box<ASTNode>),
+    /// Takes a NameList, an ExpList and a Block
+    GenericFor(Box<ASTNode>, Box<ASTNode>, Box<ASTNode>),
+    /// Takes a FunctionName and a FunctionBody
+    FunctionStat(Box<ASTNode>, Box<ASTNode>),
 
     // ArithmeticOps
     Add(Box<ASTNode>, Box<ASTNode>),
@@ -71,6 +115,11 @@ pub enum ASTNode {
     /// FunctionCall
     /// Exp
     PrefixExp(Box<ASTNode>),
+    /// Takes a prefixexp and the arguments, a string or table argument is
+    /// an ExpList with one element
+    FunctionCall(Box<ASTNode>, Box<Option<ASTNode>>),
+    /// Takes a prefixexp, the Name of the method and the arguments
+    MethodCall(Box<ASTNode>, Box<ASTNode>, Box<Option<ASTNode>>),
 
     Nil,
     VarArg,
@@ -88,13 +137,15 @@ pub enum ASTNode {
     /// Name ms
     /// Name al
     FunctionName(Box<ASTNode>, Option<Vec<ASTNode>>, Option<Box<ASTNode>>),
-    /// Takes a Name and a FunctionBody
+    /// A local function, takes a Name and a FunctionBody
     NamedFunction(Box<ASTNode>, Box<ASTNode>),
 
     // Lists
     ExpList(Vec<ASTNode>),
     VarList(Vec<ASTNode>),
-    NameList(Vec<ASTNode>),
+    /// Names paired with their attribute, only local declarations have
+    /// attributes
+    NameList(Vec<(ASTNode, Option<Attrib>)>),
     FieldList(Vec<ASTNode>),
     /// Takes a list of parameters and is vararg
     ParameterList(Box<Option<ASTNode>>, bool),
@@ -107,7 +158,8 @@ pub enum ASTNode {
     FieldAssign(Box<ASTNode>, Box<ASTNode>),
 
     // Local
-    Local(Box<ASTNode>),
+    /// Takes a NameList and the ExpList assigned to it
+    Local(Box<ASTNode>, Box<Option<ASTNode>>),
 
     // Var
     /// Takes a Name
@@ -116,150 +168,578 @@ pub enum ASTNode {
     VarPrefixed(Box<ASTNode>, Box<ASTNode>),
     /// Takes a prefixexp and a Name
     VarListAccess(Box<ASTNode>, Box<ASTNode>),
+
+    // Luau
+    /// Takes a Name and its type
+    #[cfg(feature="luau")]
+    Typed(Box<ASTNode>, Box<ASTNode>),
+    /// A FunctionBody with a return type, takes a ParameterList, the return
+    /// type and a Block
+    #[cfg(feature="luau")]
+    TypedFunctionBody(Box<Option<ASTNode>>, Box<ASTNode>, Box<ASTNode>),
+    /// Takes whether the alias is exported, its Name, the Names of its
+    /// generic parameters and the aliased type
+    #[cfg(feature="luau")]
+    TypeAlias(bool, Box<ASTNode>, Vec<ASTNode>, Box<ASTNode>),
+    /// Takes the operator, a Var and the expr, `a += 1` is `a = a + 1`
+    #[cfg(feature="luau")]
+    CompoundAssign(BinOp, Box<ASTNode>, Box<ASTNode>),
+    #[cfg(feature="luau")]
+    Continue,
+    /// Takes the condition, the expr when true and the expr when false, an
+    /// elseif is an IfExp in the last position
+    #[cfg(feature="luau")]
+    IfExp(Box<ASTNode>, Box<ASTNode>, Box<ASTNode>),
+
+    // Luau types
+    /// A type name, qualified by its module as in `M.T`, and its generic
+    /// arguments. The singleton types are String and Bool nodes.
+    #[cfg(feature="luau")]
+    TypeName(String, Vec<ASTNode>),
+    /// `T?`
+    #[cfg(feature="luau")]
+    TypeOptional(Box<ASTNode>),
+    /// `A | B`
+    #[cfg(feature="luau")]
+    TypeUnion(Vec<ASTNode>),
+    /// `A & B`
+    #[cfg(feature="luau")]
+    TypeIntersection(Vec<ASTNode>),
+    /// Takes the parameter types, which may be Typed with their name, and
+    /// the return type
+    #[cfg(feature="luau")]
+    TypeFunction(Vec<ASTNode>, Box<ASTNode>),
+    /// The types of multiple return values, `(A, B)`
+    #[cfg(feature="luau")]
+    TypePack(Vec<ASTNode>),
+    /// Takes a list of TypeField and TypeIndexer, `{T}` has a single number
+    /// indexer
+    #[cfg(feature="luau")]
+    TypeTable(Vec<ASTNode>),
+    /// Takes a Name and its type
+    #[cfg(feature="luau")]
+    TypeField(Box<ASTNode>, Box<ASTNode>),
+    /// Takes the key type and the value type
+    #[cfg(feature="luau")]
+    TypeIndexer(Box<ASTNode>, Box<ASTNode>),
+    /// `typeof(exp)`
+    #[cfg(feature="luau")]
+    TypeOf(Box<ASTNode>),
+    /// `...T`
+    #[cfg(feature="luau")]
+    TypeVariadic(Box<ASTNode>),
 }
 
+/// Writes the node as an S-expression, see `sexp`
+///
+/// `{:?}` writes it on a single line, `{:#?}` in the layout of `sexp::print`.
 impl Debug for ASTNode {
     fn fmt(&self, format: &mut Formatter) -> fmt::Result {
+        if format.alternate() {
+            format.write_str(sexp::print(self).trim_end())
+        } else {
+            format.write_str(&sexp::print_flat(self))
+        }
+    }
+}
+
+impl ASTNode {
+    /// Splits a binary operation into its operator and operands
+    pub fn as_binop(&self) -> Option<(BinOp, &ASTNode, &ASTNode)> {
         use self::ASTNode::*;
+
+        let (op, a, b) = match *self {
+            Exp(ref a, ref b) => (BinOp::Exp, a, b),
+            Mul(ref a, ref b) => (BinOp::Mul, a, b),
+            Div(ref a, ref b) => (BinOp::Div, a, b),
+            FDiv(ref a, ref b) => (BinOp::FDiv, a, b),
+            Mod(ref a, ref b) => (BinOp::Mod, a, b),
+            Add(ref a, ref b) => (BinOp::Add, a, b),
+            Sub(ref a, ref b) => (BinOp::Sub, a, b),
+            Concat(ref a, ref b) => (BinOp::Concat, a, b),
+            Lsh(ref a, ref b) => (BinOp::Lsh, a, b),
+            Rsh(ref a, ref b) => (BinOp::Rsh, a, b),
+            BitAnd(ref a, ref b) => (BinOp::BitAnd, a, b),
+            BitXor(ref a, ref b) => (BinOp::BitXor, a, b),
+            BitOr(ref a, ref b) => (BinOp::BitOr, a, b),
+            Lt(ref a, ref b) => (BinOp::Lt, a, b),
+            Gt(ref a, ref b) => (BinOp::Gt, a, b),
+            Le(ref a, ref b) => (BinOp::Le, a, b),
+            Ge(ref a, ref b) => (BinOp::Ge, a, b),
+            Ne(ref a, ref b) => (BinOp::Ne, a, b),
+            Eq(ref a, ref b) => (BinOp::Eq, a, b),
+            And(ref a, ref b) => (BinOp::And, a, b),
+            Or(ref a, ref b) => (BinOp::Or, a, b),
+            _ => return None,
+        };
+        Some((op, a, b))
+    }
+
+    /// Returns the direct children of this node, in source order, each paired
+    /// with the name of the field that holds it.
+    ///
+    /// List variants repeat the same field name once per element, and absent
+    /// optional fields are skipped.
+    pub fn children(&self) -> Vec<(&'static str, &ASTNode)> {
+        use self::ASTNode::*;
+        let mut children = Vec::new();
         match *self {
-            Integer(val) => write!(format, "{}", val),
-            Float(val) => write!(format, "{}f", val),
-            Bool(val) => write!(format, "{}", val),
-            String(ref val) => write!(format, "\"{}\"", val),
-
-            // Holds a lua name, usually a function or variable name
-            // Contains `ASTNode::String`
-            Name(ref val) => write!(format, "(name {})", val),
-
-            // Holds a lua label name
-            // Contains `ASTNode::Name`
-            Label(ref val) => write!(format, "::{}::", val),
-            // Contains an expression
-            Paren(ref expr) => write!(format, "({})", expr),
-
-            // Block
-            Block(ref statements, ref retstat) => {
-                write!(format, "(block\n");
-                for e in statements.iter() {
-                    write!(format, "\t{}\n", e);
+            Integer(_) |
+            Float(_) |
+            Bool(_) |
+            String(_) |
+            Label(_) |
+            Name(_) |
+            Nil |
+            VarArg |
+            Break |
+            EmptyStatement => {},
+            #[cfg(feature="luau")]
+            Continue => {},
+
+            Paren(ref a) |
+            PrefixExp(ref a) => children.push(("exp", &**a)),
+            Goto(ref a) => children.push(("label", &**a)),
+            Function(ref a) => children.push(("body", &**a)),
+            Do(ref a) => children.push(("block", &**a)),
+            FieldSingle(ref a) => children.push(("value", &**a)),
+            Var(ref a) => children.push(("name", &**a)),
+
+            BinNot(ref a) |
+            Not(ref a) |
+            Len(ref a) |
+            UMin(ref a) => children.push(("operand", &**a)),
+
+            Add(ref a, ref b) |
+            Sub(ref a, ref b) |
+            Mul(ref a, ref b) |
+            Div(ref a, ref b) |
+            Exp(ref a, ref b) |
+            FDiv(ref a, ref b) |
+            Mod(ref a, ref b) |
+            And(ref a, ref b) |
+            Or(ref a, ref b) |
+            Lt(ref a, ref b) |
+            Le(ref a, ref b) |
+            Gt(ref a, ref b) |
+            Ge(ref a, ref b) |
+            Eq(ref a, ref b) |
+            Ne(ref a, ref b) |
+            BitOr(ref a, ref b) |
+            BitAnd(ref a, ref b) |
+            BitXor(ref a, ref b) |
+            Rsh(ref a, ref b) |
+            Lsh(ref a, ref b) |
+            Concat(ref a, ref b) => {
+                children.push(("left", &**a));
+                children.push(("right", &**b));
+            },
+
+            FieldAssign(ref a, ref b) => {
+                children.push(("key", &**a));
+                children.push(("value", &**b));
+            },
+            VarPrefixed(ref a, ref b) => {
+                children.push(("prefix", &**a));
+                children.push(("index", &**b));
+            },
+            VarListAccess(ref a, ref b) => {
+                children.push(("prefix", &**a));
+                children.push(("name", &**b));
+            },
+            NamedFunction(ref a, ref b) |
+            FunctionStat(ref a, ref b) => {
+                children.push(("name", &**a));
+                children.push(("body", &**b));
+            },
+            Assign(ref a, ref b) => {
+                children.push(("vars", &**a));
+                children.push(("exps", &**b));
+            },
+            While(ref a, ref b) => {
+                children.push(("cond", &**a));
+                children.push(("block", &**b));
+            },
+            Repeat(ref a, ref b) => {
+                children.push(("block", &**a));
+                children.push(("cond", &**b));
+            },
+            If(ref a, ref b, ref c) => {
+                children.push(("cond", &**a));
+                children.push(("block", &**b));
+                if let Some(ref c) = **c {
+                    children.push(("else", c));
                 }
-                if let Some(ref ret_ast) = **retstat {
-                    write!(format, "\treturn {}\n", ret_ast);
+            },
+            NumericFor(ref a, ref b, ref c, ref d, ref e) => {
+                children.push(("var", &**a));
+                children.push(("start", &**b));
+                children.push(("limit", &**c));
+                if let Some(ref d) = **d {
+                    children.push(("step", d));
                 }
-                write!(format, ")")
-            }
-
-            // Statements
-            EmptyStatement => write!(format, "(statement)"),
-            RetStat(ref para) => write!(format, "(ret {:?})", para),
-            Break => write!(format, "(break)"),
-            Goto(ref loc) => write!(format, "goto {}", loc),
-
-            // ArithmeticOps
-            Add(ref left, ref right) => write!(format, "({} + {})", left, right),
-            Sub(ref left, ref right) => write!(format, "({} - {})", left, right),
-            Mul(ref left, ref right) => write!(format, "({} * {})", left, right),
-            Div(ref left, ref right) => write!(format, "({} / {})", left, right),
-            Exp(ref left, ref right) => write!(format, "({} ^ {})", left, right),
-            FDiv(ref left, ref right) => write!(format, "({} // {})", left, right),
-            Mod(ref left, ref right) => write!(format, "({} % {})", left, right),
-
-            // LogicOps
-            And(ref left, ref right) => write!(format, "({} and {})", left, right),
-            Or(ref left, ref right) => write!(format, "({} or {})", left, right),
-
-            // ArithmeticOps
-            Lt(ref left, ref right) => write!(format, "({} < {})", left, right),
-            Le(ref left, ref right) => write!(format, "({} <= {})", left, right),
-            Gt(ref left, ref right) => write!(format, "({} > {})", left, right),
-            Ge(ref left, ref right) => write!(format, "({} >= {})", left, right),
-            Eq(ref left, ref right) => write!(format, "({} == {})", left, right),
-            Ne(ref left, ref right) => write!(format, "({} ~= {})", left, right),
-
-            // BinaryOps
-            BitAnd(ref left, ref right) => write!(format, "({} & {})", left, right),
-            BitOr(ref left, ref right) => write!(format, "({} | {})", left, right),
-            BitXor(ref left, ref right) => write!(format, "({} ~ {})", left, right),
-            Rsh(ref left, ref right) => write!(format, "({} >> {})", left, right),
-            Lsh(ref left, ref right) => write!(format, "({} << {})", left, right),
-
-            // UnaryOps
-            BinNot(ref right) => write!(format, "~{}", right),
-            Len(ref right) => write!(format, "#{}", right),
-            UMin(ref right) => write!(format, "-{}", right),
-            Not(ref right) => write!(format, "not {}", right),
-
-            // ConcatenationOps
-            Concat(ref left, ref right) => write!(format, "{} .. {}", left, right),
-
-            // Exp
-            PrefixExp(ref e) => write!(format, "{}", e),
-
-            Nil => write!(format, "nil"),
-            VarArg => write!(format, "..."),
-            //TODO: Remove this debug impl
-            TableConstructor(ref fieldlist) => write!(format, "{{ {:?} }}", fieldlist),
-
-            // Function
-            Function(ref f) => write!(format, "{}", f),
-            FunctionBody(ref parlist, ref fbody) => write!(format, "function ({:?}) {}", parlist, fbody),
-            FunctionName(ref n, ref m, ref f) => write!(format, "{}.{:?}:{:?}", n, m, f),
-            NamedFunction(ref n, ref f) => write!(format, "(named {} {})", n, f),
-
-            // Lists
-            ExpList(ref explist) => {
-                write!(format, "(explist\n");
-                for e in explist.iter() {
-                    write!(format, "\t{}\n", e);
+                children.push(("block", &**e));
+            },
+            GenericFor(ref a, ref b, ref c) => {
+                children.push(("names", &**a));
+                children.push(("exps", &**b));
+                children.push(("block", &**c));
+            },
+            FunctionCall(ref a, ref b) => {
+                children.push(("prefix", &**a));
+                if let Some(ref b) = **b {
+                    children.push(("args", b));
+                }
+            },
+            MethodCall(ref a, ref b, ref c) => {
+                children.push(("prefix", &**a));
+                children.push(("name", &**b));
+                if let Some(ref c) = **c {
+                    children.push(("args", c));
+                }
+            },
+
+            RetStat(ref a) => if let Some(ref a) = **a {
+                children.push(("explist", a));
+            },
+            TableConstructor(ref a) => if let Some(ref a) = **a {
+                children.push(("fields", a));
+            },
+            ParameterList(ref a, _) => if let Some(ref a) = **a {
+                children.push(("names", a));
+            },
+
+            Local(ref a, ref b) => {
+                children.push(("names", &**a));
+                if let Some(ref b) = **b {
+                    children.push(("values", b));
                 }
-                write!(format, ")")
             },
-            VarList(ref varlist) => {
-                write!(format, "(varlist\n");
-                for e in varlist.iter() {
-                    write!(format, "\t{}\n", e);
+
+            ExpList(ref a) |
+            VarList(ref a) |
+            FieldList(ref a) => children.extend(a.iter().map(|e| ("item", e))),
+            NameList(ref a) => children.extend(a.iter().map(|(e, _)| ("item", e))),
+
+            Block(ref a, ref b) => {
+                children.extend(a.iter().map(|e| ("stat", e)));
+                if let Some(ref b) = **b {
+                    children.push(("ret", b));
                 }
-                write!(format, ")")
             },
-            NameList(ref namelist) => {
-                write!(format, "(namelist\n");
-                for e in namelist.iter() {
-                    write!(format, "\t{}\n", e);
+            FunctionBody(ref a, ref b) => {
+                if let Some(ref a) = **a {
+                    children.push(("params", a));
                 }
-                write!(format, ")")
+                children.push(("block", &**b));
             },
-            ParameterList(ref plist, ref va) => {
-                write!(format, "(paramlist\n");
-                for e in plist.iter() {
-                    write!(format, "\t{}\n", e);
+            FunctionName(ref a, ref b, ref c) => {
+                children.push(("name", &**a));
+                if let Some(ref b) = *b {
+                    children.extend(b.iter().map(|e| ("field", e)));
                 }
-                if *va {
-                    write!(format, "\t...\n");
+                if let Some(ref c) = *c {
+                    children.push(("method", &**c));
                 }
-                write!(format, ")")
             },
-            FieldList(ref fieldlist) => {
-                write!(format, "(fieldlist\n");
-                for e in fieldlist.iter() {
-                    write!(format, "\t{}\n", e);
+
+            #[cfg(feature="luau")]
+            Typed(ref a, ref b) |
+            TypeField(ref a, ref b) => {
+                children.push(("name", &**a));
+                children.push(("type", &**b));
+            },
+            #[cfg(feature="luau")]
+            TypedFunctionBody(ref a, ref b, ref c) => {
+                if let Some(ref a) = **a {
+                    children.push(("params", a));
                 }
-                write!(format, ")")
+                children.push(("returns", &**b));
+                children.push(("block", &**c));
+            },
+            #[cfg(feature="luau")]
+            TypeAlias(_, ref a, ref b, ref c) => {
+                children.push(("name", &**a));
+                children.extend(b.iter().map(|e| ("generic", e)));
+                children.push(("type", &**c));
+            },
+            #[cfg(feature="luau")]
+            CompoundAssign(_, ref a, ref b) => {
+                children.push(("var", &**a));
+                children.push(("exp", &**b));
+            },
+            #[cfg(feature="luau")]
+            IfExp(ref a, ref b, ref c) => {
+                children.push(("cond", &**a));
+                children.push(("then", &**b));
+                children.push(("else", &**c));
             },
+            #[cfg(feature="luau")]
+            TypeName(_, ref a) => children.extend(a.iter().map(|e| ("arg", e))),
+            #[cfg(feature="luau")]
+            TypeOptional(ref a) |
+            TypeVariadic(ref a) => children.push(("type", &**a)),
+            #[cfg(feature="luau")]
+            TypeOf(ref a) => children.push(("exp", &**a)),
+            #[cfg(feature="luau")]
+            TypeUnion(ref a) |
+            TypeIntersection(ref a) |
+            TypePack(ref a) |
+            TypeTable(ref a) => children.extend(a.iter().map(|e| ("item", e))),
+            #[cfg(feature="luau")]
+            TypeFunction(ref a, ref b) => {
+                children.extend(a.iter().map(|e| ("param", e)));
+                children.push(("returns", &**b));
+            },
+            #[cfg(feature="luau")]
+            TypeIndexer(ref a, ref b) => {
+                children.push(("key", &**a));
+                children.push(("value", &**b));
+            },
+        }
+        children
+    }
 
-            // Field
-            FieldSingle(ref e) => write!(format, "(field {})", e),
-            FieldAssign(ref n, ref e) => write!(format, "(field {} => {})", n, e),
+    /// Returns the direct children of this node mutably, in the same order
+    /// as `children`
+    pub fn children_mut(&mut self) -> Vec<&mut ASTNode> {
+        use self::ASTNode::*;
+        let mut children = Vec::new();
+        match *self {
+            Integer(_) |
+            Float(_) |
+            Bool(_) |
+            String(_) |
+            Label(_) |
+            Name(_) |
+            Nil |
+            VarArg |
+            Break |
+            EmptyStatement => {},
+            #[cfg(feature="luau")]
+            Continue => {},
+
+            Paren(ref mut a) |
+            PrefixExp(ref mut a) => children.push(&mut **a),
+            Goto(ref mut a) => children.push(&mut **a),
+            Function(ref mut a) => children.push(&mut **a),
+            Do(ref mut a) => children.push(&mut **a),
+            FieldSingle(ref mut a) => children.push(&mut **a),
+            Var(ref mut a) => children.push(&mut **a),
+
+            BinNot(ref mut a) |
+            Not(ref mut a) |
+            Len(ref mut a) |
+            UMin(ref mut a) => children.push(&mut **a),
+
+            Add(ref mut a, ref mut b) |
+            Sub(ref mut a, ref mut b) |
+            Mul(ref mut a, ref mut b) |
+            Div(ref mut a, ref mut b) |
+            Exp(ref mut a, ref mut b) |
+            FDiv(ref mut a, ref mut b) |
+            Mod(ref mut a, ref mut b) |
+            And(ref mut a, ref mut b) |
+            Or(ref mut a, ref mut b) |
+            Lt(ref mut a, ref mut b) |
+            Le(ref mut a, ref mut b) |
+            Gt(ref mut a, ref mut b) |
+            Ge(ref mut a, ref mut b) |
+            Eq(ref mut a, ref mut b) |
+            Ne(ref mut a, ref mut b) |
+            BitOr(ref mut a, ref mut b) |
+            BitAnd(ref mut a, ref mut b) |
+            BitXor(ref mut a, ref mut b) |
+            Rsh(ref mut a, ref mut b) |
+            Lsh(ref mut a, ref mut b) |
+            Concat(ref mut a, ref mut b) => {
+                children.push(&mut **a);
+                children.push(&mut **b);
+            },
 
-            //Local
-            Local(ref inner) => write!(format, "local {}", inner),
+            FieldAssign(ref mut a, ref mut b) => {
+                children.push(&mut **a);
+                children.push(&mut **b);
+            },
+            VarPrefixed(ref mut a, ref mut b) => {
+                children.push(&mut **a);
+                children.push(&mut **b);
+            },
+            VarListAccess(ref mut a, ref mut b) => {
+                children.push(&mut **a);
+                children.push(&mut **b);
+            },
+            NamedFunction(ref mut a, ref mut b) |
+            FunctionStat(ref mut a, ref mut b) => {
+                children.push(&mut **a);
+                children.push(&mut **b);
+            },
+            Assign(ref mut a, ref mut b) => {
+                children.push(&mut **a);
+                children.push(&mut **b);
+            },
+            While(ref mut a, ref mut b) => {
+                children.push(&mut **a);
+                children.push(&mut **b);
+            },
+            Repeat(ref mut a, ref mut b) => {
+                children.push(&mut **a);
+                children.push(&mut **b);
+            },
+            If(ref mut a, ref mut b, ref mut c) => {
+                children.push(&mut **a);
+                children.push(&mut **b);
+                if let Some(ref mut c) = **c {
+                    children.push(c);
+                }
+            },
+            NumericFor(ref mut a, ref mut b, ref mut c, ref mut d, ref mut e) => {
+                children.push(&mut **a);
+                children.push(&mut **b);
+                children.push(&mut **c);
+                if let Some(ref mut d) = **d {
+                    children.push(d);
+                }
+                children.push(&mut **e);
+            },
+            GenericFor(ref mut a, ref mut b, ref mut c) => {
+                children.push(&mut **a);
+                children.push(&mut **b);
+                children.push(&mut **c);
+            },
+            FunctionCall(ref mut a, ref mut b) => {
+                children.push(&mut **a);
+                if let Some(ref mut b) = **b {
+                    children.push(b);
+                }
+            },
+            MethodCall(ref mut a, ref mut b, ref mut c) => {
+                children.push(&mut **a);
+                children.push(&mut **b);
+                if let Some(ref mut c) = **c {
+                    children.push(c);
+                }
+            },
 
-            //Var
-            Var(ref name) => write!(format, "(var {})", name),
-            VarPrefixed(ref pe, ref e) => write!(format, "{}[{}]", pe, e),
-            VarListAccess(ref pe, ref n) => write!(format, "{}.{}", pe, n)
+            RetStat(ref mut a) => if let Some(ref mut a) = **a {
+                children.push(a);
+            },
+            TableConstructor(ref mut a) => if let Some(ref mut a) = **a {
+                children.push(a);
+            },
+            ParameterList(ref mut a, _) => if let Some(ref mut a) = **a {
+                children.push(a);
+            },
+
+            Local(ref mut a, ref mut b) => {
+                children.push(&mut **a);
+                if let Some(ref mut b) = **b {
+                    children.push(b);
+                }
+            },
+
+            ExpList(ref mut a) |
+            VarList(ref mut a) |
+            FieldList(ref mut a) => children.extend(a.iter_mut()),
+            NameList(ref mut a) => children.extend(a.iter_mut().map(|(e, _)| e)),
+
+            Block(ref mut a, ref mut b) => {
+                children.extend(a.iter_mut());
+                if let Some(ref mut b) = **b {
+                    children.push(b);
+                }
+            },
+            FunctionBody(ref mut a, ref mut b) => {
+                if let Some(ref mut a) = **a {
+                    children.push(a);
+                }
+                children.push(&mut **b);
+            },
+            FunctionName(ref mut a, ref mut b, ref mut c) => {
+                children.push(&mut **a);
+                if let Some(ref mut b) = *b {
+                    children.extend(b.iter_mut());
+                }
+                if let Some(ref mut c) = *c {
+                    children.push(&mut **c);
+                }
+            },
+
+            #[cfg(feature="luau")]
+            Typed(ref mut a, ref mut b) |
+            TypeField(ref mut a, ref mut b) => {
+                children.push(&mut **a);
+                children.push(&mut **b);
+            },
+            #[cfg(feature="luau")]
+            TypedFunctionBody(ref mut a, ref mut b, ref mut c) => {
+                if let Some(ref mut a) = **a {
+                    children.push(a);
+                }
+                children.push(&mut **b);
+                children.push(&mut **c);
+            },
+            #[cfg(feature="luau")]
+            TypeAlias(_, ref mut a, ref mut b, ref mut c) => {
+                children.push(&mut **a);
+                children.extend(b.iter_mut());
+                children.push(&mut **c);
+            },
+            #[cfg(feature="luau")]
+            CompoundAssign(_, ref mut a, ref mut b) => {
+                children.push(&mut **a);
+                children.push(&mut **b);
+            },
+            #[cfg(feature="luau")]
+            IfExp(ref mut a, ref mut b, ref mut c) => {
+                children.push(&mut **a);
+                children.push(&mut **b);
+                children.push(&mut **c);
+            },
+            #[cfg(feature="luau")]
+            TypeName(_, ref mut a) => children.extend(a.iter_mut()),
+            #[cfg(feature="luau")]
+            TypeOptional(ref mut a) |
+            TypeVariadic(ref mut a) => children.push(&mut **a),
+            #[cfg(feature="luau")]
+            TypeOf(ref mut a) => children.push(&mut **a),
+            #[cfg(feature="luau")]
+            TypeUnion(ref mut a) |
+            TypeIntersection(ref mut a) |
+            TypePack(ref mut a) |
+            TypeTable(ref mut a) => children.extend(a.iter_mut()),
+            #[cfg(feature="luau")]
+            TypeFunction(ref mut a, ref mut b) => {
+                children.extend(a.iter_mut());
+                children.push(&mut **b);
+            },
+            #[cfg(feature="luau")]
+            TypeIndexer(ref mut a, ref mut b) => {
+                children.push(&mut **a);
+                children.push(&mut **b);
+            },
         }
+        children
+    }
+
+    /// Moves this node out, leaving `Nil` in its place
+    ///
+    /// Nodes can not be destructured by value since `ASTNode` implements
+    /// `Drop`, this takes their fields instead.
+    pub fn take(&mut self) -> ASTNode {
+        mem::replace(self, ASTNode::Nil)
     }
+}
 
+// Dropping the children one after the other instead of recursively, the
+// tree of a long operator or suffix chain is as deep as the chain is long
+impl Drop for ASTNode {
+    fn drop(&mut self) {
+        let mut stack: Vec<ASTNode> = self.children_mut().into_iter().map(ASTNode::take).collect();
+        while let Some(mut node) = stack.pop() {
+            stack.extend(node.children_mut().into_iter().map(ASTNode::take));
+        }
+    }
 }
 
 impl Display for ASTNode {
@@ -272,7 +752,7 @@ impl Display for ASTNode {
             Bool(a) => write!(format, "Bool_{}_", a),
             // Dot does not allow spaces and a bunch of things in the names
             // we should change some of this stuff
-            String(ref a) => write!(format, "String_{}_", a),
+            String(ref a) => write!(format, "String_{}_", ::std::string::String::from_utf8_lossy(a)),
             Label(ref a) => write!(format, "Label_{}_", a),
             Name(ref a) => write!(format, "Name_{}_", a),
             Paren(_) => write!(format, "Paren"),
@@ -281,6 +761,14 @@ impl Display for ASTNode {
             Break => write!(format, "Break"),
             Goto(_) => write!(format, "Goto"),
             RetStat(_) => write!(format, "RetStat"),
+            Assign(_, _) => write!(format, "Assign"),
+            Do(_) => write!(format, "Do"),
+            While(_, _) => write!(format, "While"),
+            Repeat(_, _) => write!(format, "Repeat"),
+            If(_, _, _) => write!(format, "If"),
+            NumericFor(_, _, _, _, _) => write!(format, "NumericFor"),
+            GenericFor(_, _, _) => write!(format, "GenericFor"),
+            FunctionStat(_, _) => write!(format, "FunctionStat"),
             Add(_, _) => write!(format, "Add"),
             Sub(_, _) => write!(format, "Sub"),
             Mul(_, _) => write!(format, "Mul"),
@@ -307,6 +795,8 @@ impl Display for ASTNode {
             UMin(_) => write!(format, "UMin"),
             Concat(_, _) => write!(format, "Concat"),
             PrefixExp(_) => write!(format, "PrefixExp"),
+            FunctionCall(_, _) => write!(format, "FunctionCall"),
+            MethodCall(_, _, _) => write!(format, "MethodCall"),
             Nil => write!(format, "Nil"),
             VarArg => write!(format, "VarArg"),
             TableConstructor(_) => write!(format, "TableConstructor"),
@@ -321,289 +811,57 @@ impl Display for ASTNode {
             ParameterList(_, _) => write!(format, "ParameterList"),
             FieldSingle(_) => write!(format, "FieldSingle"),
             FieldAssign(_, _) => write!(format, "FieldAssign"),
-            Local(_) => write!(format, "Local"),
+            Local(_, _) => write!(format, "Local"),
             Var(_) => write!(format, "Var"),
             VarPrefixed(_, _) => write!(format, "VarPrefixed"),
             VarListAccess(_, _) => write!(format, "VarListAccess"),
+            #[cfg(feature="luau")]
+            Typed(_, _) => write!(format, "Typed"),
+            #[cfg(feature="luau")]
+            TypedFunctionBody(_, _, _) => write!(format, "TypedFunctionBody"),
+            #[cfg(feature="luau")]
+            TypeAlias(_, _, _, _) => write!(format, "TypeAlias"),
+            #[cfg(feature="luau")]
+            CompoundAssign(_, _, _) => write!(format, "CompoundAssign"),
+            #[cfg(feature="luau")]
+            Continue => write!(format, "Continue"),
+            #[cfg(feature="luau")]
+            IfExp(_, _, _) => write!(format, "IfExp"),
+            #[cfg(feature="luau")]
+            TypeName(ref a, _) => write!(format, "TypeName_{}_", a),
+            #[cfg(feature="luau")]
+            TypeOptional(_) => write!(format, "TypeOptional"),
+            #[cfg(feature="luau")]
+            TypeUnion(_) => write!(format, "TypeUnion"),
+            #[cfg(feature="luau")]
+            TypeIntersection(_) => write!(format, "TypeIntersection"),
+            #[cfg(feature="luau")]
+            TypeFunction(_, _) => write!(format, "TypeFunction"),
+            #[cfg(feature="luau")]
+            TypePack(_) => write!(format, "TypePack"),
+            #[cfg(feature="luau")]
+            TypeTable(_) => write!(format, "TypeTable"),
+            #[cfg(feature="luau")]
+            TypeField(_, _) => write!(format, "TypeField"),
+            #[cfg(feature="luau")]
+            TypeIndexer(_, _) => write!(format, "TypeIndexer"),
+            #[cfg(feature="luau")]
+            TypeOf(_) => write!(format, "TypeOf"),
+            #[cfg(feature="luau")]
+            TypeVariadic(_) => write!(format, "TypeVariadic"),
         }
     }
 }
 
-//TODO: There is a bunch of cloing here that might not be necessary
-//TODO: Is there a better way to do this, or do we need 6 cfg blocks
-#[cfg(feature="graphviz")]
-type Node = ASTNode;
-#[cfg(feature="graphviz")]
-type Edge = (ASTNode, ASTNode);
-#[cfg(feature="graphviz")]
-struct Edges(Vec<Edge>);
-#[cfg(feature="graphviz")]
-use dot;
-#[cfg(feature="graphviz")]
-use std::borrow::Cow;
 #[cfg(feature="graphviz")]
-use std::io::Write;
-
-
+use std::io::{self, Write};
 #[cfg(feature="graphviz")]
-impl<'a> dot::Labeller<'a, Node, Edge> for Edges {
-    fn graph_id(&'a self) -> dot::Id<'a> {
-        dot::Id::new("AST").unwrap()
-    }
-
-    fn node_id(&'a self, node: &Node) -> dot::Id<'a> {
-        dot::Id::new(format!("{}", node)).unwrap()
-    }
-}
-
-
-#[cfg(feature="graphviz")]
-impl<'a> dot::GraphWalk<'a, Node, Edge> for Edges {
-    fn nodes(&self) -> dot::Nodes<'a,Node> {
-        // (assumes that |N| \approxeq |E|)
-        let &Edges(ref v) = self;
-        let mut node_vec = Vec::with_capacity(v.len());
-        for n in v {
-            let &(ref s, ref t) = n;
-            node_vec.extend(s.sub_nodes());
-            node_vec.extend(t.sub_nodes());
-        }
-        node_vec.dedup();
-        Cow::Owned(node_vec)
-    }
-
-    fn edges(&'a self) -> dot::Edges<'a,Edge> {
-        let &Edges(ref edges) = self;
-        Cow::Borrowed(&edges[..])
-    }
-
-    fn source(&self, e: &Edge) -> Node { let &(ref s,_) = e; s.clone() }
-
-    fn target(&self, e: &Edge) -> Node { let &(_,ref t) = e; t.clone() }
-}
-
+use graphviz::{self, DotConfig};
 
 #[cfg(feature="graphviz")]
 impl ASTNode {
-    fn generate_edges(&self) -> Vec<(ASTNode, ASTNode)> {
-        use ASTNode::*;
-        let mut node_vec = Vec::new();
-        match (*self).clone() {
-
-            Integer(_) |
-            Float(_) |
-            Bool(_) |
-            String(_) |
-            Label(_) |
-            Name(_) |
-            Nil |
-            Break |
-            VarArg |
-            EmptyStatement => {},
-
-            Paren(a) |
-            Local(a) |
-            Var(a) |
-            Goto(a) |
-            BinNot(a) |
-            Not(a) |
-            Len(a) |
-            UMin(a) |
-            PrefixExp(a) |
-            Function(a) |
-            FieldSingle(a) |
-            PrefixExp(a) => {
-                node_vec.push(((*self).clone(), (*a).clone()));
-                node_vec.extend(a.generate_edges());
-            },
-
-            Add(a, b) |
-            Sub(a, b) |
-            Mul(a, b) |
-            Div(a, b) |
-            Exp(a, b) |
-            FDiv(a, b) |
-            Mod(a, b) |
-            And(a, b) |
-            Or(a, b) |
-            Lt(a, b) |
-            Le(a, b) |
-            Gt(a, b) |
-            Ge(a, b) |
-            Eq(a, b) |
-            Ne(a, b) |
-            BitOr(a, b) |
-            BitAnd(a, b) |
-            BitXor(a, b) |
-            Rsh(a, b) |
-            Lsh(a, b) |
-            Concat(a, b) |
-            FieldAssign(a, b) |
-            VarPrefixed(a, b) |
-            VarListAccess(a, b) |
-            NamedFunction(a, b) => {
-                node_vec.push(((*self).clone(), (*a).clone()));
-                node_vec.push(((*self).clone(), (*b).clone()));
-                node_vec.extend(a.generate_edges());
-                node_vec.extend(b.generate_edges());
-            },
-
-            RetStat(a) |
-            TableConstructor(a) |
-            ParameterList(a, _) => if let Some(sa) = *a {
-                node_vec.push(((*self).clone(), sa.clone()));
-                node_vec.extend(sa.generate_edges());
-            },
-
-            ExpList(a) |
-            VarList(a) |
-            NameList(a) |
-            FieldList(a) => {
-                a.iter().map(|ae| {
-                    node_vec.push(((*self).clone(), (*ae).clone()));
-                    node_vec.extend(ae.generate_edges());
-                });
-            },
-
-            Block(a, b) => {
-                a.iter().map(|ae| {
-                    node_vec.push(((*self).clone(), (*ae).clone()));
-                    node_vec.extend(ae.generate_edges());
-                });
-                if let Some(sb) = *b {
-                    node_vec.push(((*self).clone(), sb.clone()));
-                    node_vec.extend(sb.generate_edges());
-                };
-            },
-            FunctionBody(a, b) => {
-                if let Some(sa) = *a {
-                    node_vec.push(((*self).clone(), sa.clone()));
-                    node_vec.extend(sa.generate_edges());
-                };
-                node_vec.push(((*self).clone(), (*b).clone()));
-                node_vec.extend(b.generate_edges());
-            },
-            FunctionName(a, b, c) => {
-                node_vec.push(((*self).clone(), (*a).clone()));
-                node_vec.extend(a.generate_edges());
-                if let Some(sb) = b {
-                    sb.iter().map(|sbe| {
-                        node_vec.push(((*self).clone(), (*sbe).clone()));
-                        node_vec.extend(sbe.generate_edges());
-                    });
-                };
-                if let Some(sc) = c {
-                    node_vec.push(((*self).clone(), (*sc).clone()));
-                    node_vec.extend(sc.generate_edges());
-                };
-            },
-            _ => panic!("Unimplemented: GenEdges"),
-        }
-        node_vec
-    }
-
-    fn sub_nodes(&self) -> Vec<ASTNode> {
-        use ASTNode::*;
-        let mut node_vec = Vec::new();
-
-        node_vec.push((*self).clone());
-
-        match (*self).clone() {
-            Nil |
-            VarArg |
-            Break |
-            EmptyStatement |
-            Float(_) |
-            Bool(_) |
-            String(_) |
-            Label(_) |
-            Name(_) |
-            Integer(_) => {},
-
-            Goto(a) |
-            BinNot(a) |
-            Not(a) |
-            Len(a) |
-            UMin(a) |
-            PrefixExp(a) |
-            FieldSingle(a) |
-            Local(a) |
-            Var(a) |
-            Function(a) |
-            PrefixExp(a) |
-            Paren(a) => node_vec.extend(a.sub_nodes()),
-
-            And(a, b) |
-            Or(a, b) |
-            Lt(a, b) |
-            Le(a, b) |
-            Gt(a, b) |
-            Ge(a, b) |
-            Eq(a, b) |
-            Ne(a, b) |
-            BitOr(a, b) |
-            BitAnd(a, b) |
-            BitXor(a, b) |
-            Rsh(a, b) |
-            Lsh(a, b) |
-            FieldAssign(a, b) |
-            VarPrefixed(a, b) |
-            VarListAccess(a, b) |
-            NamedFunction(a, b) |
-            Concat(a, b) |
-            Add(a, b) |
-            Sub(a, b) |
-            Mul(a, b) |
-            Div(a, b) |
-            Exp(a, b) |
-            FDiv(a, b) |
-            Mod(a, b) => {
-                node_vec.extend(a.sub_nodes());
-                node_vec.extend(b.sub_nodes());
-            },
-
-            ExpList(a) |
-            VarList(a) |
-            NameList(a) |
-            FieldList(a) => { a.iter().map(|b| node_vec.extend(b.sub_nodes())); },
-
-            RetStat(a) |
-            ParameterList(a, _) |
-            TableConstructor(a) => if let Some(sa) = *a {
-                node_vec.extend(sa.sub_nodes());
-            },
-
-            Block(a, b) => {
-                a.iter().map(|ae| node_vec.extend(ae.sub_nodes()));
-                if let Some(sb) = *b {
-                    node_vec.extend(sb.sub_nodes());
-                }
-            },
-
-            FunctionBody(a, b) => {
-                if let Some(sa) = *a {
-                    node_vec.extend(sa.sub_nodes());
-                }
-                node_vec.extend(b.sub_nodes());
-            },
-
-            FunctionName(a, b, c) => {
-                node_vec.extend(a.sub_nodes());
-                if let Some(sb) = b {
-                    sb.iter().map(|sbe| node_vec.extend(sbe.sub_nodes()));
-                };
-                if let Some(sc) = c {
-                    node_vec.extend(sc.sub_nodes());
-                }
-            },
-            _ => panic!("Unimplemented: SubNodes"),
-        };
-        node_vec
-    }
-
-    #[cfg(feature="graphviz")]
-    pub fn graphviz_render<W: Write>(&self, output: &mut W) {
-        let edges = Edges(self.generate_edges());
-        dot::render(&edges, output).unwrap()
+    /// Writes this tree as a DOT graph, see `graphviz::render`
+    pub fn graphviz_render<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        graphviz::render(self, &DotConfig::default(), output)
     }
 }
@@ -0,0 +1,1639 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The code generator, following `lparser.c` and `lcode.c` of Lua 5.3
+//!
+//! The active locals of a function are its first registers, in order, and
+//! temporaries are allocated above them like a stack. Jumps are emitted
+//! with an empty offset and patched once their target is known. Leaving a
+//! scope whose locals were captured by a closure closes their upvalues
+//! with the `A` operand of a `JMP`.
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+// Not the String node
+use std::string::String;
+
+use arena::Arena;
+use ast::ASTNode;
+use ast::ASTNode::*;
+use ast::Attrib;
+use op::BinOp;
+use span::spans;
+use super::opcode::*;
+use super::{Constant, LocalVar, Proto, Upvalue};
+
+/// `luac` keeps registers numbers below this
+const MAX_REGISTERS: usize = 255;
+const MAX_UPVALUES: usize = 255;
+const MAX_LOCALS: usize = 200;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompileError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for CompileError {
+    fn fmt(&self, format: &mut Formatter) -> fmt::Result {
+        write!(format, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl error::Error for CompileError {}
+
+type Result<T> = ::std::result::Result<T, CompileError>;
+
+fn name(node: &ASTNode) -> &str {
+    match *node {
+        Name(ref n) | Label(ref n) => n,
+        Var(ref n) => name(n),
+        #[cfg(feature="luau")]
+        Typed(ref n, _) => name(n),
+        _ => unreachable!("{:?} is not a name", node),
+    }
+}
+
+fn list(node: &ASTNode) -> &[ASTNode] {
+    match *node {
+        ExpList(ref l) | VarList(ref l) | FieldList(ref l) => l,
+        _ => unreachable!("{:?} is not a list", node),
+    }
+}
+
+fn names(node: &Option<ASTNode>) -> Vec<&str> {
+    match *node {
+        Some(NameList(ref names)) => names.iter().map(|(n, _)| name(n)).collect(),
+        _ => vec![],
+    }
+}
+
+/// The call or `...` that `e` is, these produce several values unless
+/// they are in parentheses
+fn as_multi(e: &ASTNode) -> Option<&ASTNode> {
+    match *e {
+        VarArg | FunctionCall(_, _) | MethodCall(_, _, _) => Some(e),
+        PrefixExp(ref call) => match **call {
+            FunctionCall(_, _) | MethodCall(_, _, _) => Some(call),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Parentheses do not change a single value
+fn strip_parens(e: &ASTNode) -> &ASTNode {
+    match *e {
+        PrefixExp(ref inner) | Paren(ref inner) if as_multi(e).is_none() => strip_parens(inner),
+        _ => e,
+    }
+}
+
+fn arith_opcode(op: BinOp) -> OpCode {
+    match op {
+        BinOp::Add => OpCode::Add,
+        BinOp::Sub => OpCode::Sub,
+        BinOp::Mul => OpCode::Mul,
+        BinOp::Div => OpCode::Div,
+        BinOp::FDiv => OpCode::IDiv,
+        BinOp::Mod => OpCode::Mod,
+        BinOp::Exp => OpCode::Pow,
+        BinOp::BitAnd => OpCode::BAnd,
+        BinOp::BitOr => OpCode::BOr,
+        BinOp::BitXor => OpCode::BXor,
+        BinOp::Lsh => OpCode::Shl,
+        BinOp::Rsh => OpCode::Shr,
+        _ => unreachable!("{} is not arithmetic", op),
+    }
+}
+
+// Constants are looked up by value, floats by their bits
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Float(u64),
+    String(Vec<u8>),
+}
+
+impl<'a> From<&'a Constant> for ConstantKey {
+    fn from(k: &'a Constant) -> ConstantKey {
+        match *k {
+            Constant::Nil => ConstantKey::Nil,
+            Constant::Boolean(b) => ConstantKey::Boolean(b),
+            Constant::Integer(i) => ConstantKey::Integer(i),
+            Constant::Float(f) => ConstantKey::Float(f.to_bits()),
+            Constant::String(ref s) => ConstantKey::String(s.clone()),
+        }
+    }
+}
+
+/// How a name is reached from the current function
+#[derive(Clone, Debug, PartialEq)]
+enum Var {
+    Local(usize),
+    Upvalue(usize),
+    Global(String),
+}
+
+/// Where an assignment stores its value
+#[derive(Clone, Copy)]
+enum LValue {
+    Local(usize),
+    Upvalue(usize),
+    /// A table in a register and a key as an RK operand
+    Index(usize, u32),
+    /// `_ENV` as an upvalue and a key as an RK operand
+    Global(usize, u32),
+}
+
+struct Scope {
+    /// The number of active locals when the scope starts
+    nactvar: usize,
+    first_label: usize,
+    first_goto: usize,
+    /// Whether a closure captures one of the locals of the scope
+    upval: bool,
+    is_loop: bool,
+    breaks: Vec<usize>,
+    #[cfg(feature="luau")]
+    continues: Vec<usize>,
+    /// The first local a `continue` leaves the scope of
+    #[cfg(feature="luau")]
+    continue_level: usize,
+}
+
+/// A label, or a `goto` waiting for its label
+struct Jump {
+    name: String,
+    pc: usize,
+    line: usize,
+    /// The active locals at the label or the `goto`, lowered as a pending
+    /// `goto` leaves scopes
+    nactvar: usize,
+    /// The active locals where the `goto` is
+    level: usize,
+}
+
+struct FuncState {
+    proto: Proto,
+    /// The index in `proto.local_vars` of each active local, which is also
+    /// its register
+    actvar: Vec<usize>,
+    scopes: Vec<Scope>,
+    freereg: usize,
+    constants: HashMap<ConstantKey, usize>,
+    labels: Vec<Jump>,
+    gotos: Vec<Jump>,
+}
+
+impl FuncState {
+    fn new(source: Option<String>, line_defined: usize, last_line_defined: usize) -> FuncState {
+        FuncState {
+            proto: Proto {
+                source,
+                line_defined,
+                last_line_defined,
+                num_params: 0,
+                is_vararg: false,
+                max_stack_size: 2,
+                code: vec![],
+                constants: vec![],
+                upvalues: vec![],
+                protos: vec![],
+                line_info: vec![],
+                local_vars: vec![],
+            },
+            actvar: vec![],
+            scopes: vec![],
+            freereg: 0,
+            constants: HashMap::new(),
+            labels: vec![],
+            gotos: vec![],
+        }
+    }
+
+    fn local_name(&self, reg: usize) -> &str {
+        &self.proto.local_vars[self.actvar[reg]].name
+    }
+
+    /// Marks the scope of the local in `reg` as captured
+    fn mark_upval(&mut self, reg: usize) {
+        if let Some(scope) = self.scopes.iter_mut().rev().find(|s| s.nactvar <= reg) {
+            scope.upval = true;
+        }
+    }
+
+    // How errors about the limits of the function name it
+    fn describe(&self) -> String {
+        match self.proto.line_defined {
+            0 => "main function".to_string(),
+            line => format!("function at line {}", line),
+        }
+    }
+}
+
+struct Compiler {
+    funcs: Vec<FuncState>,
+    /// The first and last line of the nodes, by address
+    lines: HashMap<*const ASTNode, (usize, usize)>,
+    /// The line of the instructions being emitted
+    line: usize,
+}
+
+/// Finds the lines of each node from the spans of the tokens. The arena
+/// numbers the nodes in pre-order.
+fn node_lines(block: &ASTNode, source: &[u8]) -> HashMap<*const ASTNode, (usize, usize)> {
+    fn preorder<'a>(node: &'a ASTNode, nodes: &mut Vec<&'a ASTNode>) {
+        nodes.push(node);
+        for (_, child) in node.children() {
+            preorder(child, nodes);
+        }
+    }
+    let arena = Arena::from_ast(block);
+    let spans = spans(source, &arena);
+    let starts: Vec<usize> = Some(0).into_iter()
+        .chain(source.iter().enumerate().filter(|&(_, &b)| b == b'\n').map(|(i, _)| i + 1))
+        .collect();
+    let line = |offset: usize| starts.partition_point(|&s| s <= offset);
+    let mut nodes = Vec::with_capacity(arena.len());
+    preorder(block, &mut nodes);
+    nodes.into_iter().zip(arena.ids()).filter_map(|(node, id)| {
+        spans.get(id).map(|s| (node as *const ASTNode, (line(s.start), line(s.end.max(s.start + 1) - 1))))
+    }).collect()
+}
+
+/// Compiles a block parsed from `source` into the function of a chunk.
+/// `chunkname` is the source recorded in the debug information, `luac`
+/// uses `@` followed by the file name.
+pub fn compile(block: &ASTNode, source: &[u8], chunkname: &str) -> Result<Proto> {
+    let last_line = source.iter().filter(|&&b| b == b'\n').count() + 1;
+    let mut compiler = Compiler { funcs: vec![], lines: node_lines(block, source), line: 1 };
+    let mut main = FuncState::new(Some(chunkname.to_string()), 0, 0);
+    main.proto.is_vararg = true;
+    main.proto.upvalues.push(Upvalue { name: "_ENV".into(), in_stack: true, index: 0 });
+    compiler.funcs.push(main);
+    compiler.enter_scope(false);
+    compiler.statements(block, false)?;
+    compiler.line = last_line;
+    compiler.abc(OpCode::Return, 0, 1, 0);
+    compiler.leave_scope()?;
+    Ok(compiler.funcs.pop().unwrap().proto)
+}
+
+impl Compiler {
+    fn fs(&mut self) -> &mut FuncState {
+        self.funcs.last_mut().unwrap()
+    }
+
+    fn error<T, S: Into<String>>(&self, message: S) -> Result<T> {
+        Err(CompileError { line: self.line, message: message.into() })
+    }
+
+    fn set_line(&mut self, node: &ASTNode) {
+        if let Some(&(line, _)) = self.lines.get(&(node as *const ASTNode)) {
+            self.line = line;
+        }
+    }
+
+    fn pc(&self) -> usize {
+        self.funcs.last().unwrap().proto.code.len()
+    }
+
+    fn nactvar(&self) -> usize {
+        self.funcs.last().unwrap().actvar.len()
+    }
+
+    fn freereg(&self) -> usize {
+        self.funcs.last().unwrap().freereg
+    }
+
+    fn emit(&mut self, i: Instruction) -> usize {
+        let line = self.line;
+        let proto = &mut self.fs().proto;
+        proto.code.push(i);
+        proto.line_info.push(line);
+        proto.code.len() - 1
+    }
+
+    fn abc(&mut self, op: OpCode, a: usize, b: u32, c: u32) -> usize {
+        self.emit(Instruction::abc(op, a as u32, b, c))
+    }
+
+    fn abx(&mut self, op: OpCode, a: usize, bx: usize) -> Result<usize> {
+        if bx > MAXARG_BX as usize {
+            return self.error("function or expression too complex");
+        }
+        Ok(self.emit(Instruction::abx(op, a as u32, bx as u32)))
+    }
+
+    fn jump(&mut self) -> usize {
+        self.emit(Instruction::asbx(OpCode::Jmp, 0, 0))
+    }
+
+    /// Makes the jump at `pc` go to `target`
+    fn patch(&mut self, pc: usize, target: usize) -> Result<()> {
+        let offset = target as i64 - (pc as i64 + 1);
+        if offset.abs() > MAXARG_SBX as i64 {
+            return self.error("control structure too long");
+        }
+        self.fs().proto.code[pc].set_sbx(offset as i32);
+        Ok(())
+    }
+
+    fn patch_here(&mut self, jumps: &[usize]) -> Result<()> {
+        let here = self.pc();
+        for &j in jumps {
+            self.patch(j, here)?;
+        }
+        Ok(())
+    }
+
+    fn jump_to(&mut self, target: usize) -> Result<usize> {
+        let j = self.jump();
+        self.patch(j, target)?;
+        Ok(j)
+    }
+
+    /// Makes the jump at `pc` close the upvalues from register `level` up
+    fn patch_close(&mut self, pc: usize, level: usize) {
+        self.fs().proto.code[pc].set_a(level as u32 + 1);
+    }
+
+    /// Makes sure `n` more registers fit in the stack frame
+    fn check_stack(&mut self, n: usize) -> Result<()> {
+        let needed = self.freereg() + n;
+        if needed >= MAX_REGISTERS {
+            return self.error("function or expression needs too many registers");
+        }
+        let proto = &mut self.fs().proto;
+        proto.max_stack_size = proto.max_stack_size.max(needed as u8);
+        Ok(())
+    }
+
+    /// Allocates `n` registers, returning the first one
+    fn reserve(&mut self, n: usize) -> Result<usize> {
+        self.check_stack(n)?;
+        let first = self.freereg();
+        self.fs().freereg += n;
+        Ok(first)
+    }
+
+    fn free_to(&mut self, reg: usize) {
+        self.fs().freereg = reg;
+    }
+
+    fn constant(&mut self, k: Constant) -> usize {
+        let fs = self.fs();
+        let key = ConstantKey::from(&k);
+        if let Some(&index) = fs.constants.get(&key) {
+            return index;
+        }
+        fs.proto.constants.push(k);
+        let index = fs.proto.constants.len() - 1;
+        fs.constants.insert(key, index);
+        index
+    }
+
+    fn load_constant(&mut self, reg: usize, k: Constant) -> Result<()> {
+        let index = self.constant(k);
+        if index <= MAXARG_BX as usize {
+            self.abx(OpCode::LoadK, reg, index)?;
+        } else {
+            self.abx(OpCode::LoadKx, reg, 0)?;
+            self.emit(Instruction::ax(OpCode::ExtraArg, index as u32));
+        }
+        Ok(())
+    }
+
+    /// A constant as an RK operand, loaded into a new register when its
+    /// index is too large
+    fn rk(&mut self, k: Constant) -> Result<u32> {
+        let index = self.constant(k.clone());
+        if index <= MAXINDEXRK as usize {
+            return Ok(index as u32 | BITRK);
+        }
+        let reg = self.reserve(1)?;
+        self.load_constant(reg, k)?;
+        Ok(reg as u32)
+    }
+
+    fn string_rk(&mut self, s: &str) -> Result<u32> {
+        self.rk(Constant::String(s.as_bytes().to_vec()))
+    }
+
+    // Scopes
+
+    fn enter_scope(&mut self, is_loop: bool) {
+        let fs = self.fs();
+        let nactvar = fs.actvar.len();
+        fs.scopes.push(Scope {
+            nactvar,
+            first_label: fs.labels.len(),
+            first_goto: fs.gotos.len(),
+            upval: false,
+            is_loop,
+            breaks: vec![],
+            #[cfg(feature="luau")]
+            continues: vec![],
+            #[cfg(feature="luau")]
+            continue_level: nactvar,
+        });
+    }
+
+    fn leave_scope(&mut self) -> Result<Scope> {
+        let (nactvar, upval, outermost) = {
+            let fs = self.funcs.last().unwrap();
+            let scope = fs.scopes.last().unwrap();
+            (scope.nactvar, scope.upval, fs.scopes.len() == 1)
+        };
+        // Returning closes the upvalues of the whole function
+        if upval && !outermost {
+            let j = self.jump();
+            self.patch_close(j, nactvar);
+        }
+        let pc = self.pc();
+        let fs = self.fs();
+        let scope = fs.scopes.pop().unwrap();
+        for var in fs.actvar.drain(nactvar..) {
+            fs.proto.local_vars[var].end_pc = pc;
+        }
+        fs.freereg = nactvar;
+        fs.labels.truncate(scope.first_label);
+        if outermost {
+            if let Some(goto) = fs.gotos.first() {
+                let message = format!("no visible label '{}' for <goto> at line {}", goto.name, goto.line);
+                return Err(CompileError { line: goto.line, message });
+            }
+        }
+        for goto in &mut fs.gotos[scope.first_goto..] {
+            goto.nactvar = goto.nactvar.min(nactvar);
+        }
+        Ok(scope)
+    }
+
+    fn activate(&mut self, name: &str) -> Result<()> {
+        if self.nactvar() >= MAX_LOCALS {
+            let message = format!("too many local variables (limit is {}) in {}",
+                                  MAX_LOCALS, self.funcs.last().unwrap().describe());
+            return self.error(message);
+        }
+        let pc = self.pc();
+        let fs = self.fs();
+        fs.proto.local_vars.push(LocalVar { name: name.to_string(), start_pc: pc, end_pc: pc });
+        let var = fs.proto.local_vars.len() - 1;
+        fs.actvar.push(var);
+        Ok(())
+    }
+
+    // Variables
+
+    /// Looks `name` up in the function at `level` and the ones enclosing it,
+    /// adding the upvalues needed to reach it
+    fn find_var(&mut self, level: usize, name: &str) -> Result<Option<Var>> {
+        {
+            let fs = &self.funcs[level];
+            if let Some(reg) = (0..fs.actvar.len()).rev().find(|&r| fs.local_name(r) == name) {
+                return Ok(Some(Var::Local(reg)));
+            }
+            if let Some(index) = fs.proto.upvalues.iter().position(|u| u.name == name) {
+                return Ok(Some(Var::Upvalue(index)));
+            }
+        }
+        if level == 0 {
+            return Ok(None);
+        }
+        let (in_stack, index) = match self.find_var(level - 1, name)? {
+            Some(Var::Local(reg)) => {
+                self.funcs[level - 1].mark_upval(reg);
+                (true, reg)
+            }
+            Some(Var::Upvalue(index)) => (false, index),
+            _ => return Ok(None),
+        };
+        if self.funcs[level].proto.upvalues.len() >= MAX_UPVALUES {
+            let message = format!("too many upvalues (limit is {}) in {}",
+                                  MAX_UPVALUES, self.funcs[level].describe());
+            return self.error(message);
+        }
+        let upvalues = &mut self.funcs[level].proto.upvalues;
+        upvalues.push(Upvalue { name: name.to_string(), in_stack, index: index as u8 });
+        Ok(Some(Var::Upvalue(upvalues.len() - 1)))
+    }
+
+    fn single_var(&mut self, name: &str) -> Result<Var> {
+        let level = self.funcs.len() - 1;
+        Ok(self.find_var(level, name)?.unwrap_or_else(|| Var::Global(name.to_string())))
+    }
+
+    /// The `_ENV` globals are fields of
+    fn env(&mut self) -> Result<Var> {
+        match self.single_var("_ENV")? {
+            Var::Global(_) => unreachable!("the main function has an _ENV upvalue"),
+            env => Ok(env),
+        }
+    }
+
+    fn var_to_reg(&mut self, var: Var, reg: usize) -> Result<()> {
+        match var {
+            Var::Local(r) => if r != reg {
+                self.abc(OpCode::Move, reg, r as u32, 0);
+            },
+            Var::Upvalue(index) => {
+                self.abc(OpCode::GetUpval, reg, index as u32, 0);
+            }
+            Var::Global(ref name) => {
+                let key = self.string_rk(name)?;
+                match self.env()? {
+                    Var::Upvalue(env) => self.abc(OpCode::GetTabUp, reg, env as u32, key),
+                    Var::Local(env) => self.abc(OpCode::GetTable, reg, env as u32, key),
+                    Var::Global(_) => unreachable!(),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// The register of `e` if it is a local variable
+    fn local_reg(&mut self, e: &ASTNode) -> Result<Option<usize>> {
+        if let Var(ref n) = *strip_parens(e) {
+            if let Var::Local(reg) = self.single_var(name(n))? {
+                return Ok(Some(reg));
+            }
+        }
+        Ok(None)
+    }
+
+    // Expressions
+
+    /// Evaluates `e` to a single value in `reg`, which the caller reserved
+    /// above the active locals
+    fn exp_to_reg(&mut self, e: &ASTNode, reg: usize) -> Result<()> {
+        self.set_line(e);
+        let mark = self.freereg();
+        if let Some((op, a, b)) = e.as_binop() {
+            self.binop(op, a, b, reg)?;
+            self.free_to(mark);
+            return Ok(());
+        }
+        match *e {
+            Nil => {
+                self.abc(OpCode::LoadNil, reg, 0, 0);
+            }
+            Bool(b) => {
+                self.abc(OpCode::LoadBool, reg, b as u32, 0);
+            }
+            Integer(i) => self.load_constant(reg, Constant::Integer(i))?,
+            Float(f) => self.load_constant(reg, Constant::Float(f))?,
+            String(ref s) => self.load_constant(reg, Constant::String(s.clone()))?,
+            VarArg => {
+                self.check_vararg()?;
+                self.abc(OpCode::VarArg, reg, 2, 0);
+            }
+            PrefixExp(ref inner) | Paren(ref inner) => self.exp_to_reg(inner, reg)?,
+            Var(ref n) => {
+                let var = self.single_var(name(n))?;
+                self.var_to_reg(var, reg)?;
+            }
+            VarPrefixed(ref prefix, ref key) => {
+                let table = self.exp_to_any_reg(prefix)?;
+                let key = self.exp_to_rk(key)?;
+                self.abc(OpCode::GetTable, reg, table as u32, key);
+            }
+            VarListAccess(ref prefix, ref n) => {
+                let table = self.exp_to_any_reg(prefix)?;
+                let key = self.string_rk(name(n))?;
+                self.abc(OpCode::GetTable, reg, table as u32, key);
+            }
+            // Calls and constructors build their value at the top of the stack
+            FunctionCall(_, _) | MethodCall(_, _, _) | TableConstructor(_) => {
+                let top = reg + 1 == mark;
+                if top {
+                    self.free_to(reg);
+                }
+                let base = self.freereg();
+                match *e {
+                    TableConstructor(ref fields) => {
+                        self.reserve(1)?;
+                        self.table(fields, base)?;
+                    }
+                    _ => {
+                        self.call(e, Some(1))?;
+                    }
+                }
+                if !top {
+                    self.abc(OpCode::Move, reg, base as u32, 0);
+                }
+            }
+            Function(ref body) => self.closure(e, body, false, reg)?,
+            Not(ref a) | UMin(ref a) | BinNot(ref a) | Len(ref a) => {
+                let operand = self.exp_to_any_reg(a)?;
+                let op = match *e {
+                    Not(_) => OpCode::Not,
+                    UMin(_) => OpCode::Unm,
+                    BinNot(_) => OpCode::BNot,
+                    _ => OpCode::Len,
+                };
+                self.abc(op, reg, operand as u32, 0);
+            }
+            #[cfg(feature="luau")]
+            IfExp(ref cond, ref then, ref other) => {
+                let skip = self.jump_if(cond, false)?;
+                self.exp_to_reg(then, reg)?;
+                let end = self.jump();
+                self.patch_here(&skip)?;
+                self.exp_to_reg(other, reg)?;
+                self.patch_here(&[end])?;
+            }
+            _ => return self.error(format!("{:?} is not an expression", e)),
+        }
+        self.free_to(mark);
+        Ok(())
+    }
+
+    fn exp_to_next_reg(&mut self, e: &ASTNode) -> Result<usize> {
+        let reg = self.reserve(1)?;
+        self.exp_to_reg(e, reg)?;
+        Ok(reg)
+    }
+
+    /// Evaluates `e` to a register, which is the one of a local variable
+    /// or a new one
+    fn exp_to_any_reg(&mut self, e: &ASTNode) -> Result<usize> {
+        match self.local_reg(e)? {
+            Some(reg) => Ok(reg),
+            None => self.exp_to_next_reg(e),
+        }
+    }
+
+    /// Evaluates `e` to an RK operand, literals are constants
+    fn exp_to_rk(&mut self, e: &ASTNode) -> Result<u32> {
+        let k = match *strip_parens(e) {
+            Nil => Constant::Nil,
+            Bool(b) => Constant::Boolean(b),
+            Integer(i) => Constant::Integer(i),
+            Float(f) => Constant::Float(f),
+            String(ref s) => Constant::String(s.clone()),
+            _ => return Ok(self.exp_to_any_reg(e)? as u32),
+        };
+        self.rk(k)
+    }
+
+    fn binop(&mut self, op: BinOp, a: &ASTNode, b: &ASTNode, reg: usize) -> Result<()> {
+        match op {
+            BinOp::And | BinOp::Or => {
+                // The first value is the result when it decides
+                self.exp_to_reg(a, reg)?;
+                self.abc(OpCode::Test, reg, 0, (op == BinOp::Or) as u32);
+                let skip = self.jump();
+                self.exp_to_reg(b, reg)?;
+                self.patch_here(&[skip])
+            }
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+                let jumps = self.compare(op, a, b, true)?;
+                self.abc(OpCode::LoadBool, reg, 0, 1);
+                self.patch_here(&jumps)?;
+                self.abc(OpCode::LoadBool, reg, 1, 0);
+                Ok(())
+            }
+            BinOp::Concat => {
+                // a .. b .. c is right associative, the operands go into
+                // consecutive registers for a single CONCAT
+                let mut operands = vec![a];
+                let mut rest = b;
+                while let Concat(ref a, ref b) = *rest {
+                    operands.push(a);
+                    rest = b;
+                }
+                operands.push(rest);
+                let first = self.freereg();
+                for operand in operands {
+                    self.exp_to_next_reg(operand)?;
+                }
+                let last = self.freereg() - 1;
+                self.abc(OpCode::Concat, reg, first as u32, last as u32);
+                Ok(())
+            }
+            _ => {
+                let b_operand = self.exp_to_rk(a)?;
+                let c_operand = self.exp_to_rk(b)?;
+                self.abc(arith_opcode(op), reg, b_operand, c_operand);
+                Ok(())
+            }
+        }
+    }
+
+    /// Emits a comparison and the jump taken when it is `value`
+    fn compare(&mut self, op: BinOp, a: &ASTNode, b: &ASTNode, value: bool) -> Result<Vec<usize>> {
+        let mark = self.freereg();
+        let (opcode, swap, negate) = match op {
+            BinOp::Eq => (OpCode::Eq, false, false),
+            BinOp::Ne => (OpCode::Eq, false, true),
+            BinOp::Lt => (OpCode::Lt, false, false),
+            BinOp::Le => (OpCode::Le, false, false),
+            BinOp::Gt => (OpCode::Lt, true, false),
+            _ => (OpCode::Le, true, false),
+        };
+        let x = self.exp_to_rk(a)?;
+        let y = self.exp_to_rk(b)?;
+        let (x, y) = if swap { (y, x) } else { (x, y) };
+        self.abc(opcode, (value != negate) as usize, x, y);
+        let j = self.jump();
+        self.free_to(mark);
+        Ok(vec![j])
+    }
+
+    /// Emits the jumps taken when the truth of `e` is `value`, the code
+    /// falls through otherwise
+    fn jump_if(&mut self, e: &ASTNode, value: bool) -> Result<Vec<usize>> {
+        self.set_line(e);
+        if let Some((op, a, b)) = e.as_binop() {
+            match (op, value) {
+                (BinOp::And, false) | (BinOp::Or, true) => {
+                    let mut jumps = self.jump_if(a, value)?;
+                    jumps.extend(self.jump_if(b, value)?);
+                    return Ok(jumps);
+                }
+                (BinOp::And, true) | (BinOp::Or, false) => {
+                    let skip = self.jump_if(a, !value)?;
+                    let jumps = self.jump_if(b, value)?;
+                    self.patch_here(&skip)?;
+                    return Ok(jumps);
+                }
+                (BinOp::Eq, _) | (BinOp::Ne, _) | (BinOp::Lt, _) | (BinOp::Le, _) |
+                (BinOp::Gt, _) | (BinOp::Ge, _) => return self.compare(op, a, b, value),
+                _ => (),
+            }
+        }
+        match *e {
+            Not(ref a) => self.jump_if(a, !value),
+            PrefixExp(ref inner) | Paren(ref inner) if as_multi(e).is_none() => self.jump_if(inner, value),
+            Nil | Bool(false) => Ok(if value { vec![] } else { vec![self.jump()] }),
+            Bool(true) | Integer(_) | Float(_) | String(_) =>
+                Ok(if value { vec![self.jump()] } else { vec![] }),
+            _ => {
+                let mark = self.freereg();
+                let reg = self.exp_to_any_reg(e)?;
+                self.abc(OpCode::Test, reg, 0, value as u32);
+                let j = self.jump();
+                self.free_to(mark);
+                Ok(vec![j])
+            }
+        }
+    }
+
+    fn check_vararg(&self) -> Result<()> {
+        if self.funcs.last().unwrap().proto.is_vararg {
+            Ok(())
+        } else {
+            self.error("cannot use '...' outside a vararg function")
+        }
+    }
+
+    /// Evaluates a call or `...` at the first free register, keeping
+    /// `nresults` values or all of them
+    fn multi(&mut self, e: &ASTNode, nresults: Option<usize>) -> Result<()> {
+        match *e {
+            VarArg => {
+                self.check_vararg()?;
+                let base = self.freereg();
+                self.abc(OpCode::VarArg, base, nresults.map_or(0, |n| n as u32 + 1), 0);
+                if let Some(n) = nresults {
+                    self.reserve(n)?;
+                }
+                Ok(())
+            }
+            _ => self.call(e, nresults).map(|_| ()),
+        }
+    }
+
+    /// Calls with the function in the first free register, which is
+    /// returned. The results replace the function, all of them up to the
+    /// top of the stack when `nresults` is `None`.
+    fn call(&mut self, e: &ASTNode, nresults: Option<usize>) -> Result<usize> {
+        let mark = self.freereg();
+        let (base, args) = match *e {
+            FunctionCall(ref f, ref args) => (self.exp_to_next_reg(f)?, args),
+            MethodCall(ref object, ref method, ref args) => {
+                let object = self.exp_to_any_reg(object)?;
+                self.free_to(mark);
+                let base = self.reserve(2)?;
+                let key = self.string_rk(name(method))?;
+                self.set_line(e);
+                self.abc(OpCode::OpSelf, base, object as u32, key);
+                self.free_to(base + 2);
+                (base, args)
+            }
+            _ => unreachable!("{:?} is not a call", e),
+        };
+        let mut open = false;
+        if let Some(ref args) = **args {
+            open = self.explist(list(args), None)?;
+        }
+        self.set_line(e);
+        let b = if open { 0 } else { (self.freereg() - base) as u32 };
+        let c = nresults.map_or(0, |n| n as u32 + 1);
+        if b > MAXARG_B || c > MAXARG_C {
+            return self.error("function or expression needs too many registers");
+        }
+        self.abc(OpCode::Call, base, b, c);
+        self.free_to(base);
+        if let Some(n) = nresults {
+            self.reserve(n)?;
+        }
+        Ok(base)
+    }
+
+    /// Evaluates expressions into consecutive registers from the first
+    /// free one, adjusted to `want` values. Returns whether the values go
+    /// up to the top of the stack, which happens when all the values of a
+    /// last call or `...` are wanted.
+    fn explist(&mut self, exps: &[ASTNode], want: Option<usize>) -> Result<bool> {
+        let base = self.freereg();
+        for (i, e) in exps.iter().enumerate() {
+            match as_multi(e) {
+                Some(multi) if i == exps.len() - 1 => {
+                    self.set_line(e);
+                    self.multi(multi, want.map(|w| w.saturating_sub(i)))?;
+                    if want.is_none() {
+                        return Ok(true);
+                    }
+                }
+                _ => {
+                    self.exp_to_next_reg(e)?;
+                }
+            }
+        }
+        if let Some(want) = want {
+            let have = self.freereg() - base;
+            if have < want {
+                let first = self.reserve(want - have)?;
+                self.abc(OpCode::LoadNil, first, (want - have - 1) as u32, 0);
+            } else {
+                self.free_to(base + want);
+            }
+        }
+        Ok(false)
+    }
+
+    fn table(&mut self, fields: &Option<ASTNode>, table: usize) -> Result<()> {
+        let pc = self.abc(OpCode::NewTable, table, 0, 0);
+        let fields = match *fields {
+            Some(ref fields) => list(fields),
+            None => &[],
+        };
+        let (mut array, mut hash, mut pending) = (0, 0, 0);
+        for (i, field) in fields.iter().enumerate() {
+            match *field {
+                FieldSingle(ref e) => match as_multi(e) {
+                    Some(multi) if i == fields.len() - 1 => {
+                        self.multi(multi, None)?;
+                        self.set_list(table, array + 1, None)?;
+                        pending = 0;
+                    }
+                    _ => {
+                        self.exp_to_next_reg(e)?;
+                        array += 1;
+                        pending += 1;
+                        if pending == LFIELDS_PER_FLUSH as usize {
+                            self.set_list(table, array, Some(pending))?;
+                            pending = 0;
+                        }
+                    }
+                },
+                FieldAssign(ref key, ref value) => {
+                    hash += 1;
+                    let mark = self.freereg();
+                    self.set_line(field);
+                    let key = match **key {
+                        Name(ref n) => self.string_rk(n)?,
+                        ref key => self.exp_to_rk(key)?,
+                    };
+                    let value = self.exp_to_rk(value)?;
+                    self.abc(OpCode::SetTable, table, key, value);
+                    self.free_to(mark);
+                }
+                _ => unreachable!("{:?} is not a field", field),
+            }
+        }
+        if pending > 0 {
+            self.set_list(table, array, Some(pending))?;
+        }
+        let code = &mut self.fs().proto.code[pc];
+        code.set_b(int_to_fb(array as u32));
+        code.set_c(int_to_fb(hash as u32));
+        Ok(())
+    }
+
+    /// Stores the `count` items above `table`, or all of them up to the top,
+    /// which end with item number `total`
+    fn set_list(&mut self, table: usize, total: usize, count: Option<usize>) -> Result<()> {
+        let block = (total - 1) / LFIELDS_PER_FLUSH as usize + 1;
+        let b = count.map_or(0, |n| n as u32);
+        if block <= MAXARG_C as usize {
+            self.abc(OpCode::SetList, table, b, block as u32);
+        } else {
+            if block > MAXARG_AX as usize {
+                return self.error("constructor too long");
+            }
+            self.abc(OpCode::SetList, table, b, 0);
+            self.emit(Instruction::ax(OpCode::ExtraArg, block as u32));
+        }
+        self.free_to(table + 1);
+        Ok(())
+    }
+
+    /// Compiles a function into a new prototype and makes a closure of it
+    /// in `reg`. `node` is the expression or statement defining it, which
+    /// starts with `function`.
+    fn closure(&mut self, node: &ASTNode, body: &ASTNode, method: bool, reg: usize) -> Result<()> {
+        let (params, block) = match *body {
+            FunctionBody(ref params, ref block) => (params, block),
+            #[cfg(feature="luau")]
+            TypedFunctionBody(ref params, _, ref block) => (params, block),
+            _ => unreachable!("{:?} is not a function body", body),
+        };
+        let lines = |n: &ASTNode| self.lines.get(&(n as *const ASTNode)).cloned();
+        let line = lines(node).map_or(self.line, |l| l.0);
+        let last_line = lines(body).or_else(|| lines(node)).map_or(line, |l| l.1);
+        let outer_line = self.line;
+        self.funcs.push(FuncState::new(None, line, last_line));
+        self.enter_scope(false);
+        let mut parameters = vec![];
+        if method {
+            parameters.push("self");
+        }
+        if let Some(ParameterList(ref list, vararg)) = **params {
+            parameters.extend(names(list));
+            self.fs().proto.is_vararg = vararg;
+        }
+        for p in &parameters {
+            self.reserve(1)?;
+            self.activate(p)?;
+        }
+        self.fs().proto.num_params = parameters.len() as u8;
+        self.statements(block, false)?;
+        self.line = last_line;
+        self.abc(OpCode::Return, 0, 1, 0);
+        self.leave_scope()?;
+        let proto = self.funcs.pop().unwrap().proto;
+        self.line = outer_line;
+        let protos = &mut self.fs().proto.protos;
+        protos.push(proto);
+        let index = protos.len() - 1;
+        self.abx(OpCode::Closure, reg, index)?;
+        Ok(())
+    }
+
+    // Statements
+
+    /// Compiles the statements of a block in its own scope
+    fn block(&mut self, block: &ASTNode) -> Result<()> {
+        self.enter_scope(false);
+        self.statements(block, false)?;
+        self.leave_scope()?;
+        Ok(())
+    }
+
+    /// Compiles the statements of a block in the current scope. The body
+    /// of `repeat` is followed by its condition.
+    fn statements(&mut self, block: &ASTNode, repeat: bool) -> Result<()> {
+        let (statements, retstat) = match *block {
+            Block(ref statements, ref retstat) => (statements, retstat),
+            _ => unreachable!("{:?} is not a block", block),
+        };
+        for (i, statement) in statements.iter().enumerate() {
+            match *statement {
+                Label(ref label) => {
+                    // A label ending its block is out of the scope of the
+                    // locals before it
+                    let at_end = retstat.is_none() && !repeat && statements[i + 1..].iter()
+                        .all(|s| matches!(*s, Label(_) | EmptyStatement));
+                    self.set_line(statement);
+                    self.label(label, at_end)?;
+                }
+                _ => self.statement(statement)?,
+            }
+            let nactvar = self.nactvar();
+            self.free_to(nactvar);
+        }
+        if let Some(ref retstat) = **retstat {
+            self.set_line(retstat);
+            if let RetStat(ref exps) = *retstat {
+                self.ret(exps)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, statement: &ASTNode) -> Result<()> {
+        self.set_line(statement);
+        match *statement {
+            EmptyStatement => Ok(()),
+            Break => self.break_statement(),
+            Goto(ref label) => self.goto(name(label)),
+            Local(ref names, ref exps) => self.local(names, exps),
+            NamedFunction(ref n, ref body) => {
+                let reg = self.reserve(1)?;
+                self.activate(name(n))?;
+                self.closure(statement, body, false, reg)
+            }
+            FunctionStat(_, ref body) => self.function_statement(statement, body),
+            Assign(ref vars, ref exps) => self.assign(list(vars), list(exps)),
+            FunctionCall(_, _) | MethodCall(_, _, _) => self.call(statement, Some(0)).map(|_| ()),
+            Do(ref block) => self.block(block),
+            While(ref cond, ref block) => self.while_loop(cond, block),
+            Repeat(ref block, ref cond) => self.repeat_loop(block, cond),
+            If(_, _, _) => self.if_statement(statement),
+            NumericFor(ref var, ref start, ref limit, ref step, ref block) =>
+                self.numeric_for(name(var), start, limit, step, block),
+            GenericFor(ref names, ref exps, ref block) => match **names {
+                NameList(ref names) => {
+                    let names: Vec<_> = names.iter().map(|(n, _)| name(n)).collect();
+                    self.generic_for(&names, list(exps), block)
+                }
+                _ => unreachable!("{:?} is not a name list", names),
+            },
+            #[cfg(feature="luau")]
+            CompoundAssign(op, ref var, ref exp) => self.compound_assign(op, var, exp),
+            #[cfg(feature="luau")]
+            Continue => self.continue_statement(),
+            #[cfg(feature="luau")]
+            TypeAlias(_, _, _, _) => Ok(()),
+            _ => self.error(format!("{:?} is not a statement", statement)),
+        }
+    }
+
+    fn local(&mut self, names: &ASTNode, exps: &Option<ASTNode>) -> Result<()> {
+        let names = match *names {
+            NameList(ref names) => names,
+            _ => unreachable!("{:?} is not a name list", names),
+        };
+        if names.iter().any(|&(_, attrib)| attrib == Some(Attrib::Close)) {
+            return self.error("to-be-closed variables do not exist in Lua 5.3");
+        }
+        match *exps {
+            Some(ref exps) => {
+                self.explist(list(exps), Some(names.len()))?;
+            }
+            None => {
+                let first = self.reserve(names.len())?;
+                self.abc(OpCode::LoadNil, first, names.len() as u32 - 1, 0);
+            }
+        }
+        for (n, _) in names {
+            self.activate(name(n))?;
+        }
+        Ok(())
+    }
+
+    /// Evaluates the table and key of an assignment target. With `copy`
+    /// they are copied out of local variables, which an earlier store of a
+    /// multiple assignment may change.
+    fn lvalue(&mut self, var: &ASTNode, copy: bool) -> Result<LValue> {
+        let operand = |this: &mut Compiler, e: &ASTNode| -> Result<u32> {
+            if copy {
+                if let Some(reg) = this.local_reg(e)? {
+                    let new = this.reserve(1)?;
+                    this.abc(OpCode::Move, new, reg as u32, 0);
+                    return Ok(new as u32);
+                }
+            }
+            this.exp_to_rk(e)
+        };
+        Ok(match *var {
+            Var(ref n) => match self.single_var(name(n))? {
+                Var::Local(reg) => LValue::Local(reg),
+                Var::Upvalue(index) => LValue::Upvalue(index),
+                Var::Global(ref n) => {
+                    let key = self.string_rk(n)?;
+                    match self.env()? {
+                        Var::Upvalue(env) => LValue::Global(env, key),
+                        Var::Local(env) if copy => {
+                            let table = self.reserve(1)?;
+                            self.abc(OpCode::Move, table, env as u32, 0);
+                            LValue::Index(table, key)
+                        }
+                        Var::Local(env) => LValue::Index(env, key),
+                        Var::Global(_) => unreachable!(),
+                    }
+                }
+            },
+            VarPrefixed(ref prefix, ref key) => {
+                let table = operand(self, prefix)?;
+                let table = if is_k(table) {
+                    let reg = self.reserve(1)?;
+                    self.exp_to_reg(prefix, reg)?;
+                    reg as u32
+                } else {
+                    table
+                };
+                LValue::Index(table as usize, operand(self, key)?)
+            }
+            VarListAccess(ref prefix, ref n) => {
+                let table = match operand(self, prefix)? {
+                    t if is_k(t) => {
+                        let reg = self.reserve(1)?;
+                        self.exp_to_reg(prefix, reg)?;
+                        reg
+                    }
+                    t => t as usize,
+                };
+                LValue::Index(table, self.string_rk(name(n))?)
+            }
+            PrefixExp(ref var) => return self.lvalue(var, copy),
+            _ => unreachable!("{:?} can not be assigned", var),
+        })
+    }
+
+    fn store(&mut self, target: LValue, value: u32) {
+        match target {
+            LValue::Local(reg) => self.abc(OpCode::Move, reg, value, 0),
+            LValue::Upvalue(index) => self.abc(OpCode::SetUpval, value as usize, index as u32, 0),
+            LValue::Index(table, key) => self.abc(OpCode::SetTable, table, key, value),
+            LValue::Global(env, key) => self.abc(OpCode::SetTabUp, env, key, value),
+        };
+    }
+
+    /// Stores the value of `e`, which goes through a register unless it
+    /// is stored in a table
+    fn store_exp(&mut self, target: LValue, e: &ASTNode) -> Result<()> {
+        let value = match target {
+            LValue::Local(_) | LValue::Upvalue(_) => self.exp_to_next_reg(e)? as u32,
+            _ => self.exp_to_rk(e)?,
+        };
+        self.store(target, value);
+        Ok(())
+    }
+
+    fn assign(&mut self, vars: &[ASTNode], exps: &[ASTNode]) -> Result<()> {
+        if vars.len() == 1 && exps.len() == 1 {
+            let target = self.lvalue(&vars[0], false)?;
+            return self.store_exp(target, &exps[0]);
+        }
+        let mut targets = vec![];
+        for var in vars {
+            targets.push(self.lvalue(var, true)?);
+        }
+        let base = self.freereg();
+        self.explist(exps, Some(vars.len()))?;
+        // Lua stores the values from the last one
+        for (i, &target) in targets.iter().enumerate().rev() {
+            self.store(target, (base + i) as u32);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature="luau")]
+    fn compound_assign(&mut self, op: BinOp, var: &ASTNode, exp: &ASTNode) -> Result<()> {
+        // The target is evaluated once
+        let target = self.lvalue(var, false)?;
+        let reg = self.reserve(1)?;
+        match target {
+            LValue::Local(r) => {
+                self.abc(OpCode::Move, reg, r as u32, 0);
+            }
+            LValue::Upvalue(index) => {
+                self.abc(OpCode::GetUpval, reg, index as u32, 0);
+            }
+            LValue::Index(table, key) => {
+                self.abc(OpCode::GetTable, reg, table as u32, key);
+            }
+            LValue::Global(env, key) => {
+                self.abc(OpCode::GetTabUp, reg, env as u32, key);
+            }
+        }
+        if op == BinOp::Concat {
+            self.exp_to_next_reg(exp)?;
+            self.abc(OpCode::Concat, reg, reg as u32, reg as u32 + 1);
+        } else {
+            let value = self.exp_to_rk(exp)?;
+            self.abc(arith_opcode(op), reg, reg as u32, value);
+        }
+        self.store(target, reg as u32);
+        Ok(())
+    }
+
+    fn function_statement(&mut self, statement: &ASTNode, body: &ASTNode) -> Result<()> {
+        let funcname = match *statement {
+            FunctionStat(ref funcname, _) => funcname,
+            _ => unreachable!(),
+        };
+        let (first, fields, method) = match **funcname {
+            FunctionName(ref first, ref fields, ref method) => (first, fields, method),
+            _ => unreachable!("{:?} is not a function name", funcname),
+        };
+        let mut keys: Vec<&str> = fields.iter().flat_map(|f| f.iter().map(name)).collect();
+        if let Some(ref method) = *method {
+            keys.push(name(method));
+        }
+        let target = match keys.split_last() {
+            None => self.lvalue(&Var(first.clone()), false)?,
+            Some((last, path)) => {
+                let var = self.single_var(name(first))?;
+                let table = self.reserve(1)?;
+                self.var_to_reg(var, table)?;
+                for key in path {
+                    let mark = self.freereg();
+                    let key = self.string_rk(key)?;
+                    self.abc(OpCode::GetTable, table, table as u32, key);
+                    self.free_to(mark);
+                }
+                LValue::Index(table, self.string_rk(last)?)
+            }
+        };
+        let reg = self.reserve(1)?;
+        self.closure(statement, body, method.is_some(), reg)?;
+        self.store(target, reg as u32);
+        Ok(())
+    }
+
+    fn ret(&mut self, exps: &Option<ASTNode>) -> Result<()> {
+        let exps = match *exps {
+            Some(ref exps) => list(exps),
+            None => &[],
+        };
+        if exps.len() == 1 {
+            match as_multi(&exps[0]) {
+                Some(call) if *call != VarArg => {
+                    let base = self.call(call, None)?;
+                    let pc = self.pc() - 1;
+                    let code = &mut self.fs().proto.code[pc];
+                    *code = Instruction::abc(OpCode::TailCall, code.a(), code.b(), code.c());
+                    self.abc(OpCode::Return, base, 0, 0);
+                    return Ok(());
+                }
+                _ => if let Some(reg) = self.local_reg(&exps[0])? {
+                    self.abc(OpCode::Return, reg, 2, 0);
+                    return Ok(());
+                },
+            }
+        }
+        let first = self.freereg();
+        let open = self.explist(exps, None)?;
+        let b = if open { 0 } else { (self.freereg() - first + 1) as u32 };
+        self.abc(OpCode::Return, first, b, 0);
+        Ok(())
+    }
+
+    fn label(&mut self, label: &str, at_end: bool) -> Result<()> {
+        let line = self.line;
+        let pc = self.pc();
+        let nactvar = self.nactvar();
+        let fs = self.fs();
+        let scope = fs.scopes.last().unwrap();
+        if let Some(earlier) = fs.labels[scope.first_label..].iter().find(|l| l.name == label) {
+            let message = format!("label '{}' already defined on line {}", label, earlier.line);
+            return self.error(message);
+        }
+        let nactvar = if at_end { scope.nactvar } else { nactvar };
+        let first_goto = scope.first_goto;
+        fs.labels.push(Jump { name: label.to_string(), pc, line, nactvar, level: nactvar });
+        // Resolve the pending gotos of the scope
+        let mut i = first_goto;
+        while i < self.fs().gotos.len() {
+            if self.fs().gotos[i].name != label {
+                i += 1;
+                continue;
+            }
+            let goto = self.fs().gotos.remove(i);
+            if goto.nactvar < nactvar {
+                let message = format!("<goto {}> at line {} jumps into the scope of local '{}'",
+                                      label, goto.line, self.fs().local_name(goto.nactvar));
+                return Err(CompileError { line: goto.line, message });
+            }
+            self.patch(goto.pc, pc)?;
+            if goto.level > nactvar {
+                self.patch_close(goto.pc, nactvar);
+            }
+        }
+        Ok(())
+    }
+
+    fn goto(&mut self, label: &str) -> Result<()> {
+        let line = self.line;
+        let nactvar = self.nactvar();
+        let j = self.jump();
+        let fs = self.fs();
+        // A label before the goto is resolved at once
+        let backward = fs.labels.iter().rev().find(|l| l.name == label).map(|l| (l.pc, l.nactvar));
+        match backward {
+            Some((pc, level)) => {
+                self.patch(j, pc)?;
+                if nactvar > level {
+                    self.patch_close(j, level);
+                }
+            }
+            None => fs.gotos.push(Jump { name: label.to_string(), pc: j, line, nactvar, level: nactvar }),
+        }
+        Ok(())
+    }
+
+    fn innermost_loop(&mut self) -> Option<&mut Scope> {
+        self.fs().scopes.iter_mut().rev().find(|s| s.is_loop)
+    }
+
+    fn break_statement(&mut self) -> Result<()> {
+        let line = self.line;
+        let nactvar = self.nactvar();
+        let j = self.jump();
+        let level = match self.innermost_loop() {
+            Some(scope) => {
+                scope.breaks.push(j);
+                scope.nactvar
+            }
+            None => return self.error(format!("<break> at line {} not inside a loop", line)),
+        };
+        if nactvar > level {
+            self.patch_close(j, level);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature="luau")]
+    fn continue_statement(&mut self) -> Result<()> {
+        let nactvar = self.nactvar();
+        let j = self.jump();
+        let level = match self.innermost_loop() {
+            Some(scope) => {
+                scope.continues.push(j);
+                scope.continue_level
+            }
+            None => return self.error("continue outside a loop"),
+        };
+        if nactvar > level {
+            self.patch_close(j, level);
+        }
+        Ok(())
+    }
+
+    /// Leaves a loop, `next` is where `continue` goes
+    #[cfg_attr(not(feature="luau"), allow(unused_variables))]
+    fn leave_loop(&mut self, next: usize) -> Result<()> {
+        let scope = self.leave_scope()?;
+        self.patch_here(&scope.breaks)?;
+        #[cfg(feature="luau")]
+        for &j in &scope.continues {
+            self.patch(j, next)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature="luau")]
+    fn set_continue_level(&mut self, level: usize) {
+        self.innermost_loop().unwrap().continue_level = level;
+    }
+
+    #[cfg(not(feature="luau"))]
+    fn set_continue_level(&mut self, _: usize) {}
+
+    fn while_loop(&mut self, cond: &ASTNode, block: &ASTNode) -> Result<()> {
+        let start = self.pc();
+        let exits = self.jump_if(cond, false)?;
+        self.enter_scope(true);
+        self.block(block)?;
+        let back = self.jump_to(start)?;
+        self.leave_loop(back)?;
+        self.patch_here(&exits)
+    }
+
+    fn repeat_loop(&mut self, block: &ASTNode, cond: &ASTNode) -> Result<()> {
+        let start = self.pc();
+        self.enter_scope(true);
+        self.enter_scope(false);
+        self.statements(block, true)?;
+        let check = self.pc();
+        let repeats = self.jump_if(cond, false)?;
+        // Going around again leaves the scope of the body
+        let (upval, nactvar) = {
+            let scope = self.funcs.last().unwrap().scopes.last().unwrap();
+            (scope.upval, scope.nactvar)
+        };
+        for &j in &repeats {
+            self.patch(j, start)?;
+            if upval {
+                self.patch_close(j, nactvar);
+            }
+        }
+        self.leave_scope()?;
+        self.leave_loop(check)
+    }
+
+    fn if_statement(&mut self, mut statement: &ASTNode) -> Result<()> {
+        let mut exits = vec![];
+        // elseif is a nested If
+        while let If(ref cond, ref block, ref other) = *statement {
+            self.set_line(statement);
+            let skip = self.jump_if(cond, false)?;
+            self.block(block)?;
+            if other.is_some() {
+                exits.push(self.jump());
+            }
+            self.patch_here(&skip)?;
+            match **other {
+                Some(ref other @ If(_, _, _)) => statement = other,
+                Some(ref block) => {
+                    self.block(block)?;
+                    break;
+                }
+                None => break,
+            }
+        }
+        self.patch_here(&exits)
+    }
+
+    fn numeric_for(&mut self, var: &str, start: &ASTNode, limit: &ASTNode,
+                   step: &Option<ASTNode>, block: &ASTNode) -> Result<()> {
+        self.enter_scope(true);
+        let base = self.exp_to_next_reg(start)?;
+        self.exp_to_next_reg(limit)?;
+        match *step {
+            Some(ref step) => {
+                self.exp_to_next_reg(step)?;
+            }
+            None => {
+                let reg = self.reserve(1)?;
+                self.load_constant(reg, Constant::Integer(1))?;
+            }
+        }
+        for hidden in &["(for index)", "(for limit)", "(for step)"] {
+            self.activate(hidden)?;
+        }
+        let prep = self.emit(Instruction::asbx(OpCode::ForPrep, base as u32, 0));
+        self.set_continue_level(base + 3);
+        self.enter_scope(false);
+        self.reserve(1)?;
+        self.activate(var)?;
+        self.block(block)?;
+        self.leave_scope()?;
+        self.patch_here(&[prep])?;
+        let end = self.pc();
+        let offset = prep as i64 + 1 - (end as i64 + 1);
+        if offset.abs() > MAXARG_SBX as i64 {
+            return self.error("control structure too long");
+        }
+        self.emit(Instruction::asbx(OpCode::ForLoop, base as u32, offset as i32));
+        self.leave_loop(end)
+    }
+
+    fn generic_for(&mut self, names: &[&str], exps: &[ASTNode], block: &ASTNode) -> Result<()> {
+        self.enter_scope(true);
+        let base = self.freereg();
+        self.explist(exps, Some(3))?;
+        for hidden in &["(for generator)", "(for state)", "(for control)"] {
+            self.activate(hidden)?;
+        }
+        // The generator is called with copies of the state above them
+        self.check_stack(3)?;
+        let prep = self.jump();
+        self.set_continue_level(base + 3);
+        self.enter_scope(false);
+        self.reserve(names.len())?;
+        for n in names {
+            self.activate(n)?;
+        }
+        self.block(block)?;
+        self.leave_scope()?;
+        self.patch_here(&[prep])?;
+        let call = self.abc(OpCode::TForCall, base, 0, names.len() as u32);
+        let offset = prep as i64 + 1 - (call as i64 + 2);
+        if offset.abs() > MAXARG_SBX as i64 {
+            return self.error("control structure too long");
+        }
+        self.emit(Instruction::asbx(OpCode::TForLoop, base as u32 + 2, offset as i32));
+        self.leave_loop(call)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{LuaVersion, ParserConfig};
+    use parse_block_with;
+
+    fn compile_str(source: &str) -> Result<Proto> {
+        let config = ParserConfig { version: LuaVersion::Lua53, ..ParserConfig::default() };
+        let block = parse_block_with(source.as_bytes(), &config).unwrap();
+        compile(&block, source.as_bytes(), "=test")
+    }
+
+    fn ops(proto: &Proto) -> Vec<OpCode> {
+        proto.code.iter().map(|i| i.opcode().unwrap()).collect()
+    }
+
+    #[test]
+    fn globals_go_through_env() {
+        let proto = compile_str("x = y").unwrap();
+        assert_eq!(ops(&proto), vec![OpCode::GetTabUp, OpCode::SetTabUp, OpCode::Return]);
+        assert_eq!(proto.constants, vec![Constant::String(b"x".to_vec()), Constant::String(b"y".to_vec())]);
+        assert_eq!(proto.upvalues, vec![Upvalue { name: "_ENV".into(), in_stack: true, index: 0 }]);
+        assert!(proto.is_vararg);
+    }
+
+    #[test]
+    fn string_constants_are_bytes() {
+        let proto = compile_str("x = \"\\128\\255\"").unwrap();
+        assert_eq!(proto.constants, vec![Constant::String(b"x".to_vec()), Constant::String(vec![0x80, 0xFF])]);
+    }
+
+    #[test]
+    fn locals_are_registers() {
+        let proto = compile_str("local a, b = 1\nlocal c = a\nreturn c").unwrap();
+        assert_eq!(proto.code, vec![
+            Instruction::abx(OpCode::LoadK, 0, 0),
+            Instruction::abc(OpCode::LoadNil, 1, 0, 0),
+            Instruction::abc(OpCode::Move, 2, 0, 0),
+            Instruction::abc(OpCode::Return, 2, 2, 0),
+            Instruction::abc(OpCode::Return, 0, 1, 0),
+        ]);
+        assert_eq!(proto.line_info, vec![1, 1, 2, 3, 3]);
+        let names: Vec<_> = proto.local_vars.iter().map(|v| (v.name.as_str(), v.start_pc, v.end_pc)).collect();
+        assert_eq!(names, vec![("a", 2, 5), ("b", 2, 5), ("c", 3, 5)]);
+        assert_eq!(proto.max_stack_size, 3);
+    }
+
+    #[test]
+    fn upvalues() {
+        let proto = compile_str("local x = 1\nlocal function f() return function() return x, y end end").unwrap();
+        let f = &proto.protos[0];
+        assert_eq!(f.line_defined, 2);
+        // f passes _ENV on from the main function
+        assert_eq!(f.upvalues, vec![
+            Upvalue { name: "x".into(), in_stack: true, index: 0 },
+            Upvalue { name: "_ENV".into(), in_stack: false, index: 0 },
+        ]);
+        let inner = &f.protos[0];
+        assert_eq!(inner.upvalues, vec![
+            Upvalue { name: "x".into(), in_stack: false, index: 0 },
+            Upvalue { name: "_ENV".into(), in_stack: false, index: 1 },
+        ]);
+        assert_eq!(proto.protos[0].protos[0].code[0], Instruction::abc(OpCode::GetUpval, 0, 0, 0));
+    }
+
+    #[test]
+    fn captured_locals_are_closed() {
+        let proto = compile_str("while true do local x = 1 local f = function() return x end end").unwrap();
+        let close = proto.code.iter().find(|i| i.opcode() == Some(OpCode::Jmp) && i.a() > 0).unwrap();
+        assert_eq!(close.a(), 1);
+    }
+
+    #[test]
+    fn tail_calls() {
+        let proto = compile_str("return f(1)").unwrap();
+        assert_eq!(ops(&proto), vec![OpCode::GetTabUp, OpCode::LoadK, OpCode::TailCall,
+                                     OpCode::Return, OpCode::Return]);
+        assert_eq!(proto.code[3], Instruction::abc(OpCode::Return, 0, 0, 0));
+        // Parentheses keep one value, so there is no tail call
+        assert!(!ops(&compile_str("return (f(1))").unwrap()).contains(&OpCode::TailCall));
+    }
+
+    #[test]
+    fn constructors_flush_every_fifty_items() {
+        let items: Vec<_> = (0..120).map(|i| i.to_string()).collect();
+        let proto = compile_str(&format!("return {{{}, f()}}", items.join(", "))).unwrap();
+        let set_lists: Vec<_> = proto.code.iter().filter(|i| i.opcode() == Some(OpCode::SetList))
+            .map(|i| (i.b(), i.c())).collect();
+        assert_eq!(set_lists, vec![(50, 1), (50, 2), (0, 3)]);
+        assert_eq!(fb_to_int(proto.code[0].b()), 120);
+    }
+
+    #[test]
+    fn many_constants() {
+        let items: Vec<_> = (0..300).map(|i| format!("x{} = {}.5", i, i)).collect();
+        let proto = compile_str(&items.join("\n")).unwrap();
+        assert_eq!(proto.constants.len(), 600);
+        assert!(proto.code.iter().any(|i| i.opcode() == Some(OpCode::LoadK) && i.bx() > 255));
+    }
+
+    #[test]
+    fn errors() {
+        let error = |s: &str| compile_str(s).unwrap_err().to_string();
+        assert_eq!(error("x = 1\nbreak"), "line 2: <break> at line 2 not inside a loop");
+        assert_eq!(error("goto l"), "line 1: no visible label 'l' for <goto> at line 1");
+        assert_eq!(error("goto l\nlocal x\n::l:: x = 1"),
+                   "line 1: <goto l> at line 1 jumps into the scope of local 'x'");
+        assert_eq!(error("::a::\n::a::"), "line 2: label 'a' already defined on line 1");
+        assert_eq!(error("function f() return ... end"),
+                   "line 1: cannot use '...' outside a vararg function");
+        let locals: Vec<_> = (0..201).map(|i| format!("local x{} = 1", i)).collect();
+        assert_eq!(error(&locals.join("\n")),
+                   "line 201: too many local variables (limit is 200) in main function");
+        let registers: Vec<_> = (0..300).map(|i| i.to_string()).collect();
+        assert_eq!(error(&format!("f({})", registers.join(", "))),
+                   "line 1: function or expression needs too many registers");
+    }
+
+    #[test]
+    fn labels_at_the_end_of_blocks() {
+        assert!(compile_str("do goto l local x ::l:: end").is_ok());
+        assert!(compile_str("::top:: local x = 1 goto top").is_ok());
+    }
+}
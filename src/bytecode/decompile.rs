@@ -0,0 +1,1685 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Rebuilds an AST from Lua 5.3 bytecode
+//!
+//! `decompile` reads the instructions of a chunk back into statements:
+//! the tests and jumps of conditions become `if`, `while` and `repeat`
+//! blocks, `FORPREP` and `TFORCALL` become `for` loops, the locals of the
+//! debug information are declared where they start and closures become
+//! functions. `printer::print` turns the result into Lua source.
+//!
+//! ```rust
+//! # use nom_lua::bytecode::{compile, decompile, Chunk};
+//! # use nom_lua::{parse_block_with, printer, ParserConfig};
+//! let source = b"local n = 0 for i = 1, 3 do n = n + i end return n\n";
+//! let block = parse_block_with(source, &ParserConfig::default()).unwrap();
+//! let chunk = Chunk::new(compile(&block, source, "=n").unwrap());
+//! let text = printer::print(&decompile(&chunk).unwrap());
+//! assert_eq!(text, "local n = 0\nfor i = 1, 3 do\n  n = n + i\nend\nreturn n\n");
+//! ```
+//!
+//! This is a best effort for the code of `luac` 5.3 and of `compile`.
+//! Stripped functions have no local names, their registers become the
+//! locals `r0`, `r1`... of the whole function. The jumps that fit no block
+//! become `goto` statements, with labels named after their instruction.
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::mem;
+use std::str;
+use std::string::String;
+
+use ast::ASTNode;
+use ast::ASTNode::*;
+use config::LuaVersion;
+use printer::is_name;
+use super::opcode::{OpCode, BITRK, LFIELDS_PER_FLUSH};
+use super::{Chunk, Constant, LocalVar, Proto};
+
+/// Why a chunk could not be decompiled
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecompileError {
+    /// The instruction, in the function where the error was found
+    pub pc: usize,
+    pub message: String,
+}
+
+impl Display for DecompileError {
+    fn fmt(&self, format: &mut Formatter) -> fmt::Result {
+        write!(format, "instruction {}: {}", self.pc, self.message)
+    }
+}
+
+impl error::Error for DecompileError {}
+
+type Result<T> = ::std::result::Result<T, DecompileError>;
+
+fn error<T, S: Into<String>>(pc: usize, message: S) -> Result<T> {
+    Err(DecompileError { pc, message: message.into() })
+}
+
+/// How deep functions and the values jumping over their code may nest
+const MAX_DEPTH: usize = 200;
+
+/// Decompiles the main function of a Lua 5.3 chunk to a Block
+pub fn decompile(chunk: &Chunk) -> Result<ASTNode> {
+    if chunk.header.version != LuaVersion::Lua53 {
+        return error(0, "only Lua 5.3 chunks can be decompiled");
+    }
+    let mut upvalues: Vec<String> = chunk.main.upvalues.iter().map(|u| u.name.clone()).collect();
+    if let Some(env) = upvalues.first_mut() {
+        if env.is_empty() {
+            *env = "_ENV".to_string();
+        }
+    }
+    Function::new(&chunk.main, upvalues, 0).decompile()
+}
+
+/// How a value takes part in a list of values
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    /// A value, a call is truncated to its first result
+    Single,
+    /// A `nil` of `LOADNIL`, which pads a list
+    Filler,
+    /// The first results of a call or of `...`
+    Multi,
+    /// A result of a call after the first one
+    Rest,
+}
+
+/// The value waiting in a register for the instruction using it
+#[derive(Clone, Debug)]
+enum Slot {
+    Empty,
+    Value(ASTNode, Kind),
+    /// A call or `...` with its number of results, 0 when they go up to
+    /// the top of the stack
+    Multi(ASTNode, usize),
+    Rest,
+    /// A table constructor with its fields, and the number of list items
+    /// already taken from the registers above it
+    Table(Vec<ASTNode>, usize),
+    /// A method looked up by `SELF`, its object is in the next register
+    Method(ASTNode, String),
+    SelfArg,
+}
+
+#[derive(Clone)]
+struct State {
+    regs: Vec<Slot>,
+    stmts: Vec<ASTNode>,
+    /// The stores of an assignment, in the order of the code
+    assigns: Vec<(ASTNode, ASTNode, Kind)>,
+    declared: Vec<bool>,
+    /// The register of an `and` or `or` being rebuilt, which stays a
+    /// value even when it is a local
+    capture: Option<usize>,
+    /// The register of a `local function` until its `CLOSURE`
+    named: Option<usize>,
+    /// The condition of the `repeat` being rebuilt
+    until: Option<ASTNode>,
+    gotos: BTreeSet<usize>,
+    placed: BTreeSet<usize>,
+}
+
+impl State {
+    /// A copy to try a pattern on, without the statements
+    fn fork(&self) -> State {
+        State {
+            regs: self.regs.clone(),
+            stmts: vec![],
+            assigns: vec![],
+            declared: self.declared.clone(),
+            capture: self.capture,
+            named: self.named,
+            until: None,
+            gotos: BTreeSet::new(),
+            placed: BTreeSet::new(),
+        }
+    }
+
+    /// Goes on with a fork whose pattern matched
+    fn adopt(&mut self, mut fork: State) {
+        fork.stmts = std::mem::take(&mut self.stmts);
+        fork.assigns = std::mem::take(&mut self.assigns);
+        fork.gotos = std::mem::take(&mut self.gotos);
+        fork.placed = std::mem::take(&mut self.placed);
+        fork.until = self.until.take();
+        *self = fork;
+    }
+
+    /// Whether no statement was produced
+    fn quiet(&self) -> bool {
+        self.stmts.is_empty() && self.assigns.is_empty()
+    }
+}
+
+/// A test and its jump, with the code computing the operands of the test
+struct Unit {
+    start: usize,
+    /// The instruction after the jump
+    next: usize,
+    target: usize,
+    /// The condition under which the jump is taken
+    cond: ASTNode,
+    state: State,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Ctx {
+    /// Where a `break` jumps
+    exit: Option<usize>,
+    /// The start and the closing jump of the `repeat` whose body this is
+    repeat: Option<(usize, usize)>,
+    /// The last instruction of a loop, where a `goto continue` lands
+    close: Option<usize>,
+}
+
+struct Function<'a> {
+    proto: &'a Proto,
+    stripped: bool,
+    locals: Vec<LocalVar>,
+    /// The register of each local
+    registers: Vec<usize>,
+    /// Where locals start
+    starts: BTreeSet<usize>,
+    upvalues: Vec<String>,
+    /// The backward jumps by their target
+    back: BTreeMap<usize, Vec<usize>>,
+    labels: BTreeSet<usize>,
+    depth: usize,
+    nesting: Cell<usize>,
+}
+
+impl<'a> Function<'a> {
+    fn new(proto: &'a Proto, upvalues: Vec<String>, depth: usize) -> Function<'a> {
+        let stripped = proto.local_vars.is_empty() && proto.line_info.is_empty();
+        let locals: Vec<LocalVar> = if stripped {
+            (0..proto.max_stack_size.max(proto.num_params))
+                .map(|r| LocalVar { name: synthetic(r as usize, depth), start_pc: 0, end_pc: proto.code.len() })
+                .collect()
+        } else {
+            proto.local_vars.clone()
+        };
+        let registers = locals.iter().enumerate().map(|(i, l)| {
+            locals[..i].iter().filter(|o| o.start_pc <= l.start_pc && l.start_pc < o.end_pc).count()
+        }).collect();
+        let starts = locals.iter().map(|l| l.start_pc).collect();
+        let mut back = BTreeMap::new();
+        for (pc, i) in proto.code.iter().enumerate() {
+            if i.opcode() == Some(OpCode::Jmp) && i.sbx() < 0 && pc as i64 + 1 + i.sbx() as i64 >= 0 {
+                back.entry((pc as i64 + 1 + i.sbx() as i64) as usize).or_insert_with(Vec::new).push(pc);
+            }
+        }
+        Function {
+            proto, stripped, locals, registers, starts, upvalues, back,
+            labels: BTreeSet::new(),
+            depth,
+            nesting: Cell::new(0),
+        }
+    }
+
+    fn decompile(mut self) -> Result<ASTNode> {
+        if self.depth > MAX_DEPTH {
+            return error(0, "functions are nested too deeply");
+        }
+        let (block, gotos) = self.run()?;
+        if gotos.is_subset(&self.labels) {
+            return Ok(block);
+        }
+        // Labels are placed on a second pass, once the gotos are known
+        self.labels = gotos;
+        Ok(self.run()?.0)
+    }
+
+    fn function_body(self) -> Result<ASTNode> {
+        let params: Vec<_> = (0..self.proto.num_params as usize).map(|i| {
+            let name = self.locals.get(i).map_or_else(|| synthetic(i, self.depth), |l| l.name.clone());
+            (Name(name), None)
+        }).collect();
+        let params = if params.is_empty() { None } else { Some(NameList(params)) };
+        let params = ast!(ParameterList, Box::new(params), self.proto.is_vararg);
+        let block = self.decompile()?;
+        Ok(ast!(FunctionBody, Box::new(Some(params)), Box::new(block)))
+    }
+
+    fn run(&self) -> Result<(ASTNode, BTreeSet<usize>)> {
+        let params = self.proto.num_params as usize;
+        let mut st = State {
+            regs: vec![Slot::Empty; 256],
+            stmts: vec![],
+            assigns: vec![],
+            declared: (0..self.locals.len()).map(|i| i < params).collect(),
+            capture: None,
+            named: None,
+            until: None,
+            gotos: BTreeSet::new(),
+            placed: BTreeSet::new(),
+        };
+        let block = self.block(0, self.proto.code.len(), &mut st, Ctx::default())?;
+        Ok((block, st.gotos))
+    }
+
+    fn op(&self, pc: usize) -> Result<OpCode> {
+        match self.op_at(pc) {
+            Some(op) => Ok(op),
+            None => error(pc, "invalid instruction"),
+        }
+    }
+
+    fn op_at(&self, pc: usize) -> Option<OpCode> {
+        self.proto.code.get(pc).and_then(|i| i.opcode())
+    }
+
+    fn target(&self, pc: usize) -> Result<usize> {
+        let target = pc as i64 + 1 + self.proto.code[pc].sbx() as i64;
+        if target < 0 || target > self.proto.code.len() as i64 {
+            return error(pc, "jump out of the function");
+        }
+        Ok(target as usize)
+    }
+
+    fn is_test(&self, pc: usize) -> bool {
+        matches!(self.op_at(pc), Some(OpCode::Eq) | Some(OpCode::Lt) | Some(OpCode::Le) |
+                 Some(OpCode::Test) | Some(OpCode::TestSet))
+    }
+
+    /// The active local in register `r`, hidden ones such as the state of
+    /// a `for` included
+    fn active(&self, r: usize, pc: usize) -> Option<&LocalVar> {
+        self.locals.iter().filter(|l| l.start_pc <= pc && pc < l.end_pc).nth(r)
+    }
+
+    fn local(&self, r: usize, pc: usize) -> Option<&LocalVar> {
+        self.active(r, pc).filter(|l| !l.name.starts_with('('))
+    }
+
+    fn is_var(&self, st: &State, r: usize, pc: usize) -> bool {
+        st.capture != Some(r) && self.local(r, pc).is_some()
+    }
+
+    fn register_name(&self, r: usize, pc: usize) -> String {
+        self.active(r, pc).map_or_else(|| synthetic(r, self.depth), |l| l.name.clone())
+    }
+
+    fn upvalue(&self, index: usize, pc: usize) -> Result<String> {
+        match self.upvalues.get(index) {
+            Some(name) => Ok(name.clone()),
+            None => error(pc, format!("no upvalue {}", index)),
+        }
+    }
+
+    fn constant(&self, pc: usize, index: u32) -> Result<ASTNode> {
+        Ok(match self.proto.constants.get(index as usize) {
+            Some(&Constant::Nil) => Nil,
+            Some(&Constant::Boolean(b)) => Bool(b),
+            Some(&Constant::Integer(i)) => Integer(i),
+            Some(&Constant::Float(f)) => Float(f),
+            Some(Constant::String(s)) => ASTNode::String(s.clone()),
+            None => return error(pc, format!("no constant {}", index)),
+        })
+    }
+
+    /// A global, through `_ENV` when a local or an upvalue hides it
+    fn global(&self, name: String, pc: usize) -> ASTNode {
+        let hidden = self.locals.iter().any(|l| l.start_pc <= pc && pc < l.end_pc && l.name == name) ||
+            self.upvalues.iter().any(|u| *u == name && u != "_ENV");
+        if hidden {
+            index(var("_ENV".to_string()), ASTNode::String(name.into_bytes()))
+        } else {
+            var(name)
+        }
+    }
+
+    fn upvalue_index(&self, up: usize, key: ASTNode, pc: usize) -> Result<ASTNode> {
+        let table = self.upvalue(up, pc)?;
+        Ok(match as_name(&key) {
+            Some(name) if table == "_ENV" => self.global(name, pc),
+            _ => index(var(table), key),
+        })
+    }
+
+    fn take(&self, st: &mut State, r: usize, pc: usize) -> Result<(ASTNode, Kind)> {
+        let slot = match st.regs.get_mut(r) {
+            Some(slot) => mem::replace(slot, Slot::Empty),
+            None => return error(pc, format!("no register {}", r)),
+        };
+        Ok(match slot {
+            Slot::Value(e, kind) => (e, kind),
+            Slot::Multi(e, _) => (e, Kind::Multi),
+            Slot::Rest => (Nil, Kind::Rest),
+            Slot::Table(fields, _) => (table(fields), Kind::Single),
+            Slot::Method(obj, name) => (index(obj, ASTNode::String(name.into_bytes())), Kind::Single),
+            Slot::SelfArg | Slot::Empty => match self.local(r, pc) {
+                Some(l) => (var(l.name.clone()), Kind::Single),
+                None => return error(pc, format!("register {} is read before it is set", r)),
+            },
+        })
+    }
+
+    fn get(&self, st: &mut State, r: usize, pc: usize) -> Result<ASTNode> {
+        Ok(self.take(st, r, pc)?.0)
+    }
+
+    fn rk(&self, st: &mut State, x: u32, pc: usize) -> Result<(ASTNode, Kind)> {
+        if x & BITRK != 0 {
+            Ok((self.constant(pc, x & !BITRK)?, Kind::Single))
+        } else {
+            self.take(st, x as usize, pc)
+        }
+    }
+
+    fn put(&self, st: &mut State, r: usize, slot: Slot, pc: usize) -> Result<()> {
+        match st.regs.get_mut(r) {
+            Some(s) => {
+                let _: () = *s = slot;
+                Ok(())
+            },
+            None => error(pc, format!("no register {}", r)),
+        }
+    }
+
+    /// Writes a register, which assigns the variable of a local
+    fn set(&self, st: &mut State, r: usize, e: ASTNode, kind: Kind, pc: usize) -> Result<()> {
+        match self.local(r, pc) {
+            Some(l) if st.capture != Some(r) => {
+                let target = ast!(Var, Box::new(Name(l.name.clone())));
+                self.assign(st, target, e, kind, pc);
+                Ok(())
+            }
+            _ => self.put(st, r, Slot::Value(e, kind), pc),
+        }
+    }
+
+    /// The `n` results of a call or `...` in the registers from `r`
+    fn results(&self, st: &mut State, r: usize, n: usize, e: ASTNode, pc: usize) -> Result<()> {
+        if (r..r + n).all(|r| self.is_var(st, r, pc)) {
+            let targets = (r..r + n).map(|r| astb!(Var, Name(self.register_name(r, pc)))).collect();
+            self.emit(st, astb!(Assign, VarList(targets), ExpList(vec![e])));
+            return Ok(());
+        }
+        self.put(st, r, Slot::Multi(e, n), pc)?;
+        for r in r + 1..r + n {
+            self.put(st, r, Slot::Rest, pc)?;
+        }
+        Ok(())
+    }
+
+    /// The values of the registers from `first`, up to the top of the
+    /// stack when there is no count
+    fn list(&self, st: &mut State, first: usize, count: Option<usize>, pc: usize)
+        -> Result<Vec<(ASTNode, Kind)>> {
+        let mut items = Vec::new();
+        let mut r = first;
+        loop {
+            if let Some(n) = count {
+                if r >= first + n {
+                    break;
+                }
+            }
+            let open = matches!(st.regs.get(r), Some(&Slot::Multi(_, 0)));
+            items.push(self.take(st, r, pc)?);
+            r += 1;
+            if open && count.is_none() {
+                break;
+            }
+        }
+        Ok(items)
+    }
+
+    /// Adds a store to the assignment being rebuilt. The stores of a
+    /// multiple assignment follow the evaluation of all its values, in
+    /// the reverse order.
+    fn assign(&self, st: &mut State, target: ASTNode, value: ASTNode, kind: Kind, pc: usize) {
+        st.assigns.push((target, value, kind));
+        let pending = st.regs.iter().any(|s| !matches!(*s, Slot::Empty));
+        let store = matches!(self.op_at(pc + 1), Some(OpCode::Move) | Some(OpCode::SetTabUp) |
+                             Some(OpCode::SetTable) | Some(OpCode::SetUpval));
+        if !pending || !store {
+            self.flush(st);
+        }
+    }
+
+    fn flush(&self, st: &mut State) {
+        if st.assigns.is_empty() {
+            return;
+        }
+        let mut stores = std::mem::take(&mut st.assigns);
+        stores.reverse();
+        if stores.len() == 1 {
+            if let Some(stat) = function_stat(&stores[0].0, &stores[0].1) {
+                st.stmts.push(stat);
+                return;
+            }
+        }
+        let n = stores.len();
+        let (targets, values): (Vec<_>, Vec<_>) = stores.into_iter().map(|(t, v, k)| (t, (v, k))).unzip();
+        st.stmts.push(astb!(Assign, VarList(targets), ExpList(adjusted(values, n, 1))));
+    }
+
+    fn emit(&self, st: &mut State, stmt: ASTNode) {
+        self.flush(st);
+        st.stmts.push(stmt);
+    }
+
+    fn label(&self, pc: usize, st: &mut State) {
+        if self.labels.contains(&pc) && st.placed.insert(pc) {
+            self.emit(st, Label(format!("L{}", pc)));
+        }
+    }
+
+    /// Declares the locals starting at `pc` with the values waiting in
+    /// their registers
+    fn declare(&self, pc: usize, st: &mut State) -> Result<()> {
+        let new: Vec<usize> = (0..self.locals.len())
+            .filter(|&i| self.locals[i].start_pc == pc && !st.declared[i])
+            .collect();
+        for &i in &new {
+            st.declared[i] = true;
+        }
+        let new: Vec<usize> = new.into_iter().filter(|&i| !self.locals[i].name.starts_with('(')).collect();
+        if new.is_empty() {
+            return Ok(());
+        }
+        let first = self.registers[new[0]];
+        let empty = matches!(st.regs.get(first), Some(&Slot::Empty));
+        if new.len() == 1 && empty && self.op_at(pc) == Some(OpCode::Closure) &&
+            self.proto.code[pc].a() as usize == first {
+            st.named = Some(first);
+            return Ok(());
+        }
+        let mut items = vec![];
+        for r in first..first + new.len() {
+            match st.regs.get(r) {
+                None | Some(&Slot::Empty) => break,
+                _ => items.push(self.take(st, r, pc)?),
+            }
+        }
+        let names = new.iter().map(|&i| (Name(self.locals[i].name.clone()), None)).collect();
+        let exps = adjusted(items, new.len(), 0);
+        let exps = if exps.is_empty() { None } else { Some(ExpList(exps)) };
+        self.emit(st, ast!(Local, Box::new(NameList(names)), Box::new(exps)));
+        Ok(())
+    }
+
+    /// The end of a `do` block starting at `pc`, whose locals die before
+    /// the end of the current block
+    fn scope(&self, pc: usize, end: usize, st: &State) -> Option<usize> {
+        (0..self.locals.len())
+            .filter(|&i| self.locals[i].start_pc == pc && !st.declared[i] && !self.locals[i].name.starts_with('('))
+            .map(|i| self.locals[i].end_pc)
+            .filter(|&close| close < end)
+            .max()
+    }
+
+    /// The name of the variable of a loop, declared at `pc`
+    fn loop_var(&self, r: usize, pc: usize, st: &mut State) -> String {
+        for i in 0..self.locals.len() {
+            if self.locals[i].start_pc == pc && self.registers[i] == r && !st.declared[i] {
+                st.declared[i] = true;
+                return self.locals[i].name.clone();
+            }
+        }
+        synthetic(r, self.depth)
+    }
+
+    fn block(&self, start: usize, end: usize, st: &mut State, ctx: Ctx) -> Result<ASTNode> {
+        let outer = std::mem::take(&mut st.stmts);
+        let ret = self.statements(start, end, st, ctx);
+        self.flush(st);
+        let stmts = mem::replace(&mut st.stmts, outer);
+        Ok(ast!(Block, stmts, Box::new(ret?)))
+    }
+
+    /// Fills the current block with the statements of `start..end`,
+    /// returning its return statement
+    fn statements(&self, start: usize, end: usize, st: &mut State, ctx: Ctx) -> Result<Option<ASTNode>> {
+        let n = self.proto.code.len();
+        let mut pc = start;
+        while pc < end {
+            if let Some(close) = self.scope(pc, end, st) {
+                self.flush(st);
+                let body = self.block(pc, close, st, Ctx { repeat: None, close: None, ..ctx })?;
+                self.emit(st, astb!(Do, body));
+                pc = close;
+                continue;
+            }
+            self.declare(pc, st)?;
+            self.label(pc, st);
+            let limit = match ctx.repeat {
+                Some((s, j)) if s == pc && pc == start => j,
+                _ => end,
+            };
+            let back = self.back.get(&pc).and_then(|js| js.iter().cloned().filter(|&j| j < limit).max());
+            if let Some(j) = back {
+                pc = self.cycle(pc, j, st)?;
+                continue;
+            }
+            if let Some(next) = self.expression(pc, end, st)? {
+                pc = next;
+                continue;
+            }
+            let i = self.proto.code[pc];
+            let a = i.a() as usize;
+            pc = match self.op(pc)? {
+                OpCode::ForPrep => self.numeric_for(pc, st)?,
+                OpCode::Jmp => self.jump(pc, st, ctx)?,
+                OpCode::Eq | OpCode::Lt | OpCode::Le | OpCode::Test | OpCode::TestSet =>
+                    self.conditional(pc, end, st, ctx)?,
+                OpCode::Return => {
+                    let b = i.b() as usize;
+                    if b == 1 && pc + 1 == n {
+                        break;
+                    }
+                    let exps = match b {
+                        1 => vec![],
+                        0 => fixed(self.list(st, a, None, pc)?),
+                        b => fixed(self.list(st, a, Some(b - 1), pc)?),
+                    };
+                    if let Some(ret) = self.ret(exps, pc + 1, end, st) {
+                        return Ok(Some(ret));
+                    }
+                    pc + 1
+                }
+                OpCode::TailCall => {
+                    let call = self.call(st, a, i.b() as usize, pc)?;
+                    let next = if self.op_at(pc + 1) == Some(OpCode::Return) { pc + 2 } else { pc + 1 };
+                    if let Some(ret) = self.ret(vec![astb!(PrefixExp, call)], next, end, st) {
+                        return Ok(Some(ret));
+                    }
+                    next
+                }
+                op => return error(pc, format!("unexpected {}", op.info().name)),
+            };
+        }
+        // The locals whose value is the last thing of the block
+        let last = (0..self.locals.len()).any(|i| {
+            self.locals[i].start_pc == end && !st.declared[i] &&
+                !matches!(st.regs.get(self.registers[i]), Some(&Slot::Empty) | None)
+        });
+        if last {
+            self.declare(end, st)?;
+        }
+        if ctx.close == Some(end) {
+            self.label(end, st);
+        }
+        Ok(None)
+    }
+
+    /// A return that ends the block, or is wrapped in `do ... end` when
+    /// code follows it
+    fn ret(&self, exps: Vec<ASTNode>, next: usize, end: usize, st: &mut State) -> Option<ASTNode> {
+        let ret = astb!(RetStat, if exps.is_empty() { None } else { Some(ExpList(exps)) });
+        self.flush(st);
+        let n = self.proto.code.len();
+        let last = next >= end || next + 1 == n && end == n &&
+            self.op_at(next) == Some(OpCode::Return) && self.proto.code[next].b() == 1;
+        if last {
+            return Some(ret);
+        }
+        st.stmts.push(astb!(Do, ast!(Block, vec![], Box::new(Some(ret)))));
+        None
+    }
+
+    fn jump(&self, pc: usize, st: &mut State, ctx: Ctx) -> Result<usize> {
+        let target = self.target(pc)?;
+        if target == pc + 1 {
+            return Ok(pc + 1);
+        }
+        if self.op_at(target) == Some(OpCode::TForCall) && self.op_at(target + 1) == Some(OpCode::TForLoop) &&
+            self.target(target + 1).ok() == Some(pc + 1) {
+            return self.generic_for(pc, target, st);
+        }
+        let stat = self.leave(target, st, ctx);
+        self.emit(st, stat);
+        Ok(pc + 1)
+    }
+
+    /// A `break` or a `goto` to `target`
+    fn leave(&self, target: usize, st: &mut State, ctx: Ctx) -> ASTNode {
+        if ctx.exit == Some(target) {
+            Break
+        } else {
+            st.gotos.insert(target);
+            astb!(Goto, Name(format!("L{}", target)))
+        }
+    }
+
+    /// The body of a loop ending at `close`. The locals of stripped
+    /// functions above its variables are declared again, so closures
+    /// capture a variable per iteration.
+    fn loop_body(&self, free: usize, start: usize, close: usize, exit: usize, st: &mut State) -> Result<ASTNode> {
+        let ctx = Ctx { exit: Some(exit), repeat: None, close: Some(close) };
+        let mut block = self.block(start, close, st, ctx)?;
+        let max = self.proto.max_stack_size as usize;
+        match block {
+            Block(ref mut stmts, _) if self.stripped && free < max => {
+                let names = (free..max).map(|r| (Name(synthetic(r, self.depth)), None)).collect();
+                stmts.insert(0, ast!(Local, Box::new(NameList(names)), Box::new(None)));
+            }
+            _ => {},
+        }
+        Ok(block)
+    }
+
+    fn numeric_for(&self, pc: usize, st: &mut State) -> Result<usize> {
+        let a = self.proto.code[pc].a() as usize;
+        let close = self.target(pc)?;
+        if self.op_at(close) != Some(OpCode::ForLoop) {
+            return error(pc, "FORPREP does not jump to a FORLOOP");
+        }
+        let start = self.get(st, a, pc)?;
+        let limit = self.get(st, a + 1, pc)?;
+        let step = match self.get(st, a + 2, pc)? {
+            Integer(1) => None,
+            step => Some(step),
+        };
+        let name = self.loop_var(a + 3, pc + 1, st);
+        self.flush(st);
+        let body = self.loop_body(a + 4, pc + 1, close, close + 1, st)?;
+        self.emit(st, ast!(NumericFor, Box::new(Name(name)), Box::new(start), Box::new(limit),
+                           Box::new(step), Box::new(body)));
+        Ok(close + 1)
+    }
+
+    fn generic_for(&self, pc: usize, call: usize, st: &mut State) -> Result<usize> {
+        let i = self.proto.code[call];
+        let (a, n) = (i.a() as usize, i.c() as usize);
+        let items = self.list(st, a, Some(3), pc)?;
+        let exps = adjusted(items, 3, 1);
+        let names = (0..n).map(|k| (Name(self.loop_var(a + 3 + k, pc + 1, st)), None)).collect();
+        self.flush(st);
+        let body = self.loop_body(a + 3 + n, pc + 1, call, call + 2, st)?;
+        self.emit(st, astb!(GenericFor, NameList(names), ExpList(exps), body));
+        Ok(call + 2)
+    }
+
+    /// A loop closed by the backward jump at `j`: `repeat` when the jump
+    /// is conditional, `while` otherwise
+    fn cycle(&self, pc: usize, j: usize, st: &mut State) -> Result<usize> {
+        let exit = j + 1;
+        if j > pc && self.is_test(j - 1) {
+            let until = st.until.take();
+            let inner = Ctx { exit: Some(exit), repeat: Some((pc, j)), close: None };
+            let body = self.block(pc, exit, st, inner)?;
+            let cond = mem::replace(&mut st.until, until).unwrap_or(Bool(true));
+            self.emit(st, astb!(Repeat, body, cond));
+            return Ok(exit);
+        }
+        let inner = Ctx { exit: Some(exit), repeat: None, close: Some(j) };
+        let units = self.units(pc, j, st, None);
+        for k in (1..units.len() + 1).rev() {
+            let start = units[k - 1].next;
+            let outcome = |t| if t == start { Some(true) } else if t == exit { Some(false) } else { None };
+            if let Some(cond) = condition(&units[..k], &outcome) {
+                st.adopt(units[k - 1].state.clone());
+                let body = self.block(start, j, st, inner)?;
+                self.emit(st, astb!(While, cond, body));
+                return Ok(exit);
+            }
+        }
+        let body = self.block(pc, j, st, inner)?;
+        self.emit(st, astb!(While, Bool(true), body));
+        Ok(exit)
+    }
+
+    /// A statement starting with the test at `pc`
+    fn conditional(&self, pc: usize, end: usize, st: &mut State, ctx: Ctx) -> Result<usize> {
+        let units = self.units(pc, end, st, ctx.repeat.map(|(start, _)| start));
+        if units.is_empty() {
+            return error(pc, "a test is not followed by a jump");
+        }
+        if let Some((start, j)) = ctx.repeat {
+            for k in (1..units.len() + 1).rev() {
+                if end != j + 1 || units[k - 1].next != end {
+                    continue;
+                }
+                let outcome = |t| if t == end { Some(true) } else if t == start { Some(false) } else { None };
+                if let Some(cond) = condition(&units[..k], &outcome) {
+                    st.adopt(units[k - 1].state.clone());
+                    st.until = Some(cond);
+                    return Ok(end);
+                }
+            }
+        }
+        // The block skipped when the condition fails, with an else after
+        // it when it ends with a jump
+        for k in (1..units.len() + 1).rev() {
+            let next = units[k - 1].next;
+            let others = exits(&units[..k], next);
+            if others.len() != 1 || others[0] <= next || others[0] > end {
+                continue;
+            }
+            let other = others[0];
+            let outcome = |t| if t == next { Some(true) } else if t == other { Some(false) } else { None };
+            if let Some(cond) = condition(&units[..k], &outcome) {
+                st.adopt(units[k - 1].state.clone());
+                return self.if_statement(cond, next, other, end, st, ctx);
+            }
+        }
+        // A jump out of the block, such as a break
+        for k in (1..units.len() + 1).rev() {
+            let next = units[k - 1].next;
+            let others = exits(&units[..k], next);
+            if others.len() != 1 {
+                continue;
+            }
+            let other = others[0];
+            let outcome = |t| if t == other { Some(true) } else if t == next { Some(false) } else { None };
+            if let Some(cond) = condition(&units[..k], &outcome) {
+                st.adopt(units[k - 1].state.clone());
+                let stat = self.leave(other, st, ctx);
+                self.emit(st, astb!(If, cond, ast!(Block, vec![stat], Box::new(None)), None));
+                return Ok(next);
+            }
+        }
+        // A jump to the next instruction, only the test is left
+        let unit = &units[0];
+        st.adopt(unit.state.clone());
+        self.emit(st, astb!(If, unit.cond.clone(), ast!(Block, vec![], Box::new(None)), None));
+        Ok(unit.next)
+    }
+
+    fn if_statement(&self, cond: ASTNode, then: usize, other: usize, end: usize, st: &mut State, ctx: Ctx)
+        -> Result<usize> {
+        let inner = Ctx { close: None, ..ctx };
+        // An empty then block jumping out of the loop is a break or a
+        // continue, rather than an else
+        let exit = if other > then && self.op_at(other - 1) == Some(OpCode::Jmp) {
+            self.target(other - 1).ok().filter(|&t| {
+                t > other && t <= end && !(other - 1 == then && (ctx.exit == Some(t) || ctx.close == Some(t)))
+            })
+        } else {
+            None
+        };
+        self.flush(st);
+        match exit {
+            Some(exit) => {
+                let body = self.block(then, other - 1, st, inner)?;
+                let mut otherwise = self.block(other, exit, st, inner)?;
+                if let Block(ref mut stmts, ref ret) = otherwise {
+                    if stmts.len() == 1 && ret.is_none() && is_if(&stmts[0]) {
+                        otherwise = stmts.pop().unwrap();
+                    }
+                }
+                self.emit(st, astb!(If, cond, body, Some(otherwise)));
+                Ok(exit)
+            }
+            None => {
+                let body = self.block(then, other, st, inner)?;
+                self.emit(st, astb!(If, cond, body, None));
+                Ok(other)
+            }
+        }
+    }
+
+    /// Collects the tests from `pc`, each with the code computing its
+    /// operands, as long as they jump forward, or to `back`
+    fn units(&self, pc: usize, end: usize, st: &State, back: Option<usize>) -> Vec<Unit> {
+        let mut units = Vec::new();
+        if self.nesting.get() > MAX_DEPTH {
+            return units;
+        }
+        self.nesting.set(self.nesting.get() + 1);
+        let mut fork = st.fork();
+        let mut start = pc;
+        'units: loop {
+            let mut q = start;
+            while q < end && !(q == pc && self.is_test(q)) {
+                if q != pc && self.starts.contains(&q) {
+                    break 'units;
+                }
+                match self.expression(q, end, &mut fork) {
+                    Ok(Some(next)) => q = next,
+                    Ok(None) => break,
+                    Err(_) => break 'units,
+                }
+                if !fork.quiet() {
+                    break 'units;
+                }
+            }
+            if q + 1 >= end || !self.is_test(q) || self.op_at(q + 1) != Some(OpCode::Jmp) {
+                break;
+            }
+            let target = match self.target(q + 1) {
+                Ok(target) => target,
+                Err(_) => break,
+            };
+            if !units.is_empty() && target <= q + 1 && back != Some(target) {
+                break;
+            }
+            let cond = match self.jump_condition(q, &mut fork) {
+                Ok(cond) => cond,
+                Err(_) => break,
+            };
+            units.push(Unit { start, next: q + 2, target, cond, state: fork.clone() });
+            start = q + 2;
+        }
+        self.nesting.set(self.nesting.get() - 1);
+        units
+    }
+
+    fn jump_condition(&self, pc: usize, st: &mut State) -> Result<ASTNode> {
+        let i = self.proto.code[pc];
+        let (a, b, c) = (i.a(), i.b(), i.c());
+        Ok(match self.op(pc)? {
+            OpCode::Eq => {
+                let (left, right) = (self.rk(st, b, pc)?.0, self.rk(st, c, pc)?.0);
+                if a != 0 { astb!(Eq, left, right) } else { astb!(Ne, left, right) }
+            }
+            OpCode::Lt | OpCode::Le => {
+                let (left, right) = (self.rk(st, b, pc)?.0, self.rk(st, c, pc)?.0);
+                let e = if self.op_at(pc) == Some(OpCode::Lt) { astb!(Lt, left, right) } else { astb!(Le, left, right) };
+                if a != 0 { e } else { astb!(Not, e) }
+            }
+            OpCode::Test | OpCode::TestSet => {
+                let r = if self.op_at(pc) == Some(OpCode::Test) { a } else { b };
+                let e = self.get(st, r as usize, pc)?;
+                if c != 0 { e } else { negate(e) }
+            }
+            _ => return error(pc, "not a test"),
+        })
+    }
+
+    /// Runs the instruction at `pc` when it computes or stores a value,
+    /// returning the next one
+    fn expression(&self, pc: usize, end: usize, st: &mut State) -> Result<Option<usize>> {
+        let i = self.proto.code[pc];
+        let (a, b, c) = (i.a() as usize, i.b() as usize, i.c() as usize);
+        let op = self.op(pc)?;
+        match op {
+            OpCode::Move => {
+                let e = self.get(st, b, pc)?;
+                self.set(st, a, e, Kind::Single, pc)?;
+            }
+            OpCode::LoadK => {
+                let k = self.constant(pc, i.bx())?;
+                self.set(st, a, k, Kind::Single, pc)?;
+            }
+            OpCode::LoadKx => {
+                let extra = match self.proto.code.get(pc + 1) {
+                    Some(x) if x.opcode() == Some(OpCode::ExtraArg) => x.ax_arg(),
+                    _ => return error(pc, "LOADKX is not followed by EXTRAARG"),
+                };
+                let k = self.constant(pc, extra)?;
+                self.set(st, a, k, Kind::Single, pc)?;
+                return Ok(Some(pc + 2));
+            }
+            OpCode::LoadBool => {
+                self.set(st, a, Bool(b != 0), Kind::Single, pc)?;
+                if c != 0 {
+                    return Ok(Some(pc + 2));
+                }
+            }
+            OpCode::LoadNil => for r in a..a + b + 1 {
+                self.set(st, r, Nil, Kind::Filler, pc)?;
+            },
+            OpCode::GetUpval => {
+                let e = var(self.upvalue(b, pc)?);
+                self.set(st, a, e, Kind::Single, pc)?;
+            }
+            OpCode::GetTabUp => {
+                let key = self.rk(st, i.c(), pc)?.0;
+                let e = self.upvalue_index(b, key, pc)?;
+                self.set(st, a, e, Kind::Single, pc)?;
+            }
+            OpCode::GetTable => {
+                let obj = self.get(st, b, pc)?;
+                let key = self.rk(st, i.c(), pc)?.0;
+                self.set(st, a, index(obj, key), Kind::Single, pc)?;
+            }
+            OpCode::SetTabUp => {
+                let key = self.rk(st, i.b(), pc)?.0;
+                let (value, kind) = self.rk(st, i.c(), pc)?;
+                let target = unwrap(self.upvalue_index(a, key, pc)?);
+                self.assign(st, target, value, kind, pc);
+            }
+            OpCode::SetUpval => {
+                let (value, kind) = self.take(st, a, pc)?;
+                let target = astb!(Var, Name(self.upvalue(b, pc)?));
+                self.assign(st, target, value, kind, pc);
+            }
+            OpCode::SetTable => {
+                let key = self.rk(st, i.b(), pc)?.0;
+                let (value, kind) = self.rk(st, i.c(), pc)?;
+                let constructor = match st.regs.get(a) {
+                    Some(&Slot::Table(..)) => !self.is_var(st, a, pc),
+                    _ => false,
+                };
+                if constructor {
+                    self.items(st, a);
+                    if let Some(&mut Slot::Table(ref mut fields, _)) = st.regs.get_mut(a) {
+                        fields.push(astb!(FieldAssign, field_key(key), value));
+                    }
+                } else {
+                    let obj = self.get(st, a, pc)?;
+                    self.assign(st, target(obj, key), value, kind, pc);
+                }
+            }
+            OpCode::NewTable => if self.is_var(st, a, pc) {
+                self.set(st, a, table(vec![]), Kind::Single, pc)?;
+            } else {
+                self.put(st, a, Slot::Table(vec![], 0), pc)?;
+            },
+            OpCode::OpSelf => {
+                let obj = self.get(st, b, pc)?;
+                let key = self.rk(st, i.c(), pc)?.0;
+                match as_name(&key) {
+                    Some(name) => {
+                        self.put(st, a, Slot::Method(obj, name), pc)?;
+                        self.put(st, a + 1, Slot::SelfArg, pc)?;
+                    }
+                    None => {
+                        self.put(st, a, Slot::Value(index(obj.clone(), key), Kind::Single), pc)?;
+                        self.put(st, a + 1, Slot::Value(obj, Kind::Single), pc)?;
+                    }
+                }
+            }
+            OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Mod | OpCode::Pow | OpCode::Div |
+            OpCode::IDiv | OpCode::BAnd | OpCode::BOr | OpCode::BXor | OpCode::Shl | OpCode::Shr => {
+                let left = Box::new(self.rk(st, i.b(), pc)?.0);
+                let right = Box::new(self.rk(st, i.c(), pc)?.0);
+                let e = match op {
+                    OpCode::Add => Add(left, right),
+                    OpCode::Sub => Sub(left, right),
+                    OpCode::Mul => Mul(left, right),
+                    OpCode::Mod => Mod(left, right),
+                    OpCode::Pow => Exp(left, right),
+                    OpCode::Div => Div(left, right),
+                    OpCode::IDiv => FDiv(left, right),
+                    OpCode::BAnd => BitAnd(left, right),
+                    OpCode::BOr => BitOr(left, right),
+                    OpCode::BXor => BitXor(left, right),
+                    OpCode::Shl => Lsh(left, right),
+                    _ => Rsh(left, right),
+                };
+                self.set(st, a, e, Kind::Single, pc)?;
+            }
+            OpCode::Unm | OpCode::BNot | OpCode::Not | OpCode::Len => {
+                let operand = Box::new(self.get(st, b, pc)?);
+                let e = match op {
+                    OpCode::Unm => UMin(operand),
+                    OpCode::BNot => BinNot(operand),
+                    OpCode::Not => Not(operand),
+                    _ => Len(operand),
+                };
+                self.set(st, a, e, Kind::Single, pc)?;
+            }
+            OpCode::Concat => {
+                let mut e = self.get(st, c, pc)?;
+                for r in (b..c).rev() {
+                    e = astb!(Concat, self.get(st, r, pc)?, e);
+                }
+                self.set(st, a, e, Kind::Single, pc)?;
+            }
+            OpCode::Call => {
+                let call = self.call(st, a, b, pc)?;
+                match c {
+                    1 => self.emit(st, call),
+                    2 => self.set(st, a, astb!(PrefixExp, call), Kind::Single, pc)?,
+                    0 => self.put(st, a, Slot::Multi(astb!(PrefixExp, call), 0), pc)?,
+                    c => self.results(st, a, c - 1, astb!(PrefixExp, call), pc)?,
+                }
+            }
+            OpCode::Closure => self.closure(pc, st)?,
+            OpCode::VarArg => match b {
+                0 => self.put(st, a, Slot::Multi(VarArg, 0), pc)?,
+                1 => {}
+                2 => self.set(st, a, VarArg, Kind::Single, pc)?,
+                b => self.results(st, a, b - 1, VarArg, pc)?,
+            },
+            OpCode::SetList => return self.set_list(pc, st).map(Some),
+            OpCode::Eq | OpCode::Lt | OpCode::Le | OpCode::Test | OpCode::TestSet => return self.value(pc, end, st),
+            OpCode::ExtraArg => return error(pc, "unexpected EXTRAARG"),
+            _ => return Ok(None),
+        }
+        Ok(Some(pc + 1))
+    }
+
+    fn call(&self, st: &mut State, a: usize, b: usize, pc: usize) -> Result<ASTNode> {
+        let count = if b == 0 { None } else { Some(b - 1) };
+        if let Some(&Slot::Method(..)) = st.regs.get(a) {
+            if let Slot::Method(obj, name) = mem::replace(&mut st.regs[a], Slot::Empty) {
+                self.put(st, a + 1, Slot::Empty, pc)?;
+                let args = self.list(st, a + 2, count.map(|n| n.saturating_sub(1)), pc)?;
+                return Ok(astb!(MethodCall, head(obj), Name(name), arguments(args)));
+            }
+        }
+        let f = self.get(st, a, pc)?;
+        let args = self.list(st, a + 1, count, pc)?;
+        Ok(astb!(FunctionCall, head(f), arguments(args)))
+    }
+
+    fn closure(&self, pc: usize, st: &mut State) -> Result<()> {
+        let i = self.proto.code[pc];
+        let a = i.a() as usize;
+        let child = match self.proto.protos.get(i.bx() as usize) {
+            Some(child) => child,
+            None => return error(pc, format!("no function {}", i.bx())),
+        };
+        let upvalues = child.upvalues.iter().map(|u| if !u.name.is_empty() {
+            u.name.clone()
+        } else if u.in_stack {
+            self.register_name(u.index as usize, pc)
+        } else {
+            self.upvalues.get(u.index as usize).cloned().unwrap_or_else(|| format!("u{}", u.index))
+        }).collect();
+        let body = Function::new(child, upvalues, self.depth + 1).function_body()?;
+        if st.named == Some(a) {
+            st.named = None;
+            let name = self.register_name(a, pc);
+            self.emit(st, astb!(NamedFunction, Name(name), body));
+            Ok(())
+        } else {
+            self.set(st, a, astb!(Function, body), Kind::Single, pc)
+        }
+    }
+
+    /// Moves the list items computed before a field of a constructor into
+    /// it, to keep the order of evaluation
+    fn items(&self, st: &mut State, a: usize) {
+        let taken = match st.regs[a] {
+            Slot::Table(_, taken) => taken,
+            _ => return,
+        };
+        let mut fields = vec![];
+        let mut r = a + 1 + taken;
+        while r < st.regs.len() {
+            match mem::replace(&mut st.regs[r], Slot::Empty) {
+                Slot::Value(e, _) => fields.push(astb!(FieldSingle, e)),
+                Slot::Table(f, _) => fields.push(astb!(FieldSingle, table(f))),
+                slot => {
+                    st.regs[r] = slot;
+                    break;
+                }
+            }
+            r += 1;
+        }
+        if let Slot::Table(ref mut all, ref mut taken) = st.regs[a] {
+            *taken += fields.len();
+            all.extend(fields);
+        }
+    }
+
+    fn set_list(&self, pc: usize, st: &mut State) -> Result<usize> {
+        let i = self.proto.code[pc];
+        let (a, b, c) = (i.a() as usize, i.b() as usize, i.c() as usize);
+        let (batch, next) = if c != 0 {
+            (c, pc + 1)
+        } else {
+            match self.proto.code.get(pc + 1) {
+                Some(x) if x.opcode() == Some(OpCode::ExtraArg) => (x.ax_arg() as usize, pc + 2),
+                _ => return error(pc, "SETLIST is not followed by EXTRAARG"),
+            }
+        };
+        if let Some(&Slot::Table(_, taken)) = st.regs.get(a) {
+            if !self.is_var(st, a, pc) {
+                let count = if b == 0 { None } else { Some(b.saturating_sub(taken)) };
+                let items = fixed(self.list(st, a + 1 + taken, count, pc)?);
+                if let Slot::Table(ref mut fields, ref mut taken) = st.regs[a] {
+                    fields.extend(items.into_iter().map(|e| astb!(FieldSingle, e)));
+                    *taken = 0;
+                }
+                return Ok(next);
+            }
+        }
+        // The items of a table in a local of a stripped function
+        let items = fixed(self.list(st, a + 1, if b == 0 { None } else { Some(b) }, pc)?);
+        let obj = self.get(st, a, pc)?;
+        let base = batch.saturating_sub(1) * LFIELDS_PER_FLUSH as usize;
+        if b != 0 {
+            let targets = (0..items.len()).map(|k| target(obj.clone(), Integer((base + k + 1) as i64))).collect();
+            self.emit(st, astb!(Assign, VarList(targets), ExpList(items)));
+        } else {
+            let key = if base == 0 {
+                var("i_".to_string())
+            } else {
+                astb!(Add, Integer(base as i64), var("i_".to_string()))
+            };
+            let store = astb!(Assign, VarList(vec![target(obj, key)]), ExpList(vec![var("v_".to_string())]));
+            let names = NameList(vec![(Name("i_".to_string()), None), (Name("v_".to_string()), None)]);
+            let list = table(items.into_iter().map(|e| astb!(FieldSingle, e)).collect());
+            self.emit(st, astb!(GenericFor, names, ExpList(vec![var("next".to_string()), list]),
+                                ast!(Block, vec![store], Box::new(None))));
+        }
+        Ok(next)
+    }
+
+    /// Rebuilds an `and` or an `or` whose test skips the code of its
+    /// second operand, or a comparison made a boolean by `LOADBOOL`
+    fn value(&self, pc: usize, end: usize, st: &mut State) -> Result<Option<usize>> {
+        if self.op_at(pc + 1) != Some(OpCode::Jmp) || self.nesting.get() > MAX_DEPTH {
+            return Ok(None);
+        }
+        self.nesting.set(self.nesting.get() + 1);
+        let result = self.operators(pc, end, st);
+        self.nesting.set(self.nesting.get() - 1);
+        result
+    }
+
+    fn operators(&self, pc: usize, end: usize, st: &mut State) -> Result<Option<usize>> {
+        let i = self.proto.code[pc];
+        let (a, b, c) = (i.a() as usize, i.b() as usize, i.c());
+        let target = self.target(pc + 1)?;
+        if target > pc + 2 && target <= end {
+            let first = match self.op(pc)? {
+                // luac keeps the value being tested with TESTSET
+                OpCode::TestSet => {
+                    let mut fork = st.fork();
+                    let first = self.get(&mut fork, b, pc)?;
+                    Some((first, fork))
+                }
+                // compile tests it in a temporary register
+                OpCode::Test if !self.is_var(st, a, pc) && !self.dead(target) => match st.regs.get(a) {
+                    Some(&Slot::Value(..)) | Some(&Slot::Table(..)) => {
+                        let mut fork = st.fork();
+                        let first = self.get(&mut fork, a, pc)?;
+                        Some((first, fork))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            if let Some((first, mut fork)) = first {
+                fork.capture = Some(a);
+                fork.regs[a] = Slot::Empty;
+                if let Some(mut fork) = self.range(pc + 2, target, fork) {
+                    let second = match mem::replace(&mut fork.regs[a], Slot::Empty) {
+                        Slot::Value(e, _) => Some(e),
+                        Slot::Table(fields, _) => Some(table(fields)),
+                        _ => None,
+                    };
+                    if let Some(second) = second {
+                        fork.capture = st.capture;
+                        st.adopt(fork);
+                        let e = if c == 0 { and(first, second) } else { or(first, second) };
+                        self.set(st, a, e, Kind::Single, pc)?;
+                        return Ok(Some(target));
+                    }
+                }
+            }
+        }
+        if let Some(p) = self.bool_pair(pc, end) {
+            let units = self.units(pc, p, st, None);
+            if units.last().is_some_and(|u| u.next == p) {
+                let outcome = |t| if t == p { Some(false) } else if t == p + 1 { Some(true) } else { None };
+                if let Some(cond) = condition(&units, &outcome) {
+                    st.adopt(units[units.len() - 1].state.clone());
+                    let cond = if boolean(&cond) { cond } else { astb!(Not, astb!(Not, cond)) };
+                    let r = self.proto.code[p].a() as usize;
+                    self.set(st, r, cond, Kind::Single, p)?;
+                    return Ok(Some(p + 2));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether a local starts and dies at `pc`, the last statement of a
+    /// block that a test skips
+    fn dead(&self, pc: usize) -> bool {
+        self.locals.iter().any(|l| l.start_pc == pc && l.end_pc == pc)
+    }
+
+    /// Runs the code of a value, which must not have statements
+    fn range(&self, start: usize, end: usize, mut fork: State) -> Option<State> {
+        let mut pc = start;
+        while pc < end {
+            if self.starts.contains(&pc) {
+                return None;
+            }
+            match self.expression(pc, end, &mut fork) {
+                Ok(Some(next)) => pc = next,
+                _ => return None,
+            }
+            if !fork.quiet() {
+                return None;
+            }
+        }
+        if pc == end { Some(fork) } else { None }
+    }
+
+    /// The `LOADBOOL` pair ending the comparisons from `pc`, which all
+    /// jump to it
+    fn bool_pair(&self, pc: usize, end: usize) -> Option<usize> {
+        let code = &self.proto.code;
+        let mut reach = 0;
+        let mut q = pc;
+        while q + 1 < end {
+            let i = code[q];
+            match i.opcode()? {
+                OpCode::LoadBool if q > pc + 1 && i.b() == 0 && i.c() == 1 && reach <= q + 1 => {
+                    let j = code[q + 1];
+                    if j.opcode() == Some(OpCode::LoadBool) && j.a() == i.a() && j.b() == 1 && j.c() == 0 {
+                        return Some(q);
+                    }
+                }
+                OpCode::Jmp => {
+                    let target = self.target(q).ok()?;
+                    if target <= q {
+                        return None;
+                    }
+                    reach = reach.max(target);
+                }
+                OpCode::Return | OpCode::TailCall | OpCode::ForPrep | OpCode::ForLoop | OpCode::TForCall |
+                OpCode::TForLoop | OpCode::SetTabUp | OpCode::SetUpval => return None,
+                OpCode::Call if i.c() == 1 => return None,
+                _ => {}
+            }
+            q += 1;
+        }
+        None
+    }
+}
+
+/// The name of a register without a local, `r1` in the main function and
+/// `r1_2` in a function nested twice, so closures see their upvalues
+fn synthetic(r: usize, depth: usize) -> String {
+    if depth == 0 { format!("r{}", r) } else { format!("r{}_{}", r, depth) }
+}
+
+/// The condition of a chain of tests, where `outcome` tells whether each
+/// target outside the chain is reached when the condition holds
+fn condition(units: &[Unit], outcome: &dyn Fn(usize) -> Option<bool>) -> Option<ASTNode> {
+    let unit = &units[0];
+    if units.len() == 1 {
+        return match (outcome(unit.target), outcome(unit.next)) {
+            (Some(true), Some(false)) => Some(unit.cond.clone()),
+            (Some(false), Some(true)) => Some(negate(unit.cond.clone())),
+            _ => None,
+        };
+    }
+    match outcome(unit.target) {
+        Some(true) => Some(or(unit.cond.clone(), condition(&units[1..], outcome)?)),
+        Some(false) => Some(and(negate(unit.cond.clone()), condition(&units[1..], outcome)?)),
+        None => {
+            // The tests up to the target form a group, whose other jumps
+            // all go to the same outcome
+            let m = units.iter().position(|u| u.start == unit.target)?;
+            let (group, rest) = units.split_at(m);
+            let join = rest[0].start;
+            let mut value = None;
+            for u in group {
+                if u.target == join || group.iter().any(|v| v.start == u.target) {
+                    continue;
+                }
+                let o = outcome(u.target)?;
+                if value.is_some_and(|v| v != o) {
+                    return None;
+                }
+                value = Some(o);
+            }
+            let value = value.unwrap_or(false);
+            let inner = |t| if t == join { Some(!value) } else { outcome(t) };
+            let group = condition(group, &inner)?;
+            let rest = condition(rest, outcome)?;
+            Some(if value { or(group, rest) } else { and(group, rest) })
+        }
+    }
+}
+
+/// The targets of a chain of tests that are not in the chain, other than
+/// `next`
+fn exits(units: &[Unit], next: usize) -> Vec<usize> {
+    let targets: BTreeSet<usize> = units.iter().map(|u| u.target)
+        .filter(|&t| t != next && !units[1..].iter().any(|u| u.start == t))
+        .collect();
+    targets.into_iter().collect()
+}
+
+/// Whether an expression is always a boolean
+fn boolean(e: &ASTNode) -> bool {
+    match *e {
+        Eq(_, _) | Ne(_, _) | Lt(_, _) | Le(_, _) | Gt(_, _) | Ge(_, _) | Not(_) | Bool(_) => true,
+        And(ref a, ref b) | Or(ref a, ref b) => boolean(a) && boolean(b),
+        _ => false,
+    }
+}
+
+// Conditions are only tested for truth, `not not x` is `x` there
+fn negate(mut e: ASTNode) -> ASTNode {
+    match e {
+        Eq(ref mut a, ref mut b) => astb!(Ne, a.take(), b.take()),
+        Ne(ref mut a, ref mut b) => astb!(Eq, a.take(), b.take()),
+        Not(ref mut a) => a.take(),
+        _ => astb!(Not, e),
+    }
+}
+
+// `a and (b and c)` is `(a and b) and c`, whichever the values are
+fn and(a: ASTNode, mut b: ASTNode) -> ASTNode {
+    match b {
+        And(ref mut x, ref mut y) => astb!(And, and(a, x.take()), y.take()),
+        _ => astb!(And, a, b),
+    }
+}
+
+fn or(a: ASTNode, mut b: ASTNode) -> ASTNode {
+    match b {
+        Or(ref mut x, ref mut y) => astb!(Or, or(a, x.take()), y.take()),
+        _ => astb!(Or, a, b),
+    }
+}
+
+fn is_if(node: &ASTNode) -> bool {
+    matches!(*node, If(_, _, _))
+}
+
+fn var(name: String) -> ASTNode {
+    astb!(PrefixExp, astb!(Var, Name(name)))
+}
+
+/// Parenthesizes an expression that cannot be called or indexed as is
+fn head(e: ASTNode) -> ASTNode {
+    match e {
+        PrefixExp(_) => e,
+        e => astb!(PrefixExp, e),
+    }
+}
+
+fn unwrap(mut e: ASTNode) -> ASTNode {
+    match e {
+        PrefixExp(ref mut inner) => inner.take(),
+        _ => e,
+    }
+}
+
+// A string key that can be written as a name, as in `t.name`
+fn as_name(key: &ASTNode) -> Option<String> {
+    match *key {
+        ASTNode::String(ref s) => str::from_utf8(s).ok().filter(|name| is_name(name)).map(str::to_string),
+        _ => None,
+    }
+}
+
+fn target(obj: ASTNode, key: ASTNode) -> ASTNode {
+    match as_name(&key) {
+        Some(name) => astb!(VarListAccess, head(obj), Name(name)),
+        None => astb!(VarPrefixed, head(obj), key),
+    }
+}
+
+fn index(obj: ASTNode, key: ASTNode) -> ASTNode {
+    astb!(PrefixExp, target(obj, key))
+}
+
+fn field_key(key: ASTNode) -> ASTNode {
+    match as_name(&key) {
+        Some(name) => Name(name),
+        None => key,
+    }
+}
+
+fn table(fields: Vec<ASTNode>) -> ASTNode {
+    astb!(TableConstructor, if fields.is_empty() { None } else { Some(FieldList(fields)) })
+}
+
+fn is_multi(e: &ASTNode) -> bool {
+    match *e {
+        VarArg => true,
+        PrefixExp(ref e) => matches!(**e, FunctionCall(_, _) | MethodCall(_, _, _)),
+        _ => false,
+    }
+}
+
+/// The expressions of a list of fixed length, where a call truncated to
+/// one value is parenthesized
+fn fixed(items: Vec<(ASTNode, Kind)>) -> Vec<ASTNode> {
+    let n = items.len();
+    items.into_iter().enumerate().filter(|&(_, (_, kind))| kind != Kind::Rest).map(|(k, (e, kind))| {
+        if k + 1 == n && kind == Kind::Single && is_multi(&e) { astb!(PrefixExp, e) } else { e }
+    }).collect()
+}
+
+/// The expressions assigned to `targets` variables, without the padding
+/// of `LOADNIL` but at least `keep` of them
+fn adjusted(items: Vec<(ASTNode, Kind)>, targets: usize, keep: usize) -> Vec<ASTNode> {
+    let mut items: Vec<_> = items.into_iter().filter(|&(_, kind)| kind != Kind::Rest).collect();
+    while items.len() > keep && items.last().is_some_and(|&(_, kind)| kind == Kind::Filler) {
+        items.pop();
+    }
+    let truncated = items.len() < targets && items.last().is_some_and(|&(ref e, kind)| {
+        kind == Kind::Single && is_multi(e)
+    });
+    let mut exps: Vec<ASTNode> = items.into_iter().map(|(e, _)| e).collect();
+    if truncated {
+        let last = exps.pop().unwrap();
+        exps.push(astb!(PrefixExp, last));
+    }
+    exps
+}
+
+fn arguments(items: Vec<(ASTNode, Kind)>) -> Option<ASTNode> {
+    let exps = fixed(items);
+    if exps.is_empty() { None } else { Some(ExpList(exps)) }
+}
+
+/// The names of a function statement assigning to `target`
+fn path(target: &ASTNode) -> Option<Vec<String>> {
+    match *target {
+        Var(ref name) => match **name {
+            Name(ref name) => Some(vec![name.clone()]),
+            _ => None,
+        },
+        VarListAccess(ref obj, ref key) => match (&**obj, &**key) {
+            (PrefixExp(obj), Name(key)) => {
+                let mut names = path(obj)?;
+                names.push(key.clone());
+                Some(names)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// `function a.b:c() end` for a function assigned to a field, a method
+/// when its first parameter is `self`
+fn function_stat(target: &ASTNode, value: &ASTNode) -> Option<ASTNode> {
+    let mut names = path(target)?;
+    let mut body = match *value {
+        Function(ref body) => (**body).clone(),
+        _ => return None,
+    };
+    let mut method = None;
+    if names.len() > 1 {
+        if let FunctionBody(ref mut params, _) = body {
+            if let Some(ParameterList(ref mut list, _)) = **params {
+                let is_self = match **list {
+                    Some(NameList(ref names)) => names.first().is_some_and(|(n, _)| *n == Name("self".into())),
+                    _ => false,
+                };
+                if is_self {
+                    if let Some(NameList(ref mut names)) = **list {
+                        names.remove(0);
+                    }
+                    if let Some(NameList(ref names)) = **list {
+                        if names.is_empty() {
+                            **list = None;
+                        }
+                    }
+                    method = names.pop().map(|m| Box::new(Name(m)));
+                }
+            }
+        }
+    }
+    let first = names.remove(0);
+    let fields = if names.is_empty() { None } else { Some(names.into_iter().map(Name).collect()) };
+    Some(astb!(FunctionStat, ast!(FunctionName, Box::new(Name(first)), fields, method), body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytecode::vm::load;
+    use bytecode::{compile, undump, Instruction};
+    use config::ParserConfig;
+    use eval::Interpreter;
+    use parse_block_with;
+    use printer::print;
+
+    fn run(source: &str) -> Vec<String> {
+        let config = ParserConfig { version: LuaVersion::Lua53, ..ParserConfig::default() };
+        let block = parse_block_with(source.as_bytes(), &config).unwrap_or_else(|e| panic!("{}\n{}", e, source));
+        let mut lua = Interpreter::new();
+        let main = load(&lua, compile(&block, source.as_bytes(), "=test").unwrap());
+        match lua.call(&main, vec![]) {
+            Ok(values) => values.iter().map(|v| v.to_string()).collect(),
+            Err(e) => vec![e.to_string()],
+        }
+    }
+
+    fn decompiled(source: &str, strip: bool) -> String {
+        let config = ParserConfig { version: LuaVersion::Lua53, ..ParserConfig::default() };
+        let block = parse_block_with(source.as_bytes(), &config).unwrap();
+        let proto = compile(&block, source.as_bytes(), "=test").unwrap();
+        let chunk = undump(&proto.dump(strip)).unwrap();
+        print(&decompile(&chunk).unwrap_or_else(|e| panic!("{}\n{}", e, source)))
+    }
+
+    /// Decompiles the code of `source`, whose printed text has to run the
+    /// same way
+    fn check(source: &str, strip: bool) -> String {
+        let text = decompiled(source, strip);
+        assert_eq!(run(&text), run(source), "{}\n{}", source, text);
+        text
+    }
+
+    #[test]
+    fn exact() {
+        assert_eq!(check("local a, b = 1, 2 a, b = b, a return a, b", false),
+                   "local a, b = 1, 2\na, b = b, a\nreturn a, b\n");
+        assert_eq!(check("local x = 3 if x > 2 and x < 5 then x = 1 elseif x == 9 then x = 2 else x = 3 end return x", false),
+                   "local x = 3\nif 2 < x and x < 5 then\n  x = 1\nelseif x == 9 then\n  x = 2\nelse\n  x = 3\nend\nreturn x\n");
+        assert_eq!(check("local t = {1, 2, x = 3} function t.f(a) return a or t.x end return t.f(), #t", false),
+                   "local t = {1, 2, x = 3}\nfunction t.f(a)\n  return a or t.x\nend\nreturn t.f(), #t\n");
+        assert_eq!(check("local n = 0 while n < 3 do n = n + 1 end repeat n = n - 1 until n == 0 return n", false),
+                   "local n = 0\nwhile n < 3 do\n  n = n + 1\nend\nrepeat\n  n = n - 1\nuntil n == 0\nreturn n\n");
+        assert_eq!(check("local x = 1 return x == 1, not x", false), "local x = 1\nreturn x == 1, not x\n");
+        assert_eq!(check("local n = 0 for i = 1, 5 do if i == 3 then goto continue end n = n + i ::continue:: end return n", false),
+                   "local n = 0\nfor i = 1, 5 do\n  if i == 3 then\n    goto L10\n  end\n  n = n + i\n  ::L10::\nend\nreturn n\n");
+    }
+
+    #[test]
+    fn expressions() {
+        for &strip in &[false, true] {
+            check("local a, b = 7, 2 return a + b, a - b, a * b, a / b, a // b, a % b, a ^ b", strip);
+            check("local a = 6 return a & 3, a | 1, a ~ 5, a << 2, a >> 1, ~a, -a, #'abc'", strip);
+            check("local a, b = 1, 2 return a < b, a <= b, a > b, a >= b, a == b, a ~= b, not a", strip);
+            check("local x = nil return x and 1, x or 2, 1 and 2, false or nil, 1 or error()", strip);
+            check("local s = 'b' return 'a' .. s .. 1 .. 2.5, (('x') .. 'y') .. 'z'", strip);
+            check("local x = 3 return x > 2 and x < 5 or x == 10, not (x == 3 or y), 2 > x", strip);
+            check("local a, b, c = 1, false, nil return (a or b) and c, a and (b or c), not (a and b) or c", strip);
+            check("local t = {n = {1, 2, 'x y'}, ['a b'] = 4, [1.5] = 5} return t.n[3], t['a b'], t[1.5]", strip);
+        }
+    }
+
+    #[test]
+    fn statements() {
+        for &strip in &[false, true] {
+            check("local t = {1, 2} local i, j = 1, 2 t[i], t[j] = t[j], t[i] return t[1], t[2]", strip);
+            check("local t = {} t.x, t = 1, {} return t.x", strip);
+            check("x, y = 1 return x, y", strip);
+            check("local t = {a = {b = {}}} function t.a.b.f(x) return x * 2 end \
+                   function t.a.b:m(x) return self == t.a.b, x end return t.a.b.f(4), t.a.b:m(5)", strip);
+            check("local x = 1 do local x = 2 end if x == 1 then x = 10 elseif x == 2 then x = 20 else x = 30 end return x", strip);
+            check("local n = 0 while true do n = n + 1 if n > 5 then break end end return n", strip);
+            check("local s = 0 for i = 10, 1, -2 do s = s + i end for i = 1, 2, 0.5 do s = s + i end return s", strip);
+            check("local s = '' for i = 1, 3 do for j = i, 3 do if j == 2 then goto next end s = s .. i .. j ::next:: end end return s", strip);
+            check("local t = {} for k, v in pairs({a = 1, b = 2}) do t[#t + 1] = k .. v end return #t", strip);
+            check("local s = 0 for i, v in ipairs({5, 6, 7}) do s = s + i * v end return s", strip);
+            check("local i = 0 ::top:: i = i + 1 if i < 4 then goto top end return i", strip);
+            check("local n = 0 for i = 1, 10 do if i % 2 == 0 and i > 4 or i == 1 then n = n + i end end return n", strip);
+            check("local n, m = 0, 0 while n < 10 and m ~= 3 do n = n + 2 if n > 4 then m = m + 1 end end return n, m", strip);
+            check("local function f(x) if x then return 1 end return 2 end return f(true), f(false)", strip);
+        }
+        check("local n = 0 repeat local m = n n = n + 1 until m >= 3 return n", false);
+    }
+
+    #[test]
+    fn functions() {
+        for &strip in &[false, true] {
+            check("local function fib(n) if n < 2 then return n end return fib(n - 1) + fib(n - 2) end return fib(15)", strip);
+            check("local function f(...) return select('#', ...), ... end return f(1, nil, 3)", strip);
+            check("local function f(...) local a, b = ... return {...}, a, b end local t, a, b = f(4, 5, 6) return #t, a, b", strip);
+            check("local function f() return 1, 2, 3 end return f(), (f()), #{f(), f()}, f()", strip);
+            check("local o = {n = 1} function o:get(x) return self.n + x end return o:get(2), o.get(o, 3)", strip);
+            check("local function counter() local n = 0 return function() n = n + 1 return n end end \
+                   local a, b = counter(), counter() a() return a(), b()", strip);
+            check("local fs = {} for i = 1, 3 do fs[i] = function() return i end end return fs[1](), fs[2](), fs[3]()", strip);
+            check("return pcall(error, 'message')", strip);
+        }
+        check("local fs = {} local i = 1 while i <= 3 do local j = i fs[i] = function() j = j + 10 return j end i = i + 1 end \
+               return fs[1](), fs[1](), fs[2]()", false);
+        check("local fs = {} local i = 0 repeat local j = i fs[#fs + 1] = function() return j end i = i + 1 until j >= 2 \
+               return fs[1](), fs[3]()", false);
+    }
+
+    #[test]
+    fn big_functions() {
+        let items: Vec<_> = (1..131).map(|i| i.to_string()).collect();
+        for &strip in &[false, true] {
+            check(&format!("local function f() return 1, 2 end local t = {{{}, f()}} return #t, t[50], t[51], t[132]",
+                           items.join(", ")), strip);
+        }
+    }
+
+    // luac tests a copy of the value with TESTSET and compares constants
+    // on the left
+    #[test]
+    fn luac_code() {
+        let mut proto = Proto {
+            source: None,
+            line_defined: 0,
+            last_line_defined: 0,
+            num_params: 2,
+            is_vararg: false,
+            max_stack_size: 3,
+            code: vec![
+                Instruction::abc(OpCode::TestSet, 2, 0, 1),
+                Instruction::asbx(OpCode::Jmp, 0, 1),
+                Instruction::abc(OpCode::Move, 2, 1, 0),
+                Instruction::abc(OpCode::Lt, 1, BITRK, 2),
+                Instruction::asbx(OpCode::Jmp, 0, 0),
+                Instruction::abc(OpCode::Return, 2, 2, 0),
+                Instruction::abc(OpCode::Return, 0, 1, 0),
+            ],
+            constants: vec![Constant::Integer(0)],
+            upvalues: vec![],
+            protos: vec![],
+            line_info: vec![1; 7],
+            local_vars: vec![
+                LocalVar { name: "a".to_string(), start_pc: 0, end_pc: 7 },
+                LocalVar { name: "b".to_string(), start_pc: 0, end_pc: 7 },
+                LocalVar { name: "c".to_string(), start_pc: 3, end_pc: 7 },
+            ],
+        };
+        let text = |proto: &Proto| print(&decompile(&Chunk::new(proto.clone())).unwrap());
+        assert_eq!(text(&proto), "local c = a or b\nif 0 < c then end\nreturn c\n");
+        proto.code[0] = Instruction::abc(OpCode::Move, 2, 7, 0);
+        assert_eq!(decompile(&Chunk::new(proto)).unwrap_err().to_string(),
+                   "instruction 0: register 7 is read before it is set");
+    }
+
+    #[test]
+    fn errors() {
+        let block = parse_block_with(b"return 1", &ParserConfig::default()).unwrap();
+        let mut chunk = Chunk::new(compile(&block, b"return 1", "=test").unwrap());
+        chunk.header.version = LuaVersion::Lua51;
+        assert_eq!(decompile(&chunk).unwrap_err().to_string(),
+                   "instruction 0: only Lua 5.3 chunks can be decompiled");
+        chunk.header.version = LuaVersion::Lua53;
+        chunk.main.code[0] = Instruction(63);
+        assert_eq!(decompile(&chunk).unwrap_err(),
+                   DecompileError { pc: 0, message: "invalid instruction".to_string() });
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The binary chunk format of `luac` 5.3, as written by `ldump.c`
+//!
+//! Numbers are in the byte order of the machine, the header holds an
+//! integer and a float known in advance so that the loader can check it.
+
+use super::{Constant, Proto};
+
+pub const SIGNATURE: &[u8] = b"\x1bLua";
+pub const VERSION: u8 = 0x53;
+pub const FORMAT: u8 = 0;
+/// Catches conversions of line endings and other corruptions
+pub const DATA: &[u8] = b"\x19\x93\r\n\x1a\n";
+pub const INT_CHECK: i64 = 0x5678;
+pub const FLOAT_CHECK: f64 = 370.5;
+
+/// Strings up to this length are interned by Lua
+pub const MAX_SHORT_LEN: usize = 40;
+
+pub const TAG_NIL: u8 = 0;
+pub const TAG_BOOLEAN: u8 = 1;
+pub const TAG_FLOAT: u8 = 3;
+pub const TAG_INTEGER: u8 = 3 | 1 << 4;
+pub const TAG_SHORT_STRING: u8 = 4;
+pub const TAG_LONG_STRING: u8 = 4 | 1 << 4;
+
+struct Writer {
+    output: Vec<u8>,
+    strip: bool,
+}
+
+impl Writer {
+    fn byte(&mut self, b: u8) {
+        self.output.push(b);
+    }
+
+    // A C int
+    fn int(&mut self, i: usize) {
+        self.output.extend_from_slice(&(i as u32).to_ne_bytes());
+    }
+
+    fn size(&mut self, s: usize) {
+        self.output.extend_from_slice(&(s as u64).to_ne_bytes());
+    }
+
+    fn integer(&mut self, i: i64) {
+        self.output.extend_from_slice(&i.to_ne_bytes());
+    }
+
+    fn number(&mut self, f: f64) {
+        self.output.extend_from_slice(&f.to_bits().to_ne_bytes());
+    }
+
+    // The size counts a terminating NUL which is not written, 0 is NULL
+    fn string(&mut self, s: Option<&[u8]>) {
+        let s = match s {
+            Some(s) => s,
+            None => return self.byte(0),
+        };
+        if s.len() + 1 < 0xff {
+            self.byte(s.len() as u8 + 1);
+        } else {
+            self.byte(0xff);
+            self.size(s.len() + 1);
+        }
+        self.output.extend_from_slice(s);
+    }
+
+    fn header(&mut self) {
+        self.output.extend_from_slice(SIGNATURE);
+        self.byte(VERSION);
+        self.byte(FORMAT);
+        self.output.extend_from_slice(DATA);
+        // The sizes of int, size_t, Instruction, lua_Integer and lua_Number
+        for &size in &[4, 8, 4, 8, 8] {
+            self.byte(size);
+        }
+        self.integer(INT_CHECK);
+        self.number(FLOAT_CHECK);
+    }
+
+    fn function(&mut self, f: &Proto, parent_source: Option<&str>) {
+        let source = f.source.as_deref();
+        if self.strip || source == parent_source {
+            self.string(None);
+        } else {
+            self.string(source.map(str::as_bytes));
+        }
+        self.int(f.line_defined);
+        self.int(f.last_line_defined);
+        self.byte(f.num_params);
+        self.byte(f.is_vararg as u8);
+        self.byte(f.max_stack_size);
+
+        self.int(f.code.len());
+        for i in &f.code {
+            self.output.extend_from_slice(&i.0.to_ne_bytes());
+        }
+
+        self.int(f.constants.len());
+        for k in &f.constants {
+            match *k {
+                Constant::Nil => self.byte(TAG_NIL),
+                Constant::Boolean(b) => {
+                    self.byte(TAG_BOOLEAN);
+                    self.byte(b as u8);
+                }
+                Constant::Float(n) => {
+                    self.byte(TAG_FLOAT);
+                    self.number(n);
+                }
+                Constant::Integer(i) => {
+                    self.byte(TAG_INTEGER);
+                    self.integer(i);
+                }
+                Constant::String(ref s) => {
+                    self.byte(if s.len() <= MAX_SHORT_LEN { TAG_SHORT_STRING } else { TAG_LONG_STRING });
+                    self.string(Some(s));
+                }
+            }
+        }
+
+        self.int(f.upvalues.len());
+        for u in &f.upvalues {
+            self.byte(u.in_stack as u8);
+            self.byte(u.index);
+        }
+
+        self.int(f.protos.len());
+        // A nested function without a source has the one of its parent
+        let inherited = source.or(parent_source);
+        for p in &f.protos {
+            self.function(p, inherited);
+        }
+
+        if self.strip {
+            // No line info, local variables and upvalue names
+            self.int(0);
+            self.int(0);
+            self.int(0);
+            return;
+        }
+        self.int(f.line_info.len());
+        for &line in &f.line_info {
+            self.int(line);
+        }
+        self.int(f.local_vars.len());
+        for v in &f.local_vars {
+            self.string(Some(v.name.as_bytes()));
+            self.int(v.start_pc);
+            self.int(v.end_pc);
+        }
+        self.int(f.upvalues.len());
+        for u in &f.upvalues {
+            self.string(Some(u.name.as_bytes()));
+        }
+    }
+}
+
+pub fn dump(f: &Proto, strip: bool) -> Vec<u8> {
+    let mut writer = Writer { output: Vec::new(), strip };
+    writer.header();
+    writer.byte(f.upvalues.len() as u8);
+    writer.function(f, None);
+    writer.output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytecode::{Instruction, OpCode, Upvalue};
+
+    fn empty() -> Proto {
+        Proto {
+            source: Some("=stdin".into()),
+            line_defined: 0,
+            last_line_defined: 0,
+            num_params: 0,
+            is_vararg: true,
+            max_stack_size: 2,
+            code: vec![Instruction::abc(OpCode::Return, 0, 1, 0)],
+            constants: vec![],
+            upvalues: vec![Upvalue { name: "_ENV".into(), in_stack: true, index: 0 }],
+            protos: vec![],
+            line_info: vec![1],
+            local_vars: vec![],
+        }
+    }
+
+    #[test]
+    fn empty_chunk() {
+        // What luac 5.3 writes for an empty file on a little-endian machine
+        let mut expected = b"\x1bLua\x53\x00\x19\x93\r\n\x1a\n\x04\x08\x04\x08\x08".to_vec();
+        expected.extend_from_slice(&0x5678i64.to_le_bytes());
+        expected.extend_from_slice(&370.5f64.to_bits().to_le_bytes());
+        expected.extend_from_slice(b"\x01\x07=stdin");
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2]);
+        expected.extend_from_slice(&[1, 0, 0, 0, 0x26, 0, 0x80, 0]);
+        expected.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(&[1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 5]);
+        expected.extend_from_slice(b"_ENV");
+        if cfg!(target_endian = "little") {
+            assert_eq!(dump(&empty(), false), expected);
+        }
+    }
+
+    #[test]
+    fn strings_and_stripping() {
+        let mut f = empty();
+        f.constants = vec![Constant::String(vec![b'x'; 300]), Constant::Nil];
+        let stripped = dump(&f, true);
+        assert!(stripped.ends_with(&[0; 12]));
+        let long = stripped.windows(9).position(|w| w[0] == 0xff && w[1..] == 301u64.to_ne_bytes()).unwrap();
+        assert_eq!(stripped[long - 1], TAG_LONG_STRING);
+        assert_eq!(stripped[long + 9 + 300], TAG_NIL);
+        // The source is NULL once stripped
+        assert!(!stripped.windows(6).any(|w| w == b"=stdin"));
+    }
+}
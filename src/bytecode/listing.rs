@@ -0,0 +1,399 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Listings of functions in the format of `luac -l`, from `print.c` of
+//! 5.1 and `luac.c` of 5.3
+//!
+//! The addresses printed by `luac` are those of the functions in memory,
+//! here they are the addresses of the `Proto`s.
+
+use std::fmt::Write;
+
+use config::LuaVersion;
+use eval::{format_float, format_g14};
+use super::opcode::*;
+use super::{Chunk, Constant, Proto};
+
+struct Lister<'a> {
+    version: LuaVersion,
+    full: bool,
+    output: String,
+    // The source of the function being listed, which its functions inherit
+    source: Option<&'a str>,
+}
+
+fn plural(n: usize) -> &'static str {
+    if n == 1 { "" } else { "s" }
+}
+
+// MYK in luac, constants are printed as negative numbers
+fn k(index: u32) -> i64 {
+    -1 - index as i64
+}
+
+fn rk(x: u32) -> i64 {
+    if is_k(x) { k(x & !BITRK) } else { x as i64 }
+}
+
+fn string(output: &mut String, s: &[u8]) {
+    output.push('"');
+    for &c in s {
+        match c {
+            b'"' => output.push_str("\\\""),
+            b'\\' => output.push_str("\\\\"),
+            0x07 => output.push_str("\\a"),
+            0x08 => output.push_str("\\b"),
+            0x0c => output.push_str("\\f"),
+            b'\n' => output.push_str("\\n"),
+            b'\r' => output.push_str("\\r"),
+            b'\t' => output.push_str("\\t"),
+            0x0b => output.push_str("\\v"),
+            b' '..=b'~' => output.push(c as char),
+            _ => write!(output, "\\{:03}", c).unwrap(),
+        }
+    }
+    output.push('"');
+}
+
+impl<'a> Lister<'a> {
+    fn constant(&mut self, f: &Proto, index: usize) {
+        match f.constants.get(index) {
+            Some(&Constant::Nil) => self.output.push_str("nil"),
+            Some(&Constant::Boolean(b)) => write!(self.output, "{}", b).unwrap(),
+            Some(&Constant::Integer(i)) => write!(self.output, "{}", i).unwrap(),
+            Some(&Constant::Float(n)) if self.version == LuaVersion::Lua51 => self.output.push_str(&format_g14(n)),
+            Some(&Constant::Float(n)) => self.output.push_str(&format_float(n)),
+            Some(Constant::String(s)) => string(&mut self.output, s),
+            None => self.output.push('?'),
+        }
+    }
+
+    fn upvalue_name(f: &Proto, index: u32) -> &str {
+        match f.upvalues.get(index as usize) {
+            Some(u) if !u.name.is_empty() => &u.name,
+            _ => "-",
+        }
+    }
+
+    fn header(&mut self, f: &'a Proto) {
+        let source = f.source.as_deref().or(self.source).unwrap_or("=?");
+        let name = if source.starts_with('@') || source.starts_with('=') {
+            &source[1..]
+        } else if source.starts_with('\x1b') {
+            "(bstring)"
+        } else {
+            "(string)"
+        };
+        self.source = Some(source);
+        write!(self.output, "\n{} <{}:{},{}> ({} instruction{}",
+               if f.line_defined == 0 { "main" } else { "function" }, name,
+               f.line_defined, f.last_line_defined, f.code.len(), plural(f.code.len())).unwrap();
+        if self.version == LuaVersion::Lua51 {
+            write!(self.output, ", {} bytes", f.code.len() * 4).unwrap();
+        }
+        writeln!(self.output, " at {:p})", f).unwrap();
+        writeln!(self.output, "{}{} param{}, {} slot{}, {} upvalue{}, {} local{}, {} constant{}, {} function{}",
+                 f.num_params, if f.is_vararg { "+" } else { "" }, plural(f.num_params as usize),
+                 f.max_stack_size, plural(f.max_stack_size as usize),
+                 f.upvalues.len(), plural(f.upvalues.len()),
+                 f.local_vars.len(), plural(f.local_vars.len()),
+                 f.constants.len(), plural(f.constants.len()),
+                 f.protos.len(), plural(f.protos.len())).unwrap();
+    }
+
+    fn code(&mut self, f: &Proto) {
+        let mut pc = 0;
+        while pc < f.code.len() {
+            let i = f.code[pc];
+            let (a, b, c, bx, sbx) = (i.a(), i.b(), i.c(), i.bx(), i.sbx());
+            write!(self.output, "\t{}\t", pc + 1).unwrap();
+            match f.line_info.get(pc) {
+                Some(&line) if line > 0 => write!(self.output, "[{}]\t", line).unwrap(),
+                _ => self.output.push_str("[-]\t"),
+            }
+            let info = match op_info(self.version, (i.0 & 0x3f) as u8) {
+                Some(info) => info,
+                None => {
+                    writeln!(self.output, "{:<9}\t{:#010x}", "?", i.0).unwrap();
+                    pc += 1;
+                    continue;
+                }
+            };
+            write!(self.output, "{:<9}\t", info.name).unwrap();
+            match info.mode {
+                Mode::ABC => {
+                    write!(self.output, "{}", a).unwrap();
+                    if info.b != ArgMode::N {
+                        write!(self.output, " {}", rk(b)).unwrap();
+                    }
+                    if info.c != ArgMode::N {
+                        write!(self.output, " {}", rk(c)).unwrap();
+                    }
+                }
+                Mode::ABx if self.version == LuaVersion::Lua51 => {
+                    let bx = if info.b == ArgMode::K { k(bx) } else { bx as i64 };
+                    write!(self.output, "{} {}", a, bx).unwrap();
+                }
+                Mode::ABx => {
+                    write!(self.output, "{}", a).unwrap();
+                    match info.b {
+                        ArgMode::K => write!(self.output, " {}", k(bx)).unwrap(),
+                        ArgMode::U => write!(self.output, " {}", bx).unwrap(),
+                        _ => {}
+                    }
+                }
+                Mode::AsBx if self.version == LuaVersion::Lua51 && info.name == "JMP" => {
+                    write!(self.output, "{}", sbx).unwrap();
+                }
+                Mode::AsBx => write!(self.output, "{} {}", a, sbx).unwrap(),
+                Mode::Ax => write!(self.output, "{}", k(i.ax_arg())).unwrap(),
+            }
+            match info.name {
+                "LOADK" => {
+                    self.output.push_str("\t; ");
+                    self.constant(f, bx as usize);
+                }
+                "GETUPVAL" | "SETUPVAL" => write!(self.output, "\t; {}", Self::upvalue_name(f, b)).unwrap(),
+                "GETGLOBAL" | "SETGLOBAL" => {
+                    self.output.push_str("\t; ");
+                    match f.constants.get(bx as usize) {
+                        Some(Constant::String(s)) => self.output.push_str(&String::from_utf8_lossy(s)),
+                        _ => self.output.push('?'),
+                    }
+                }
+                "GETTABUP" => {
+                    write!(self.output, "\t; {}", Self::upvalue_name(f, b)).unwrap();
+                    self.keys(f, None, Some(c));
+                }
+                "SETTABUP" => {
+                    write!(self.output, "\t; {}", Self::upvalue_name(f, a)).unwrap();
+                    self.keys(f, Some(b), Some(c));
+                }
+                "GETTABLE" | "SELF" if is_k(c) => {
+                    self.output.push_str("\t; ");
+                    self.constant(f, (c & !BITRK) as usize);
+                }
+                "SETTABLE" | "ADD" | "SUB" | "MUL" | "MOD" | "POW" | "DIV" | "IDIV" | "BAND" | "BOR" |
+                "BXOR" | "SHL" | "SHR" | "EQ" | "LT" | "LE" if is_k(b) || is_k(c) => {
+                    self.output.push_str("\t; ");
+                    self.operand(f, b);
+                    self.output.push(' ');
+                    self.operand(f, c);
+                }
+                "JMP" | "FORLOOP" | "FORPREP" | "TFORLOOP" if info.mode == Mode::AsBx => {
+                    write!(self.output, "\t; to {}", sbx as i64 + pc as i64 + 2).unwrap();
+                }
+                "CLOSURE" => match f.protos.get(bx as usize) {
+                    Some(p) => write!(self.output, "\t; {:p}", p).unwrap(),
+                    None => self.output.push_str("\t; ?"),
+                },
+                "SETLIST" if c == 0 => {
+                    pc += 1;
+                    write!(self.output, "\t; {}", f.code.get(pc).map_or(0, |i| i.0)).unwrap();
+                }
+                "SETLIST" => write!(self.output, "\t; {}", c).unwrap(),
+                "EXTRAARG" => {
+                    self.output.push_str("\t; ");
+                    self.constant(f, i.ax_arg() as usize);
+                }
+                _ => {}
+            }
+            self.output.push('\n');
+            pc += 1;
+        }
+    }
+
+    // A constant operand, or - for a register
+    fn operand(&mut self, f: &Proto, x: u32) {
+        if is_k(x) {
+            self.constant(f, (x & !BITRK) as usize);
+        } else {
+            self.output.push('-');
+        }
+    }
+
+    // The constant keys of GETTABUP and SETTABUP, after a space
+    fn keys(&mut self, f: &Proto, b: Option<u32>, c: Option<u32>) {
+        for x in b.into_iter().chain(c) {
+            if is_k(x) {
+                self.output.push(' ');
+                self.constant(f, (x & !BITRK) as usize);
+            }
+        }
+    }
+
+    fn debug(&mut self, f: &Proto) {
+        writeln!(self.output, "constants ({}) for {:p}:", f.constants.len(), f).unwrap();
+        for i in 0..f.constants.len() {
+            write!(self.output, "\t{}\t", i + 1).unwrap();
+            self.constant(f, i);
+            self.output.push('\n');
+        }
+        writeln!(self.output, "locals ({}) for {:p}:", f.local_vars.len(), f).unwrap();
+        for (i, v) in f.local_vars.iter().enumerate() {
+            writeln!(self.output, "\t{}\t{}\t{}\t{}", i, v.name, v.start_pc + 1, v.end_pc + 1).unwrap();
+        }
+        if self.version == LuaVersion::Lua51 {
+            // Only the names are listed, which stripped functions do not have
+            let names = if f.upvalues.iter().all(|u| u.name.is_empty()) { 0 } else { f.upvalues.len() };
+            writeln!(self.output, "upvalues ({}) for {:p}:", names, f).unwrap();
+            for (i, u) in f.upvalues.iter().take(names).enumerate() {
+                writeln!(self.output, "\t{}\t{}", i, u.name).unwrap();
+            }
+            return;
+        }
+        writeln!(self.output, "upvalues ({}) for {:p}:", f.upvalues.len(), f).unwrap();
+        for (i, u) in f.upvalues.iter().enumerate() {
+            writeln!(self.output, "\t{}\t{}\t{}\t{}", i, Self::upvalue_name(f, i as u32),
+                     u.in_stack as u8, u.index).unwrap();
+        }
+    }
+
+    fn function(&mut self, f: &'a Proto) {
+        let parent_source = self.source;
+        self.header(f);
+        self.code(f);
+        if self.full {
+            self.debug(f);
+        }
+        for p in &f.protos {
+            self.function(p);
+        }
+        self.source = parent_source;
+    }
+}
+
+/// Lists the functions of a chunk like `luac -l` does, or like `luac -l -l`
+/// with `full`, which adds the constants, locals and upvalues
+///
+/// ```rust
+/// # use nom_lua::bytecode::{compile, listing, Chunk};
+/// # use nom_lua::{parse_block_with, ParserConfig};
+/// let block = parse_block_with(b"x = 1", &ParserConfig::default()).unwrap();
+/// let chunk = Chunk::new(compile(&block, b"x = 1", "=stdin").unwrap());
+/// let listing = listing(&chunk, false);
+/// assert!(listing.contains("\t1\t[1]\tSETTABUP \t0 -1 -2\t; _ENV \"x\" 1\n"));
+/// ```
+pub fn listing(chunk: &Chunk, full: bool) -> String {
+    let mut lister = Lister { version: chunk.header.version, full, output: String::new(), source: None };
+    lister.function(&chunk.main);
+    lister.output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytecode::{compile, undump, Header, Instruction, OpCode, Upvalue};
+    use config::ParserConfig;
+    use parse_block_with;
+
+    // Addresses differ between runs
+    fn without_addresses(listing: &str) -> String {
+        let mut output = String::new();
+        let mut rest = listing;
+        while let Some(start) = rest.find("0x") {
+            output.push_str(&rest[..start]);
+            output.push_str("0x?");
+            rest = rest[start + 2..].trim_start_matches(|c: char| c.is_ascii_hexdigit());
+        }
+        output + rest
+    }
+
+    fn list(source: &str, full: bool) -> String {
+        let block = parse_block_with(source.as_bytes(), &ParserConfig::default()).unwrap();
+        let proto = compile(&block, source.as_bytes(), "@test.lua").unwrap();
+        without_addresses(&listing(&undump(&proto.dump(false)).unwrap(), full))
+    }
+
+    #[test]
+    fn lua53() {
+        assert_eq!(list("local t = {x = 'a\\n'} t.y = 1.0 + t.x\nreturn function(...) return t, ... end", true), "
+main <test.lua:0,0> (8 instructions at 0x?)
+0+ params, 3 slots, 1 upvalue, 1 local, 4 constants, 1 function
+\t1\t[1]\tNEWTABLE \t0 0 1
+\t2\t[1]\tSETTABLE \t0 -1 -2\t; \"x\" \"a\\n\"
+\t3\t[1]\tGETTABLE \t2 0 -1\t; \"x\"
+\t4\t[1]\tADD      \t1 -4 2\t; 1.0 -
+\t5\t[1]\tSETTABLE \t0 -3 1\t; \"y\" -
+\t6\t[2]\tCLOSURE  \t1 0\t; 0x?
+\t7\t[2]\tRETURN   \t1 2
+\t8\t[2]\tRETURN   \t0 1
+constants (4) for 0x?:
+\t1\t\"x\"
+\t2\t\"a\\n\"
+\t3\t\"y\"
+\t4\t1.0
+locals (1) for 0x?:
+\t0\tt\t3\t9
+upvalues (1) for 0x?:
+\t0\t_ENV\t1\t0
+
+function <test.lua:2,2> (4 instructions at 0x?)
+0+ params, 2 slots, 1 upvalue, 0 locals, 0 constants, 0 functions
+\t1\t[2]\tGETUPVAL \t0 0\t; t
+\t2\t[2]\tVARARG   \t1 0
+\t3\t[2]\tRETURN   \t0 0
+\t4\t[2]\tRETURN   \t0 1
+constants (0) for 0x?:
+locals (0) for 0x?:
+upvalues (1) for 0x?:
+\t0\tt\t1\t0
+");
+    }
+
+    #[test]
+    fn lua51() {
+        let op = |op: u32, i: Instruction| Instruction(i.0 | op);
+        let chunk = Chunk {
+            header: Header { version: LuaVersion::Lua51, integer_size: 0, ..Header::native() },
+            main: Proto {
+                source: Some("=stdin".into()),
+                line_defined: 0,
+                last_line_defined: 0,
+                num_params: 0,
+                is_vararg: true,
+                max_stack_size: 2,
+                code: vec![
+                    op(5, Instruction::abx(OpCode::Move, 0, 0)),
+                    op(1, Instruction::abx(OpCode::Move, 1, 1)),
+                    op(4, Instruction::abc(OpCode::Move, 1, 0, 0)),
+                    op(23, Instruction::abc(OpCode::Move, 1, 1, BITRK | 1)),
+                    op(22, Instruction::asbx(OpCode::Move, 0, 1)),
+                    op(35, Instruction::abc(OpCode::Move, 0, 0, 0)),
+                    op(28, Instruction::abc(OpCode::Move, 0, 2, 1)),
+                    op(30, Instruction::abc(OpCode::Move, 0, 1, 0)),
+                    Instruction(40),
+                ],
+                constants: vec![Constant::String(b"print".to_vec()), Constant::Float(10.0)],
+                upvalues: vec![Upvalue { name: "u".into(), in_stack: false, index: 0 }],
+                protos: vec![],
+                line_info: vec![1, 1, 1, 1, 1, 2, 2, 2],
+                local_vars: vec![],
+            },
+        };
+        assert_eq!(without_addresses(&listing(&chunk, true)), "
+main <stdin:0,0> (9 instructions, 36 bytes at 0x?)
+0+ params, 2 slots, 1 upvalue, 0 locals, 2 constants, 0 functions
+\t1\t[1]\tGETGLOBAL\t0 -1\t; print
+\t2\t[1]\tLOADK    \t1 -2\t; 10
+\t3\t[1]\tGETUPVAL \t1 0\t; u
+\t4\t[1]\tEQ       \t1 1 -2\t; - 10
+\t5\t[1]\tJMP      \t1\t; to 7
+\t6\t[2]\tCLOSE    \t0
+\t7\t[2]\tCALL     \t0 2 1
+\t8\t[2]\tRETURN   \t0 1
+\t9\t[-]\t?        \t0x?
+constants (2) for 0x?:
+\t1\t\"print\"
+\t2\t10
+locals (0) for 0x?:
+upvalues (1) for 0x?:
+\t0\tu
+");
+    }
+}
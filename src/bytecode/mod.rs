@@ -0,0 +1,120 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A compiler to Lua 5.3 bytecode
+//!
+//! `compile` turns a block into a `Proto`, the function prototype of the
+//! chunk, the same way `luac` 5.3 does: locals live in registers,
+//! constants are shared in a table per function, captured locals become
+//! upvalues and every instruction keeps its line. `Proto::dump` writes it
+//! in the binary chunk format of `luac`, which the stock `lua` 5.3 runs.
+//!
+//! ```rust
+//! # use nom_lua::bytecode::compile;
+//! # use nom_lua::{parse_block_with, ParserConfig};
+//! let source = b"local t = {} for i = 1, 3 do t[i] = i * i end return #t\n";
+//! let block = parse_block_with(source, &ParserConfig::default()).unwrap();
+//! let proto = compile(&block, source, "@squares.lua").unwrap();
+//! assert!(proto.dump(false).starts_with(b"\x1bLua\x53"));
+//! ```
+//!
+//! The code is not optimized the way `luac` does it, assignments go
+//! through a temporary register and constant expressions are not folded.
+
+pub mod opcode;
+mod compile;
+mod decompile;
+mod dump;
+mod undump;
+mod listing;
+#[cfg(test)]
+mod vm;
+
+pub use self::compile::{compile, CompileError};
+pub use self::decompile::{decompile, DecompileError};
+pub use self::listing::listing;
+pub use self::opcode::{Instruction, OpCode};
+pub use self::undump::{undump, Chunk, Header, UndumpError};
+
+/// A constant of a function
+#[derive(Clone, Debug)]
+pub enum Constant {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    /// Lua strings are bytes
+    String(Vec<u8>),
+}
+
+// Floats are compared by their bits, 0.0 and -0.0 or two NaNs are not
+// interchangeable constants
+impl PartialEq for Constant {
+    fn eq(&self, other: &Constant) -> bool {
+        match (self, other) {
+            (&Constant::Nil, &Constant::Nil) => true,
+            (&Constant::Boolean(a), &Constant::Boolean(b)) => a == b,
+            (&Constant::Integer(a), &Constant::Integer(b)) => a == b,
+            (&Constant::Float(a), &Constant::Float(b)) => a.to_bits() == b.to_bits(),
+            (Constant::String(a), Constant::String(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Where a closure finds an upvalue when it is created
+#[derive(Clone, Debug, PartialEq)]
+pub struct Upvalue {
+    /// Debug information, empty when stripped
+    pub name: String,
+    /// Whether it is a register of the enclosing function, otherwise it is
+    /// one of its upvalues
+    pub in_stack: bool,
+    pub index: u8,
+}
+
+/// Debug information on a local variable
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalVar {
+    pub name: String,
+    /// The first instruction where the variable is active
+    pub start_pc: usize,
+    /// The first instruction where the variable is dead
+    pub end_pc: usize,
+}
+
+/// A compiled function
+#[derive(Clone, Debug, PartialEq)]
+pub struct Proto {
+    /// The chunk name, such as `@file.lua`. Nested functions usually leave
+    /// it out, they have the source of the function enclosing them.
+    pub source: Option<String>,
+    /// The line of `function`, 0 for the main chunk
+    pub line_defined: usize,
+    /// The line of the closing `end`, 0 for the main chunk
+    pub last_line_defined: usize,
+    pub num_params: u8,
+    pub is_vararg: bool,
+    pub max_stack_size: u8,
+    pub code: Vec<Instruction>,
+    pub constants: Vec<Constant>,
+    pub upvalues: Vec<Upvalue>,
+    pub protos: Vec<Proto>,
+    /// The line of each instruction, empty when stripped
+    pub line_info: Vec<usize>,
+    /// Empty when stripped
+    pub local_vars: Vec<LocalVar>,
+}
+
+impl Proto {
+    /// Serializes the function as a `luac` 5.3 binary chunk. `strip` leaves
+    /// out the debug information, as `luac -s` does.
+    pub fn dump(&self, strip: bool) -> Vec<u8> {
+        dump::dump(self, strip)
+    }
+}
@@ -0,0 +1,337 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Lua 5.3 instructions
+//!
+//! An instruction is 32 bits: the opcode in the low 6 bits, then `A` in 8
+//! bits and either `C` and `B` in 9 bits each, `Bx` in 18 bits or `Ax` in
+//! 26 bits. `sBx` is `Bx` biased by `MAXARG_SBX`. A `B` or `C` operand
+//! with the `BITRK` bit set is a constant index instead of a register.
+
+use std::fmt;
+
+use config::LuaVersion;
+use self::ArgMode::*;
+use self::Mode::*;
+
+pub const MAXARG_A: u32 = (1 << 8) - 1;
+pub const MAXARG_B: u32 = (1 << 9) - 1;
+pub const MAXARG_C: u32 = (1 << 9) - 1;
+pub const MAXARG_BX: u32 = (1 << 18) - 1;
+pub const MAXARG_SBX: i32 = (MAXARG_BX >> 1) as i32;
+pub const MAXARG_AX: u32 = (1 << 26) - 1;
+/// Marks a `B` or `C` operand as a constant index
+pub const BITRK: u32 = 1 << 8;
+/// The largest constant index that fits in a `B` or `C` operand
+pub const MAXINDEXRK: u32 = BITRK - 1;
+/// The number of list items stored by one `SETLIST`
+pub const LFIELDS_PER_FLUSH: u32 = 50;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OpCode {
+    Move,
+    LoadK,
+    LoadKx,
+    LoadBool,
+    LoadNil,
+    GetUpval,
+    GetTabUp,
+    GetTable,
+    SetTabUp,
+    SetUpval,
+    SetTable,
+    NewTable,
+    OpSelf,
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    Pow,
+    Div,
+    IDiv,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+    Unm,
+    BNot,
+    Not,
+    Len,
+    Concat,
+    Jmp,
+    Eq,
+    Lt,
+    Le,
+    Test,
+    TestSet,
+    Call,
+    TailCall,
+    Return,
+    ForLoop,
+    ForPrep,
+    TForCall,
+    TForLoop,
+    SetList,
+    Closure,
+    VarArg,
+    ExtraArg,
+}
+
+const OPCODES: [OpCode; 47] = [
+    OpCode::Move, OpCode::LoadK, OpCode::LoadKx, OpCode::LoadBool, OpCode::LoadNil,
+    OpCode::GetUpval, OpCode::GetTabUp, OpCode::GetTable, OpCode::SetTabUp, OpCode::SetUpval,
+    OpCode::SetTable, OpCode::NewTable, OpCode::OpSelf, OpCode::Add, OpCode::Sub, OpCode::Mul,
+    OpCode::Mod, OpCode::Pow, OpCode::Div, OpCode::IDiv, OpCode::BAnd, OpCode::BOr,
+    OpCode::BXor, OpCode::Shl, OpCode::Shr, OpCode::Unm, OpCode::BNot, OpCode::Not,
+    OpCode::Len, OpCode::Concat, OpCode::Jmp, OpCode::Eq, OpCode::Lt, OpCode::Le,
+    OpCode::Test, OpCode::TestSet, OpCode::Call, OpCode::TailCall, OpCode::Return,
+    OpCode::ForLoop, OpCode::ForPrep, OpCode::TForCall, OpCode::TForLoop, OpCode::SetList,
+    OpCode::Closure, OpCode::VarArg, OpCode::ExtraArg,
+];
+
+impl OpCode {
+    pub fn from_u8(op: u8) -> Option<OpCode> {
+        OPCODES.get(op as usize).cloned()
+    }
+
+    /// The name and operand modes of the opcode
+    pub fn info(self) -> &'static OpInfo {
+        &OPINFO_53[self as usize]
+    }
+}
+
+/// The layout of the operands of an instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    ABC,
+    ABx,
+    AsBx,
+    Ax,
+}
+
+/// How an instruction uses its `B` or `C` operand
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArgMode {
+    /// Not used
+    N,
+    /// Used as a plain number
+    U,
+    /// A register or a jump offset
+    R,
+    /// A constant index or a register with `BITRK`
+    K,
+}
+
+/// The description of an opcode in `lopcodes.c`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OpInfo {
+    /// The upper case name printed by `luac -l`
+    pub name: &'static str,
+    pub mode: Mode,
+    pub b: ArgMode,
+    pub c: ArgMode,
+}
+
+const fn op(name: &'static str, b: ArgMode, c: ArgMode, mode: Mode) -> OpInfo {
+    OpInfo { name, mode, b, c }
+}
+
+static OPINFO_51: [OpInfo; 38] = [
+    op("MOVE", R, N, ABC), op("LOADK", K, N, ABx), op("LOADBOOL", U, U, ABC),
+    op("LOADNIL", R, N, ABC), op("GETUPVAL", U, N, ABC), op("GETGLOBAL", K, N, ABx),
+    op("GETTABLE", R, K, ABC), op("SETGLOBAL", K, N, ABx), op("SETUPVAL", U, N, ABC),
+    op("SETTABLE", K, K, ABC), op("NEWTABLE", U, U, ABC), op("SELF", R, K, ABC),
+    op("ADD", K, K, ABC), op("SUB", K, K, ABC), op("MUL", K, K, ABC), op("DIV", K, K, ABC),
+    op("MOD", K, K, ABC), op("POW", K, K, ABC), op("UNM", R, N, ABC), op("NOT", R, N, ABC),
+    op("LEN", R, N, ABC), op("CONCAT", R, R, ABC), op("JMP", R, N, AsBx), op("EQ", K, K, ABC),
+    op("LT", K, K, ABC), op("LE", K, K, ABC), op("TEST", R, U, ABC), op("TESTSET", R, U, ABC),
+    op("CALL", U, U, ABC), op("TAILCALL", U, U, ABC), op("RETURN", U, N, ABC),
+    op("FORLOOP", R, N, AsBx), op("FORPREP", R, N, AsBx), op("TFORLOOP", N, U, ABC),
+    op("SETLIST", U, U, ABC), op("CLOSE", N, N, ABC), op("CLOSURE", U, N, ABx),
+    op("VARARG", U, N, ABC),
+];
+
+static OPINFO_53: [OpInfo; 47] = [
+    op("MOVE", R, N, ABC), op("LOADK", K, N, ABx), op("LOADKX", N, N, ABx),
+    op("LOADBOOL", U, U, ABC), op("LOADNIL", U, N, ABC), op("GETUPVAL", U, N, ABC),
+    op("GETTABUP", U, K, ABC), op("GETTABLE", R, K, ABC), op("SETTABUP", K, K, ABC),
+    op("SETUPVAL", U, N, ABC), op("SETTABLE", K, K, ABC), op("NEWTABLE", U, U, ABC),
+    op("SELF", R, K, ABC), op("ADD", K, K, ABC), op("SUB", K, K, ABC), op("MUL", K, K, ABC),
+    op("MOD", K, K, ABC), op("POW", K, K, ABC), op("DIV", K, K, ABC), op("IDIV", K, K, ABC),
+    op("BAND", K, K, ABC), op("BOR", K, K, ABC), op("BXOR", K, K, ABC), op("SHL", K, K, ABC),
+    op("SHR", K, K, ABC), op("UNM", R, N, ABC), op("BNOT", R, N, ABC), op("NOT", R, N, ABC),
+    op("LEN", R, N, ABC), op("CONCAT", R, R, ABC), op("JMP", R, N, AsBx), op("EQ", K, K, ABC),
+    op("LT", K, K, ABC), op("LE", K, K, ABC), op("TEST", N, U, ABC), op("TESTSET", R, U, ABC),
+    op("CALL", U, U, ABC), op("TAILCALL", U, U, ABC), op("RETURN", U, N, ABC),
+    op("FORLOOP", R, N, AsBx), op("FORPREP", R, N, AsBx), op("TFORCALL", N, U, ABC),
+    op("TFORLOOP", R, N, AsBx), op("SETLIST", U, U, ABC), op("CLOSURE", U, N, ABx),
+    op("VARARG", U, N, ABC), op("EXTRAARG", U, U, Ax),
+];
+
+/// Describes the opcode `op` of the instruction set of `version`, which is
+/// either 5.1 or 5.3
+pub fn op_info(version: LuaVersion, op: u8) -> Option<&'static OpInfo> {
+    match version {
+        LuaVersion::Lua51 => OPINFO_51.get(op as usize),
+        LuaVersion::Lua53 => OPINFO_53.get(op as usize),
+        _ => None,
+    }
+}
+
+/// An encoded instruction
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Instruction(pub u32);
+
+impl Instruction {
+    pub fn abc(op: OpCode, a: u32, b: u32, c: u32) -> Instruction {
+        debug_assert!(a <= MAXARG_A && b <= MAXARG_B && c <= MAXARG_C);
+        Instruction(op as u32 | a << 6 | c << 14 | b << 23)
+    }
+
+    pub fn abx(op: OpCode, a: u32, bx: u32) -> Instruction {
+        debug_assert!(a <= MAXARG_A && bx <= MAXARG_BX);
+        Instruction(op as u32 | a << 6 | bx << 14)
+    }
+
+    pub fn asbx(op: OpCode, a: u32, sbx: i32) -> Instruction {
+        Instruction::abx(op, a, (sbx + MAXARG_SBX) as u32)
+    }
+
+    pub fn ax(op: OpCode, ax: u32) -> Instruction {
+        debug_assert!(ax <= MAXARG_AX);
+        Instruction(op as u32 | ax << 6)
+    }
+
+    /// The opcode, `None` if it is not a Lua 5.3 one
+    pub fn opcode(self) -> Option<OpCode> {
+        OpCode::from_u8((self.0 & 0x3f) as u8)
+    }
+
+    pub fn a(self) -> u32 {
+        self.0 >> 6 & MAXARG_A
+    }
+
+    pub fn b(self) -> u32 {
+        self.0 >> 23 & MAXARG_B
+    }
+
+    pub fn c(self) -> u32 {
+        self.0 >> 14 & MAXARG_C
+    }
+
+    pub fn bx(self) -> u32 {
+        self.0 >> 14
+    }
+
+    pub fn sbx(self) -> i32 {
+        self.bx() as i32 - MAXARG_SBX
+    }
+
+    pub fn ax_arg(self) -> u32 {
+        self.0 >> 6
+    }
+
+    pub(crate) fn set_a(&mut self, a: u32) {
+        self.0 = self.0 & !(MAXARG_A << 6) | a << 6;
+    }
+
+    pub(crate) fn set_b(&mut self, b: u32) {
+        self.0 = self.0 & !(MAXARG_B << 23) | b << 23;
+    }
+
+    pub(crate) fn set_c(&mut self, c: u32) {
+        self.0 = self.0 & !(MAXARG_C << 14) | c << 14;
+    }
+
+    pub(crate) fn set_sbx(&mut self, sbx: i32) {
+        self.0 = self.0 & 0x3fff | ((sbx + MAXARG_SBX) as u32) << 14;
+    }
+}
+
+impl fmt::Debug for Instruction {
+    fn fmt(&self, format: &mut fmt::Formatter) -> fmt::Result {
+        match self.opcode() {
+            Some(op) => write!(format, "{:?}({}, {}, {})", op, self.a(), self.b(), self.c()),
+            None => write!(format, "Instruction({:#010x})", self.0),
+        }
+    }
+}
+
+/// Whether the operand `x` is a constant index
+pub fn is_k(x: u32) -> bool {
+    x & BITRK != 0
+}
+
+/// Encodes a size hint of `NEWTABLE` as a "floating point byte",
+/// `eeeeexxx` meaning `(1xxx) * 2^(eeeee - 1)` when `eeeee` is not 0
+pub fn int_to_fb(mut x: u32) -> u32 {
+    let mut e = 0;
+    if x < 8 {
+        return x;
+    }
+    while x >= 8 << 4 {
+        x = (x + 0xf) >> 4;
+        e += 4;
+    }
+    while x >= 8 << 1 {
+        x = (x + 1) >> 1;
+        e += 1;
+    }
+    ((e + 1) << 3) | (x - 8)
+}
+
+/// Decodes a "floating point byte"
+pub fn fb_to_int(x: u32) -> u32 {
+    if x < 8 {
+        x
+    } else {
+        ((x & 7) + 8) << ((x >> 3) - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operands_round_trip() {
+        let i = Instruction::abc(OpCode::Call, 3, 0, 511);
+        assert_eq!((i.opcode(), i.a(), i.b(), i.c()), (Some(OpCode::Call), 3, 0, 511));
+        let j = Instruction::asbx(OpCode::Jmp, 1, -5);
+        assert_eq!((j.opcode(), j.a(), j.sbx()), (Some(OpCode::Jmp), 1, -5));
+        let mut k = Instruction::abx(OpCode::LoadK, 255, MAXARG_BX);
+        assert_eq!(k.bx(), MAXARG_BX);
+        k.set_a(7);
+        assert_eq!((k.a(), k.bx()), (7, MAXARG_BX));
+        assert_eq!(Instruction::ax(OpCode::ExtraArg, MAXARG_AX).ax_arg(), MAXARG_AX);
+        // luac encodes "RETURN 0 1" as 0x00800026
+        assert_eq!(Instruction::abc(OpCode::Return, 0, 1, 0).0, 0x0080_0026);
+    }
+
+    #[test]
+    fn floating_point_bytes() {
+        for &x in &[0, 7, 8, 15, 16, 100, 1000, 65536] {
+            assert!(fb_to_int(int_to_fb(x)) >= x, "{}", x);
+        }
+        assert_eq!(int_to_fb(50), 0x1d);
+        assert_eq!(fb_to_int(0x1d), 52);
+    }
+
+    #[test]
+    fn opcode_names() {
+        for (i, &op) in OPCODES.iter().enumerate() {
+            assert_eq!(op_info(LuaVersion::Lua53, i as u8), Some(op.info()));
+        }
+        assert_eq!(OpCode::OpSelf.info().name, "SELF");
+        assert_eq!(OpCode::ExtraArg.info().mode, Mode::Ax);
+        assert_eq!(op_info(LuaVersion::Lua51, 5).unwrap().name, "GETGLOBAL");
+        assert_eq!(op_info(LuaVersion::Lua51, 38), None);
+        assert_eq!(op_info(LuaVersion::Lua52, 0), None);
+    }
+}
@@ -0,0 +1,446 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Loads binary chunks of `luac` 5.1 and 5.3, as `lundump.c` does
+//!
+//! Unlike the loader of Lua, it reads chunks of any byte order and with
+//! 4 or 8 byte numbers, the header tells which ones these are.
+
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use config::LuaVersion;
+use super::dump::*;
+use super::{Constant, Instruction, LocalVar, Proto, Upvalue};
+
+/// The machine a chunk was dumped on, from the header of the chunk
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    /// `Lua51` or `Lua53`
+    pub version: LuaVersion,
+    pub format: u8,
+    pub little_endian: bool,
+    pub int_size: u8,
+    pub size_t_size: u8,
+    pub instruction_size: u8,
+    /// The size of `lua_Integer`, 0 in 5.1 chunks
+    pub integer_size: u8,
+    pub number_size: u8,
+    /// Whether `lua_Number` is an integer type, only possible in 5.1 chunks
+    pub integral: bool,
+}
+
+impl Header {
+    /// The header of the chunks written by `Proto::dump` on this machine
+    pub fn native() -> Header {
+        Header {
+            version: LuaVersion::Lua53,
+            format: FORMAT,
+            little_endian: cfg!(target_endian = "little"),
+            int_size: 4,
+            size_t_size: 8,
+            instruction_size: 4,
+            integer_size: 8,
+            number_size: 8,
+            integral: false,
+        }
+    }
+}
+
+/// A loaded binary chunk
+///
+/// 5.1 functions keep their instructions as they are, `opcode::op_info`
+/// decodes them. Their upvalues only have names, `in_stack` is false and
+/// `index` is the position of the upvalue.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chunk {
+    pub header: Header,
+    pub main: Proto,
+}
+
+impl Chunk {
+    /// A 5.3 chunk of this machine
+    pub fn new(main: Proto) -> Chunk {
+        Chunk { header: Header::native(), main }
+    }
+}
+
+/// Why a binary chunk could not be loaded, with the messages of `lundump.c`
+#[derive(Clone, Debug, PartialEq)]
+pub struct UndumpError {
+    /// Where in the chunk the error was found
+    pub offset: usize,
+    pub message: String,
+}
+
+impl Display for UndumpError {
+    fn fmt(&self, format: &mut Formatter) -> fmt::Result {
+        write!(format, "byte {}: {}", self.offset, self.message)
+    }
+}
+
+impl error::Error for UndumpError {}
+
+type Result<T> = ::std::result::Result<T, UndumpError>;
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    header: Header,
+}
+
+impl<'a> Reader<'a> {
+    fn error<T, S: Into<String>>(&self, message: S) -> Result<T> {
+        Err(UndumpError { offset: self.pos, message: message.into() })
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.data.len() - self.pos < n {
+            return self.error("truncated precompiled chunk");
+        }
+        self.pos += n;
+        Ok(&self.data[self.pos - n..self.pos])
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    // An unsigned number of `size` bytes in the byte order of the chunk
+    fn unsigned(&mut self, size: u8) -> Result<u64> {
+        let little_endian = self.header.little_endian;
+        let bytes = self.bytes(size as usize)?;
+        let fold = |n: u64, &b: &u8| n << 8 | b as u64;
+        Ok(if little_endian { bytes.iter().rev().fold(0, fold) } else { bytes.iter().fold(0, fold) })
+    }
+
+    fn signed(&mut self, size: u8) -> Result<i64> {
+        let n = self.unsigned(size)?;
+        let shift = 64 - 8 * size as u32;
+        Ok((n << shift) as i64 >> shift)
+    }
+
+    // A count or a line, which are C ints
+    fn int(&mut self) -> Result<usize> {
+        let size = self.header.int_size;
+        let n = self.signed(size)?;
+        if n < 0 {
+            return self.error("corrupted precompiled chunk");
+        }
+        Ok(n as usize)
+    }
+
+    fn size_t(&mut self) -> Result<usize> {
+        let size = self.header.size_t_size;
+        Ok(self.unsigned(size)? as usize)
+    }
+
+    fn integer(&mut self) -> Result<i64> {
+        let size = self.header.integer_size;
+        self.signed(size)
+    }
+
+    fn float(&mut self) -> Result<f64> {
+        Ok(match self.header.number_size {
+            4 => f32::from_bits(self.unsigned(4)? as u32) as f64,
+            _ => f64::from_bits(self.unsigned(8)?),
+        })
+    }
+
+    fn number(&mut self) -> Result<Constant> {
+        if self.header.integral {
+            let size = self.header.number_size;
+            Ok(Constant::Integer(self.signed(size)?))
+        } else {
+            Ok(Constant::Float(self.float()?))
+        }
+    }
+
+    // 5.1 strings have a size_t size which counts the NUL they end with,
+    // 5.3 ones a byte size which counts a NUL which is not there
+    fn string(&mut self) -> Result<Option<Vec<u8>>> {
+        let size = if self.header.version == LuaVersion::Lua51 {
+            self.size_t()?
+        } else {
+            match self.byte()? {
+                0xff => self.size_t()?,
+                n => n as usize,
+            }
+        };
+        if size == 0 {
+            return Ok(None);
+        }
+        let s = self.bytes(if self.header.version == LuaVersion::Lua51 { size } else { size - 1 })?;
+        Ok(Some(if self.header.version == LuaVersion::Lua51 { &s[..size - 1] } else { s }.to_vec()))
+    }
+
+    fn name(&mut self) -> Result<String> {
+        Ok(self.string()?.map(|s| String::from_utf8_lossy(&s).into_owned()).unwrap_or_default())
+    }
+
+    fn source(&mut self) -> Result<Option<String>> {
+        Ok(self.string()?.map(|s| String::from_utf8_lossy(&s).into_owned()))
+    }
+
+    fn check_size(&mut self, what: &str, valid: &[u8]) -> Result<u8> {
+        let size = self.byte()?;
+        if !valid.contains(&size) {
+            return self.error(format!("{} size mismatch in precompiled chunk", what));
+        }
+        Ok(size)
+    }
+
+    fn header(&mut self) -> Result<()> {
+        if self.bytes(SIGNATURE.len()).ok() != Some(SIGNATURE) {
+            self.pos = 0;
+            return self.error("bad binary format (not a precompiled chunk)");
+        }
+        self.header.version = match self.byte()? {
+            0x51 => LuaVersion::Lua51,
+            VERSION => LuaVersion::Lua53,
+            _ => return self.error("version mismatch in precompiled chunk"),
+        };
+        self.header.format = self.byte()?;
+        if self.header.format != FORMAT {
+            return self.error("format mismatch in precompiled chunk");
+        }
+        if self.header.version == LuaVersion::Lua51 {
+            self.header.little_endian = self.byte()? == 1;
+            self.header.int_size = self.check_size("int", &[4, 8])?;
+            self.header.size_t_size = self.check_size("size_t", &[4, 8])?;
+            self.header.instruction_size = self.check_size("Instruction", &[4])?;
+            self.header.number_size = self.check_size("lua_Number", &[4, 8])?;
+            self.header.integer_size = 0;
+            self.header.integral = self.byte()? != 0;
+            return Ok(());
+        }
+        if self.bytes(DATA.len())? != DATA {
+            return self.error("corrupted precompiled chunk");
+        }
+        self.header.int_size = self.check_size("int", &[4, 8])?;
+        self.header.size_t_size = self.check_size("size_t", &[4, 8])?;
+        self.header.instruction_size = self.check_size("Instruction", &[4])?;
+        self.header.integer_size = self.check_size("lua_Integer", &[4, 8])?;
+        self.header.number_size = self.check_size("lua_Number", &[4, 8])?;
+        // The byte order is the one which reads the test integer right
+        let start = self.pos;
+        self.header.little_endian = true;
+        if self.integer()? != INT_CHECK {
+            self.pos = start;
+            self.header.little_endian = false;
+            if self.integer()? != INT_CHECK {
+                return self.error("endianness mismatch in precompiled chunk");
+            }
+        }
+        if self.float()? != FLOAT_CHECK {
+            return self.error("float format mismatch in precompiled chunk");
+        }
+        // The number of upvalues of the main function, repeated in it
+        self.byte()?;
+        Ok(())
+    }
+
+    fn code(&mut self) -> Result<Vec<Instruction>> {
+        let n = self.int()?;
+        (0..n).map(|_| Ok(Instruction(self.unsigned(4)? as u32))).collect()
+    }
+
+    fn constant(&mut self) -> Result<Constant> {
+        let tag = self.byte()?;
+        Ok(match tag {
+            TAG_NIL => Constant::Nil,
+            TAG_BOOLEAN => Constant::Boolean(self.byte()? != 0),
+            TAG_FLOAT if self.header.version == LuaVersion::Lua51 => self.number()?,
+            TAG_FLOAT => Constant::Float(self.float()?),
+            TAG_INTEGER if self.header.version == LuaVersion::Lua53 => Constant::Integer(self.integer()?),
+            TAG_SHORT_STRING | TAG_LONG_STRING => match self.string()? {
+                Some(s) => Constant::String(s),
+                None => return self.error("corrupted precompiled chunk"),
+            },
+            _ => {
+                self.pos -= 1;
+                return self.error(format!("bad constant type {}", tag));
+            }
+        })
+    }
+
+    fn debug(&mut self, f: &mut Proto) -> Result<()> {
+        let n = self.int()?;
+        f.line_info = (0..n).map(|_| self.int()).collect::<Result<_>>()?;
+        let n = self.int()?;
+        for _ in 0..n {
+            let name = self.name()?;
+            let start_pc = self.int()?;
+            let end_pc = self.int()?;
+            f.local_vars.push(LocalVar { name, start_pc, end_pc });
+        }
+        let n = self.int()?;
+        for i in 0..n {
+            let name = self.name()?;
+            match f.upvalues.get_mut(i) {
+                Some(upvalue) => upvalue.name = name,
+                None => return self.error("corrupted precompiled chunk"),
+            }
+        }
+        Ok(())
+    }
+
+    fn function51(&mut self) -> Result<Proto> {
+        let source = self.source()?;
+        let line_defined = self.int()?;
+        let last_line_defined = self.int()?;
+        let upvalues = (0..self.byte()?)
+            .map(|index| Upvalue { name: String::new(), in_stack: false, index })
+            .collect();
+        let mut f = Proto {
+            source,
+            line_defined,
+            last_line_defined,
+            num_params: self.byte()?,
+            // Besides VARARG_ISVARARG, the flags of the compatibility `arg`
+            is_vararg: self.byte()? != 0,
+            max_stack_size: self.byte()?,
+            code: self.code()?,
+            constants: vec![],
+            upvalues,
+            protos: vec![],
+            line_info: vec![],
+            local_vars: vec![],
+        };
+        let n = self.int()?;
+        f.constants = (0..n).map(|_| self.constant()).collect::<Result<_>>()?;
+        let n = self.int()?;
+        f.protos = (0..n).map(|_| self.function51()).collect::<Result<_>>()?;
+        self.debug(&mut f)?;
+        Ok(f)
+    }
+
+    fn function53(&mut self) -> Result<Proto> {
+        let mut f = Proto {
+            source: self.source()?,
+            line_defined: self.int()?,
+            last_line_defined: self.int()?,
+            num_params: self.byte()?,
+            is_vararg: self.byte()? != 0,
+            max_stack_size: self.byte()?,
+            code: self.code()?,
+            constants: vec![],
+            upvalues: vec![],
+            protos: vec![],
+            line_info: vec![],
+            local_vars: vec![],
+        };
+        let n = self.int()?;
+        f.constants = (0..n).map(|_| self.constant()).collect::<Result<_>>()?;
+        let n = self.int()?;
+        for _ in 0..n {
+            let in_stack = self.byte()? != 0;
+            let index = self.byte()?;
+            f.upvalues.push(Upvalue { name: String::new(), in_stack, index });
+        }
+        let n = self.int()?;
+        f.protos = (0..n).map(|_| self.function53()).collect::<Result<_>>()?;
+        self.debug(&mut f)?;
+        Ok(f)
+    }
+}
+
+/// Loads a binary chunk written by `luac` 5.1 or 5.3, or by `Proto::dump`
+pub fn undump(data: &[u8]) -> Result<Chunk> {
+    let mut reader = Reader { data, pos: 0, header: Header::native() };
+    reader.header()?;
+    let main = if reader.header.version == LuaVersion::Lua51 {
+        reader.function51()?
+    } else {
+        reader.function53()?
+    };
+    Ok(Chunk { header: reader.header, main })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytecode::compile;
+    use parse_block_with;
+    use config::ParserConfig;
+
+    fn compiled(source: &str) -> Proto {
+        let block = parse_block_with(source.as_bytes(), &ParserConfig::default()).unwrap();
+        compile(&block, source.as_bytes(), "@test.lua").unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let proto = compiled("local s = 'x' local t = {1, 2.5, true, nil, s .. ('y'):rep(50)}\n\
+                              return function(...) return t, s, ... end");
+        let chunk = undump(&proto.dump(false)).unwrap();
+        assert_eq!(chunk.header, Header::native());
+        // Nested functions have the source of the chunk
+        let mut expected = proto.clone();
+        expected.protos[0].source = None;
+        assert_eq!(chunk.main, expected);
+        let stripped = undump(&proto.dump(true)).unwrap().main;
+        assert_eq!(stripped.source, None);
+        assert!(stripped.line_info.is_empty() && stripped.local_vars.is_empty());
+        assert_eq!(stripped.upvalues[0], Upvalue { name: String::new(), in_stack: true, index: 0 });
+        assert_eq!(stripped.code, proto.code);
+    }
+
+    #[test]
+    fn big_endian() {
+        let mut chunk = b"\x1bLua\x53\x00\x19\x93\r\n\x1a\n\x04\x04\x04\x08\x08".to_vec();
+        chunk.extend_from_slice(&0x5678i64.to_be_bytes());
+        chunk.extend_from_slice(&370.5f64.to_bits().to_be_bytes());
+        chunk.extend_from_slice(b"\x01\x07=stdin\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x02");
+        chunk.extend_from_slice(b"\x00\x00\x00\x01\x00\x80\x00\x26");
+        chunk.extend_from_slice(b"\x00\x00\x00\x01\x13\x00\x00\x00\x00\x00\x00\x00\x2a");
+        chunk.extend_from_slice(b"\x00\x00\x00\x01\x01\x00\x00\x00\x00\x00");
+        chunk.extend_from_slice(b"\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00");
+        let chunk = undump(&chunk).unwrap();
+        assert!(!chunk.header.little_endian);
+        assert_eq!(chunk.header.size_t_size, 4);
+        assert_eq!(chunk.main.source, Some("=stdin".into()));
+        assert_eq!(chunk.main.code, vec![Instruction(0x0080_0026)]);
+        assert_eq!(chunk.main.constants, vec![Constant::Integer(42)]);
+        assert_eq!(chunk.main.upvalues.len(), 1);
+    }
+
+    #[test]
+    fn lua51() {
+        // luac 5.1 on `local x = "hi" return x`
+        let mut chunk = b"\x1bLua\x51\x00\x01\x04\x08\x04\x08\x00".to_vec();
+        chunk.extend_from_slice(b"\x0a\x00\x00\x00\x00\x00\x00\x00@test.lua\x00");
+        chunk.extend_from_slice(b"\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x02");
+        chunk.extend_from_slice(b"\x03\x00\x00\x00\x01\x00\x00\x00\x1e\x00\x00\x01\x1e\x00\x80\x00");
+        chunk.extend_from_slice(b"\x01\x00\x00\x00\x04\x03\x00\x00\x00\x00\x00\x00\x00hi\x00");
+        chunk.extend_from_slice(b"\x00\x00\x00\x00");
+        chunk.extend_from_slice(b"\x03\x00\x00\x00\x01\x00\x00\x00\x01\x00\x00\x00\x01\x00\x00\x00");
+        chunk.extend_from_slice(b"\x01\x00\x00\x00\x02\x00\x00\x00\x00\x00\x00\x00x\x00\x01\x00\x00\x00\x03\x00\x00\x00");
+        chunk.extend_from_slice(b"\x00\x00\x00\x00");
+        let chunk = undump(&chunk).unwrap();
+        assert_eq!(chunk.header.version, LuaVersion::Lua51);
+        assert_eq!(chunk.main.source, Some("@test.lua".into()));
+        assert!(chunk.main.is_vararg);
+        assert_eq!(chunk.main.constants, vec![Constant::String(b"hi".to_vec())]);
+        assert_eq!(chunk.main.local_vars, vec![LocalVar { name: "x".into(), start_pc: 1, end_pc: 3 }]);
+        assert_eq!(chunk.main.line_info, vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn errors() {
+        let message = |data: &[u8]| undump(data).unwrap_err().to_string();
+        assert_eq!(message(b"return 1"), "byte 0: bad binary format (not a precompiled chunk)");
+        assert_eq!(message(b"\x1bLua\x52\x00"), "byte 5: version mismatch in precompiled chunk");
+        let dumped = compiled("return 1").dump(false);
+        assert!(message(&dumped[..dumped.len() - 3]).ends_with(": truncated precompiled chunk"));
+        let mut corrupted = dumped.clone();
+        corrupted[17] ^= 1;
+        assert_eq!(message(&corrupted), "byte 25: endianness mismatch in precompiled chunk");
+    }
+}
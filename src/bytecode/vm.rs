@@ -0,0 +1,503 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Runs compiled code with the values of `eval`, so that the compiler is
+//! tested against the interpreter without a `lua` binary around
+//!
+//! Registers are shared cells, a closure capturing a register holds its
+//! cell until closing the upvalue gives the register a new one.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use eval::{arith, Interpreter, RuntimeError, Value};
+use op::BinOp;
+use super::opcode::*;
+use super::{Constant, Proto};
+
+type Cell<'a> = Rc<RefCell<Value<'a>>>;
+type Result<'a, T> = ::std::result::Result<T, RuntimeError<'a>>;
+
+fn cell<'a>(value: Value<'a>) -> Cell<'a> {
+    Rc::new(RefCell::new(value))
+}
+
+fn error<'a, T>(message: String) -> Result<'a, T> {
+    Err(RuntimeError(message.into()))
+}
+
+fn constant<'a>(k: &Constant) -> Value<'a> {
+    match *k {
+        Constant::Nil => Value::Nil,
+        Constant::Boolean(b) => Value::Boolean(b),
+        Constant::Integer(i) => Value::Integer(i),
+        Constant::Float(f) => Value::Float(f),
+        Constant::String(ref s) => String::from_utf8_lossy(s).into_owned().into(),
+    }
+}
+
+fn index<'a>(table: Value<'a>, key: Value<'a>) -> Result<'a, Value<'a>> {
+    match table {
+        Value::Table(ref t) => Ok(t.borrow().get(&key)),
+        _ => error(format!("attempt to index a {} value", table.type_name())),
+    }
+}
+
+fn set_index<'a>(table: Value<'a>, key: Value<'a>, value: Value<'a>) -> Result<'a, ()> {
+    match table {
+        Value::Table(ref t) => t.borrow_mut().set(key, value).or_else(|e| error(e.into())),
+        _ => error(format!("attempt to index a {} value", table.type_name())),
+    }
+}
+
+/// Loads a compiled chunk with the globals of `lua` as its `_ENV`
+pub fn load<'a>(lua: &Interpreter<'a>, proto: Proto) -> Value<'a> {
+    closure(Rc::new(proto), vec![cell(Value::Table(lua.globals().clone()))])
+}
+
+fn closure<'a>(proto: Rc<Proto>, upvalues: Vec<Cell<'a>>) -> Value<'a> {
+    Value::native(move |lua, args| execute(lua, &proto, &upvalues, args))
+}
+
+struct Frame<'a> {
+    registers: Vec<Cell<'a>>,
+    /// The end of the values of an open call or `...`
+    top: usize,
+}
+
+impl<'a> Frame<'a> {
+    fn get(&self, r: usize) -> Value<'a> {
+        self.registers.get(r).map_or(Value::Nil, |c| c.borrow().clone())
+    }
+
+    // Open results can go past the stack size
+    fn set(&mut self, r: usize, value: Value<'a>) {
+        while self.registers.len() <= r {
+            self.registers.push(cell(Value::Nil));
+        }
+        *self.registers[r].borrow_mut() = value;
+    }
+
+    fn set_all(&mut self, first: usize, values: Vec<Value<'a>>) {
+        self.top = first + values.len();
+        for (i, v) in values.into_iter().enumerate() {
+            self.set(first + i, v);
+        }
+    }
+
+    fn set_adjusted(&mut self, first: usize, values: Vec<Value<'a>>, n: usize) {
+        let mut values = values.into_iter();
+        for i in 0..n {
+            self.set(first + i, values.next().unwrap_or(Value::Nil));
+        }
+    }
+
+    /// The values from `first`, `count` of them or up to the top
+    fn values(&self, first: usize, count: Option<usize>) -> Vec<Value<'a>> {
+        let end = count.map_or(self.top, |n| first + n);
+        (first..end).map(|r| self.get(r)).collect()
+    }
+
+    fn rk(&self, proto: &Proto, x: u32) -> Value<'a> {
+        if is_k(x) {
+            constant(&proto.constants[(x & !BITRK) as usize])
+        } else {
+            self.get(x as usize)
+        }
+    }
+
+    fn close(&mut self, level: usize) {
+        for r in level..self.registers.len() {
+            let value = self.get(r);
+            self.registers[r] = cell(value);
+        }
+    }
+}
+
+fn arith_op(op: OpCode) -> BinOp {
+    match op {
+        OpCode::Add => BinOp::Add,
+        OpCode::Sub => BinOp::Sub,
+        OpCode::Mul => BinOp::Mul,
+        OpCode::Mod => BinOp::Mod,
+        OpCode::Pow => BinOp::Exp,
+        OpCode::Div => BinOp::Div,
+        OpCode::IDiv => BinOp::FDiv,
+        OpCode::BAnd => BinOp::BitAnd,
+        OpCode::BOr => BinOp::BitOr,
+        OpCode::BXor => BinOp::BitXor,
+        OpCode::Shl => BinOp::Lsh,
+        OpCode::Shr => BinOp::Rsh,
+        OpCode::Eq => BinOp::Eq,
+        OpCode::Lt => BinOp::Lt,
+        _ => BinOp::Le,
+    }
+}
+
+/// Converts a float limit of an integer loop, also telling whether the
+/// loop does not run at all
+fn for_limit(limit: &Value, step: i64) -> Option<(i64, bool)> {
+    let f = match arith::to_number(limit)? {
+        Value::Integer(i) => return Some((i, false)),
+        Value::Float(f) => if step > 0 { f.floor() } else { f.ceil() },
+        _ => unreachable!(),
+    };
+    if f.is_nan() {
+        return None;
+    }
+    if f >= 9223372036854775808.0 {
+        Some((i64::MAX, step < 0))
+    } else if f < -9223372036854775808.0 {
+        Some((i64::MIN, step > 0))
+    } else {
+        Some((f as i64, false))
+    }
+}
+
+fn for_prep<'a>(frame: &mut Frame<'a>, a: usize) -> Result<'a, ()> {
+    let number = |v: Value<'a>, what: &str| match arith::to_number(&v) {
+        Some(n) => Ok(n),
+        None => error(format!("'for' {} must be a number", what)),
+    };
+    let init = number(frame.get(a), "initial value")?;
+    let limit = number(frame.get(a + 1), "limit")?;
+    let step = number(frame.get(a + 2), "step")?;
+    if let (&Value::Integer(i), &Value::Integer(s)) = (&init, &step) {
+        if let Some((limit, stop)) = for_limit(&limit, s) {
+            frame.set(a + 1, Value::Integer(limit));
+            frame.set(a, Value::Integer(if stop { limit } else { i }.wrapping_sub(s)));
+            if stop {
+                // Makes the first check fail
+                frame.set(a, Value::Integer(if s > 0 { i64::MAX } else { i64::MIN }));
+            }
+            return Ok(());
+        }
+    }
+    let float = |v: &Value| match *v {
+        Value::Integer(i) => i as f64,
+        Value::Float(f) => f,
+        _ => unreachable!(),
+    };
+    frame.set(a + 1, Value::Float(float(&limit)));
+    frame.set(a + 2, Value::Float(float(&step)));
+    frame.set(a, Value::Float(float(&init) - float(&step)));
+    Ok(())
+}
+
+fn for_loop(frame: &mut Frame, a: usize) -> bool {
+    let (next, more) = match (frame.get(a), frame.get(a + 1), frame.get(a + 2)) {
+        (Value::Integer(i), Value::Integer(limit), Value::Integer(step)) => {
+            // The index overflows only when it passed the limit
+            match i.checked_add(step) {
+                Some(next) => (Value::Integer(next), if step > 0 { next <= limit } else { limit <= next }),
+                None => (Value::Nil, false),
+            }
+        }
+        (Value::Float(i), Value::Float(limit), Value::Float(step)) => {
+            let next = i + step;
+            (Value::Float(next), if step > 0.0 { next <= limit } else { limit <= next })
+        }
+        _ => unreachable!(),
+    };
+    if more {
+        frame.set(a, next.clone());
+        frame.set(a + 3, next);
+    }
+    more
+}
+
+fn execute<'a>(lua: &mut Interpreter<'a>, proto: &Proto, upvalues: &[Cell<'a>], mut args: Vec<Value<'a>>)
+    -> Result<'a, Vec<Value<'a>>> {
+    let params = proto.num_params as usize;
+    let varargs = if args.len() > params { args.split_off(params) } else { vec![] };
+    let mut frame = Frame {
+        registers: (0..proto.max_stack_size).map(|_| cell(Value::Nil)).collect(),
+        top: 0,
+    };
+    for (i, arg) in args.into_iter().enumerate() {
+        frame.set(i, arg);
+    }
+    let mut pc = 0;
+    loop {
+        let i = proto.code[pc];
+        pc += 1;
+        let (a, b, c) = (i.a() as usize, i.b(), i.c());
+        let op = i.opcode().unwrap();
+        match op {
+            OpCode::Move => {
+                let v = frame.get(b as usize);
+                frame.set(a, v);
+            }
+            OpCode::LoadK => frame.set(a, constant(&proto.constants[i.bx() as usize])),
+            OpCode::LoadKx => {
+                let k = proto.code[pc].ax_arg() as usize;
+                pc += 1;
+                frame.set(a, constant(&proto.constants[k]));
+            }
+            OpCode::LoadBool => {
+                frame.set(a, Value::Boolean(b != 0));
+                if c != 0 {
+                    pc += 1;
+                }
+            }
+            OpCode::LoadNil => for r in a..=a + b as usize {
+                frame.set(r, Value::Nil);
+            },
+            OpCode::GetUpval => {
+                let v = upvalues[b as usize].borrow().clone();
+                frame.set(a, v);
+            }
+            OpCode::GetTabUp => {
+                let table = upvalues[b as usize].borrow().clone();
+                let v = index(table, frame.rk(proto, c))?;
+                frame.set(a, v);
+            }
+            OpCode::GetTable => {
+                let v = index(frame.get(b as usize), frame.rk(proto, c))?;
+                frame.set(a, v);
+            }
+            OpCode::SetTabUp => {
+                let table = upvalues[a].borrow().clone();
+                set_index(table, frame.rk(proto, b), frame.rk(proto, c))?;
+            }
+            OpCode::SetUpval => *upvalues[b as usize].borrow_mut() = frame.get(a),
+            OpCode::SetTable => set_index(frame.get(a), frame.rk(proto, b), frame.rk(proto, c))?,
+            OpCode::NewTable => frame.set(a, Value::table()),
+            OpCode::OpSelf => {
+                let object = frame.get(b as usize);
+                frame.set(a + 1, object.clone());
+                let method = index(object, frame.rk(proto, c))?;
+                frame.set(a, method);
+            }
+            OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Mod | OpCode::Pow | OpCode::Div |
+            OpCode::IDiv | OpCode::BAnd | OpCode::BOr | OpCode::BXor | OpCode::Shl | OpCode::Shr => {
+                let v = arith::arith(arith_op(op), &frame.rk(proto, b), &frame.rk(proto, c)).or_else(error)?;
+                frame.set(a, v);
+            }
+            OpCode::Unm | OpCode::BNot | OpCode::Len => {
+                let operand = frame.get(b as usize);
+                let v = match op {
+                    OpCode::Unm => arith::negate(&operand),
+                    OpCode::BNot => arith::bit_not(&operand),
+                    _ => arith::len(&operand),
+                }.or_else(error)?;
+                frame.set(a, v);
+            }
+            OpCode::Not => {
+                let v = !frame.get(b as usize).is_truthy();
+                frame.set(a, Value::Boolean(v));
+            }
+            OpCode::Concat => {
+                let mut v = frame.get(c as usize);
+                for r in (b..c).rev() {
+                    v = arith::arith(BinOp::Concat, &frame.get(r as usize), &v).or_else(error)?;
+                }
+                frame.set(a, v);
+            }
+            OpCode::Jmp => {
+                if a > 0 {
+                    frame.close(a - 1);
+                }
+                pc = (pc as i64 + i.sbx() as i64) as usize;
+            }
+            OpCode::Eq | OpCode::Lt | OpCode::Le => {
+                let v = arith::relational(arith_op(op), &frame.rk(proto, b), &frame.rk(proto, c)).or_else(error)?;
+                if v.is_truthy() != (a != 0) {
+                    pc += 1;
+                }
+            }
+            OpCode::Test => if frame.get(a).is_truthy() != (c != 0) {
+                pc += 1;
+            },
+            OpCode::TestSet => {
+                let v = frame.get(b as usize);
+                if v.is_truthy() == (c != 0) {
+                    frame.set(a, v);
+                } else {
+                    pc += 1;
+                }
+            }
+            OpCode::Call | OpCode::TailCall => {
+                let args = frame.values(a + 1, if b == 0 { None } else { Some(b as usize - 1) });
+                let results = lua.call(&frame.get(a), args)?;
+                if op == OpCode::TailCall {
+                    return Ok(results);
+                }
+                if c == 0 {
+                    frame.set_all(a, results);
+                } else {
+                    frame.set_adjusted(a, results, c as usize - 1);
+                }
+            }
+            OpCode::Return => {
+                return Ok(frame.values(a, if b == 0 { None } else { Some(b as usize - 1) }));
+            }
+            OpCode::ForLoop => if for_loop(&mut frame, a) {
+                pc = (pc as i64 + i.sbx() as i64) as usize;
+            },
+            OpCode::ForPrep => {
+                for_prep(&mut frame, a)?;
+                pc = (pc as i64 + i.sbx() as i64) as usize;
+            }
+            OpCode::TForCall => {
+                let args = vec![frame.get(a + 1), frame.get(a + 2)];
+                let results = lua.call(&frame.get(a), args)?;
+                frame.set_adjusted(a + 3, results, c as usize);
+            }
+            OpCode::TForLoop => {
+                let control = frame.get(a + 1);
+                if !control.is_nil() {
+                    frame.set(a, control);
+                    pc = (pc as i64 + i.sbx() as i64) as usize;
+                }
+            }
+            OpCode::SetList => {
+                let n = if b == 0 { frame.top - a - 1 } else { b as usize };
+                let block = if c == 0 {
+                    pc += 1;
+                    proto.code[pc - 1].ax_arg()
+                } else {
+                    c
+                };
+                let first = (block as usize - 1) * LFIELDS_PER_FLUSH as usize;
+                let table = frame.get(a);
+                for j in 1..=n {
+                    set_index(table.clone(), Value::Integer((first + j) as i64), frame.get(a + j))?;
+                }
+            }
+            OpCode::Closure => {
+                let p = &proto.protos[i.bx() as usize];
+                let captured = p.upvalues.iter().map(|u| {
+                    if u.in_stack {
+                        frame.set(u.index as usize, frame.get(u.index as usize));
+                        frame.registers[u.index as usize].clone()
+                    } else {
+                        upvalues[u.index as usize].clone()
+                    }
+                }).collect();
+                frame.set(a, closure(Rc::new(p.clone()), captured));
+            }
+            OpCode::VarArg => if b == 0 {
+                frame.set_all(a, varargs.clone());
+            } else {
+                frame.set_adjusted(a, varargs.clone(), b as usize - 1);
+            },
+            OpCode::ExtraArg => unreachable!("EXTRAARG follows the instruction using it"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytecode::compile;
+    use config::{LuaVersion, ParserConfig};
+    use parse_block_with;
+
+    fn strings(result: Result<Vec<Value>>) -> ::std::result::Result<Vec<String>, String> {
+        result.map(|values| values.iter().map(|v| v.to_string()).collect())
+              .map_err(|e| e.to_string())
+    }
+
+    /// Runs `source` with the interpreter and compiled, which have to agree
+    fn check(source: &str) -> Vec<String> {
+        let config = ParserConfig { version: LuaVersion::Lua53, ..ParserConfig::default() };
+        let block = parse_block_with(source.as_bytes(), &config).unwrap();
+        let expected = strings(Interpreter::new().exec(&block));
+        let proto = compile(&block, source.as_bytes(), "=test").unwrap();
+        let mut lua = Interpreter::new();
+        let main = load(&lua, proto);
+        let result = strings(lua.call(&main, vec![]));
+        assert_eq!(result, expected, "{}", source);
+        result.unwrap_or_else(|e| vec![e])
+    }
+
+    #[test]
+    fn expressions() {
+        assert_eq!(check("local a, b = 7, 2 return a + b, a - b, a * b, a / b, a // b, a % b, a ^ b"),
+                   vec!["9", "5", "14", "3.5", "3", "1", "49.0"]);
+        assert_eq!(check("local a = 6 return a & 3, a | 1, a ~ 5, a << 2, a >> 1, ~a, -a, #'abc'"),
+                   vec!["2", "7", "3", "24", "3", "-7", "-6", "3"]);
+        assert_eq!(check("local a, b = 1, 2 return a < b, a <= b, a > b, a >= b, a == b, a ~= b, not a"),
+                   vec!["true", "true", "false", "false", "false", "true", "false"]);
+        assert_eq!(check("local x = nil return x and 1, x or 2, 1 and 2, false or nil, 1 or error()"),
+                   vec!["nil", "2", "2", "nil", "1"]);
+        assert_eq!(check("local s = 'b' return 'a' .. s .. 1 .. 2.5, (('x') .. 'y') .. 'z'"),
+                   vec!["ab12.5", "xyz"]);
+        check("local x = 3 return x > 2 and x < 5 or x == 10, not (x == 3 or y), 2 > x");
+    }
+
+    #[test]
+    fn statements() {
+        check("local a, b = 1, 2 a, b = b, a return a, b");
+        check("local t = {1, 2} local i, j = 1, 2 t[i], t[j] = t[j], t[i] return t[1], t[2]");
+        check("local t = {} t.x, t = 1, {} return t.x");
+        check("x, y = 1 return x, y");
+        check("local t = {a = {b = {}}} function t.a.b.f(x) return x * 2 end \
+               function t.a.b:m(x) return self == t.a.b, x end return t.a.b.f(4), t.a.b:m(5)");
+        check("local x = 1 do local x = 2 end if x == 1 then x = 10 elseif x == 2 then x = 20 else x = 30 end return x");
+        check("local n = 0 while true do n = n + 1 if n > 5 then break end end return n");
+        check("local n = 0 repeat local m = n n = n + 1 until m >= 3 return n");
+        check("local s = 0 for i = 10, 1, -2 do s = s + i end for i = 1, 2, 0.5 do s = s + i end return s");
+        check("local s = '' for i = 1, 3 do for j = i, 3 do if j == 2 then goto next end s = s .. i .. j ::next:: end end return s");
+        check("local t = {} for k, v in pairs({a = 1, b = 2}) do t[#t + 1] = k .. v end return #t");
+        check("local s = 0 for i, v in ipairs({5, 6, 7}) do s = s + i * v end return s");
+        check("local i = 0 ::top:: i = i + 1 if i < 4 then goto top end return i");
+    }
+
+    #[test]
+    fn functions() {
+        check("local function fib(n) if n < 2 then return n end return fib(n - 1) + fib(n - 2) end return fib(15)");
+        check("local function f(...) return select('#', ...), ... end return f(1, nil, 3)");
+        check("local function f(...) local a, b = ... return {...}, a, b end local t, a, b = f(4, 5, 6) return #t, a, b");
+        check("local function f() return 1, 2, 3 end return f(), (f()), #{f(), f()}, f()");
+        check("local function f() return 1, 2, 3 end local t = {f(), f()} return #t");
+        check("local o = {n = 1} function o:get(x) return self.n + x end return o:get(2), o.get(o, 3)");
+        // The messages of the VM do not name variables
+        check("return (pcall(function() local x = nil return x.y end))");
+        check("return pcall(error, 'message')");
+    }
+
+    #[test]
+    fn closures() {
+        check("local fs = {} for i = 1, 3 do fs[i] = function() return i end end return fs[1](), fs[2](), fs[3]()");
+        check("local fs = {} local i = 1 while i <= 3 do local j = i fs[i] = function() j = j + 10 return j end i = i + 1 end \
+               return fs[1](), fs[1](), fs[2]()");
+        check("local function counter() local n = 0 return function() n = n + 1 return n end end \
+               local a, b = counter(), counter() a() return a(), b()");
+        check("local x = 1 local function f() local function g() x = x + 1 return x end return g() end return f(), x");
+        check("local fs = {} for k, v in pairs({10, 20}) do fs[k] = function() return v end end return fs[1](), fs[2]()");
+        check("local fs = {} local i = 0 repeat local j = i fs[#fs + 1] = function() return j end i = i + 1 until j >= 2 \
+               return fs[1](), fs[3]()");
+        check("local fs = {} for i = 1, 3 do local k = i * 2 fs[i] = function() return k end if i == 2 then break end end \
+               return #fs, fs[1](), fs[2]()");
+        check("local fs = {} do local i = 1 ::again:: local j = i fs[i] = function() return j end i = i + 1 \
+               if i <= 2 then goto again end end return fs[1](), fs[2]()");
+    }
+
+    #[test]
+    fn big_functions() {
+        let items: Vec<_> = (1..=130).map(|i| i.to_string()).collect();
+        check(&format!("local function f() return 1, 2 end local t = {{{}, f()}} return #t, t[50], t[51], t[132]", items.join(", ")));
+        let constants: Vec<_> = (0..300).map(|i| format!("t[{}] = 'k{}'", i, i)).collect();
+        check(&format!("local t = {{}} {} return t[0] .. t[299], #t", constants.join(" ")));
+    }
+
+    // The interpreter has no _ENV
+    #[test]
+    fn env() {
+        let run = |source: &str| {
+            let block = parse_block_with(source.as_bytes(), &ParserConfig::default()).unwrap();
+            let mut lua = Interpreter::new();
+            let main = load(&lua, compile(&block, source.as_bytes(), "=test").unwrap());
+            strings(lua.call(&main, vec![])).unwrap()
+        };
+        assert_eq!(run("local _ENV = {x = 1} return x"), vec!["1"]);
+        assert_eq!(run("x = 5 local function f() local _ENV = {x = 2} return x end return f(), x"), vec!["2", "5"]);
+    }
+}
@@ -0,0 +1,426 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Parser configuration
+//!
+//! The parsers are plain nom functions, so the configuration of the current
+//! parse is kept in thread local state. It is installed by
+//! `parse_chunk_with` and read by the parsers that need it.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use nom::{ErrorKind, IResult};
+
+use space::parse_space;
+
+/// The default for `ParserConfig::max_depth`
+///
+/// Every level of nesting costs several kilobytes of stack in debug builds,
+/// this keeps the parser well within the 2MB stack of a spawned thread.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+/// A Lua dialect, syntax introduced by a version is rejected by older ones
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LuaVersion {
+    Lua51,
+    /// Adds `goto` and labels, along with the `\x` and `\z` escapes
+    Lua52,
+    /// Adds integer division, the bitwise operators and the `\u{XXX}` escape
+    Lua53,
+    /// Adds the `<const>` and `<close>` local attributes
+    Lua54,
+}
+
+impl Display for LuaVersion {
+    fn fmt(&self, format: &mut Formatter) -> fmt::Result {
+        match *self {
+            LuaVersion::Lua51 => write!(format, "5.1"),
+            LuaVersion::Lua52 => write!(format, "5.2"),
+            LuaVersion::Lua53 => write!(format, "5.3"),
+            LuaVersion::Lua54 => write!(format, "5.4"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParserConfig {
+    /// How deep expressions may nest before the parse is aborted with
+    /// `Error::TooDeeplyNested`. Parentheses, table constructors, function
+    /// bodies, nested blocks and unary operators each count as one level.
+    /// Chains of binary operators or of suffixes such as `[1]` or `:m()`
+    /// are parsed in a loop and do not count, however long they are.
+    pub max_depth: usize,
+    /// The dialect to accept, `Lua53` by default
+    pub version: LuaVersion,
+}
+
+impl Default for ParserConfig {
+    fn default() -> ParserConfig {
+        ParserConfig {
+            max_depth: DEFAULT_MAX_DEPTH,
+            version: LuaVersion::Lua53,
+        }
+    }
+}
+
+struct State {
+    config: ParserConfig,
+    depth: usize,
+    too_deep: bool,
+    // Each feature rejected because of the version, with the length of the
+    // input left where it was found
+    unsupported: Vec<(&'static str, LuaVersion, usize)>,
+    // Each message of `reject`, with the length of the input left where
+    // it applies
+    rejected: Vec<(String, usize)>,
+}
+
+thread_local!(static STATE: RefCell<State> = RefCell::new(State {
+    config: ParserConfig::default(),
+    depth: 0,
+    too_deep: false,
+    unsupported: Vec::new(),
+    rejected: Vec::new(),
+}));
+
+/// Runs `f` with `config` installed as the configuration of this thread
+pub fn with_config<F, R>(config: &ParserConfig, f: F) -> R
+    where F: FnOnce() -> R
+{
+    let previous = STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        s.depth = 0;
+        s.too_deep = false;
+        s.unsupported.clear();
+        s.rejected.clear();
+        ::std::mem::replace(&mut s.config, config.clone())
+    });
+    let result = f();
+    STATE.with(|s| s.borrow_mut().config = previous);
+    result
+}
+
+/// Whether the last parse on this thread was aborted for nesting too deep
+pub fn too_deep() -> bool {
+    STATE.with(|s| s.borrow().too_deep)
+}
+
+/// Checks that `levels` more levels of nesting fit under the limit
+///
+/// Once the limit has been hit, every following check fails, so the parse
+/// unwinds without trying any other alternative.
+pub fn check_depth(levels: usize) -> bool {
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        if !s.too_deep && s.depth + levels > s.config.max_depth {
+            s.too_deep = true;
+        }
+        !s.too_deep
+    })
+}
+
+/// The Lua version being parsed on this thread
+pub fn version() -> LuaVersion {
+    STATE.with(|s| s.borrow().config.version)
+}
+
+/// Checks that `feature`, introduced in `version` and found at `input`, is
+/// part of the dialect being parsed
+///
+/// Features that fail this check are remembered with their position, so
+/// that one can be reported if the parse fails there. Checks made by
+/// alternatives that were abandoned lie before the failure and are ignored.
+pub fn require(feature: &'static str, version: LuaVersion, input: &[u8]) -> bool {
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        let supported = s.config.version >= version;
+        let left = skip_space(input).len();
+        if !supported && !s.unsupported.iter().any(|&(_, _, at)| at == left) {
+            s.unsupported.push((feature, version, left));
+        }
+        supported
+    })
+}
+
+/// The first feature the last parse on this thread rejected because of the
+/// configured version at or after `rest`, the input left where the parse
+/// failed, along with the version that introduced it
+pub fn unsupported_at(rest: &[u8]) -> Option<(&'static str, LuaVersion)> {
+    let left = skip_space(rest).len();
+    STATE.with(|s| {
+        s.borrow().unsupported.iter()
+            .filter(|&&(_, _, at)| at <= left)
+            .max_by_key(|&&(_, _, at)| at)
+            .map(|&(feature, version, _)| (feature, version))
+    })
+}
+
+/// Fails a parse at `input` for the reason given by `message`
+///
+/// The message is remembered with its position, so that it can be reported
+/// if the parse fails there, as for `require`.
+pub fn reject<T>(message: String, input: &[u8]) -> IResult<&[u8], T> {
+    let left = skip_space(input).len();
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        if !s.rejected.iter().any(|&(_, at)| at == left) {
+            s.rejected.push((message, left));
+        }
+    });
+    IResult::Error(error_code!(ErrorKind::Custom(0)))
+}
+
+/// The first message of `reject` in the last parse on this thread at or
+/// after `rest`, with the length of the input left where it applies
+pub fn rejected_at(rest: &[u8]) -> Option<(String, usize)> {
+    let left = skip_space(rest).len();
+    STATE.with(|s| {
+        s.borrow().rejected.iter()
+            .filter(|&&(_, at)| at <= left)
+            .max_by_key(|&&(_, at)| at)
+            .cloned()
+    })
+}
+
+/// `input` without its leading whitespace and comments
+pub(crate) fn skip_space(input: &[u8]) -> &[u8] {
+    match parse_space(input) {
+        IResult::Done(rest, _) => rest,
+        _ => input,
+    }
+}
+
+/// Holds one level of nesting, which is released when dropped
+pub struct DepthGuard(());
+
+impl DepthGuard {
+    pub fn enter() -> Option<DepthGuard> {
+        if !check_depth(1) {
+            return None;
+        }
+        STATE.with(|s| s.borrow_mut().depth += 1);
+        Some(DepthGuard(()))
+    }
+}
+
+impl Drop for DepthGuard {
+    fn drop(&mut self) {
+        STATE.with(|s| s.borrow_mut().depth -= 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::Error;
+    use {parse_block_with, parse_chunk_with};
+    use quickcheck::{Arbitrary, Gen};
+
+    fn nested(open: &str, inner: &str, close: &str, depth: usize) -> String {
+        format!("{}{}{}", open.repeat(depth), inner, close.repeat(depth))
+    }
+
+    fn limit(max_depth: usize) -> ParserConfig {
+        ParserConfig { max_depth, ..ParserConfig::default() }
+    }
+
+    #[test]
+    fn nesting_under_the_limit() {
+        let config = limit(10);
+        assert!(parse_chunk_with(nested("(", "1", ")", 9).as_bytes(), &config).is_ok());
+        assert!(parse_chunk_with(nested("{", "nil", "}", 9).as_bytes(), &config).is_ok());
+        assert!(parse_chunk_with(nested("- ", "1", "", 9).as_bytes(), &config).is_ok());
+        assert!(parse_chunk_with(format!("a{}", "[1]".repeat(11)).as_bytes(), &config).is_ok());
+        assert!(parse_chunk_with(format!("1{}", " + 1".repeat(11)).as_bytes(), &config).is_ok());
+    }
+
+    #[test]
+    fn nesting_over_the_limit() {
+        let config = limit(10);
+        for input in &[nested("(", "1", ")", 11),
+                       nested("{", "nil", "}", 11),
+                       nested("{ a = ", "nil", " }", 11),
+                       nested("- ", "1", "", 11),
+                       nested("function () return ", "nil", " end", 11)] {
+            assert_eq!(parse_chunk_with(input.as_bytes(), &config),
+                       Err(Error::TooDeeplyNested(10)));
+        }
+    }
+
+    #[test]
+    fn pathological_nesting() {
+        let config = ParserConfig::default();
+        for open in &["(", "{", "{ [", "- ", "not ", "#", "~", "(-", "2 ^ "] {
+            let input = open.repeat(10000);
+            assert_eq!(parse_chunk_with(input.as_bytes(), &config),
+                       Err(Error::TooDeeplyNested(DEFAULT_MAX_DEPTH)));
+        }
+        assert_eq!(parse_chunk_with(nested("(", "1", ")", 1000).as_bytes(), &config),
+                   Err(Error::TooDeeplyNested(DEFAULT_MAX_DEPTH)));
+    }
+
+    #[test]
+    fn flat_chains() {
+        // Parsed in a loop, their trees are as deep as they are long but
+        // nothing recurses while parsing or dropping them
+        let config = ParserConfig::default();
+        for &count in &[1000, 300000] {
+            for (start, term) in &[("x = a", "[1]"), ("x = a", ".b"), ("a", "()"), ("a", ":b()"),
+                                   ("x = a", " + b"), ("x = a", " .. b"), ("x = 1", " * 2 - 3"),
+                                   ("x = a", " and b or c")] {
+                let input = format!("{}{}", start, term.repeat(count));
+                assert!(parse_block_with(input.as_bytes(), &config).is_ok(), "{} x {}", term, count);
+            }
+        }
+    }
+
+    #[test]
+    fn pathological_block_nesting() {
+        use function::parse_block;
+
+        for open in &["do ", "while 1 do ", "if 1 then ", "repeat ", "for i = 1, 2 do "] {
+            let input = open.repeat(10000);
+            with_config(&ParserConfig::default(), || {
+                let _ = parse_block(input.as_bytes());
+                assert!(too_deep(), "{}", open);
+            });
+        }
+    }
+
+    #[test]
+    fn limit_is_reset_between_parses() {
+        let config = ParserConfig::default();
+        assert!(parse_chunk_with(nested("(", "1", ")", 10000).as_bytes(), &config).is_err());
+        assert!(parse_chunk_with(b"(1)", &config).is_ok());
+        assert!(!too_deep());
+    }
+
+    fn version(version: LuaVersion) -> ParserConfig {
+        ParserConfig { version, ..ParserConfig::default() }
+    }
+
+    #[test]
+    fn features_require_their_version() {
+        for &(input, feature, introduced) in &[
+                ("1 // 2", "integer division", LuaVersion::Lua53),
+                ("1 & 2", "bitwise operators", LuaVersion::Lua53),
+                ("1 << 2", "bitwise operators", LuaVersion::Lua53),
+                ("~1", "bitwise operators", LuaVersion::Lua53),
+                (r#""\u{48}""#, "unicode escapes", LuaVersion::Lua53),
+                (r#""\x48""#, "hexadecimal escapes", LuaVersion::Lua52),
+                ("'a\\z  b'", "the \\z escape", LuaVersion::Lua52)] {
+            let older = match introduced {
+                LuaVersion::Lua52 => LuaVersion::Lua51,
+                _ => LuaVersion::Lua52,
+            };
+            assert_eq!(parse_chunk_with(input.as_bytes(), &version(older)),
+                       Err(Error::RequiresVersion(feature, introduced)), "{}", input);
+            assert!(parse_chunk_with(input.as_bytes(), &version(introduced)).is_ok(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn goto_and_labels_require_lua52() {
+        use nom::IResult;
+        use statement::parse_statement;
+
+        for input in &["goto done", "::done::"] {
+            with_config(&version(LuaVersion::Lua51), || {
+                assert!(!parse_statement(input.as_bytes()).is_done());
+                assert!(unsupported_at(input.as_bytes()).is_some());
+            });
+            with_config(&version(LuaVersion::Lua52), || {
+                match parse_statement(input.as_bytes()) {
+                    IResult::Done(rest, _) => assert!(rest.is_empty()),
+                    other => panic!("{}: {:?}", input, other),
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn local_attribs_require_lua54() {
+        use nom::IResult;
+        use statement::parse_statement;
+
+        with_config(&version(LuaVersion::Lua53), || {
+            match parse_statement(b"local x <const> = 1") {
+                IResult::Done(rest, _) => assert_eq!(rest, b"<const> = 1"),
+                other => panic!("{:?}", other),
+            }
+            assert_eq!(unsupported_at(b"<const> = 1"), Some(("local attributes", LuaVersion::Lua54)));
+        });
+        with_config(&version(LuaVersion::Lua54), || {
+            match parse_statement(b"local x <const> = 1") {
+                IResult::Done(rest, _) => assert!(rest.is_empty()),
+                other => panic!("{:?}", other),
+            }
+        });
+    }
+
+    #[test]
+    fn abandoned_features_are_not_reported() {
+        use parse_block_with;
+
+        // goto is tried as a statement first, then parsed as a name
+        let config = version(LuaVersion::Lua51);
+        assert_eq!(parse_block_with(b"goto = 1\nx = = 2", &config), Err(Error::Syntax(9)));
+        assert_eq!(parse_block_with(b"goto = 1\ngoto x", &config),
+                   Err(Error::RequiresVersion("goto", LuaVersion::Lua52)));
+    }
+
+    #[test]
+    fn goto_is_a_name_before_lua52() {
+        let config = version(LuaVersion::Lua51);
+        assert!(parse_chunk_with(b"goto", &config).is_ok());
+        assert!(parse_chunk_with(b"goto", &ParserConfig::default()).is_err());
+    }
+
+    #[test]
+    fn whole_blocks() {
+        use parse_block_with;
+
+        let config = ParserConfig::default();
+        assert!(parse_block_with(b"local x = 1\nreturn x\n", &config).is_ok());
+        assert_eq!(parse_block_with(b"x = 1 y", &config), Err(Error::Syntax(6)));
+        assert_eq!(parse_chunk_with(b"  -- c\n )", &config), Err(Error::Syntax(8)));
+        assert_eq!(parse_block_with(b"local x <const> = 1", &config),
+                   Err(Error::RequiresVersion("local attributes", LuaVersion::Lua54)));
+    }
+
+    #[test]
+    fn version_display() {
+        assert_eq!(Error::RequiresVersion("goto", LuaVersion::Lua52).to_string(),
+                   "Lua 5.2 is required for goto");
+        assert_eq!(Error::RequiresVersion("local attributes", LuaVersion::Lua54).to_string(),
+                   "Lua 5.4 is required for local attributes");
+    }
+
+    /// A random sequence of tokens that open a new level of nesting
+    #[derive(Clone, Debug)]
+    struct Openers(String);
+
+    impl Arbitrary for Openers {
+        fn arbitrary<G: Gen>(g: &mut G) -> Openers {
+            const OPENERS: &[&str] = &["(", "{", "{ a = ", "{ [", "-", "not ", "#", "1 + (", "function () return "];
+            let len = g.gen_range(0, 100 * g.size());
+            Openers((0..len).map(|_| *g.choose(OPENERS).unwrap()).collect())
+        }
+    }
+
+    quickcheck! {
+        fn quickcheck_nesting_never_overflows(openers: Openers) -> bool {
+            match parse_chunk_with(openers.0.as_bytes(), &ParserConfig::default()) {
+                Err(Error::TooDeeplyNested(_)) | Err(Error::Syntax(_)) => true,
+                _ => openers.0.is_empty(),
+            }
+        }
+    }
+}
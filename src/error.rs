@@ -0,0 +1,42 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use config::LuaVersion;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The input nests deeper than the configured limit, which is included
+    TooDeeplyNested(usize),
+    /// The input is not valid Lua, parsing stopped at this byte offset
+    Syntax(usize),
+    /// The input uses a feature that the configured Lua version lacks,
+    /// holds the feature and the version that introduced it
+    RequiresVersion(&'static str, LuaVersion),
+    /// The input is not valid Lua for a reason the parser can name, holds
+    /// the byte offset and the message, worded as `luac` does
+    Invalid(usize, String),
+}
+
+impl Display for Error {
+    fn fmt(&self, format: &mut Formatter) -> fmt::Result {
+        match *self {
+            Error::TooDeeplyNested(limit) =>
+                write!(format, "too deeply nested (limit is {})", limit),
+            Error::Syntax(offset) => write!(format, "syntax error at byte {}", offset),
+            Error::RequiresVersion(feature, version) =>
+                write!(format, "Lua {} is required for {}", version, feature),
+            Error::Invalid(offset, ref message) => write!(format, "{} at byte {}", message, offset),
+        }
+    }
+}
+
+impl error::Error for Error {}
@@ -0,0 +1,271 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The Lua 5.3 rules for operators
+//!
+//! Integers wrap around on overflow, `/` and `^` always produce floats and
+//! strings are converted to numbers by arithmetic and back by concatenation.
+
+use std::cmp::Ordering;
+
+use nom::IResult;
+
+use ast::ASTNode;
+use number::parse_number;
+use op::BinOp;
+use super::value::{float_to_integer, Value};
+
+/// Converts a string to a number following the rules of the lexer
+pub fn str_to_number<'a>(s: &str) -> Option<Value<'a>> {
+    let s = s.trim_matches(|c: char| c.is_ascii_whitespace());
+    let (negative, s) = match s.as_bytes().first() {
+        Some(&b'-') => (true, &s[1..]),
+        Some(&b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    match parse_number(s.as_bytes()) {
+        IResult::Done(&[], ASTNode::Integer(i)) =>
+            Some(Value::Integer(if negative { i.wrapping_neg() } else { i })),
+        IResult::Done(&[], ASTNode::Float(f)) =>
+            Some(Value::Float(if negative { -f } else { f })),
+        _ => None,
+    }
+}
+
+/// The value as a number, converting strings
+pub fn to_number<'a>(v: &Value<'a>) -> Option<Value<'a>> {
+    match *v {
+        Value::Integer(_) | Value::Float(_) => Some(v.clone()),
+        Value::String(ref s) => str_to_number(s),
+        _ => None,
+    }
+}
+
+fn to_float(v: &Value) -> Option<f64> {
+    match to_number(v) {
+        Some(Value::Integer(i)) => Some(i as f64),
+        Some(Value::Float(f)) => Some(f),
+        _ => None,
+    }
+}
+
+/// The value as an integer, for the bitwise operators
+pub fn to_integer(v: &Value) -> Result<i64, String> {
+    match to_number(v) {
+        Some(Value::Integer(i)) => Ok(i),
+        Some(Value::Float(f)) => float_to_integer(f)
+            .ok_or_else(|| "number has no integer representation".to_string()),
+        _ => Err(format!("attempt to perform bitwise operation on a {} value", v.type_name())),
+    }
+}
+
+fn shift_left(a: i64, b: i64) -> i64 {
+    if b <= -64 || b >= 64 {
+        0
+    } else if b >= 0 {
+        ((a as u64) << b) as i64
+    } else {
+        ((a as u64) >> -b) as i64
+    }
+}
+
+fn float_mod(a: f64, b: f64) -> f64 {
+    let m = a % b;
+    if m != 0.0 && (m < 0.0) != (b < 0.0) {
+        m + b
+    } else {
+        m
+    }
+}
+
+/// Applies an arithmetic, bitwise or concatenation operator
+pub fn arith<'a>(op: BinOp, a: &Value<'a>, b: &Value<'a>) -> Result<Value<'a>, String> {
+    match op {
+        BinOp::BitAnd | BinOp::BitOr | BinOp::BitXor | BinOp::Lsh | BinOp::Rsh => {
+            let (a, b) = (to_integer(a)?, to_integer(b)?);
+            return Ok(Value::Integer(match op {
+                BinOp::BitAnd => a & b,
+                BinOp::BitOr => a | b,
+                BinOp::BitXor => a ^ b,
+                BinOp::Lsh => shift_left(a, b),
+                _ => shift_left(a, b.wrapping_neg()),
+            }));
+        }
+        BinOp::Concat => return concat(a, b),
+        _ => (),
+    }
+    let (x, y) = match (to_number(a), to_number(b)) {
+        (Some(x), Some(y)) => (x, y),
+        (None, _) => return Err(format!("attempt to perform arithmetic on a {} value", a.type_name())),
+        (_, None) => return Err(format!("attempt to perform arithmetic on a {} value", b.type_name())),
+    };
+    if let (&Value::Integer(x), &Value::Integer(y)) = (&x, &y) {
+        match op {
+            BinOp::Add => return Ok(Value::Integer(x.wrapping_add(y))),
+            BinOp::Sub => return Ok(Value::Integer(x.wrapping_sub(y))),
+            BinOp::Mul => return Ok(Value::Integer(x.wrapping_mul(y))),
+            BinOp::FDiv if y == 0 => return Err("attempt to perform 'n//0'".into()),
+            BinOp::FDiv => {
+                let q = x.wrapping_div(y);
+                let floor = if x.wrapping_rem(y) != 0 && (x < 0) != (y < 0) { q - 1 } else { q };
+                return Ok(Value::Integer(floor));
+            }
+            BinOp::Mod if y == 0 => return Err("attempt to perform 'n%0'".into()),
+            BinOp::Mod => {
+                let m = x.wrapping_rem(y);
+                return Ok(Value::Integer(if m != 0 && (m < 0) != (y < 0) { m + y } else { m }));
+            }
+            _ => (),
+        }
+    }
+    let (x, y) = (to_float(&x).unwrap(), to_float(&y).unwrap());
+    Ok(Value::Float(match op {
+        BinOp::Add => x + y,
+        BinOp::Sub => x - y,
+        BinOp::Mul => x * y,
+        BinOp::Div => x / y,
+        BinOp::Exp => x.powf(y),
+        BinOp::FDiv => (x / y).floor(),
+        BinOp::Mod => float_mod(x, y),
+        _ => unreachable!("{} is not arithmetic", op),
+    }))
+}
+
+/// Unary minus
+pub fn negate<'a>(v: &Value<'a>) -> Result<Value<'a>, String> {
+    match to_number(v) {
+        Some(Value::Integer(i)) => Ok(Value::Integer(i.wrapping_neg())),
+        Some(Value::Float(f)) => Ok(Value::Float(-f)),
+        _ => Err(format!("attempt to perform arithmetic on a {} value", v.type_name())),
+    }
+}
+
+/// Unary `~`
+pub fn bit_not<'a>(v: &Value<'a>) -> Result<Value<'a>, String> {
+    Ok(Value::Integer(!to_integer(v)?))
+}
+
+fn concat<'a>(a: &Value<'a>, b: &Value<'a>) -> Result<Value<'a>, String> {
+    for v in &[a, b] {
+        match **v {
+            Value::String(_) | Value::Integer(_) | Value::Float(_) => (),
+            _ => return Err(format!("attempt to concatenate a {} value", v.type_name())),
+        }
+    }
+    Ok(format!("{}{}", a, b).into())
+}
+
+/// The length operator
+pub fn len<'a>(v: &Value<'a>) -> Result<Value<'a>, String> {
+    match *v {
+        Value::String(ref s) => Ok(Value::Integer(s.len() as i64)),
+        Value::Table(ref t) => Ok(Value::Integer(t.borrow().len() as i64)),
+        _ => Err(format!("attempt to get length of a {} value", v.type_name())),
+    }
+}
+
+// Compares an integer with a float without losing precision
+fn cmp_integer_float(i: i64, f: f64) -> Option<Ordering> {
+    const LIMIT: f64 = 9223372036854775808.0;
+    if f.is_nan() {
+        None
+    } else if f >= LIMIT {
+        Some(Ordering::Less)
+    } else if f < -LIMIT {
+        Some(Ordering::Greater)
+    } else {
+        // f is in range, so comparing with its floor and ceiling is exact
+        Some(match i.cmp(&(f.floor() as i64)) {
+            Ordering::Equal if f.fract() != 0.0 => Ordering::Less,
+            ordering => ordering,
+        })
+    }
+}
+
+/// Orders numbers and strings, other values can't be compared
+pub fn compare<'a>(a: &Value<'a>, b: &Value<'a>) -> Result<Option<Ordering>, String> {
+    Ok(match (a, b) {
+        (&Value::Integer(x), &Value::Integer(y)) => Some(x.cmp(&y)),
+        (&Value::Float(x), &Value::Float(y)) => x.partial_cmp(&y),
+        (&Value::Integer(x), &Value::Float(y)) => cmp_integer_float(x, y),
+        (&Value::Float(x), &Value::Integer(y)) => cmp_integer_float(y, x).map(Ordering::reverse),
+        (Value::String(x), Value::String(y)) => Some(x.as_bytes().cmp(y.as_bytes())),
+        _ if a.type_name() == b.type_name() =>
+            return Err(format!("attempt to compare two {} values", a.type_name())),
+        _ => return Err(format!("attempt to compare {} with {}", a.type_name(), b.type_name())),
+    })
+}
+
+/// Applies a relational operator
+pub fn relational<'a>(op: BinOp, a: &Value<'a>, b: &Value<'a>) -> Result<Value<'a>, String> {
+    Ok(Value::Boolean(match op {
+        BinOp::Eq => a == b,
+        BinOp::Ne => a != b,
+        BinOp::Lt => compare(a, b)? == Some(Ordering::Less),
+        BinOp::Le => compare(a, b)?.is_some_and(|o| o != Ordering::Greater),
+        BinOp::Gt => compare(a, b)? == Some(Ordering::Greater),
+        BinOp::Ge => compare(a, b)?.is_some_and(|o| o != Ordering::Less),
+        _ => unreachable!("{} is not relational", op),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int<'a>(i: i64) -> Value<'a> {
+        Value::Integer(i)
+    }
+
+    #[test]
+    fn integer_arithmetic_wraps() {
+        assert_eq!(arith(BinOp::Add, &int(i64::MAX), &int(1)), Ok(int(i64::MIN)));
+        assert_eq!(arith(BinOp::Mul, &int(6), &int(7)), Ok(int(42)));
+    }
+
+    #[test]
+    fn division_rounds_down() {
+        assert_eq!(arith(BinOp::FDiv, &int(7), &int(-2)), Ok(int(-4)));
+        assert_eq!(arith(BinOp::Mod, &int(-7), &int(3)), Ok(int(2)));
+        assert_eq!(arith(BinOp::Mod, &int(7), &int(-3)), Ok(int(-2)));
+        assert_eq!(arith(BinOp::FDiv, &int(i64::MIN), &int(-1)), Ok(int(i64::MIN)));
+        assert_eq!(arith(BinOp::Mod, &Value::Float(-5.5), &int(2)), Ok(Value::Float(0.5)));
+        assert_eq!(arith(BinOp::Div, &int(7), &int(2)), Ok(Value::Float(3.5)));
+        assert!(arith(BinOp::FDiv, &int(1), &int(0)).is_err());
+        assert_eq!(arith(BinOp::FDiv, &Value::Float(1.0), &int(0)),
+                   Ok(Value::Float(f64::INFINITY)));
+    }
+
+    #[test]
+    fn strings_are_numbers() {
+        assert_eq!(arith(BinOp::Add, &"10".into(), &int(1)), Ok(int(11)));
+        assert_eq!(arith(BinOp::Add, &" 0x10 ".into(), &"-1.5".into()), Ok(Value::Float(14.5)));
+        assert!(arith(BinOp::Add, &"1e".into(), &int(1)).is_err());
+        assert_eq!(arith(BinOp::Concat, &int(1), &Value::Float(2.0)), Ok("12.0".into()));
+    }
+
+    #[test]
+    fn bitwise() {
+        assert_eq!(arith(BinOp::Lsh, &int(1), &int(64)), Ok(int(0)));
+        assert_eq!(arith(BinOp::Rsh, &int(-1), &int(63)), Ok(int(1)));
+        assert_eq!(arith(BinOp::Lsh, &int(4), &int(-1)), Ok(int(2)));
+        assert_eq!(arith(BinOp::BitAnd, &Value::Float(3.0), &int(1)), Ok(int(1)));
+        assert!(arith(BinOp::BitAnd, &Value::Float(3.5), &int(1)).is_err());
+    }
+
+    #[test]
+    fn mixed_comparisons_are_exact() {
+        let big = int(1 << 53 | 1);
+        assert_eq!(relational(BinOp::Lt, &Value::Float(9007199254740992.0), &big), Ok(true.into()));
+        assert_eq!(relational(BinOp::Le, &int(2), &Value::Float(2.5)), Ok(true.into()));
+        assert_eq!(relational(BinOp::Ge, &int(3), &Value::Float(2.5)), Ok(true.into()));
+        assert_eq!(relational(BinOp::Lt, &int(1), &Value::Float(f64::NAN)), Ok(false.into()));
+        assert_eq!(relational(BinOp::Eq, &int(1), &Value::Float(1.0)), Ok(true.into()));
+        assert!(relational(BinOp::Lt, &int(1), &"2".into()).is_err());
+    }
+}
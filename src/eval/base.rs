@@ -0,0 +1,219 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The functions of the basic library that need no access to the outside
+//! world: `assert`, `error`, `ipairs`, `next`, `pairs`, `pcall`, `rawequal`,
+//! `rawget`, `rawlen`, `rawset`, `select`, `tonumber`, `tostring` and `type`
+
+use super::arith::{str_to_number, to_integer};
+use super::{Interpreter, RuntimeError, Value};
+
+type Result<'a> = ::std::result::Result<Vec<Value<'a>>, RuntimeError<'a>>;
+
+fn arg<'a>(args: &[Value<'a>], i: usize) -> Value<'a> {
+    args.get(i).cloned().unwrap_or(Value::Nil)
+}
+
+fn bad_argument<'a, T>(i: usize, name: &str, message: &str) -> ::std::result::Result<T, RuntimeError<'a>> {
+    Err(RuntimeError(format!("bad argument #{} to '{}' ({})", i + 1, name, message).into()))
+}
+
+fn check_table<'a>(args: &[Value<'a>], i: usize, name: &str) -> ::std::result::Result<super::TableRef<'a>, RuntimeError<'a>> {
+    match arg(args, i) {
+        Value::Table(t) => Ok(t),
+        v => bad_argument(i, name, &format!("table expected, got {}", v.type_name())),
+    }
+}
+
+fn check_integer<'a>(args: &[Value<'a>], i: usize, name: &str) -> ::std::result::Result<i64, RuntimeError<'a>> {
+    match arg(args, i) {
+        v @ Value::Integer(_) | v @ Value::Float(_) | v @ Value::String(_) =>
+            to_integer(&v).or_else(|_| bad_argument(i, name, "number has no integer representation")),
+        v => bad_argument(i, name, &format!("number expected, got {}", v.type_name())),
+    }
+}
+
+fn next<'a>(_: &mut Interpreter<'a>, args: Vec<Value<'a>>) -> Result<'a> {
+    let t = check_table(&args, 0, "next")?;
+    let entry = t.borrow().next(&arg(&args, 1)).map_err(|e| RuntimeError(e.into()))?;
+    Ok(match entry {
+        Some((k, v)) => vec![k, v],
+        None => vec![Value::Nil],
+    })
+}
+
+fn ipairs_next<'a>(_: &mut Interpreter<'a>, args: Vec<Value<'a>>) -> Result<'a> {
+    let t = check_table(&args, 0, "ipairs")?;
+    let i = check_integer(&args, 1, "ipairs")?.wrapping_add(1);
+    let v = t.borrow().get(&Value::Integer(i));
+    Ok(if v.is_nil() { vec![Value::Nil] } else { vec![Value::Integer(i), v] })
+}
+
+fn tonumber<'a>(args: &[Value<'a>]) -> Result<'a> {
+    let base = match arg(args, 1) {
+        Value::Nil => return Ok(vec![match arg(args, 0) {
+            v @ Value::Integer(_) | v @ Value::Float(_) => v,
+            Value::String(ref s) => str_to_number(s).unwrap_or(Value::Nil),
+            Value::Nil => return bad_argument(0, "tonumber", "value expected"),
+            _ => Value::Nil,
+        }]),
+        _ => check_integer(args, 1, "tonumber")?,
+    };
+    if !(2..=36).contains(&base) {
+        return bad_argument(1, "tonumber", "base out of range");
+    }
+    let s = match arg(args, 0) {
+        Value::String(s) => s,
+        v => return bad_argument(0, "tonumber", &format!("string expected, got {}", v.type_name())),
+    };
+    let s = s.trim_matches(|c: char| c.is_ascii_whitespace());
+    let (negative, digits) = match s.as_bytes().first() {
+        Some(&b'-') => (true, &s[1..]),
+        _ => (false, s),
+    };
+    if digits.is_empty() {
+        return Ok(vec![Value::Nil]);
+    }
+    let mut n: i64 = 0;
+    for c in digits.chars() {
+        match c.to_digit(base as u32) {
+            Some(d) => n = n.wrapping_mul(base).wrapping_add(d as i64),
+            None => return Ok(vec![Value::Nil]),
+        }
+    }
+    Ok(vec![Value::Integer(if negative { n.wrapping_neg() } else { n })])
+}
+
+fn select<'a>(mut args: Vec<Value<'a>>) -> Result<'a> {
+    let count = args.len() as i64 - 1;
+    if let Some(Value::String(s)) = args.first() {
+        if &**s == "#" {
+            return Ok(vec![Value::Integer(count)]);
+        }
+    }
+    let n = check_integer(&args, 0, "select")?;
+    let start = if n < 0 { count + n } else if n == 0 {
+        return bad_argument(0, "select", "index out of range");
+    } else { n - 1 };
+    if start < 0 {
+        return bad_argument(0, "select", "index out of range");
+    }
+    Ok(args.split_off(1).into_iter().skip(start as usize).collect())
+}
+
+/// Installs the library in the globals of `interpreter`
+pub fn open(interpreter: &mut Interpreter) {
+    let globals = Value::Table(interpreter.globals().clone());
+    // The interpreter owns its globals, this makes a cycle until it is dropped
+    interpreter.set_global("_G", globals);
+    interpreter.set_global("assert", Value::native(|_, args| {
+        if arg(&args, 0).is_truthy() {
+            Ok(args)
+        } else {
+            match args.into_iter().nth(1) {
+                Some(message) => Err(RuntimeError(message)),
+                None => Err(RuntimeError("assertion failed!".into())),
+            }
+        }
+    }));
+    interpreter.set_global("error", Value::native(|_, args| Err(RuntimeError(arg(&args, 0)))));
+    let next = Value::native(next);
+    interpreter.set_global("next", next.clone());
+    interpreter.set_global("pairs", Value::native(move |_, args| {
+        check_table(&args, 0, "pairs")?;
+        Ok(vec![next.clone(), arg(&args, 0), Value::Nil])
+    }));
+    let ipairs_next = Value::native(ipairs_next);
+    interpreter.set_global("ipairs", Value::native(move |_, args| {
+        check_table(&args, 0, "ipairs")?;
+        Ok(vec![ipairs_next.clone(), arg(&args, 0), Value::Integer(0)])
+    }));
+    interpreter.set_global("pcall", Value::native(|interpreter, mut args| {
+        if args.is_empty() {
+            return bad_argument(0, "pcall", "value expected");
+        }
+        let rest = args.split_off(1);
+        Ok(match interpreter.call(&args[0], rest) {
+            Ok(mut values) => {
+                values.insert(0, Value::Boolean(true));
+                values
+            }
+            Err(RuntimeError(e)) => vec![Value::Boolean(false), e],
+        })
+    }));
+    interpreter.set_global("rawequal", Value::native(|_, args| {
+        Ok(vec![Value::Boolean(arg(&args, 0) == arg(&args, 1))])
+    }));
+    interpreter.set_global("rawget", Value::native(|_, args| {
+        let t = check_table(&args, 0, "rawget")?;
+        let v = t.borrow().get(&arg(&args, 1));
+        Ok(vec![v])
+    }));
+    interpreter.set_global("rawset", Value::native(|_, args| {
+        let t = check_table(&args, 0, "rawset")?;
+        t.borrow_mut().set(arg(&args, 1), arg(&args, 2)).map_err(|e| RuntimeError(e.into()))?;
+        Ok(vec![Value::Table(t)])
+    }));
+    interpreter.set_global("rawlen", Value::native(|_, args| match arg(&args, 0) {
+        Value::Table(t) => Ok(vec![Value::Integer(t.borrow().len() as i64)]),
+        Value::String(s) => Ok(vec![Value::Integer(s.len() as i64)]),
+        _ => bad_argument(0, "rawlen", "table or string expected"),
+    }));
+    interpreter.set_global("select", Value::native(|_, args| select(args)));
+    interpreter.set_global("tonumber", Value::native(|_, args| tonumber(&args)));
+    interpreter.set_global("tostring", Value::native(|_, args| {
+        Ok(vec![arg(&args, 0).to_string().into()])
+    }));
+    interpreter.set_global("type", Value::native(|_, args| match args.first() {
+        Some(v) => Ok(vec![v.type_name().into()]),
+        None => bad_argument(0, "type", "value expected"),
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call<'a>(f: &str, args: Vec<Value<'a>>) -> Result<'a> {
+        let mut lua = Interpreter::new();
+        let f = lua.get_global(f);
+        lua.call(&f, args)
+    }
+
+    #[test]
+    fn tonumber_bases() {
+        assert_eq!(call("tonumber", vec!["0x10".into()]), Ok(vec![16.into()]));
+        assert_eq!(call("tonumber", vec![" ff ".into(), 16.into()]), Ok(vec![255.into()]));
+        assert_eq!(call("tonumber", vec!["-zz".into(), 36.into()]), Ok(vec![(-1295).into()]));
+        assert_eq!(call("tonumber", vec!["8".into(), 8.into()]), Ok(vec![Value::Nil]));
+        assert_eq!(call("tonumber", vec!["1e1".into()]), Ok(vec![10.0.into()]));
+        assert_eq!(call("tonumber", vec![Value::table()]), Ok(vec![Value::Nil]));
+    }
+
+    #[test]
+    fn select_counts_from_the_end() {
+        let args = || vec![Value::Nil, 1.into(), 2.into(), 3.into()];
+        let mut negative = args();
+        negative[0] = (-1).into();
+        assert_eq!(call("select", negative), Ok(vec![3.into()]));
+        let mut count = args();
+        count[0] = "#".into();
+        assert_eq!(call("select", count), Ok(vec![3.into()]));
+        let mut second = args();
+        second[0] = 2.into();
+        assert_eq!(call("select", second), Ok(vec![2.into(), 3.into()]));
+    }
+
+    #[test]
+    fn assert_passes_its_arguments() {
+        assert_eq!(call("assert", vec![1.into(), "m".into()]), Ok(vec![1.into(), "m".into()]));
+        assert_eq!(call("assert", vec![false.into()]), Err(RuntimeError("assertion failed!".into())));
+        assert_eq!(call("type", vec![]),
+                   Err(RuntimeError("bad argument #1 to 'type' (value expected)".into())));
+    }
+}
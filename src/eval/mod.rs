@@ -0,0 +1,984 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A tree-walking interpreter
+//!
+//! Runs a `Block` as returned by `function::parse_block`. The interpreter has
+//! no access to the outside world, the only globals are the ones installed by
+//! the embedder and the functions of `base`.
+//!
+//! ```
+//! use nom_lua::IResult;
+//! use nom_lua::eval::{Interpreter, Value};
+//! use nom_lua::function::parse_block;
+//!
+//! let block = match parse_block(b"local t = {} for i = 1, 3 do t[i] = i * i end return #t, t[3]") {
+//!     IResult::Done(_, block) => block,
+//!     _ => unreachable!(),
+//! };
+//! let mut lua = Interpreter::new();
+//! assert_eq!(lua.exec(&block), Ok(vec![Value::Integer(3), Value::Integer(9)]));
+//! ```
+//!
+//! Metatables are not supported. Closures that refer to themselves form
+//! reference cycles, which are only freed when the program ends.
+
+use std::cell::RefCell;
+use std::error;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+// Not the String node
+use std::string::String;
+
+use ast::ASTNode;
+use ast::ASTNode::*;
+use ast::Attrib;
+use op::BinOp;
+
+pub mod arith;
+pub mod base;
+mod value;
+
+pub use self::value::{Closure, Function, NativeFunction, Table, TableRef, Value};
+pub(crate) use self::value::{format_float, format_g14};
+
+/// The default for `Interpreter::max_call_depth`
+///
+/// Every Lua call goes through several Rust frames, this keeps the
+/// interpreter within the 2MB stack of a spawned thread in debug builds.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 100;
+
+/// An error raised while running, holding the value passed to `error`
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuntimeError<'a>(pub Value<'a>);
+
+impl<'a> Display for RuntimeError<'a> {
+    fn fmt(&self, format: &mut Formatter) -> fmt::Result {
+        match self.0 {
+            Value::String(_) | Value::Integer(_) | Value::Float(_) => write!(format, "{}", self.0),
+            _ => write!(format, "(error object is a {} value)", self.0.type_name()),
+        }
+    }
+}
+
+impl<'a> error::Error for RuntimeError<'a> {}
+
+fn error<'a, T, S: Into<String>>(message: S) -> Result<T, RuntimeError<'a>> {
+    Err(RuntimeError(Value::String(message.into().into())))
+}
+
+struct Binding<'a> {
+    name: &'a str,
+    value: RefCell<Value<'a>>,
+    next: Option<Rc<Binding<'a>>>,
+}
+
+/// The locals in scope, as a persistent list that closures can share, and
+/// the varargs of the enclosing function
+#[derive(Clone)]
+pub(crate) struct Env<'a> {
+    locals: Option<Rc<Binding<'a>>>,
+    varargs: Rc<Vec<Value<'a>>>,
+}
+
+impl<'a> Env<'a> {
+    fn lookup(&self, name: &str) -> Option<&Binding<'a>> {
+        let mut binding = self.locals.as_ref();
+        while let Some(b) = binding {
+            if b.name == name {
+                return Some(b);
+            }
+            binding = b.next.as_ref();
+        }
+        None
+    }
+
+    fn bind(&mut self, name: &'a str, value: Value<'a>) {
+        self.locals = Some(Rc::new(Binding {
+            name,
+            value: RefCell::new(value),
+            next: self.locals.take(),
+        }));
+    }
+}
+
+/// How a statement finished
+enum Flow<'a> {
+    Normal,
+    Break,
+    Return(Vec<Value<'a>>),
+    Goto(&'a str),
+    #[cfg(feature="luau")]
+    Continue,
+}
+
+/// Where an assignment stores its value
+enum Target<'a> {
+    Name(&'a str),
+    Field(Value<'a>, Value<'a>),
+}
+
+fn name(node: &ASTNode) -> &str {
+    match *node {
+        Name(ref n) | Label(ref n) => n,
+        Var(ref n) => name(n),
+        #[cfg(feature="luau")]
+        Typed(ref n, _) => name(n),
+        _ => unreachable!("{:?} is not a name", node),
+    }
+}
+
+fn expect_list(node: &ASTNode) -> &[ASTNode] {
+    match *node {
+        ExpList(ref l) | VarList(ref l) | FieldList(ref l) => l,
+        _ => unreachable!("{:?} is not a list", node),
+    }
+}
+
+fn find_label(statements: &[ASTNode], label: &str) -> Option<usize> {
+    statements.iter().position(|s| match *s {
+        Label(ref l) => l == label,
+        _ => false,
+    })
+}
+
+/// Pads or truncates `values` to `n` values
+fn adjust(mut values: Vec<Value>, n: usize) -> Vec<Value> {
+    values.resize(n, Value::Nil);
+    values
+}
+
+pub struct Interpreter<'a> {
+    globals: TableRef<'a>,
+    depth: usize,
+    /// How deep calls may nest before failing with a stack overflow
+    pub max_call_depth: usize,
+}
+
+impl<'a> Default for Interpreter<'a> {
+    fn default() -> Interpreter<'a> {
+        Interpreter::new()
+    }
+}
+
+impl<'a> Interpreter<'a> {
+    /// Creates an interpreter with the functions of `base` as globals
+    pub fn new() -> Interpreter<'a> {
+        let mut interpreter = Interpreter {
+            globals: Rc::new(RefCell::new(Table::new())),
+            depth: 0,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+        };
+        base::open(&mut interpreter);
+        interpreter
+    }
+
+    /// The table of global variables
+    pub fn globals(&self) -> &TableRef<'a> {
+        &self.globals
+    }
+
+    pub fn get_global(&self, name: &str) -> Value<'a> {
+        self.globals.borrow().get(&name.into())
+    }
+
+    pub fn set_global(&mut self, name: &str, value: Value<'a>) {
+        self.globals.borrow_mut().set(name.into(), value).unwrap();
+    }
+
+    /// Runs a chunk, returning the values it returns
+    pub fn exec(&mut self, block: &'a ASTNode) -> Result<Vec<Value<'a>>, RuntimeError<'a>> {
+        let env = Env { locals: None, varargs: Rc::new(vec![]) };
+        let (flow, _) = self.exec_block(block, env)?;
+        self.finish(flow)
+    }
+
+    /// Calls a function with `args`
+    pub fn call(&mut self, f: &Value<'a>, args: Vec<Value<'a>>) -> Result<Vec<Value<'a>>, RuntimeError<'a>> {
+        let f = match *f {
+            Value::Function(ref f) => f.clone(),
+            _ => return error(format!("attempt to call a {} value", f.type_name())),
+        };
+        if self.depth >= self.max_call_depth {
+            return error("stack overflow");
+        }
+        self.depth += 1;
+        let result = match f {
+            Function::Native(ref f) => f(self, args),
+            Function::Lua(ref closure) => self.call_closure(closure, args),
+        };
+        self.depth -= 1;
+        result
+    }
+
+    fn call_closure(&mut self, closure: &Closure<'a>, mut args: Vec<Value<'a>>) -> Result<Vec<Value<'a>>, RuntimeError<'a>> {
+        let (params, block) = match *closure.body {
+            FunctionBody(ref params, ref block) => (params, block),
+            #[cfg(feature="luau")]
+            TypedFunctionBody(ref params, _, ref block) => (params, block),
+            _ => unreachable!("{:?} is not a function body", closure.body),
+        };
+        let (names, vararg): (&[(ASTNode, Option<Attrib>)], bool) = match **params {
+            Some(ParameterList(ref names, vararg)) => match **names {
+                Some(NameList(ref names)) => (names, vararg),
+                _ => (&[], vararg),
+            },
+            _ => (&[], false),
+        };
+        let mut env = closure.env.clone();
+        let mut args = args.drain(..);
+        if closure.method {
+            env.bind("self", args.next().unwrap_or(Value::Nil));
+        }
+        for (n, _) in names {
+            env.bind(name(n), args.next().unwrap_or(Value::Nil));
+        }
+        env.varargs = Rc::new(if vararg { args.collect() } else { vec![] });
+        let (flow, _) = self.exec_block(block, env)?;
+        self.finish(flow)
+    }
+
+    fn finish(&self, flow: Flow<'a>) -> Result<Vec<Value<'a>>, RuntimeError<'a>> {
+        match flow {
+            Flow::Normal => Ok(vec![]),
+            Flow::Return(values) => Ok(values),
+            Flow::Break => error("break outside a loop"),
+            Flow::Goto(label) => error(format!("no visible label '{}' for goto", label)),
+            #[cfg(feature="luau")]
+            Flow::Continue => error("continue outside a loop"),
+        }
+    }
+
+    /// Runs the statements of a block, returning how it finished and the
+    /// locals in scope at that point
+    fn exec_block(&mut self, block: &'a ASTNode, mut env: Env<'a>) -> Result<(Flow<'a>, Env<'a>), RuntimeError<'a>> {
+        let (statements, retstat) = match *block {
+            Block(ref statements, ref retstat) => (statements, retstat),
+            _ => unreachable!("{:?} is not a block", block),
+        };
+        // The locals in scope at each label that was reached, to restore
+        // when jumping back to it
+        let mut labels: Vec<(usize, Env<'a>)> = vec![];
+        let mut i = 0;
+        while i < statements.len() {
+            let flow = match statements[i] {
+                Label(_) => {
+                    labels.retain(|&(at, _)| at != i);
+                    labels.push((i, env.clone()));
+                    Flow::Normal
+                }
+                ref statement => self.exec_statement(statement, &mut env)?,
+            };
+            match flow {
+                Flow::Normal => i += 1,
+                Flow::Goto(label) => match find_label(statements, label) {
+                    Some(target) => {
+                        // Jumping forward into the scope of a local is not
+                        // allowed, so the current locals are right
+                        if let Some((_, e)) = labels.iter().find(|&&(at, _)| at == target) {
+                            env = e.clone();
+                        }
+                        i = target;
+                    }
+                    None => return Ok((Flow::Goto(label), env)),
+                },
+                flow => return Ok((flow, env)),
+            }
+        }
+        match **retstat {
+            Some(RetStat(ref exps)) => {
+                let values = self.eval_retstat(exps, &env)?;
+                Ok((Flow::Return(values), env))
+            }
+            _ => Ok((Flow::Normal, env)),
+        }
+    }
+
+    fn eval_retstat(&mut self, exps: &'a Option<ASTNode>, env: &Env<'a>) -> Result<Vec<Value<'a>>, RuntimeError<'a>> {
+        match *exps {
+            Some(ref exps) => self.eval_explist(exps, env),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Runs the body of a loop, returning whether the loop carries on
+    fn exec_loop_body(&mut self, block: &'a ASTNode, env: Env<'a>) -> Result<(Option<Flow<'a>>, Env<'a>), RuntimeError<'a>> {
+        let (flow, env) = self.exec_block(block, env)?;
+        Ok((match flow {
+            Flow::Normal => None,
+            #[cfg(feature="luau")]
+            Flow::Continue => None,
+            Flow::Break => Some(Flow::Normal),
+            flow => Some(flow),
+        }, env))
+    }
+
+    // Statements that are not control flow live in their own functions, to
+    // keep this frame small as it is on the stack once per nested call
+    fn exec_statement(&mut self, statement: &'a ASTNode, env: &mut Env<'a>) -> Result<Flow<'a>, RuntimeError<'a>> {
+        match *statement {
+            EmptyStatement => (),
+            Break => return Ok(Flow::Break),
+            Goto(ref label) => return Ok(Flow::Goto(name(label))),
+            Local(ref names, ref exps) => self.exec_local(names, exps, env)?,
+            NamedFunction(ref n, ref body) => {
+                env.bind(name(n), Value::Nil);
+                let f = self.closure(body, env, false);
+                *env.lookup(name(n)).unwrap().value.borrow_mut() = f;
+            }
+            FunctionStat(ref funcname, ref body) => self.exec_function_stat(funcname, body, env)?,
+            Assign(ref vars, ref exps) => self.exec_assign(vars, exps, env)?,
+            FunctionCall(_, _) | MethodCall(_, _, _) => {
+                self.eval_call(statement, env)?;
+            }
+            Do(ref block) => return Ok(self.exec_block(block, env.clone())?.0),
+            While(ref cond, ref block) => {
+                while self.eval(cond, env)?.is_truthy() {
+                    if let (Some(flow), _) = self.exec_loop_body(block, env.clone())? {
+                        return Ok(flow);
+                    }
+                }
+            }
+            Repeat(ref block, ref cond) => loop {
+                // The condition can see the locals of the body
+                let (flow, inner) = self.exec_loop_body(block, env.clone())?;
+                if let Some(flow) = flow {
+                    return Ok(flow);
+                }
+                if self.eval(cond, &inner)?.is_truthy() {
+                    break;
+                }
+            },
+            If(ref cond, ref block, ref other) => {
+                if self.eval(cond, env)?.is_truthy() {
+                    return Ok(self.exec_block(block, env.clone())?.0);
+                }
+                match **other {
+                    Some(ref elseif @ If(_, _, _)) => return self.exec_statement(elseif, env),
+                    Some(ref block) => return Ok(self.exec_block(block, env.clone())?.0),
+                    None => (),
+                }
+            }
+            NumericFor(ref var, ref start, ref limit, ref step, ref block) =>
+                return self.exec_numeric_for(name(var), start, limit, step, block, env),
+            GenericFor(ref names, ref exps, ref block) => return self.exec_generic_for(names, exps, block, env),
+            #[cfg(feature="luau")]
+            CompoundAssign(op, ref var, ref exp) => self.exec_compound_assign(op, var, exp, env)?,
+            #[cfg(feature="luau")]
+            Continue => return Ok(Flow::Continue),
+            #[cfg(feature="luau")]
+            TypeAlias(_, _, _, _) => (),
+            _ => unreachable!("{:?} is not a statement", statement),
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn exec_local(&mut self, names: &'a ASTNode, exps: &'a Option<ASTNode>, env: &mut Env<'a>) -> Result<(), RuntimeError<'a>> {
+        let names = match *names {
+            NameList(ref names) => names,
+            _ => unreachable!(),
+        };
+        let values = match *exps {
+            Some(ref exps) => self.eval_explist(exps, env)?,
+            None => vec![],
+        };
+        for (&(ref n, attrib), value) in names.iter().zip(adjust(values, names.len())) {
+            if attrib == Some(Attrib::Close) && value.is_truthy() {
+                return error(format!("variable '{}' got a non-closable value", name(n)));
+            }
+            env.bind(name(n), value);
+        }
+        Ok(())
+    }
+
+    fn exec_function_stat(&mut self, funcname: &'a ASTNode, body: &'a ASTNode, env: &Env<'a>) -> Result<(), RuntimeError<'a>> {
+        let (base, fields, method) = match *funcname {
+            FunctionName(ref base, ref fields, ref method) => (base, fields, method),
+            _ => unreachable!(),
+        };
+        let mut path: Vec<&'a ASTNode> = fields.iter().flat_map(|f| f.iter()).collect();
+        path.extend(method.iter().map(|m| &**m));
+        let f = self.closure(body, env, method.is_some());
+        match path.pop() {
+            None => self.assign(Target::Name(name(base)), f, env),
+            Some(last) => {
+                let mut table = self.lookup(name(base), env);
+                for field in path {
+                    table = self.index(table, name(field).into(), Some(field))?;
+                }
+                self.assign(Target::Field(table, name(last).into()), f, env)
+            }
+        }
+    }
+
+    fn exec_assign(&mut self, vars: &'a ASTNode, exps: &'a ASTNode, env: &Env<'a>) -> Result<(), RuntimeError<'a>> {
+        // Every expression is evaluated before anything is assigned
+        let mut targets = vec![];
+        for var in expect_list(vars) {
+            targets.push(self.target(var, env)?);
+        }
+        let values = adjust(self.eval_explist(exps, env)?, targets.len());
+        for (target, value) in targets.into_iter().zip(values) {
+            self.assign(target, value, env)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature="luau")]
+    fn exec_compound_assign(&mut self, op: BinOp, var: &'a ASTNode, exp: &'a ASTNode, env: &Env<'a>) -> Result<(), RuntimeError<'a>> {
+        let target = self.target(var, env)?;
+        let current = match target {
+            Target::Name(n) => self.lookup(n, env),
+            Target::Field(ref t, ref k) => self.index(t.clone(), k.clone(), None)?,
+        };
+        let value = self.eval(exp, env)?;
+        let result = arith::arith(op, &current, &value).or_else(error)?;
+        self.assign(target, result, env)
+    }
+
+    fn exec_generic_for(&mut self, names: &'a ASTNode, exps: &'a ASTNode, block: &'a ASTNode, env: &Env<'a>) -> Result<Flow<'a>, RuntimeError<'a>> {
+        let names = match *names {
+            NameList(ref names) => names,
+            _ => unreachable!(),
+        };
+        let mut state = adjust(self.eval_explist(exps, env)?, 3).into_iter();
+        let (f, s, mut control) = (state.next().unwrap(), state.next().unwrap(), state.next().unwrap());
+        loop {
+            let values = adjust(self.call(&f, vec![s.clone(), control])?, names.len());
+            if values[0].is_nil() {
+                return Ok(Flow::Normal);
+            }
+            control = values[0].clone();
+            let mut inner = env.clone();
+            for ((n, _), value) in names.iter().zip(values) {
+                inner.bind(name(n), value);
+            }
+            if let (Some(flow), _) = self.exec_loop_body(block, inner)? {
+                return Ok(flow);
+            }
+        }
+    }
+
+    fn exec_numeric_for(&mut self, var: &'a str, start: &'a ASTNode, limit: &'a ASTNode,
+                        step: &'a Option<ASTNode>, block: &'a ASTNode, env: &Env<'a>)
+                        -> Result<Flow<'a>, RuntimeError<'a>> {
+        let number = |v: Value<'a>, what: &str| match arith::to_number(&v) {
+            Some(n) => Ok(n),
+            None => error(format!("'for' {} must be a number", what)),
+        };
+        let start = number(self.eval(start, env)?, "initial value")?;
+        let limit = number(self.eval(limit, env)?, "limit")?;
+        let step = match *step {
+            Some(ref step) => number(self.eval(step, env)?, "step")?,
+            None => Value::Integer(1),
+        };
+        let body = |this: &mut Interpreter<'a>, value: Value<'a>| {
+            let mut inner = env.clone();
+            inner.bind(var, value);
+            this.exec_loop_body(block, inner).map(|(flow, _)| flow)
+        };
+        if let (&Value::Integer(start), &Value::Integer(step)) = (&start, &step) {
+            if step == 0 {
+                return error("'for' step is zero");
+            }
+            // Floats limits are rounded towards the loop
+            let limit = match limit {
+                Value::Integer(l) => l,
+                Value::Float(l) => match value::float_to_integer(if step > 0 { l.floor() } else { l.ceil() }) {
+                    Some(l) => l,
+                    None if l.is_nan() => return Ok(Flow::Normal),
+                    None => if (l > 0.0) == (step > 0) {
+                        if step > 0 { i64::MAX } else { i64::MIN }
+                    } else {
+                        return Ok(Flow::Normal);
+                    },
+                },
+                _ => unreachable!(),
+            };
+            if (step > 0 && start > limit) || (step < 0 && start < limit) {
+                return Ok(Flow::Normal);
+            }
+            // Counting the iterations avoids overflowing past the limit
+            let mut count = if step > 0 {
+                (limit as u64).wrapping_sub(start as u64) / step as u64
+            } else {
+                (start as u64).wrapping_sub(limit as u64) / (step as u64).wrapping_neg()
+            };
+            let mut i = start;
+            loop {
+                if let Some(flow) = body(self, Value::Integer(i))? {
+                    return Ok(flow);
+                }
+                if count == 0 {
+                    return Ok(Flow::Normal);
+                }
+                count -= 1;
+                i = i.wrapping_add(step);
+            }
+        }
+        let float = |v: Value| match v {
+            Value::Integer(i) => i as f64,
+            Value::Float(f) => f,
+            _ => unreachable!(),
+        };
+        let (mut i, limit, step) = (float(start), float(limit), float(step));
+        if step == 0.0 {
+            return error("'for' step is zero");
+        }
+        while (step > 0.0 && i <= limit) || (step < 0.0 && i >= limit) {
+            if let Some(flow) = body(self, Value::Float(i))? {
+                return Ok(flow);
+            }
+            i += step;
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn closure(&self, body: &'a ASTNode, env: &Env<'a>, method: bool) -> Value<'a> {
+        Value::Function(Function::Lua(Rc::new(Closure { body, env: env.clone(), method })))
+    }
+
+    fn lookup(&self, name: &str, env: &Env<'a>) -> Value<'a> {
+        match env.lookup(name) {
+            Some(binding) => binding.value.borrow().clone(),
+            None => self.get_global(name),
+        }
+    }
+
+    /// Describes the variable `node` reads, for error messages
+    fn describe(node: &ASTNode, env: &Env<'a>) -> String {
+        match *node {
+            PrefixExp(ref e) => Interpreter::describe(e, env),
+            Var(ref n) if env.lookup(name(n)).is_some() => format!(" (local '{}')", name(n)),
+            Var(ref n) => format!(" (global '{}')", name(n)),
+            VarListAccess(_, ref n) => format!(" (field '{}')", name(n)),
+            MethodCall(_, ref n, _) => format!(" (method '{}')", name(n)),
+            _ => String::new(),
+        }
+    }
+
+    fn index(&self, table: Value<'a>, key: Value<'a>, node: Option<&ASTNode>) -> Result<Value<'a>, RuntimeError<'a>> {
+        match table {
+            Value::Table(ref t) => Ok(t.borrow().get(&key)),
+            _ => {
+                let variable = match node {
+                    Some(Name(n)) => format!(" (field '{}')", n),
+                    _ => String::new(),
+                };
+                error(format!("attempt to index a {} value{}", table.type_name(), variable))
+            }
+        }
+    }
+
+    fn target(&mut self, var: &'a ASTNode, env: &Env<'a>) -> Result<Target<'a>, RuntimeError<'a>> {
+        Ok(match *var {
+            Var(ref n) => Target::Name(name(n)),
+            VarPrefixed(ref prefix, ref key) => Target::Field(self.eval(prefix, env)?, self.eval(key, env)?),
+            VarListAccess(ref prefix, ref n) => Target::Field(self.eval(prefix, env)?, name(n).into()),
+            _ => unreachable!("{:?} is not a var", var),
+        })
+    }
+
+    fn assign(&mut self, target: Target<'a>, value: Value<'a>, env: &Env<'a>) -> Result<(), RuntimeError<'a>> {
+        match target {
+            Target::Name(n) => match env.lookup(n) {
+                Some(binding) => *binding.value.borrow_mut() = value,
+                None => self.set_global(n, value),
+            },
+            Target::Field(Value::Table(t), key) => t.borrow_mut().set(key, value).or_else(error)?,
+            Target::Field(t, _) => return error(format!("attempt to index a {} value", t.type_name())),
+        }
+        Ok(())
+    }
+
+    /// Evaluates an expression list, expanding the values of the last one
+    fn eval_explist(&mut self, exps: &'a ASTNode, env: &Env<'a>) -> Result<Vec<Value<'a>>, RuntimeError<'a>> {
+        let exps = expect_list(exps);
+        let mut values = Vec::with_capacity(exps.len());
+        if let Some((last, init)) = exps.split_last() {
+            for e in init {
+                values.push(self.eval(e, env)?);
+            }
+            values.extend(self.eval_multi(last, env)?);
+        }
+        Ok(values)
+    }
+
+    /// Evaluates an expression that may produce several values, only calls
+    /// and `...` do unless they are in parentheses
+    fn eval_multi(&mut self, e: &'a ASTNode, env: &Env<'a>) -> Result<Vec<Value<'a>>, RuntimeError<'a>> {
+        match *e {
+            VarArg => Ok((*env.varargs).clone()),
+            FunctionCall(_, _) | MethodCall(_, _, _) => self.eval_call(e, env),
+            PrefixExp(ref call) => match **call {
+                FunctionCall(_, _) | MethodCall(_, _, _) => self.eval_call(call, env),
+                _ => Ok(vec![self.eval(e, env)?]),
+            },
+            _ => Ok(vec![self.eval(e, env)?]),
+        }
+    }
+
+    fn eval_call(&mut self, call: &'a ASTNode, env: &Env<'a>) -> Result<Vec<Value<'a>>, RuntimeError<'a>> {
+        let (f, mut args, args_node) = match *call {
+            FunctionCall(ref prefix, ref args) => (self.eval(prefix, env)?, vec![], args),
+            MethodCall(ref prefix, ref method, ref args) => {
+                let object = self.eval(prefix, env)?;
+                let f = self.index(object.clone(), name(method).into(), None)?;
+                (f, vec![object], args)
+            }
+            _ => unreachable!(),
+        };
+        if let Some(ref exps) = **args_node {
+            args.extend(self.eval_explist(exps, env)?);
+        }
+        match f {
+            Value::Function(_) => self.call(&f, args),
+            _ => {
+                let variable = match *call {
+                    FunctionCall(ref prefix, _) => Interpreter::describe(prefix, env),
+                    _ => Interpreter::describe(call, env),
+                };
+                error(format!("attempt to call a {} value{}", f.type_name(), variable))
+            }
+        }
+    }
+
+    /// Evaluates an expression to a single value
+    pub(crate) fn eval(&mut self, e: &'a ASTNode, env: &Env<'a>) -> Result<Value<'a>, RuntimeError<'a>> {
+        if let Some((op, a, b)) = e.as_binop() {
+            return self.eval_binop(op, a, b, env);
+        }
+        Ok(match *e {
+            Nil => Value::Nil,
+            Bool(b) => Value::Boolean(b),
+            Integer(i) => Value::Integer(i),
+            Float(f) => Value::Float(f),
+            PrefixExp(ref e) | Paren(ref e) => self.eval(e, env)?,
+            Var(ref n) => self.lookup(name(n), env),
+            VarPrefixed(ref prefix, ref key) => {
+                let key = self.eval(key, env)?;
+                self.eval_index(prefix, key, env)?
+            }
+            VarListAccess(ref prefix, ref n) => self.eval_index(prefix, name(n).into(), env)?,
+            FunctionCall(_, _) | MethodCall(_, _, _) =>
+                self.eval_call(e, env)?.into_iter().next().unwrap_or(Value::Nil),
+            _ => self.eval_other(e, env)?,
+        })
+    }
+
+    // The less common expressions, apart to keep the frame of eval small
+    fn eval_other(&mut self, e: &'a ASTNode, env: &Env<'a>) -> Result<Value<'a>, RuntimeError<'a>> {
+        Ok(match *e {
+            String(ref s) => ::std::string::String::from_utf8_lossy(s).as_ref().into(),
+            VarArg => env.varargs.first().cloned().unwrap_or(Value::Nil),
+            Function(ref body) => self.closure(body, env, false),
+            TableConstructor(ref fields) => self.eval_table(fields, env)?,
+            Not(ref a) => Value::Boolean(!self.eval(a, env)?.is_truthy()),
+            UMin(ref a) | BinNot(ref a) | Len(ref a) => self.eval_unop(e, a, env)?,
+            #[cfg(feature="luau")]
+            IfExp(ref cond, ref then, ref other) => if self.eval(cond, env)?.is_truthy() {
+                self.eval(then, env)?
+            } else {
+                self.eval(other, env)?
+            },
+            _ => unreachable!("{:?} is not an expression", e),
+        })
+    }
+
+    fn eval_index(&mut self, prefix: &'a ASTNode, key: Value<'a>, env: &Env<'a>) -> Result<Value<'a>, RuntimeError<'a>> {
+        match self.eval(prefix, env)? {
+            Value::Table(ref t) => Ok(t.borrow().get(&key)),
+            table => error(format!("attempt to index a {} value{}",
+                                   table.type_name(), Interpreter::describe(prefix, env))),
+        }
+    }
+
+    fn eval_unop(&mut self, op: &'a ASTNode, e: &'a ASTNode, env: &Env<'a>) -> Result<Value<'a>, RuntimeError<'a>> {
+        let value = self.eval(e, env)?;
+        match *op {
+            UMin(_) => arith::negate(&value),
+            BinNot(_) => arith::bit_not(&value),
+            _ => arith::len(&value),
+        }.or_else(error)
+    }
+
+    fn eval_binop(&mut self, op: BinOp, a: &'a ASTNode, b: &'a ASTNode, env: &Env<'a>) -> Result<Value<'a>, RuntimeError<'a>> {
+        let a = self.eval(a, env)?;
+        match op {
+            BinOp::And if !a.is_truthy() => return Ok(a),
+            BinOp::Or if a.is_truthy() => return Ok(a),
+            BinOp::And | BinOp::Or => return self.eval(b, env),
+            _ => (),
+        }
+        let b = self.eval(b, env)?;
+        match op {
+            BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge | BinOp::Eq | BinOp::Ne =>
+                arith::relational(op, &a, &b),
+            _ => arith::arith(op, &a, &b),
+        }.or_else(error)
+    }
+
+    fn eval_table(&mut self, fields: &'a Option<ASTNode>, env: &Env<'a>) -> Result<Value<'a>, RuntimeError<'a>> {
+        let mut table = Table::new();
+        let fields = match *fields {
+            Some(ref fields) => expect_list(fields),
+            None => &[],
+        };
+        let mut n = 0;
+        for (i, field) in fields.iter().enumerate() {
+            match *field {
+                FieldSingle(ref e) if i == fields.len() - 1 => {
+                    for value in self.eval_multi(e, env)? {
+                        n += 1;
+                        table.set(Value::Integer(n), value).or_else(error)?;
+                    }
+                }
+                FieldSingle(ref e) => {
+                    n += 1;
+                    let value = self.eval(e, env)?;
+                    table.set(Value::Integer(n), value).or_else(error)?;
+                }
+                FieldAssign(ref key, ref e) => {
+                    let key = match **key {
+                        Name(ref n) => n.as_str().into(),
+                        ref key => self.eval(key, env)?,
+                    };
+                    let value = self.eval(e, env)?;
+                    table.set(key, value).or_else(error)?;
+                }
+                _ => unreachable!("{:?} is not a field", field),
+            }
+        }
+        Ok(Value::Table(Rc::new(RefCell::new(table))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+    use config::{with_config, LuaVersion, ParserConfig};
+    use function::parse_block;
+    use nom::IResult;
+
+    fn parse(source: &str) -> ASTNode {
+        let config = ParserConfig { version: LuaVersion::Lua54, ..ParserConfig::default() };
+        with_config(&config, || match parse_block(source.as_bytes()) {
+            IResult::Done(rest, block) => {
+                assert!(rest.is_empty(), "unparsed: {:?}", ::std::str::from_utf8(rest));
+                block
+            }
+            other => panic!("{:?}", other),
+        })
+    }
+
+    fn run(source: &str) -> Result<Vec<String>, String> {
+        let block = parse(source);
+        let mut lua = Interpreter::new();
+        lua.exec(&block)
+           .map(|values| values.iter().map(|v| v.to_string()).collect())
+           .map_err(|e| e.to_string())
+    }
+
+    fn ok(source: &str) -> Vec<String> {
+        run(source).unwrap()
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(ok("return 1 + 2, 7 // 2, 7 / 2, 2 ^ 2, -7 % 3, 1 + 2.0, 3 | 4, '1' + 1"),
+                   ["3", "3", "3.5", "4.0", "2", "3.0", "7", "2"]);
+        assert_eq!(ok("return 2 ^ 3 ^ 2, -2 ^ 2, 1 .. 2, 10 == 10.0, 'a' < 'b'"),
+                   ["512.0", "-4.0", "12", "true", "true"]);
+        assert_eq!(run("return 1 // 0"), Err("attempt to perform 'n//0'".into()));
+        assert_eq!(run("return {} < {}"), Err("attempt to compare two table values".into()));
+    }
+
+    #[test]
+    fn logic_short_circuits() {
+        assert_eq!(ok("return nil and x.y, 1 or x.y, false or nil, not nil"),
+                   ["nil", "1", "nil", "true"]);
+    }
+
+    #[test]
+    fn locals_shadow_globals() {
+        assert_eq!(ok("x = 1 local x = 2 do local x = 3 end return x"), ["2"]);
+        assert_eq!(ok("local a, b, c = (function() return 1, 2, 3 end)() return a, b, c"),
+                   ["1", "2", "3"]);
+        assert_eq!(ok("local a, b = 1 return a, b"), ["1", "nil"]);
+    }
+
+    #[test]
+    fn closures_share_upvalues() {
+        assert_eq!(ok("
+            local function counter()
+                local n = 0
+                return function() n = n + 1 return n end, function() return n end
+            end
+            local inc, get = counter()
+            inc() inc()
+            return get()"), ["2"]);
+        // Each iteration has a fresh variable
+        assert_eq!(ok("
+            local fs = {}
+            for i = 1, 3 do fs[i] = function() return i end end
+            return fs[1](), fs[3]()"), ["1", "3"]);
+    }
+
+    #[test]
+    fn recursion() {
+        assert_eq!(ok("
+            local function fib(n) if n < 2 then return n end return fib(n - 1) + fib(n - 2) end
+            return fib(15)"), ["610"]);
+        assert_eq!(run("local function f() return f() + 1 end return f()"),
+                   Err("stack overflow".into()));
+    }
+
+    #[test]
+    fn varargs() {
+        assert_eq!(ok("
+            local function f(...) return select('#', ...), ... end
+            return f(1, nil, 3)"), ["3", "1", "nil", "3"]);
+        assert_eq!(ok("local function f(...) return (...) end return f(1, 2)"), ["1"]);
+        assert_eq!(ok("local function f(...) local t = {...} return #t end return f(1, 2, 3)"),
+                   ["3"]);
+        assert_eq!(ok("local function f() return 1, 2 end return ({f(), f()})[3], (f())"),
+                   ["2", "1"]);
+    }
+
+    #[test]
+    fn methods() {
+        assert_eq!(ok("
+            local obj = {n = 1}
+            function obj:add(k) self.n = self.n + k return self end
+            obj:add(2):add(3)
+            return obj.n"), ["6"]);
+        assert_eq!(ok("local a = {b = {}} function a.b.c() return 'c' end return a.b.c()"), ["c"]);
+    }
+
+    #[test]
+    fn loops() {
+        assert_eq!(ok("local s = 0 for i = 10, 1, -3 do s = s + i end return s"), ["22"]);
+        assert_eq!(ok("local s = 0 for i = 1, 2, 0.5 do s = s + i end return s"), ["4.5"]);
+        assert_eq!(ok("local n = 0 for i = 1, 2.5 do n = n + 1 end return n"), ["2"]);
+        assert_eq!(ok("local n = 0 for i = 1, 1 / 0 do n = n + 1 if n == 3 then break end end return n"),
+                   ["3"]);
+        assert_eq!(ok("local n = 0 for i = 9223372036854775806, 9223372036854775807 do n = n + 1 end return n"),
+                   ["2"]);
+        assert_eq!(run("for i = 1, 2, 0 do end"), Err("'for' step is zero".into()));
+        assert_eq!(run("for i = {}, 2 do end"), Err("'for' initial value must be a number".into()));
+        assert_eq!(ok("local i = 0 while true do i = i + 1 if i == 5 then break end end return i"),
+                   ["5"]);
+        assert_eq!(ok("local i = 0 repeat local j = i i = i + 1 until j >= 3 return i"), ["4"]);
+        assert_eq!(ok("
+            local t, keys = {10, 20, 30, x = 1}, 0
+            for k, v in pairs(t) do keys = keys + 1 end
+            local sum = 0
+            for i, v in ipairs(t) do sum = sum + v end
+            return keys, sum"), ["4", "60"]);
+    }
+
+    #[test]
+    fn if_elseif_else() {
+        let source = "
+            local function sign(n)
+                if n < 0 then return -1 elseif n == 0 then return 0 else return 1 end
+            end
+            return sign(-5), sign(0), sign(5)";
+        assert_eq!(ok(source), ["-1", "0", "1"]);
+    }
+
+    #[test]
+    fn goto_and_labels() {
+        assert_eq!(ok("
+            local i = 1
+            ::top::
+            if i < 3 then i = i + 1 goto top end
+            return i"), ["3"]);
+        assert_eq!(ok("
+            local s = 0
+            for i = 1, 5 do
+                if i % 2 == 0 then goto continue end
+                s = s + i
+                ::continue::
+            end
+            return s"), ["9"]);
+        // Each pass over the label starts a fresh scope for the locals after it
+        assert_eq!(ok("
+            local fs, i = {}, 1
+            ::again::
+            local j = i
+            fs[i] = function() return j end
+            i = i + 1
+            if i <= 2 then goto again end
+            return fs[1](), fs[2]()"), ["1", "2"]);
+        assert_eq!(run("goto nowhere"), Err("no visible label 'nowhere' for goto".into()));
+    }
+
+    #[test]
+    fn tables() {
+        assert_eq!(ok("local t = {1, 2, [10] = 3, x = 'y', ['z'] = 4; 5} return #t, t[3], t.x, t.z, t[10]"),
+                   ["3", "5", "y", "4", "3"]);
+        assert_eq!(ok("local t = {} t[1.0] = 'a' t[2] = 'b' return t[1], #t"), ["a", "2"]);
+        assert_eq!(run("local t = {} t[nil] = 1"), Err("table index is nil".into()));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(run("x.y = 1"), Err("attempt to index a nil value".into()));
+        assert_eq!(run("return x.y"), Err("attempt to index a nil value (global 'x')".into()));
+        assert_eq!(run("local t = {} t.f()"), Err("attempt to call a nil value (field 'f')".into()));
+        assert_eq!(run("local t = {} t:m()"), Err("attempt to call a nil value (method 'm')".into()));
+        assert_eq!(run("local f f()"), Err("attempt to call a nil value (local 'f')".into()));
+        assert_eq!(run("error('boom')"), Err("boom".into()));
+        assert_eq!(run("error({})"), Err("(error object is a table value)".into()));
+        assert_eq!(ok("return pcall(error, 'x')"), ["false", "x"]);
+        assert_eq!(ok("return select(2, pcall(function() return 1 + {} end))"),
+                   ["attempt to perform arithmetic on a table value"]);
+    }
+
+    #[test]
+    fn close_needs_a_closable_value() {
+        assert_eq!(ok("local x <close> = nil local y <const> = 1 return y"), ["1"]);
+        assert_eq!(run("local x <close> = 1"), Err("variable 'x' got a non-closable value".into()));
+    }
+
+    #[test]
+    fn embedding() {
+        let block = parse("return double(x)");
+        let add = parse("function add(a, b) return a + b end");
+        let mut lua = Interpreter::new();
+        lua.set_global("x", Value::Integer(21));
+        lua.set_global("double", Value::native(|_, args| {
+            arith::arith(BinOp::Mul, &args[0], &Value::Integer(2))
+                .map(|v| vec![v])
+                .map_err(|e| RuntimeError(e.into()))
+        }));
+        assert_eq!(lua.exec(&block), Ok(vec![Value::Integer(42)]));
+
+        lua.exec(&add).unwrap();
+        let add = lua.get_global("add");
+        assert_eq!(lua.call(&add, vec![1.into(), 2.5.into()]), Ok(vec![Value::Float(3.5)]));
+    }
+
+    #[cfg(feature="luau")]
+    #[test]
+    fn luau() {
+        assert_eq!(ok("
+            type Point = { x: number }
+            local s: number = 0
+            for i = 1, 5 do
+                if i == 3 then continue end
+                s += i
+            end
+            local t = { n = 1 }
+            t.n ..= 'x'
+            return s, t.n, if s > 10 then 'big' else 'small'"), ["12", "1x", "big"]);
+    }
+}
@@ -0,0 +1,455 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::{Debug, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use ast::ASTNode;
+use super::{Env, Interpreter, RuntimeError};
+
+/// A shared, mutable table
+pub type TableRef<'a> = Rc<RefCell<Table<'a>>>;
+
+/// A function implemented in Rust, called with the interpreter and the
+/// arguments
+pub type NativeFunction<'a> =
+    dyn Fn(&mut Interpreter<'a>, Vec<Value<'a>>) -> Result<Vec<Value<'a>>, RuntimeError<'a>> + 'a;
+
+/// A Lua value, borrowing the functions it holds from the AST
+#[derive(Clone)]
+pub enum Value<'a> {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    String(Rc<str>),
+    Table(TableRef<'a>),
+    Function(Function<'a>),
+}
+
+#[derive(Clone)]
+pub enum Function<'a> {
+    Lua(Rc<Closure<'a>>),
+    Native(Rc<NativeFunction<'a>>),
+}
+
+/// A function defined in Lua along with the locals it captured
+pub struct Closure<'a> {
+    pub(super) body: &'a ASTNode,
+    pub(super) env: Env<'a>,
+    /// Defined with `:`, so it takes an implicit `self`
+    pub(super) method: bool,
+}
+
+impl<'a> Value<'a> {
+    /// Wraps a Rust closure as a Lua function
+    pub fn native<F>(f: F) -> Value<'a>
+        where F: Fn(&mut Interpreter<'a>, Vec<Value<'a>>) -> Result<Vec<Value<'a>>, RuntimeError<'a>> + 'a
+    {
+        Value::Function(Function::Native(Rc::new(f)))
+    }
+
+    /// Creates an empty table
+    pub fn table() -> Value<'a> {
+        Value::Table(Rc::new(RefCell::new(Table::new())))
+    }
+
+    /// The name `type` gives to the type of this value
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Value::Nil => "nil",
+            Value::Boolean(_) => "boolean",
+            Value::Integer(_) | Value::Float(_) => "number",
+            Value::String(_) => "string",
+            Value::Table(_) => "table",
+            Value::Function(_) => "function",
+        }
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(*self, Value::Nil)
+    }
+
+    /// Everything but `nil` and `false` is true
+    pub fn is_truthy(&self) -> bool {
+        !matches!(*self, Value::Nil | Value::Boolean(false))
+    }
+}
+
+impl<'a> From<bool> for Value<'a> {
+    fn from(b: bool) -> Value<'a> {
+        Value::Boolean(b)
+    }
+}
+
+impl<'a> From<i64> for Value<'a> {
+    fn from(i: i64) -> Value<'a> {
+        Value::Integer(i)
+    }
+}
+
+impl<'a> From<f64> for Value<'a> {
+    fn from(f: f64) -> Value<'a> {
+        Value::Float(f)
+    }
+}
+
+impl<'a, 'b> From<&'b str> for Value<'a> {
+    fn from(s: &'b str) -> Value<'a> {
+        Value::String(s.into())
+    }
+}
+
+impl<'a> From<String> for Value<'a> {
+    fn from(s: String) -> Value<'a> {
+        Value::String(s.into())
+    }
+}
+
+impl<'a> Function<'a> {
+    fn address(&self) -> usize {
+        match *self {
+            Function::Lua(ref c) => Rc::as_ptr(c) as usize,
+            Function::Native(ref f) => Rc::as_ptr(f) as *const u8 as usize,
+        }
+    }
+}
+
+/// Raw equality, numbers are equal if they have the same mathematical value
+/// and tables and functions are compared by identity
+impl<'a> PartialEq for Value<'a> {
+    fn eq(&self, other: &Value<'a>) -> bool {
+        match (self, other) {
+            (&Value::Nil, &Value::Nil) => true,
+            (&Value::Boolean(a), &Value::Boolean(b)) => a == b,
+            (&Value::Integer(a), &Value::Integer(b)) => a == b,
+            (&Value::Float(a), &Value::Float(b)) => a == b,
+            (&Value::Integer(i), &Value::Float(f)) |
+            (&Value::Float(f), &Value::Integer(i)) => float_to_integer(f) == Some(i),
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Table(a), Value::Table(b)) => Rc::ptr_eq(a, b),
+            (Value::Function(a), Value::Function(b)) => a.address() == b.address(),
+            _ => false,
+        }
+    }
+}
+
+impl<'a> Display for Value<'a> {
+    fn fmt(&self, format: &mut Formatter) -> fmt::Result {
+        match *self {
+            Value::Nil => write!(format, "nil"),
+            Value::Boolean(b) => write!(format, "{}", b),
+            Value::Integer(i) => write!(format, "{}", i),
+            Value::Float(f) => write!(format, "{}", format_float(f)),
+            Value::String(ref s) => write!(format, "{}", s),
+            Value::Table(ref t) => write!(format, "table: {:p}", Rc::as_ptr(t)),
+            Value::Function(ref f) => write!(format, "function: 0x{:x}", f.address()),
+        }
+    }
+}
+
+impl<'a> Debug for Value<'a> {
+    fn fmt(&self, format: &mut Formatter) -> fmt::Result {
+        match *self {
+            Value::String(ref s) => write!(format, "{:?}", s),
+            _ => write!(format, "{}", self),
+        }
+    }
+}
+
+/// Converts a float with an exact integer representation
+pub fn float_to_integer(f: f64) -> Option<i64> {
+    // 2^63 is exact as a float, unlike i64::MAX
+    const LIMIT: f64 = 9223372036854775808.0;
+    if f.fract() == 0.0 && (-LIMIT..LIMIT).contains(&f) {
+        Some(f as i64)
+    } else {
+        None
+    }
+}
+
+/// Formats a float like Lua's `%.14g`, adding `.0` to integral values
+pub fn format_float(f: f64) -> String {
+    let s = format_g14(f);
+    if s.bytes().all(|b| b == b'-' || b.is_ascii_digit()) {
+        s + ".0"
+    } else {
+        s
+    }
+}
+
+/// Formats a float like C's `%.14g`, the number format of Lua 5.1
+pub fn format_g14(f: f64) -> String {
+    if f.is_nan() {
+        return if f.is_sign_negative() { "-nan" } else { "nan" }.into();
+    }
+    if f.is_infinite() {
+        return if f > 0.0 { "inf" } else { "-inf" }.into();
+    }
+    fn trim_zeros(s: &str) -> &str {
+        if s.contains('.') {
+            s.trim_end_matches('0').trim_end_matches('.')
+        } else {
+            s
+        }
+    }
+    // The exponent after rounding to 14 significant digits decides between
+    // the fixed and the scientific notation
+    let scientific = format!("{:.13e}", f);
+    let e = scientific.find('e').unwrap();
+    let exponent: i32 = scientific[e + 1..].parse().unwrap();
+    if !(-4..14).contains(&exponent) {
+        format!("{}e{}{:02}",
+                trim_zeros(&scientific[..e]),
+                if exponent < 0 { '-' } else { '+' },
+                exponent.abs())
+    } else {
+        trim_zeros(&format!("{:.*}", (13 - exponent) as usize, f)).into()
+    }
+}
+
+/// A table key, floats with an integer value are stored as that integer,
+/// `nil` and NaN are not valid keys
+#[derive(Clone, Debug)]
+struct Key<'a>(Value<'a>);
+
+impl<'a> Key<'a> {
+    fn new(value: Value<'a>) -> Result<Key<'a>, &'static str> {
+        match value {
+            Value::Nil => Err("table index is nil"),
+            Value::Float(f) if f.is_nan() => Err("table index is NaN"),
+            Value::Float(f) => Ok(Key(float_to_integer(f).map(Value::Integer).unwrap_or(value))),
+            _ => Ok(Key(value)),
+        }
+    }
+
+    /// The position of this key in the array part
+    fn index(&self) -> Option<usize> {
+        match self.0 {
+            Value::Integer(i) if i >= 1 && i as u64 <= usize::MAX as u64 => Some(i as usize - 1),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> PartialEq for Key<'a> {
+    fn eq(&self, other: &Key<'a>) -> bool {
+        // Keys are normalised, so only identical variants can be equal
+        match (&self.0, &other.0) {
+            (&Value::Integer(_), &Value::Float(_)) |
+            (&Value::Float(_), &Value::Integer(_)) => false,
+            (a, b) => a == b,
+        }
+    }
+}
+
+impl<'a> Eq for Key<'a> {}
+
+impl<'a> Hash for Key<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self.0 {
+            Value::Nil => 0.hash(state),
+            Value::Boolean(b) => b.hash(state),
+            Value::Integer(i) => i.hash(state),
+            Value::Float(f) => f.to_bits().hash(state),
+            Value::String(ref s) => s.hash(state),
+            Value::Table(ref t) => (Rc::as_ptr(t) as usize).hash(state),
+            Value::Function(ref f) => f.address().hash(state),
+        }
+    }
+}
+
+/// A Lua table
+///
+/// The keys `1..n` live in an array, the rest in a hash part that remembers
+/// the insertion order. Removed hash entries are kept as `nil` until a new
+/// key is inserted, so that `next` can carry on past them while traversing.
+#[derive(Default)]
+pub struct Table<'a> {
+    array: Vec<Value<'a>>,
+    entries: Vec<(Key<'a>, Value<'a>)>,
+    index: HashMap<Key<'a>, usize>,
+    removed: usize,
+}
+
+impl<'a> Table<'a> {
+    pub fn new() -> Table<'a> {
+        Table::default()
+    }
+
+    pub fn get(&self, key: &Value<'a>) -> Value<'a> {
+        let key = match Key::new(key.clone()) {
+            Ok(key) => key,
+            Err(_) => return Value::Nil,
+        };
+        if let Some(v) = key.index().and_then(|i| self.array.get(i)) {
+            return v.clone();
+        }
+        match self.index.get(&key) {
+            Some(&i) => self.entries[i].1.clone(),
+            None => Value::Nil,
+        }
+    }
+
+    /// Sets `key` to `value`, assigning `nil` removes the key
+    pub fn set(&mut self, key: Value<'a>, value: Value<'a>) -> Result<(), &'static str> {
+        let key = Key::new(key)?;
+        match key.index() {
+            Some(i) if i < self.array.len() => {
+                self.array[i] = value;
+                while self.array.last().is_some_and(Value::is_nil) {
+                    self.array.pop();
+                }
+                return Ok(());
+            }
+            Some(i) if i == self.array.len() && !value.is_nil() => {
+                self.array.push(value);
+                self.migrate();
+                return Ok(());
+            }
+            _ => (),
+        }
+        match self.index.get(&key) {
+            Some(&i) => {
+                match (self.entries[i].1.is_nil(), value.is_nil()) {
+                    (true, false) => self.removed -= 1,
+                    (false, true) => self.removed += 1,
+                    _ => (),
+                }
+                self.entries[i].1 = value;
+            }
+            None if !value.is_nil() => {
+                if self.removed > 8 && self.removed > self.entries.len() / 2 {
+                    self.compact();
+                }
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+            None => (),
+        }
+        Ok(())
+    }
+
+    // Moves the keys that follow the array part out of the hash part
+    fn migrate(&mut self) {
+        loop {
+            let key = Key(Value::Integer(self.array.len() as i64 + 1));
+            let value = match self.index.get(&key) {
+                Some(&i) if !self.entries[i].1.is_nil() => {
+                    self.removed += 1;
+                    ::std::mem::replace(&mut self.entries[i].1, Value::Nil)
+                }
+                _ => return,
+            };
+            self.array.push(value);
+        }
+    }
+
+    fn compact(&mut self) {
+        self.entries.retain(|(_, v)| !v.is_nil());
+        self.index = self.entries.iter().enumerate().map(|(i, (k, _))| (k.clone(), i)).collect();
+        self.removed = 0;
+    }
+
+    /// The length operator, a border of the table
+    pub fn len(&self) -> usize {
+        self.array.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.array.is_empty() && self.entries.len() == self.removed
+    }
+
+    /// The entry after `key` in traversal order, `nil` starts the traversal.
+    /// Returns `Err` if `key` is not in the table.
+    pub fn next(&self, key: &Value<'a>) -> Result<Option<(Value<'a>, Value<'a>)>, &'static str> {
+        let start = match *key {
+            Value::Nil => 0,
+            _ => {
+                let key = Key::new(key.clone()).map_err(|_| "invalid key to 'next'")?;
+                match (key.index(), self.index.get(&key)) {
+                    (Some(i), _) if i < self.array.len() => i + 1,
+                    (_, Some(&i)) => self.array.len() + i + 1,
+                    // Assigning nil to the last element while traversing
+                    // shrinks the array, carry on with the hash part
+                    (Some(_), None) => self.array.len(),
+                    (None, None) => return Err("invalid key to 'next'"),
+                }
+            }
+        };
+        for i in start..self.array.len() {
+            if !self.array[i].is_nil() {
+                return Ok(Some((Value::Integer(i as i64 + 1), self.array[i].clone())));
+            }
+        }
+        let start = start.saturating_sub(self.array.len());
+        Ok(self.entries.iter().skip(start)
+               .find(|(_, v)| !v.is_nil())
+               .map(|(k, v)| (k.0.clone(), v.clone())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_floats() {
+        for &(f, s) in &[(1.0, "1.0"), (-0.0, "-0.0"), (0.5, "0.5"), (1e15, "1e+15"),
+                         (1e100, "1e+100"), (2f64.powi(53), "9.007199254741e+15"),
+                         (0.1, "0.1"), (1.0 / 3.0, "0.33333333333333"), (1e-5, "1e-05"),
+                         (123456.789, "123456.789"), (f64::INFINITY, "inf")] {
+            assert_eq!(format_float(f), s);
+        }
+    }
+
+    #[test]
+    fn float_keys_are_integers() {
+        let mut t = Table::new();
+        t.set(Value::Float(1.0), "a".into()).unwrap();
+        assert_eq!(t.get(&Value::Integer(1)), "a".into());
+        assert_eq!(t.len(), 1);
+        assert_eq!(t.set(Value::Nil, Value::Nil), Err("table index is nil"));
+        assert_eq!(t.set(Value::Float(f64::NAN), Value::Nil), Err("table index is NaN"));
+    }
+
+    #[test]
+    fn array_part_grows_from_hash() {
+        let mut t = Table::new();
+        t.set(Value::Integer(3), Value::Integer(3)).unwrap();
+        t.set(Value::Integer(2), Value::Integer(2)).unwrap();
+        assert_eq!(t.len(), 0);
+        t.set(Value::Integer(1), Value::Integer(1)).unwrap();
+        assert_eq!(t.len(), 3);
+        t.set(Value::Integer(3), Value::Nil).unwrap();
+        assert_eq!(t.len(), 2);
+    }
+
+    #[test]
+    fn next_survives_removal() {
+        let mut t = Table::new();
+        for i in 1..4 {
+            t.set(Value::Integer(i), Value::Integer(i)).unwrap();
+            t.set(format!("k{}", i).into(), Value::Integer(i)).unwrap();
+        }
+        let mut key = Value::Nil;
+        let mut seen = 0;
+        while let Some((k, _)) = t.next(&key).unwrap() {
+            t.set(k.clone(), Value::Nil).unwrap();
+            key = k;
+            seen += 1;
+        }
+        assert_eq!(seen, 6);
+        assert!(t.is_empty());
+        assert!(t.next(&"missing".into()).is_err());
+    }
+}
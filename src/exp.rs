@@ -13,45 +13,55 @@ use number::parse_number;
 use op::parse_op;
 use string::parse_string;
 use function::parse_functiondef;
-use field::parse_fieldlist;
-use var::parse_var;
+use field::{parse_fieldlist, parse_fieldsep};
+use var::parse_suffixed;
+#[cfg(feature="luau")]
+use luau::parse_if_exp;
+
+#[cfg(not(feature="luau"))]
+named!(parse_if_exp<ASTNode>, fail!());
 
 named!(parse_vararg<ASTNode>, map!(tag!("..."), |_| ast!(VarArg)));
-named!(parse_nil<ASTNode>, map!(tag!("nil"), |_| ast!(Nil)));
-named!(parse_bool<ASTNode>, alt!(map!(tag!("false"), |_| ast!(Bool, false)) |
-                                 map!(tag!("true"), |_| ast!(Bool, true))));
+// A name may start with a keyword, which must not be reported as Incomplete
+named!(parse_nil<ASTNode>, map!(complete!(keyword!("nil")), |_| ast!(Nil)));
+named!(parse_bool<ASTNode>, alt!(map!(complete!(keyword!("false")), |_| ast!(Bool, false)) |
+                                 map!(complete!(keyword!("true")), |_| ast!(Bool, true))));
 
-named!(pub parse_prefixexp<ASTNode>, map!(map!(alt!(
-        //parse_functioncall |
-        delimited!(tag!("("), ws!(parse_exp), tag!(")")) |
-        parse_var
-), Box::new), ASTNode::PrefixExp)) ;
+//TODO: parse_functioncall
+named!(pub parse_prefixexp<ASTNode>, map!(parse_suffixed, |(e, _)| astb!(PrefixExp, e)));
 
 named!(pub parse_explist<ASTNode>, map!(
             map!(do_parse!(
                    a: parse_exp
-                >> b: many0!(preceded!(ws!(tag!(",")), parse_exp))
+                >> b: many0!(complete!(preceded!(ws!(tag!(",")), parse_exp)))
                 >> (a,b)
             ), |(a, mut b): (_, Vec < ASTNode >) | { b.insert(0, a); b }),
 ASTNode::ExpList));
 
-named!(pub parse_exp<ASTNode>, alt!(
-                parse_op |
+// Every recursive rule goes back through parse_exp, so this is where the
+// nesting depth is limited
+named!(pub parse_exp<ASTNode>, nested!(parse_op));
+
+// The operands of parse_op. Each alternative starts with a different token,
+// so a parenthesised expression is only ever parsed once.
+named!(pub parse_simple_exp<ASTNode>, alt!(
+                parse_number |
                 parse_nil |
                 parse_bool |
                 parse_string |
                 parse_vararg |
-                parse_functiondef |
+                complete!(parse_functiondef) |
                 parse_prefixexp |
-                parse_tableconstructor
+                parse_tableconstructor |
+                complete!(parse_if_exp)
 ));
 
-// TODO: Missing tests
-named!(parse_tableconstructor<ASTNode>,
+named!(pub parse_tableconstructor<ASTNode>,
        map!(
        do_parse!(
               tag!("{")
-           >> f: ws!(opt!(parse_fieldlist))
+           // a separator may only follow a field, `{,}` is not a table
+           >> f: ws!(opt!(terminated!(parse_fieldlist, opt!(ws!(parse_fieldsep)))))
            >> tag!("}")
            >> (Box::new(f))), ASTNode::TableConstructor));
 
@@ -66,6 +76,26 @@ mod tests {
     ast_test!(parse_bool_f, parse_bool, "false", ast!(Bool, false));
     ast_test!(parse_vararg, parse_vararg, "...", ast!(VarArg));
 
+    ast_test!(parse_prefixexp_1, parse_prefixexp, "a",
+              astb!(PrefixExp, astb!(Var, ast!(Name, "a".into()))));
+    ast_test!(parse_prefixexp_2, parse_prefixexp, "(nil)", astb!(PrefixExp, ast!(Nil)));
+
+    // Used to take exponential time, trying every parenthesis as both an
+    // operand and a prefixexp
+    ast_invalid!(parse_exp_backtracking, parse_exp, "((((((((((((((((((((((((((((((((((((((((+");
+
+    ast_test!(parse_tableconstructor_1, parse_exp, "{}", ast!(TableConstructor, Box::new(None)));
+    ast_test!(parse_tableconstructor_2, parse_exp, "{ a = nil; true }",
+              ast!(TableConstructor, Box::new(Some(ast!(FieldList, vec![
+                  astb!(FieldAssign, ast!(Name, "a".into()), ast!(Nil)),
+                  astb!(FieldSingle, ast!(Bool, true))
+              ])))));
+    ast_test!(parse_tableconstructor_3, parse_exp, "{ 1, }",
+              ast!(TableConstructor, Box::new(Some(ast!(FieldList, vec![
+                  astb!(FieldSingle, ast!(Integer, 1))
+              ])))));
+    ast_invalid!(parse_tableconstructor_4, parse_exp, "{ , }");
+
     ast_test!(parse_explist_1, parse_explist, "true", ast!(ExpList, vec![
         ast!(Bool, true)
     ]));
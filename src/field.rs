@@ -33,7 +33,7 @@ named!(parse_field<ASTNode>, ws!(alt!(
         map!(map!(parse_exp, Box::new), ASTNode::FieldSingle)
 )));
 
-named!(parse_fieldsep, alt!(tag!(",") | tag!(";")));
+named!(pub parse_fieldsep, alt!(tag!(",") | tag!(";")));
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,167 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Constant folding
+//!
+//! Replaces operations on literals with their result, using the same Lua 5.3
+//! rules as `eval`. An operation that would raise an error, such as integer
+//! division by zero or a bitwise operation on `1.5`, is left for the program
+//! to raise at run time. Like `luac`, results that are float zeros or not
+//! finite are not folded either, since `-0.0`, NaN and infinities have no
+//! literal.
+
+use ast::ASTNode;
+use ast::ASTNode::*;
+use eval::arith;
+use eval::Value;
+use op::BinOp;
+
+/// Folds the constant expressions in `node` and its children
+pub fn fold_constants(node: &mut ASTNode) {
+    for child in node.children_mut() {
+        fold_constants(child);
+    }
+    if let Some(folded) = fold(node) {
+        *node = folded;
+    }
+}
+
+fn constant(node: &ASTNode) -> Option<Value<'static>> {
+    Some(match *node {
+        Nil => Value::Nil,
+        Bool(b) => Value::Boolean(b),
+        Integer(i) => Value::Integer(i),
+        Float(f) => Value::Float(f),
+        // The interpreter's strings are text, other bytes are left alone
+        String(ref s) => ::std::str::from_utf8(s).ok()?.into(),
+        _ => return None,
+    })
+}
+
+fn is_number(v: &Value) -> bool {
+    matches!(*v, Value::Integer(_) | Value::Float(_))
+}
+
+fn literal(v: Value) -> Option<ASTNode> {
+    Some(match v {
+        Value::Nil => Nil,
+        Value::Boolean(b) => Bool(b),
+        Value::Integer(i) => Integer(i),
+        Value::Float(f) if f.is_finite() && f != 0.0 => Float(f),
+        Value::String(ref s) => String(s.as_bytes().to_vec()),
+        _ => return None,
+    })
+}
+
+fn fold(node: &ASTNode) -> Option<ASTNode> {
+    if let Some((op, a, b)) = node.as_binop() {
+        let (a, b) = (constant(a)?, constant(b)?);
+        let result = match op {
+            BinOp::And | BinOp::Or => return None,
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge =>
+                arith::relational(op, &a, &b),
+            // Arithmetic on strings converts them at run time, only numbers
+            // are folded
+            BinOp::Concat => arith::arith(op, &a, &b),
+            _ if is_number(&a) && is_number(&b) => arith::arith(op, &a, &b),
+            _ => return None,
+        };
+        return result.ok().and_then(literal);
+    }
+    let result = match *node {
+        // Parentheses only truncate to one value, which a literal is
+        PrefixExp(ref e) => return constant(e).and_then(literal),
+        UMin(ref e) => match constant(e) {
+            Some(ref v) if is_number(v) => arith::negate(v),
+            _ => return None,
+        },
+        BinNot(ref e) => match constant(e) {
+            Some(ref v) if is_number(v) => arith::bit_not(v),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    result.ok().and_then(literal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exp::parse_exp;
+    use nom::IResult;
+
+    fn folded(input: &str) -> ASTNode {
+        match parse_exp(input.as_bytes()) {
+            IResult::Done(_, mut e) => {
+                fold_constants(&mut e);
+                e
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(folded("60 * 60 * 24"), ast!(Integer, 86400));
+        assert_eq!(folded("1 << 10"), ast!(Integer, 1024));
+        assert_eq!(folded("7 // 2"), ast!(Integer, 3));
+        assert_eq!(folded("-7 // 2"), ast!(Integer, -4));
+        assert_eq!(folded("-7 % 3"), ast!(Integer, 2));
+        assert_eq!(folded("7 / 2"), ast!(Float, 3.5));
+        assert_eq!(folded("4 / 2"), ast!(Float, 2.0));
+        assert_eq!(folded("2 ^ 10"), ast!(Float, 1024.0));
+        assert_eq!(folded("1 + 2.0"), ast!(Float, 3.0));
+        assert_eq!(folded("(1 + 2) * 3"), ast!(Integer, 9));
+        assert_eq!(folded("~0"), ast!(Integer, -1));
+        assert_eq!(folded("3.0 | 4"), ast!(Integer, 7));
+    }
+
+    #[test]
+    fn integers_wrap() {
+        assert_eq!(folded("9223372036854775807 + 1"), ast!(Integer, i64::MIN));
+        assert_eq!(folded("-9223372036854775807 - 2"), ast!(Integer, i64::MAX));
+        assert_eq!(folded("1 << 64"), ast!(Integer, 0));
+    }
+
+    #[test]
+    fn concat_and_comparisons() {
+        assert_eq!(folded("'a' .. 'b' .. 1"), ast!(String, "ab1".into()));
+        // Not text, the interpreter can not concatenate it
+        assert!(matches!(folded("'\\255' .. 1"), Concat(_, _)));
+        assert_eq!(folded("1.5 .. ''"), ast!(String, "1.5".into()));
+        assert_eq!(folded("1 == 1.0"), ast!(Bool, true));
+        assert_eq!(folded("'a' < 'b'"), ast!(Bool, true));
+        assert_eq!(folded("nil ~= false"), ast!(Bool, true));
+        assert_eq!(folded("2 ^ 53 < 2 ^ 53 + 1"), ast!(Bool, false));
+    }
+
+    #[test]
+    fn errors_are_not_folded() {
+        for input in &["1 // 0", "1 % 0", "1.5 | 0", "1 < 'x'", "{} .. 'x'", "'10' + 1", "-'2'",
+                       "1 .. nil", "true + 1"] {
+            let e = folded(input);
+            assert!(e.as_binop().is_some() || matches!(e, UMin(_)),
+                    "{} folded to {:?}", input, e);
+        }
+    }
+
+    #[test]
+    fn non_literal_floats_are_not_folded() {
+        assert_eq!(folded("1 / 0"), astb!(Div, ast!(Integer, 1), ast!(Integer, 0)));
+        assert_eq!(folded("1.0 // 0"), astb!(FDiv, ast!(Float, 1.0), ast!(Integer, 0)));
+        assert_eq!(folded("-0.0"), astb!(UMin, ast!(Float, 0.0)));
+        assert_eq!(folded("0.5 - 0.5"), astb!(Sub, ast!(Float, 0.5), ast!(Float, 0.5)));
+    }
+
+    #[test]
+    fn variables_are_kept() {
+        assert_eq!(folded("x * (2 * 3)"),
+                   astb!(Mul, astb!(PrefixExp, astb!(Var, ast!(Name, "x".into()))), ast!(Integer, 6)));
+        assert_eq!(folded("1 and 2"), astb!(And, ast!(Integer, 1), ast!(Integer, 2)));
+    }
+}
@@ -10,27 +10,47 @@ use ast::ASTNode;
 use ast::ASTNode::*;
 use statement::{parse_retstat, parse_statement};
 use name::{parse_name, parse_namelist};
+#[cfg(feature="luau")]
+use luau::parse_return_annotation;
+
+#[cfg(not(feature="luau"))]
+named!(parse_return_annotation<ASTNode>, fail!());
 
 // TODO: Needs ws! macros
 
 named!(pub parse_functiondef<ASTNode>,
-       do_parse!(tag!("function") >> f: ws!(parse_funcbody) >> (astb!(Function, f))));
+       do_parse!(keyword!("function") >> f: ws!(parse_funcbody) >> (astb!(Function, f))));
 
 named!(pub parse_local_function<ASTNode>, do_parse!(
-           tag!("local")
-        >> ws!(tag!("function"))
-        >> n: parse_name
+           keyword!("local")
+        >> ws!(keyword!("function"))
+        >> n: ws!(parse_name)
         >> f: parse_funcbody
         >> (astb!(NamedFunction, n, f))));
 
+named!(pub parse_function_stat<ASTNode>, do_parse!(
+           keyword!("function")
+        >> n: ws!(parse_funcname)
+        >> f: parse_funcbody
+        >> (astb!(FunctionStat, n, f))));
+
 named!(parse_funcbody<ASTNode>, do_parse!(
            parlist: delimited!(tag!("("), opt!(ws!(parse_parlist)), tag!(")"))
+        >> returns: opt!(complete!(parse_return_annotation))
         >> block: ws!(parse_block)
         >> tag!("end")
-        >> (astb!(FunctionBody, parlist, block))));
+        >> (function_body(parlist, returns, block))));
+
+fn function_body(parlist: Option<ASTNode>, returns: Option<ASTNode>, block: ASTNode) -> ASTNode {
+    match returns {
+        #[cfg(feature="luau")]
+        Some(returns) => astb!(TypedFunctionBody, parlist, returns, block),
+        _ => astb!(FunctionBody, parlist, block),
+    }
+}
 
 // This is here because rustc complains about lack of type annotations
-named!(parse_multiname<Vec<ASTNode>>, many1!(preceded!(ws!(tag!(".")), parse_name)));
+named!(parse_multiname<Vec<ASTNode>>, many1!(complete!(preceded!(ws!(tag!(".")), parse_name))));
 named!(parse_funcname<ASTNode>, do_parse!(
        n: map!(parse_name, Box::new)
     >> m: opt!(complete!(parse_multiname))
@@ -45,11 +65,13 @@ named!(parse_parlist<ASTNode>, do_parse!(
     >> (ASTNode::ParameterList(Box::new(nl), va.is_some()))
 ));
 
-named!(pub parse_block<ASTNode>, do_parse!(
-           s: many0!(complete!(parse_statement))
+// Statements nest blocks without going through parse_exp, so blocks are
+// limited as well
+named!(pub parse_block<ASTNode>, nested!(do_parse!(
+           s: many0!(complete!(ws!(parse_statement)))
         >> rs: opt!(ws!(complete!(parse_retstat)))
         >> (ast!(Block, s, Box::new(rs)))
-));
+)));
 
 #[cfg(test)]
 mod tests {
@@ -63,13 +85,13 @@ mod tests {
 
     ast_test!(parse_parlist_3, parse_parlist, "name , ...",
               ast!(ParameterList, Box::new(Some(ast!(NameList, vec![
-                ast!(Name, "name".into())
+                (ast!(Name, "name".into()), None)
               ]))), true));
 
     ast_test!(parse_parlist_5, parse_parlist, "a,b",
               ast!(ParameterList, Box::new(Some(ast!(NameList, vec![
-                ast!(Name, "a".into()),
-                ast!(Name, "b".into())
+                (ast!(Name, "a".into()), None),
+                (ast!(Name, "b".into()), None)
               ]))), false));
 
 
@@ -89,8 +111,8 @@ mod tests {
     ast_test!(parse_funcbody_1, parse_funcbody, "( a, b ) ; end",
         astb!(FunctionBody,
               Some(ast!(ParameterList, Box::new(Some(ast!(NameList, vec![
-                ast!(Name, "a".into()),
-                ast!(Name, "b".into())
+                (ast!(Name, "a".into()), None),
+                (ast!(Name, "b".into()), None)
               ]))), false)),
               ast!(Block, vec![
                 ast!(EmptyStatement)
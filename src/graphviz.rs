@@ -0,0 +1,267 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Renders an AST as a Graphviz DOT graph
+//!
+//! Every node of the tree gets its own graph node, named after its
+//! `NodeId` in the `Arena` of the tree, and labelled with its kind and its
+//! value for literals and names. Edges are labelled with the field of the
+//! parent they come from and keep the order of the source.
+//!
+//! ```rust
+//! # use nom_lua::graphviz::{render, DotConfig};
+//! let ast = nom_lua::parse_string("1 + 1".as_bytes()).unwrap();
+//! let mut dot = Vec::new();
+//! render(&ast, &DotConfig::default(), &mut dot).unwrap();
+//! let dot = String::from_utf8(dot).unwrap();
+//! assert!(dot.contains("N1 [label=\"Integer\\n1\"];"));
+//! assert!(dot.contains("N0 -> N2 [label=\"right\"];"));
+//! ```
+
+use std::io::{self, Write};
+
+use arena::{Arena, NodeId, NodeKind};
+use printer::quote;
+
+/// How `render` lays out the graph
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DotConfig {
+    /// Draws the nodes of each function body in a box of its own
+    pub cluster_functions: bool,
+}
+
+/// Writes `ast` to `output` as a DOT digraph named `AST`
+pub fn render<W: Write>(ast: &::ASTNode, config: &DotConfig, output: &mut W) -> io::Result<()> {
+    let arena = Arena::from_ast(ast);
+    writeln!(output, "digraph AST {{")?;
+    writeln!(output, "    ordering=out;")?;
+    writeln!(output, "    node [shape=box];")?;
+    nodes(&arena, arena.root(), config, 1, output)?;
+    for id in arena.ids() {
+        for &(field, child) in &arena[id].children {
+            writeln!(output, "    N{} -> N{} [label=\"{}\"];", id.index(), child.index(),
+                     escape(&edge_label(&arena, id, field, child)))?;
+        }
+    }
+    writeln!(output, "}}")
+}
+
+// Nodes belong to the subgraph where they are first named, so they are
+// all declared before the edges
+fn nodes<W: Write>(arena: &Arena, id: NodeId, config: &DotConfig, depth: usize, output: &mut W)
+    -> io::Result<()> {
+    let indent = "    ".repeat(depth);
+    let cluster = config.cluster_functions && is_function_body(arena.kind(id));
+    if cluster {
+        writeln!(output, "{}subgraph cluster_N{} {{", indent, id.index())?;
+        writeln!(output, "{}    label=\"{}\";", indent, escape(&function_name(arena, id)))?;
+    }
+    let depth = if cluster { depth + 1 } else { depth };
+    writeln!(output, "{}N{} [label=\"{}\"];", "    ".repeat(depth), id.index(), escape(&label(arena.kind(id))))?;
+    for child in arena.children(id) {
+        nodes(arena, child, config, depth, output)?;
+    }
+    if cluster {
+        writeln!(output, "{}}}", indent)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature="luau"))]
+fn is_function_body(kind: &NodeKind) -> bool {
+    *kind == NodeKind::FunctionBody
+}
+
+#[cfg(feature="luau")]
+fn is_function_body(kind: &NodeKind) -> bool {
+    *kind == NodeKind::FunctionBody || *kind == NodeKind::TypedFunctionBody
+}
+
+/// The title of the cluster of a function body, with the name the function
+/// is declared with
+fn function_name(arena: &Arena, body: NodeId) -> String {
+    let parent = match arena.parent(body) {
+        Some(parent) => parent,
+        None => return "function".to_string(),
+    };
+    let names = |id: NodeId| arena.children(id).filter_map(|c| match *arena.kind(c) {
+        NodeKind::Name(ref name) => Some(name.clone()),
+        _ => None,
+    }).collect::<Vec<_>>();
+    match *arena.kind(parent) {
+        NodeKind::NamedFunction => format!("local function {}", names(parent).join("")),
+        NodeKind::FunctionStat => match arena.child(parent, "name") {
+            Some(name) => {
+                let mut path = names(name).join(".");
+                if arena.child(name, "method").is_some() {
+                    // The method is the last name
+                    if let Some(dot) = path.rfind('.') {
+                        path.replace_range(dot..dot + 1, ":");
+                    }
+                }
+                format!("function {}", path)
+            }
+            None => "function".to_string(),
+        },
+        _ => "function".to_string(),
+    }
+}
+
+/// The label of a node, its kind followed by its value on a second line
+pub fn label(kind: &NodeKind) -> String {
+    match *kind {
+        NodeKind::Integer(a) => format!("Integer\n{}", a),
+        NodeKind::Float(a) => format!("Float\n{:?}", a),
+        NodeKind::Bool(a) => format!("Bool\n{}", a),
+        NodeKind::String(ref a) => format!("String\n{}", quote(a)),
+        NodeKind::Label(ref a) => format!("Label\n{}", a),
+        NodeKind::Name(ref a) => format!("Name\n{}", a),
+        NodeKind::NameList(ref attribs) if attribs.iter().any(|a| a.is_some()) => {
+            let attribs: Vec<_> = attribs.iter()
+                .map(|a| a.map_or_else(|| "-".to_string(), |a| a.to_string()))
+                .collect();
+            format!("NameList\n{}", attribs.join(", "))
+        }
+        NodeKind::NameList(_) => "NameList".to_string(),
+        NodeKind::ParameterList(true) => "ParameterList\n...".to_string(),
+        NodeKind::ParameterList(false) => "ParameterList".to_string(),
+        #[cfg(feature="luau")]
+        NodeKind::TypeAlias(true) => "TypeAlias\nexport".to_string(),
+        #[cfg(feature="luau")]
+        NodeKind::TypeAlias(false) => "TypeAlias".to_string(),
+        #[cfg(feature="luau")]
+        NodeKind::CompoundAssign(op) => format!("CompoundAssign\n{}=", op),
+        #[cfg(feature="luau")]
+        NodeKind::TypeName(ref a) => format!("TypeName\n{}", a),
+        ref kind => format!("{:?}", kind),
+    }
+}
+
+/// The label of the edge from `parent` to `child`, the field it is stored
+/// in along with its position for lists
+pub fn edge_label(arena: &Arena, parent: NodeId, field: &str, child: NodeId) -> String {
+    let siblings: Vec<NodeId> = arena[parent].children.iter()
+        .filter(|&&(f, _)| f == field)
+        .map(|&(_, c)| c)
+        .collect();
+    if siblings.len() > 1 || is_list(field) {
+        let position = siblings.iter().position(|&c| c == child).unwrap_or(0);
+        format!("{}[{}]", field, position)
+    } else {
+        field.to_string()
+    }
+}
+
+// The fields holding any number of children
+fn is_list(field: &str) -> bool {
+    matches!(field, "stat" | "item" | "field" | "generic" | "arg" | "param")
+}
+
+/// Escapes `text` for a double-quoted DOT string, line breaks become `\n`
+pub fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{LuaVersion, ParserConfig};
+    use parse_block_with;
+
+    fn dot(source: &str, config: &DotConfig) -> String {
+        let parser = ParserConfig { version: LuaVersion::Lua54, ..ParserConfig::default() };
+        let ast = parse_block_with(source.as_bytes(), &parser).unwrap();
+        let mut output = Vec::new();
+        render(&ast, config, &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn unique_nodes() {
+        let dot = dot("return 1 + 1", &DotConfig::default());
+        assert_eq!(dot, "digraph AST {
+    ordering=out;
+    node [shape=box];
+    N0 [label=\"Block\"];
+    N1 [label=\"RetStat\"];
+    N2 [label=\"ExpList\"];
+    N3 [label=\"Add\"];
+    N4 [label=\"Integer\\n1\"];
+    N5 [label=\"Integer\\n1\"];
+    N0 -> N1 [label=\"ret\"];
+    N1 -> N2 [label=\"explist\"];
+    N2 -> N3 [label=\"item[0]\"];
+    N3 -> N4 [label=\"left\"];
+    N3 -> N5 [label=\"right\"];
+}
+");
+    }
+
+    #[test]
+    fn lists() {
+        let dot = dot("local a <const>, b = 1, 2.0 f(a) g()", &DotConfig::default());
+        assert!(dot.contains("[label=\"NameList\\n<const>, -\"]"));
+        assert!(dot.contains("[label=\"Float\\n2.0\"]"));
+        assert!(dot.contains("N0 -> N1 [label=\"stat[0]\"];"));
+        assert_eq!(dot.matches("label=\"stat[").count(), 3);
+        assert_eq!(dot.matches("label=\"item[").count(), 5);
+    }
+
+    #[test]
+    fn escaped_labels() {
+        let dot = dot("x = 'say \"hi\"\\n\\\\' ..  'é'", &DotConfig::default());
+        assert!(dot.contains(r#"[label="String\n\"say \\\"hi\\\"\\n\\\\\""]"#), "{}", dot);
+        assert!(dot.contains("[label=\"String\\n\\\"é\\\"\"]"));
+    }
+
+    #[test]
+    fn clusters() {
+        let source = "local function f() return function() end end function a.b:c() end";
+        let config = DotConfig { cluster_functions: true };
+        let dot = dot(source, &config);
+        assert_eq!(dot.matches("subgraph cluster_").count(), 3);
+        assert!(dot.contains("label=\"local function f\";"));
+        assert!(dot.contains("label=\"function a.b:c\";"));
+        assert!(dot.contains("        subgraph cluster_"));
+        assert!(!super::tests::dot(source, &DotConfig::default()).contains("subgraph"));
+    }
+
+    // The dot crate renders an Arena with the same labels
+    #[test]
+    fn dot_traits() {
+        let ast = ::parse_string(r"'a\\b' .. x".as_bytes()).unwrap();
+        let mut output = Vec::new();
+        ::dot::render(&Arena::from_ast(&ast), &mut output).unwrap();
+        let dot = String::from_utf8(output).unwrap();
+        assert!(dot.contains(r#"N1[label="String\n\"a\\\\b\""];"#), "{}", dot);
+        assert!(dot.contains(r#"N0 -> N1[label="left"];"#), "{}", dot);
+    }
+
+    // Every kind of node renders, the fuzz corpus has them all
+    #[test]
+    fn corpus() {
+        let dir = ::std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus/parse_chunk");
+        for entry in ::std::fs::read_dir(dir).unwrap() {
+            let source = ::std::fs::read(entry.unwrap().path()).unwrap();
+            if let Ok(ast) = parse_block_with(&source, &ParserConfig::default()) {
+                let config = DotConfig { cluster_functions: true };
+                render(&ast, &config, &mut Vec::new()).unwrap();
+            }
+        }
+    }
+}
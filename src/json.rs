@@ -0,0 +1,321 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Reads and writes ASTs as JSON documents
+//!
+//! A document is an object with the version of its schema, the tree and,
+//! when the source was given, the spans of its nodes:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "ast": {"type": "Block", "value": [[...], null]},
+//!   "spans": [{"start": 0, "end": 12}, null, ...]
+//! }
+//! ```
+//!
+//! Each node is an object whose `type` is the name of its `ASTNode`
+//! variant. Its fields go in `value`: the field itself for variants with a
+//! single one, an array of them for the others and nothing for `Nil`,
+//! `Break` and the other variants without fields. Optional fields are
+//! `null` when absent, `Float` values that JSON can not hold are the
+//! strings `"inf"`, `"-inf"` and `"nan"`.
+//!
+//! `spans` has an entry for every node, in the order a pre-order walk of
+//! `ast` meets them, where the fields of a node come in the order of its
+//! `value`. It is the order of the `NodeId`s of an `Arena`. Nodes the
+//! source gives no position for have `null`, see `span::spans`.
+//!
+//! Version 1 is the schema described here. A change to the variants of
+//! `ASTNode` or to their fields comes with a new version, and `from_json`
+//! rejects the versions it does not know.
+//!
+//! ```rust
+//! # use nom_lua::json::{from_json, to_json};
+//! # use nom_lua::{parse_block_with, ParserConfig};
+//! let ast = parse_block_with(b"return 1", &ParserConfig::default()).unwrap();
+//! let json = to_json(&ast);
+//! assert_eq!(json, r#"{"version":1,"ast":{"type":"Block","value":[[],{"type":"RetStat","value":{"type":"ExpList","value":[{"type":"Integer","value":1}]}}]}}"#);
+//! assert_eq!(from_json(&json).unwrap(), ast);
+//! ```
+
+use std::error;
+use std::fmt::{self, Display, Formatter};
+
+use serde::Deserialize;
+use serde_json;
+
+use arena::Arena;
+use ast::ASTNode;
+use span::{spans, Span};
+
+/// The version of the schema written by `to_json`
+pub const VERSION: u64 = 1;
+
+/// The deepest nesting of arrays and objects `from_document` reads
+///
+/// Reading recurses once per level, this keeps it within the 2MB stack of
+/// a spawned thread in debug builds. Each binary operator of a chain like
+/// `1 + 1 + ...` takes two levels.
+pub const MAX_DEPTH: usize = 512;
+
+/// A JSON document read by `from_document`
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Document {
+    pub version: u64,
+    pub ast: ASTNode,
+    /// The span of each node in pre-order, when the document has them
+    #[serde(default)]
+    pub spans: Option<Vec<Option<Span>>>,
+}
+
+#[derive(Serialize)]
+struct Output<'a> {
+    version: u64,
+    ast: &'a ASTNode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spans: Option<Vec<Option<Span>>>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    version: u64,
+}
+
+/// Why a document could not be read
+#[derive(Debug)]
+pub enum JsonError {
+    /// The text is not a document of the schema
+    Invalid(serde_json::Error),
+    /// The document has a version of the schema this crate does not read
+    Version(u64),
+    /// The document nests arrays and objects deeper than `MAX_DEPTH`
+    TooDeeplyNested(usize),
+}
+
+impl Display for JsonError {
+    fn fmt(&self, format: &mut Formatter) -> fmt::Result {
+        match *self {
+            JsonError::Invalid(ref e) => write!(format, "invalid AST document: {}", e),
+            JsonError::Version(v) => write!(format, "unsupported AST schema version {} (expected {})", v, VERSION),
+            JsonError::TooDeeplyNested(max) => write!(format, "document nested deeper than {} levels", max),
+        }
+    }
+}
+
+impl error::Error for JsonError {}
+
+impl From<serde_json::Error> for JsonError {
+    fn from(e: serde_json::Error) -> JsonError {
+        JsonError::Invalid(e)
+    }
+}
+
+fn write(ast: &ASTNode, spans: Option<Vec<Option<Span>>>) -> String {
+    let output = Output { version: VERSION, ast, spans };
+    // Floats are the only values JSON can not hold, and they are written
+    // as strings when they are not finite
+    serde_json::to_string(&output).expect("ASTs always serialize")
+}
+
+/// Writes `ast` as a document without spans
+pub fn to_json(ast: &ASTNode) -> String {
+    write(ast, None)
+}
+
+/// Writes `ast`, which was parsed from `source`, as a document with the
+/// spans of its nodes
+pub fn to_json_with_spans(ast: &ASTNode, source: &[u8]) -> String {
+    let arena = Arena::from_ast(ast);
+    let table = spans(source, &arena);
+    write(ast, Some(arena.ids().map(|id| table.get(id).cloned()).collect()))
+}
+
+/// Reads a whole document
+pub fn from_document(json: &str) -> Result<Document, JsonError> {
+    if depth(json) > MAX_DEPTH {
+        return Err(JsonError::TooDeeplyNested(MAX_DEPTH));
+    }
+    // serde_json stops at 128 levels, which deep trees easily reach
+    let mut deserializer = serde_json::Deserializer::from_str(json);
+    deserializer.disable_recursion_limit();
+    let header = Header::deserialize(&mut deserializer)?;
+    if header.version != VERSION {
+        return Err(JsonError::Version(header.version));
+    }
+    let mut deserializer = serde_json::Deserializer::from_str(json);
+    deserializer.disable_recursion_limit();
+    let document = Document::deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(document)
+}
+
+// The deepest nesting of arrays and objects in `json`, which may be invalid
+fn depth(json: &str) -> usize {
+    let (mut depth, mut max) = (0usize, 0);
+    let (mut string, mut escaped) = (false, false);
+    for b in json.bytes() {
+        match b {
+            _ if escaped => escaped = false,
+            b'\\' if string => escaped = true,
+            b'"' => string = !string,
+            b'[' | b'{' if !string => {
+                depth += 1;
+                max = max.max(depth);
+            }
+            b']' | b'}' if !string => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    max
+}
+
+/// Reads the tree of a document
+pub fn from_json(json: &str) -> Result<ASTNode, JsonError> {
+    from_document(json).map(|document| document.ast)
+}
+
+/// Writes the non-finite floats of `Float` nodes as strings
+pub(crate) mod float {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Float {
+        Number(f64),
+        Text(String),
+    }
+
+    pub fn serialize<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        if value.is_finite() {
+            serializer.serialize_f64(*value)
+        } else if value.is_nan() {
+            serializer.serialize_str("nan")
+        } else if *value > 0.0 {
+            serializer.serialize_str("inf")
+        } else {
+            serializer.serialize_str("-inf")
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        match Float::deserialize(deserializer)? {
+            Float::Number(value) => Ok(value),
+            Float::Text(ref text) if text == "inf" => Ok(f64::INFINITY),
+            Float::Text(ref text) if text == "-inf" => Ok(f64::NEG_INFINITY),
+            Float::Text(ref text) if text == "nan" => Ok(f64::NAN),
+            Float::Text(text) => Err(D::Error::custom(format!("invalid float {:?}", text))),
+        }
+    }
+}
+
+/// Writes the bytes of `String` nodes as a JSON string when they are UTF-8,
+/// and as an array of numbers otherwise
+pub(crate) mod bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::str;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Bytes {
+        Text(String),
+        Raw(Vec<u8>),
+    }
+
+    pub fn serialize<S: Serializer>(value: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match str::from_utf8(value) {
+            Ok(text) => serializer.serialize_str(text),
+            Err(_) => serializer.collect_seq(value),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        Ok(match Bytes::deserialize(deserializer)? {
+            Bytes::Text(text) => text.into_bytes(),
+            Bytes::Raw(bytes) => bytes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast::ASTNode::*;
+    use config::{LuaVersion, ParserConfig};
+    use parse_block_with;
+
+    fn parse(source: &str) -> ASTNode {
+        let config = ParserConfig { version: LuaVersion::Lua54, ..ParserConfig::default() };
+        parse_block_with(source.as_bytes(), &config).unwrap()
+    }
+
+    #[test]
+    fn nodes() {
+        assert_eq!(to_json(&Nil), r#"{"version":1,"ast":{"type":"Nil"}}"#);
+        assert_eq!(to_json(&ast!(Name, "x".to_string())), r#"{"version":1,"ast":{"type":"Name","value":"x"}}"#);
+        let local = parse("local a <const> = 1.5");
+        let json = to_json(&local);
+        assert!(json.contains(r#"{"type":"NameList","value":[[{"type":"Name","value":"a"},"Const"]]}"#), "{}", json);
+        assert!(json.contains(r#"{"type":"Float","value":1.5}"#));
+        assert_eq!(from_json(&json).unwrap(), local);
+    }
+
+    #[test]
+    fn floats() {
+        for &value in &[f64::INFINITY, f64::NEG_INFINITY, -0.0, 1e300] {
+            assert_eq!(from_json(&to_json(&Float(value))).unwrap(), Float(value));
+        }
+        assert!(to_json(&Float(f64::NAN)).contains(r#""value":"nan""#));
+        match from_json(&to_json(&Float(f64::NAN))).unwrap() {
+            Float(value) => assert!(value.is_nan()),
+            node => panic!("{:?}", node),
+        }
+        let json = r#"{"version":1,"ast":{"type":"Float","value":2}}"#;
+        assert_eq!(from_json(json).unwrap(), Float(2.0));
+        let json = r#"{"version":1,"ast":{"type":"Float","value":"big"}}"#;
+        assert!(from_json(json).is_err());
+    }
+
+    #[test]
+    fn spans() {
+        let source = "local x = 1";
+        let document = from_document(&to_json_with_spans(&parse(source), source.as_bytes())).unwrap();
+        let spans = document.spans.unwrap();
+        assert_eq!(spans.len(), Arena::from_ast(&document.ast).len());
+        assert_eq!(spans[0], Some(Span { start: 0, end: 11 }));
+        assert_eq!(spans.last(), Some(&Some(Span { start: 10, end: 11 })));
+        assert_eq!(from_document(&to_json(&document.ast)).unwrap().spans, None);
+    }
+
+    #[test]
+    fn deep_trees() {
+        let chain = parse(&format!("return 1{}", " + 1".repeat(100)));
+        assert_eq!(from_json(&to_json(&chain)).unwrap(), chain);
+        assert_eq!(depth(r#"{"a":"[{\"[","b":[[]]}"#), 3);
+    }
+
+    #[test]
+    fn errors() {
+        match from_json(r#"{"version":2,"ast":{"type":"Nil"}}"#) {
+            Err(JsonError::Version(2)) => {}
+            result => panic!("{:?}", result),
+        }
+        assert_eq!(from_json(r#"{"version":2}"#).unwrap_err().to_string(),
+                   "unsupported AST schema version 2 (expected 1)");
+        assert!(from_json(r#"{"ast":{"type":"Nil"}}"#).is_err());
+        assert!(from_json(r#"{"version":1,"ast":{"type":"Add","value":[]}}"#).is_err());
+        assert!(from_json(r#"{"version":1,"ast":{"type":"Unknown"}}"#).is_err());
+        assert!(from_json(r#"{"version":1,"ast":{"type":"Nil"}} x"#).is_err());
+        let deep = format!("{}{}", "[".repeat(MAX_DEPTH + 1), "]".repeat(MAX_DEPTH + 1));
+        match from_json(&deep) {
+            Err(JsonError::TooDeeplyNested(MAX_DEPTH)) => {}
+            result => panic!("{:?}", result),
+        }
+    }
+}
@@ -19,7 +19,13 @@ extern crate nom;
 #[cfg(feature="graphviz")]
 extern crate dot;
 
-#[cfg(test)]
+#[cfg(feature="serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature="serde")]
+extern crate serde_json;
+
+#[cfg(any(test, feature="quickcheck"))]
 #[macro_use]
 extern crate quickcheck;
 
@@ -35,6 +41,9 @@ use std::io::Read;
 mod macros;
 
 pub mod ast;
+pub mod arena;
+#[cfg(feature="quickcheck")]
+pub mod arbitrary;
 pub mod op;
 pub mod number;
 pub mod exp;
@@ -44,12 +53,79 @@ pub mod var;
 pub mod field;
 pub mod statement;
 pub mod function;
+pub mod config;
+pub mod eval;
+pub mod fold;
+pub mod printer;
+pub mod lint;
+pub mod validate;
+pub mod resolve;
+pub mod space;
+pub mod span;
+pub mod sexp;
+pub mod bytecode;
+pub mod error;
+#[cfg(feature="graphviz")]
+pub mod graphviz;
+#[cfg(feature="serde")]
+pub mod json;
+#[cfg(feature="serde")]
+pub mod luaparse;
+#[cfg(feature="luau")]
+pub mod luau;
 
 pub use nom::IResult;
+pub use config::{LuaVersion, ParserConfig};
+pub use error::Error;
 
-//named!(pub parse_chunk<ASTNode>, ws!(parse_block));
+//named!(chunk<ASTNode>, ws!(parse_block));
 use exp::parse_exp;
-named!(pub parse_chunk<ASTNode>, dbg_dmp!(ws!(parse_exp)));
+named!(chunk<ASTNode>, ws!(parse_exp));
+
+/// Parses a chunk with the default `ParserConfig`
+pub fn parse_chunk(input: &[u8]) -> IResult<&[u8], ASTNode> {
+    config::with_config(&ParserConfig::default(), || chunk(input))
+}
+
+/// Parses a whole chunk with `config`, failing if any input is left over
+pub fn parse_chunk_with(input: &[u8], config: &ParserConfig) -> Result<ASTNode, Error> {
+    parse_all(chunk, input, config)
+}
+
+named!(block<ASTNode>, ws!(parse_block));
+
+/// Parses a whole block, such as the contents of a Lua file, with `config`
+pub fn parse_block_with(input: &[u8], config: &ParserConfig) -> Result<ASTNode, Error> {
+    parse_all(block, input, config)
+}
+
+fn parse_all(parser: fn(&[u8]) -> IResult<&[u8], ASTNode>, input: &[u8], config: &ParserConfig)
+    -> Result<ASTNode, Error> {
+    let result = config::with_config(config, || parser(input));
+    if config::too_deep() {
+        return Err(Error::TooDeeplyNested(config.max_depth));
+    }
+    // The input left where the parse failed
+    let rest = match result {
+        IResult::Done(rest, ast) => if rest.is_empty() {
+            return Ok(ast);
+        } else {
+            rest
+        },
+        // The parser failed without consuming anything but whitespace
+        IResult::Error(_) => config::skip_space(input),
+        IResult::Incomplete(_) => &input[input.len()..],
+    };
+    // Syntax from a newer version makes the parse stop where it is used, so
+    // report it instead of the syntax error it caused
+    if let Some((feature, version)) = config::unsupported_at(rest) {
+        return Err(Error::RequiresVersion(feature, version));
+    }
+    match config::rejected_at(rest) {
+        Some((message, left)) => Err(Error::Invalid(input.len() - left, message)),
+        None => Err(Error::Syntax(input.len() - rest.len())),
+    }
+}
 
 // TODO: Implement our own Error type
 pub fn parse_string<'a, T: Into<&'a [u8]>>(s: T) -> Option<ASTNode> {
@@ -61,7 +137,9 @@ pub fn parse_string<'a, T: Into<&'a [u8]>>(s: T) -> Option<ASTNode> {
 
 pub fn parse<T: Read>(mut s: T) -> Option<ASTNode> {
     let mut buf = vec![];
-    s.read_to_end(&mut buf);
+    if s.read_to_end(&mut buf).is_err() {
+        return None;
+    }
     buf.pop(); //Remove EOF
     match parse_chunk(&buf) {
         IResult::Done(_, a) => Some(a),
@@ -0,0 +1,262 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Static checks on Lua code
+//!
+//! A `Linter` runs a list of `Rule`s over an `Arena` and turns what they
+//! find into `Diagnostic`s, with the severity configured for each rule and
+//! the span of the offending node. The built-in rules are listed in
+//! `rules`. Other rules are added with `Linter::add_rule`.
+//!
+//! ```rust
+//! # use nom_lua::lint::{LintConfig, Linter, Severity};
+//! # use nom_lua::ParserConfig;
+//! let mut config = LintConfig::default();
+//! config.severities.insert("shadowed-local".into(), Severity::Allow);
+//! config.globals.insert("vim".into());
+//! let linter = Linter::new(config);
+//! let source = b"local x = 1\nreturn y\n";
+//! for diagnostic in linter.lint(source, &ParserConfig::default()).unwrap() {
+//!     let (line, column) = diagnostic.span.unwrap().line_column(source);
+//!     println!("{}:{}: {}", line, column, diagnostic);
+//! }
+//! ```
+
+pub mod rules;
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use arena::{Arena, NodeId};
+use config::ParserConfig;
+use error::Error;
+use resolve::{resolve, Resolution};
+use span::{spans, Span};
+use parse_block_with;
+
+/// How a rule is reported, `Allow` turns it off
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Allow,
+    Warning,
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, format: &mut Formatter) -> fmt::Result {
+        match *self {
+            Severity::Allow => write!(format, "allow"),
+            Severity::Warning => write!(format, "warning"),
+            Severity::Error => write!(format, "error"),
+        }
+    }
+}
+
+/// A problem found by a rule
+#[derive(Clone, Debug, PartialEq)]
+pub struct Finding {
+    pub node: NodeId,
+    pub message: String,
+}
+
+/// What a rule can look at
+pub struct Context<'a> {
+    pub arena: &'a Arena,
+    pub resolution: &'a Resolution,
+    pub config: &'a LintConfig,
+}
+
+pub trait Rule {
+    /// The name of the rule in diagnostics and in `LintConfig::severities`
+    fn id(&self) -> &'static str;
+
+    fn default_severity(&self) -> Severity {
+        Severity::Warning
+    }
+
+    fn check(&self, context: &Context, findings: &mut Vec<Finding>);
+}
+
+/// A finding of a rule, ready to be shown
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub rule: &'static str,
+    pub severity: Severity,
+    pub message: String,
+    pub node: NodeId,
+    /// Missing when the source was not given or the node has no tokens
+    pub span: Option<Span>,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, format: &mut Formatter) -> fmt::Result {
+        write!(format, "{}: {} [{}]", self.severity, self.message, self.rule)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LintConfig {
+    /// Overrides the default severity of rules, by rule ID
+    pub severities: HashMap<String, Severity>,
+    /// The globals that may be used without being assigned by the chunk,
+    /// the Lua 5.3 standard library by default
+    pub globals: BTreeSet<String>,
+}
+
+/// The globals of the Lua 5.3 standard library
+pub const STANDARD_GLOBALS: &[&str] = &[
+    "_G", "_VERSION", "assert", "collectgarbage", "coroutine", "debug", "dofile", "error",
+    "getmetatable", "io", "ipairs", "load", "loadfile", "math", "next", "os", "package",
+    "pairs", "pcall", "print", "rawequal", "rawget", "rawlen", "rawset", "require", "select",
+    "setmetatable", "string", "table", "tonumber", "tostring", "type", "utf8", "xpcall",
+];
+
+impl Default for LintConfig {
+    fn default() -> LintConfig {
+        LintConfig {
+            severities: HashMap::new(),
+            globals: STANDARD_GLOBALS.iter().map(|g| g.to_string()).collect(),
+        }
+    }
+}
+
+pub struct Linter {
+    config: LintConfig,
+    rules: Vec<Box<dyn Rule>>,
+}
+
+impl Linter {
+    /// A linter running the built-in rules
+    pub fn new(config: LintConfig) -> Linter {
+        Linter { config, rules: rules::builtin() }
+    }
+
+    pub fn config(&self) -> &LintConfig {
+        &self.config
+    }
+
+    pub fn add_rule<R: Rule + 'static>(&mut self, rule: R) {
+        self.rules.push(Box::new(rule));
+    }
+
+    pub fn rules(&self) -> impl Iterator<Item = &dyn Rule> {
+        self.rules.iter().map(|r| &**r)
+    }
+
+    /// The severity of `rule` after configuration
+    pub fn severity(&self, rule: &dyn Rule) -> Severity {
+        self.config.severities.get(rule.id()).cloned().unwrap_or_else(|| rule.default_severity())
+    }
+
+    /// Checks a block, `source` is used to find the spans of the diagnostics
+    pub fn check(&self, arena: &Arena, source: Option<&[u8]>) -> Vec<Diagnostic> {
+        let resolution = resolve(arena);
+        let context = Context { arena, resolution: &resolution, config: &self.config };
+        let spans = source.map(|s| spans(s, arena));
+        let mut diagnostics = Vec::new();
+        for rule in &self.rules {
+            let severity = self.severity(&**rule);
+            if severity == Severity::Allow {
+                continue;
+            }
+            let mut findings = Vec::new();
+            rule.check(&context, &mut findings);
+            diagnostics.extend(findings.into_iter().map(|f| Diagnostic {
+                rule: rule.id(),
+                severity,
+                span: spans.as_ref().and_then(|s| s.get(f.node).cloned()),
+                message: f.message,
+                node: f.node,
+            }));
+        }
+        diagnostics.sort_by_key(|d| (d.span.map(|s| s.start), d.node));
+        diagnostics
+    }
+
+    /// Parses and checks a block
+    pub fn lint(&self, source: &[u8], config: &ParserConfig) -> Result<Vec<Diagnostic>, Error> {
+        let block = parse_block_with(source, config)?;
+        Ok(self.check(&Arena::from_ast(&block), Some(source)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arena::NodeKind;
+
+    fn lint(linter: &Linter, source: &str) -> Vec<(&'static str, Severity, String)> {
+        linter.lint(source.as_bytes(), &ParserConfig::default()).unwrap()
+            .into_iter()
+            .map(|d| (d.rule, d.severity, source[d.span.unwrap().start..d.span.unwrap().end].to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn diagnostics_have_spans() {
+        let linter = Linter::new(LintConfig::default());
+        let source = "local x = 1\nprint(y)\nlocal z = {a = 1, a = 2}\nreturn z\n";
+        assert_eq!(lint(&linter, source), vec![
+            ("unused-local", Severity::Warning, "x".to_string()),
+            ("undefined-global", Severity::Warning, "y".to_string()),
+            ("duplicate-key", Severity::Warning, "a = 2".to_string()),
+        ]);
+        let diagnostics = linter.lint(source.as_bytes(), &ParserConfig::default()).unwrap();
+        assert_eq!(diagnostics[1].span.unwrap().line_column(source.as_bytes()), (2, 7));
+        assert_eq!(diagnostics[1].to_string(),
+                   "warning: accessing undefined variable 'y' [undefined-global]");
+    }
+
+    #[test]
+    fn severities_are_configurable() {
+        let mut config = LintConfig::default();
+        config.severities.insert("unused-local".into(), Severity::Allow);
+        config.severities.insert("undefined-global".into(), Severity::Error);
+        let linter = Linter::new(config);
+        assert_eq!(lint(&linter, "local x = y"),
+                   vec![("undefined-global", Severity::Error, "y".to_string())]);
+    }
+
+    #[test]
+    fn globals_are_configurable() {
+        let mut config = LintConfig::default();
+        config.globals.insert("vim".into());
+        let linter = Linter::new(config);
+        assert_eq!(lint(&linter, "vim.print(vim)"), vec![]);
+    }
+
+    struct NoWhile;
+
+    impl Rule for NoWhile {
+        fn id(&self) -> &'static str {
+            "no-while"
+        }
+
+        fn default_severity(&self) -> Severity {
+            Severity::Error
+        }
+
+        fn check(&self, context: &Context, findings: &mut Vec<Finding>) {
+            for id in context.arena.ids() {
+                if *context.arena.kind(id) == NodeKind::While {
+                    findings.push(Finding { node: id, message: "while loop".into() });
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn custom_rules() {
+        let mut linter = Linter::new(LintConfig::default());
+        linter.add_rule(NoWhile);
+        assert_eq!(lint(&linter, "while true do end"),
+                   vec![("no-while", Severity::Error, "while true do end".to_string())]);
+        assert!(linter.rules().any(|r| r.id() == "no-while"));
+    }
+}
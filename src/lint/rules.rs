@@ -0,0 +1,443 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The built-in rules
+//!
+//! | ID                  | Severity | Reports                                          |
+//! |---------------------|----------|--------------------------------------------------|
+//! | `unused-local`      | warning  | locals, local functions and loop variables never used |
+//! | `shadowed-local`    | warning  | locals hiding a visible local of the same name   |
+//! | `undefined-global`  | warning  | globals read but neither known nor assigned       |
+//! | `global-assignment` | warning  | assignments to globals that are not known         |
+//! | `unreachable-code`  | warning  | statements after `return`, `break` or `goto`      |
+//! | `duplicate-key`     | warning  | constant keys given twice in a table constructor  |
+//! | `goto-into-scope`   | error    | `goto` jumping forward into the scope of a local  |
+//! | `unused-label`      | warning  | labels no `goto` jumps to                         |
+//!
+//! Names starting with `_` are never reported as unused. Known globals are
+//! listed in `LintConfig::globals`.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use arena::{Arena, NodeId, NodeKind};
+use ast::Attrib;
+use eval::arith::to_integer;
+use eval::Value;
+use resolve::{Binding, DeclKind};
+use validate::{entered_local, target};
+use super::{Context, Finding, Rule, Severity};
+
+/// Every built-in rule
+pub fn builtin() -> Vec<Box<dyn Rule>> {
+    vec![
+        Box::new(UnusedLocal),
+        Box::new(ShadowedLocal),
+        Box::new(UndefinedGlobal),
+        Box::new(GlobalAssignment),
+        Box::new(UnreachableCode),
+        Box::new(DuplicateKey),
+        Box::new(GotoIntoScope),
+        Box::new(UnusedLabel),
+    ]
+}
+
+fn name(arena: &Arena, id: NodeId) -> &str {
+    match *arena.kind(id) {
+        NodeKind::Name(ref name) | NodeKind::Label(ref name) => name,
+        _ => "",
+    }
+}
+
+// Whether the `Name` node `id` is assigned to rather than read
+fn is_assignment(arena: &Arena, id: NodeId) -> bool {
+    let parent = match arena.parent(id) {
+        Some(parent) => parent,
+        None => return false,
+    };
+    match *arena.kind(parent) {
+        NodeKind::Var => match arena.parent(parent).map(|p| arena.kind(p)) {
+            Some(&NodeKind::VarList) => true,
+            #[cfg(feature="luau")]
+            Some(&NodeKind::CompoundAssign(_)) => true,
+            _ => false,
+        },
+        // `function f()` assigns `f`, `function t.f()` only reads `t`
+        NodeKind::FunctionName => arena.children(parent).count() == 1,
+        _ => false,
+    }
+}
+
+pub struct UnusedLocal;
+
+impl Rule for UnusedLocal {
+    fn id(&self) -> &'static str {
+        "unused-local"
+    }
+
+    fn check(&self, context: &Context, findings: &mut Vec<Finding>) {
+        for d in context.resolution.declarations() {
+            if !d.uses.is_empty() || d.name.starts_with('_') || d.attrib == Some(Attrib::Close) {
+                continue;
+            }
+            let what = match d.kind {
+                DeclKind::Local => "variable",
+                DeclKind::LocalFunction => "function",
+                DeclKind::ForVariable => "loop variable",
+                DeclKind::Parameter | DeclKind::SelfParameter => continue,
+            };
+            findings.push(Finding { node: d.node, message: format!("unused {} '{}'", what, d.name) });
+        }
+    }
+}
+
+pub struct ShadowedLocal;
+
+impl Rule for ShadowedLocal {
+    fn id(&self) -> &'static str {
+        "shadowed-local"
+    }
+
+    fn check(&self, context: &Context, findings: &mut Vec<Finding>) {
+        let resolution = context.resolution;
+        for scope in resolution.scopes() {
+            for &id in &scope.declarations {
+                let d = resolution.declaration(id);
+                if d.kind == DeclKind::SelfParameter || d.name == "_" {
+                    continue;
+                }
+                // Declarations are numbered in source order, so the ones
+                // before `id` in enclosing scopes are visible from it
+                let mut outer = Some(d.scope);
+                while let Some(s) = outer {
+                    let shadowed = resolution.scope(s).declarations.iter().rev()
+                        .any(|&e| e < id && resolution.declaration(e).name == d.name);
+                    if shadowed {
+                        let what = if resolution.function(s) == resolution.function(d.scope) {
+                            "local"
+                        } else {
+                            "upvalue"
+                        };
+                        findings.push(Finding {
+                            node: d.node,
+                            message: format!("shadowing {} '{}'", what, d.name),
+                        });
+                        break;
+                    }
+                    outer = resolution.scope(s).parent;
+                }
+            }
+        }
+    }
+}
+
+// The globals the chunk reads, or assigns if `assigned`
+fn globals<'a>(context: &'a Context, assigned: bool) -> impl Iterator<Item = (NodeId, &'a str)> + 'a {
+    let arena = context.arena;
+    context.resolution.bindings().iter()
+        .filter(|&(_, &b)| b == Binding::Global)
+        .filter(move |&(id, _)| is_assignment(arena, id) == assigned)
+        .map(move |(id, _)| (id, name(arena, id)))
+}
+
+pub struct UndefinedGlobal;
+
+impl Rule for UndefinedGlobal {
+    fn id(&self) -> &'static str {
+        "undefined-global"
+    }
+
+    fn check(&self, context: &Context, findings: &mut Vec<Finding>) {
+        let assigned: HashSet<_> = globals(context, true).map(|(_, name)| name).collect();
+        for (id, name) in globals(context, false) {
+            if !assigned.contains(name) && !context.config.globals.contains(name) {
+                findings.push(Finding { node: id, message: format!("accessing undefined variable '{}'", name) });
+            }
+        }
+    }
+}
+
+pub struct GlobalAssignment;
+
+impl Rule for GlobalAssignment {
+    fn id(&self) -> &'static str {
+        "global-assignment"
+    }
+
+    fn check(&self, context: &Context, findings: &mut Vec<Finding>) {
+        for (id, name) in globals(context, true) {
+            if !context.config.globals.contains(name) {
+                findings.push(Finding {
+                    node: id,
+                    message: format!("setting non-standard global variable '{}'", name),
+                });
+            }
+        }
+    }
+}
+
+// Whether control never leaves the statement `id` at its end
+fn exits(arena: &Arena, id: NodeId) -> bool {
+    match *arena.kind(id) {
+        NodeKind::Break | NodeKind::Goto | NodeKind::RetStat => true,
+        #[cfg(feature="luau")]
+        NodeKind::Continue => true,
+        NodeKind::Do => arena.child(id, "block").is_some_and(|b| block_exits(arena, b)),
+        NodeKind::If => {
+            let exits_else = match arena.child(id, "else") {
+                Some(e) if *arena.kind(e) == NodeKind::Block => block_exits(arena, e),
+                Some(e) => exits(arena, e),
+                None => false,
+            };
+            exits_else && arena.child(id, "block").is_some_and(|b| block_exits(arena, b))
+        }
+        _ => false,
+    }
+}
+
+fn block_exits(arena: &Arena, block: NodeId) -> bool {
+    arena.children(block)
+        .filter(|&s| *arena.kind(s) != NodeKind::EmptyStatement)
+        .last()
+        .is_some_and(|s| exits(arena, s))
+}
+
+pub struct UnreachableCode;
+
+impl Rule for UnreachableCode {
+    fn id(&self) -> &'static str {
+        "unreachable-code"
+    }
+
+    fn check(&self, context: &Context, findings: &mut Vec<Finding>) {
+        let arena = context.arena;
+        for block in arena.ids().filter(|&id| *arena.kind(id) == NodeKind::Block) {
+            let mut exited = false;
+            for stat in arena.children(block) {
+                match *arena.kind(stat) {
+                    // A label can be jumped to
+                    NodeKind::Label(_) => exited = false,
+                    NodeKind::EmptyStatement => (),
+                    _ if exited => {
+                        findings.push(Finding { node: stat, message: "unreachable code".into() });
+                        break;
+                    }
+                    _ => exited = exits(arena, stat),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    String(Vec<u8>),
+    Integer(i64),
+    Float(u64),
+    Bool(bool),
+}
+
+impl Display for Key {
+    fn fmt(&self, format: &mut Formatter) -> fmt::Result {
+        match *self {
+            Key::String(ref s) => write!(format, "'{}'", String::from_utf8_lossy(s)),
+            Key::Integer(i) => write!(format, "{}", i),
+            Key::Float(bits) => write!(format, "{}", Value::Float(f64::from_bits(bits))),
+            Key::Bool(b) => write!(format, "{}", b),
+        }
+    }
+}
+
+fn key(arena: &Arena, id: NodeId) -> Option<Key> {
+    Some(match *arena.kind(id) {
+        NodeKind::Name(ref s) => Key::String(s.as_bytes().to_vec()),
+        NodeKind::String(ref s) => Key::String(s.clone()),
+        NodeKind::Integer(i) => Key::Integer(i),
+        NodeKind::Float(f) => match to_integer(&Value::Float(f)) {
+            Ok(i) => Key::Integer(i),
+            Err(_) if f.is_nan() => return None,
+            Err(_) => Key::Float(f.to_bits()),
+        },
+        NodeKind::Bool(b) => Key::Bool(b),
+        _ => return None,
+    })
+}
+
+pub struct DuplicateKey;
+
+impl Rule for DuplicateKey {
+    fn id(&self) -> &'static str {
+        "duplicate-key"
+    }
+
+    fn check(&self, context: &Context, findings: &mut Vec<Finding>) {
+        let arena = context.arena;
+        for list in arena.ids().filter(|&id| *arena.kind(id) == NodeKind::FieldList) {
+            let mut seen = HashSet::new();
+            let mut position = 0;
+            for field in arena.children(list) {
+                let key = match *arena.kind(field) {
+                    NodeKind::FieldSingle => {
+                        position += 1;
+                        Some(Key::Integer(position))
+                    }
+                    _ => arena.child(field, "key").and_then(|k| key(arena, k)),
+                };
+                if let Some(key) = key {
+                    if !seen.insert(key.clone()) {
+                        findings.push(Finding {
+                            node: field,
+                            message: format!("duplicate key {} in table constructor", key),
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn gotos(arena: &Arena) -> impl Iterator<Item = NodeId> + '_ {
+    arena.ids().filter(move |&id| *arena.kind(id) == NodeKind::Goto)
+}
+
+pub struct GotoIntoScope;
+
+impl Rule for GotoIntoScope {
+    fn id(&self) -> &'static str {
+        "goto-into-scope"
+    }
+
+    fn default_severity(&self) -> Severity {
+        Severity::Error
+    }
+
+    fn check(&self, context: &Context, findings: &mut Vec<Finding>) {
+        let arena = context.arena;
+        for goto in gotos(arena) {
+            let local = target(arena, goto).and_then(|t| entered_local(arena, &t));
+            if let Some(local) = local {
+                findings.push(Finding {
+                    node: goto,
+                    message: format!("<goto {}> jumps into the scope of local '{}'",
+                                     name(arena, arena.child(goto, "label").unwrap_or(goto)),
+                                     name(arena, local)),
+                });
+            }
+        }
+    }
+}
+
+pub struct UnusedLabel;
+
+impl Rule for UnusedLabel {
+    fn id(&self) -> &'static str {
+        "unused-label"
+    }
+
+    fn check(&self, context: &Context, findings: &mut Vec<Finding>) {
+        let arena = context.arena;
+        let used: HashSet<_> = gotos(arena)
+            .filter_map(|goto| target(arena, goto))
+            .filter_map(|t| arena.children(t.block).nth(t.label))
+            .collect();
+        for id in arena.ids() {
+            if let NodeKind::Label(ref label) = *arena.kind(id) {
+                if !used.contains(&id) {
+                    findings.push(Finding { node: id, message: format!("unused label '{}'", label) });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::ParserConfig;
+    use lint::{LintConfig, Linter};
+
+    // The messages of `rule` for `source`
+    fn check(rule: &str, source: &str) -> Vec<String> {
+        Linter::new(LintConfig::default()).lint(source.as_bytes(), &ParserConfig::default())
+            .unwrap()
+            .into_iter()
+            .filter(|d| d.rule == rule)
+            .map(|d| d.message)
+            .collect()
+    }
+
+    #[test]
+    fn unused_locals() {
+        assert_eq!(check("unused-local", "local a, _b = 1 local function f() end for i = 1, 2 do end"),
+                   vec!["unused variable 'a'", "unused function 'f'", "unused loop variable 'i'"]);
+        assert!(check("unused-local", "local a = 1 local function f(x) return f end return a, f").is_empty());
+    }
+
+    #[test]
+    fn shadowed_locals() {
+        assert_eq!(check("shadowed-local", "local x local function f(x) local x end for _, v in f do local v end"),
+                   vec!["shadowing upvalue 'x'", "shadowing local 'x'", "shadowing local 'v'"]);
+        assert!(check("shadowed-local", "do local x end local x local _ local _").is_empty());
+    }
+
+    #[test]
+    fn globals() {
+        let source = "function helper() return print, helper, missing end x = 1 local t = {} t.y = z";
+        assert_eq!(check("undefined-global", source),
+                   vec!["accessing undefined variable 'missing'", "accessing undefined variable 'z'"]);
+        assert_eq!(check("global-assignment", source),
+                   vec!["setting non-standard global variable 'helper'",
+                        "setting non-standard global variable 'x'"]);
+        assert!(check("global-assignment", "function string.trim() end print = nil").is_empty());
+        assert!(check("undefined-global", "local t = _ENV _ENV = {} return _ENV.print").is_empty());
+    }
+
+    #[test]
+    fn unreachable_code() {
+        assert_eq!(check("unreachable-code",
+                         "while true do break f() g() end do return end h() goto l ::l:: i()"),
+                   vec!["unreachable code", "unreachable code"]);
+        assert_eq!(check("unreachable-code", "if a then return else do return end end f()"),
+                   vec!["unreachable code"]);
+        assert!(check("unreachable-code", "if a then return end f() goto l ; ::l:: f()").is_empty());
+    }
+
+    #[test]
+    fn duplicate_keys() {
+        assert_eq!(check("duplicate-key", "return {a = 1, ['a'] = 2, 3, [1] = 4, [2.0] = 5, [2] = 6, [x] = 7, [x] = 8}"),
+                   vec!["duplicate key 'a' in table constructor",
+                        "duplicate key 1 in table constructor",
+                        "duplicate key 2 in table constructor"]);
+        assert!(check("duplicate-key", "return {{a = 1}, {a = 2}, [true] = 1, [1.5] = 2, [0/0] = 3}").is_empty());
+    }
+
+    #[test]
+    fn goto_into_scope() {
+        assert_eq!(check("goto-into-scope", "goto l local x = 1 ::l:: print(x)"),
+                   vec!["<goto l> jumps into the scope of local 'x'"]);
+        assert_eq!(check("goto-into-scope", "do goto l end local function f() end ::l:: f()"),
+                   vec!["<goto l> jumps into the scope of local 'f'"]);
+        assert!(check("goto-into-scope", "while x do goto continue local y = 1 ::continue:: end").is_empty());
+        assert!(check("goto-into-scope", "local x ::l:: x = 1 goto l").is_empty());
+    }
+
+    #[test]
+    fn unused_labels() {
+        assert_eq!(check("unused-label", "::a:: ::b:: goto a do ::c:: end function f() goto b end"),
+                   vec!["unused label 'b'", "unused label 'c'"]);
+        let source = "do :: a :: end";
+        let spans: Vec<_> = Linter::new(LintConfig::default()).lint(source.as_bytes(), &ParserConfig::default())
+            .unwrap()
+            .into_iter()
+            .filter_map(|d| d.span)
+            .map(|span| &source[span.start..span.end])
+            .collect();
+        assert_eq!(spans, vec![":: a ::"]);
+    }
+}
@@ -0,0 +1,776 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Writes a block as the JSON AST of the [luaparse] npm package
+//!
+//! The output has the node types and fields of `luaparse.parse`, so tools
+//! written for it can read it unchanged: a `Chunk` with its `body` and
+//! `comments`, `LocalStatement`, `CallExpression`, `MemberExpression` with
+//! its `indexer` and so on. Literals keep their source text in `raw`,
+//! parenthesized expressions are marked with `inParens` and `...` in a
+//! parameter list is a `VarargLiteral`.
+//!
+//! Like the `locations` and `ranges` options of luaparse, `LuaparseConfig`
+//! adds `loc`, with 1-based lines and 0-based columns, and `range`. Both
+//! count UTF-16 code units, as JavaScript strings do, so they index the
+//! source as read by Node.js.
+//!
+//! luaparse reads Lua 5.1 to 5.3. The Lua 5.4 attributes of locals and
+//! the Luau syntax have no luaparse shape and are reported as
+//! `LuaparseError::Unsupported`.
+//!
+//! ```rust
+//! # use nom_lua::luaparse::{to_json, LuaparseConfig};
+//! # use nom_lua::{parse_block_with, ParserConfig};
+//! let source = b"local a = f(1)";
+//! let block = parse_block_with(source, &ParserConfig::default()).unwrap();
+//! let json = to_json(&block, source, &LuaparseConfig::default()).unwrap();
+//! assert!(json.starts_with(r#"{"type":"Chunk","body":[{"type":"LocalStatement","variables":[{"type":"Identifier","name":"a"}],"init":[{"type":"CallExpression","#));
+//! ```
+//!
+//! [luaparse]: https://github.com/fstirlitz/luaparse
+
+use std::error;
+use std::fmt::{self, Display, Formatter};
+
+use serde_json::{self, Number, Value};
+
+use arena::{Arena, NodeId, NodeKind, SideTable};
+use ast::ASTNode;
+use span::{self, parenthesized, Span};
+
+/// The luaparse options `to_json` follows
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LuaparseConfig {
+    /// Adds the `loc` of every node, with its start and end lines and
+    /// columns
+    pub locations: bool,
+    /// Adds the `range` of every node, its start and end offsets
+    pub ranges: bool,
+}
+
+/// Why a block could not be written
+#[derive(Clone, Debug, PartialEq)]
+pub enum LuaparseError {
+    /// The tree is not a `Block`, luaparse only parses whole chunks
+    NotABlock,
+    /// The tree uses syntax luaparse has no node for
+    Unsupported(&'static str),
+}
+
+impl Display for LuaparseError {
+    fn fmt(&self, format: &mut Formatter) -> fmt::Result {
+        match *self {
+            LuaparseError::NotABlock => write!(format, "luaparse ASTs are made from whole blocks"),
+            LuaparseError::Unsupported(syntax) => write!(format, "luaparse has no node for {}", syntax),
+        }
+    }
+}
+
+impl error::Error for LuaparseError {}
+
+type Result<T> = ::std::result::Result<T, LuaparseError>;
+
+#[derive(Serialize)]
+struct Node {
+    #[serde(flatten)]
+    kind: Kind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    loc: Option<Location>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    range: Option<[usize; 2]>,
+    #[serde(rename = "inParens", skip_serializing_if = "is_false")]
+    in_parens: bool,
+    // luaparse adds the comments to the chunk after its location
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comments: Option<Vec<Node>>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+#[derive(Serialize)]
+struct Location {
+    start: Position,
+    end: Position,
+}
+
+#[derive(Serialize)]
+struct Position {
+    line: usize,
+    column: usize,
+}
+
+type Child = Box<Node>;
+
+// The node types of luaparse, with their fields in its order
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum Kind {
+    Chunk { body: Vec<Node> },
+    Comment { value: String, raw: String },
+    LabelStatement { label: Child },
+    BreakStatement {},
+    GotoStatement { label: Child },
+    ReturnStatement { arguments: Vec<Node> },
+    IfStatement { clauses: Vec<Node> },
+    IfClause { condition: Child, body: Vec<Node> },
+    ElseifClause { condition: Child, body: Vec<Node> },
+    ElseClause { body: Vec<Node> },
+    WhileStatement { condition: Child, body: Vec<Node> },
+    DoStatement { body: Vec<Node> },
+    RepeatStatement { condition: Child, body: Vec<Node> },
+    LocalStatement { variables: Vec<Node>, init: Vec<Node> },
+    AssignmentStatement { variables: Vec<Node>, init: Vec<Node> },
+    CallStatement { expression: Child },
+    FunctionDeclaration {
+        identifier: Option<Child>,
+        #[serde(rename = "isLocal")]
+        is_local: bool,
+        parameters: Vec<Node>,
+        body: Vec<Node>,
+    },
+    ForNumericStatement { variable: Child, start: Child, end: Child, step: Option<Child>, body: Vec<Node> },
+    ForGenericStatement { variables: Vec<Node>, iterators: Vec<Node>, body: Vec<Node> },
+    Identifier { name: String },
+    StringLiteral { value: Value, raw: String },
+    NumericLiteral { value: Value, raw: String },
+    BooleanLiteral { value: Value, raw: String },
+    NilLiteral { value: Value, raw: String },
+    VarargLiteral { value: Value, raw: String },
+    TableKey { key: Child, value: Child },
+    TableKeyString { key: Child, value: Child },
+    TableValue { value: Child },
+    TableConstructorExpression { fields: Vec<Node> },
+    BinaryExpression { operator: String, left: Child, right: Child },
+    LogicalExpression { operator: String, left: Child, right: Child },
+    UnaryExpression { operator: &'static str, argument: Child },
+    MemberExpression { indexer: &'static str, identifier: Child, base: Child },
+    IndexExpression { base: Child, index: Child },
+    CallExpression { base: Child, arguments: Vec<Node> },
+    TableCallExpression { base: Child, arguments: Child },
+    StringCallExpression { base: Child, argument: Child },
+}
+
+/// Writes `block`, which was parsed from `source`, as the JSON luaparse
+/// gives for `source`
+pub fn to_json(block: &ASTNode, source: &[u8], config: &LuaparseConfig) -> Result<String> {
+    let chunk = Writer::new(block, source, config).chunk()?;
+    Ok(serde_json::to_string(&chunk).expect("luaparse nodes always serialize"))
+}
+
+// Converts byte offsets to the UTF-16 offsets, lines and columns of
+// JavaScript
+struct Positions {
+    // The UTF-16 offset of each byte offset, a byte in the middle of a
+    // character has the offset of the character
+    offsets: Vec<usize>,
+    // The UTF-16 offset each line starts at
+    lines: Vec<usize>,
+}
+
+impl Positions {
+    fn new(source: &[u8]) -> Positions {
+        let mut offsets = Vec::with_capacity(source.len() + 1);
+        let mut units = 0;
+        for chunk in source.utf8_chunks() {
+            for c in chunk.valid().chars() {
+                offsets.extend(::std::iter::repeat_n(units, c.len_utf8()));
+                units += c.len_utf16();
+            }
+            // Decoding replaces invalid bytes with a single U+FFFD
+            if !chunk.invalid().is_empty() {
+                offsets.extend(::std::iter::repeat_n(units, chunk.invalid().len()));
+                units += 1;
+            }
+        }
+        offsets.push(units);
+        // `\r\n` and `\n\r` are one line break, as in `lua`
+        let mut lines = vec![0];
+        let mut i = 0;
+        while i < source.len() {
+            match source[i] {
+                c @ b'\n' | c @ b'\r' => {
+                    let pair = if c == b'\n' { b'\r' } else { b'\n' };
+                    i += if source.get(i + 1) == Some(&pair) { 2 } else { 1 };
+                    lines.push(offsets[i]);
+                }
+                _ => i += 1,
+            }
+        }
+        Positions { offsets, lines }
+    }
+
+    fn offset(&self, byte: usize) -> usize {
+        self.offsets[byte.min(self.offsets.len() - 1)]
+    }
+
+    fn position(&self, byte: usize) -> Position {
+        let offset = self.offset(byte);
+        let line = self.lines.partition_point(|&start| start <= offset);
+        Position { line, column: offset - self.lines[line - 1] }
+    }
+}
+
+struct Writer<'a> {
+    source: &'a [u8],
+    config: &'a LuaparseConfig,
+    arena: Arena,
+    spans: SideTable<Span>,
+    tokens: Vec<Span>,
+    comments: Vec<Span>,
+    positions: Positions,
+}
+
+impl<'a> Writer<'a> {
+    fn new(block: &ASTNode, source: &'a [u8], config: &'a LuaparseConfig) -> Writer<'a> {
+        let arena = Arena::from_ast(block);
+        let spans = span::spans(source, &arena);
+        let (tokens, comments) = span::token_spans(source);
+        Writer { source, config, arena, spans, tokens, comments, positions: Positions::new(source) }
+    }
+
+    fn node(&self, kind: Kind, span: Span) -> Node {
+        let loc = if self.config.locations {
+            Some(Location { start: self.positions.position(span.start), end: self.positions.position(span.end) })
+        } else {
+            None
+        };
+        let range = if self.config.ranges {
+            Some([self.positions.offset(span.start), self.positions.offset(span.end)])
+        } else {
+            None
+        };
+        Node { kind, loc, range, in_parens: false, comments: None }
+    }
+
+    // Nodes the source has no token for, such as a block of `;`, are
+    // placed where the previous token ends
+    fn span(&self, id: NodeId) -> Span {
+        self.spans.get(id).cloned().unwrap_or_else(|| {
+            let end = self.arena.ancestors(id)
+                .find_map(|a| self.spans.get(a))
+                .map_or(0, |s| s.start);
+            Span { start: end, end }
+        })
+    }
+
+    fn text(&self, span: Span) -> String {
+        String::from_utf8_lossy(&self.source[span.start..span.end]).into_owned()
+    }
+
+    // The first token starting at or after `offset`
+    fn token_after(&self, offset: usize) -> Option<Span> {
+        let i = self.tokens.partition_point(|t| t.start < offset);
+        self.tokens.get(i).cloned()
+    }
+
+    // The last token ending at or before `offset`
+    fn token_before(&self, offset: usize) -> Option<Span> {
+        let i = self.tokens.partition_point(|t| t.end <= offset);
+        if i == 0 { None } else { Some(self.tokens[i - 1]) }
+    }
+
+    fn is_token(&self, token: Option<Span>, text: &str) -> bool {
+        token.is_some_and(|t| &self.source[t.start..t.end] == text.as_bytes())
+    }
+
+    fn child(&self, id: NodeId, field: &str) -> NodeId {
+        self.arena.child(id, field).expect("nodes have their required fields")
+    }
+
+    fn list(&self, id: Option<NodeId>) -> Vec<NodeId> {
+        id.map_or_else(Vec::new, |id| self.arena.children(id).collect())
+    }
+
+    fn chunk(&self) -> Result<Node> {
+        let root = self.arena.root();
+        if *self.arena.kind(root) != NodeKind::Block {
+            return Err(LuaparseError::NotABlock);
+        }
+        let span = self.spans.get(root).cloned()
+            .unwrap_or(Span { start: self.source.len(), end: self.source.len() });
+        let mut chunk = self.node(Kind::Chunk { body: self.block(root)? }, span);
+        let comments = self.comments.iter().map(|&span| {
+            let raw = self.text(span);
+            // The value of a long comment is the text between its brackets
+            let value = match span::long_bracket(self.source, span.start + 2) {
+                Some(level) => raw.get(level + 4..raw.len() - level - 2).unwrap_or("").to_string(),
+                None => raw[2..].to_string(),
+            };
+            self.node(Kind::Comment { value, raw }, span)
+        }).collect();
+        chunk.comments = Some(comments);
+        Ok(chunk)
+    }
+
+    fn block(&self, id: NodeId) -> Result<Vec<Node>> {
+        let mut body = Vec::new();
+        for child in self.arena.children(id) {
+            if let Some(statement) = self.statement(child)? {
+                body.push(statement);
+            }
+        }
+        Ok(body)
+    }
+
+    fn statement(&self, id: NodeId) -> Result<Option<Node>> {
+        let mut span = self.span(id);
+        let kind = match *self.arena.kind(id) {
+            NodeKind::EmptyStatement => return Ok(None),
+            NodeKind::Break => Kind::BreakStatement {},
+            NodeKind::Label(ref name) => {
+                let identifier = self.node(Kind::Identifier { name: name.clone() },
+                                           Span { start: span.start, end: span.start + name.len() });
+                if let Some(open) = self.token_before(span.start) {
+                    span.start = open.start;
+                }
+                Kind::LabelStatement { label: Box::new(identifier) }
+            }
+            NodeKind::Goto => Kind::GotoStatement { label: Box::new(self.expression(self.child(id, "label"))?) },
+            NodeKind::RetStat => {
+                // The `;` after a return belongs to it
+                let semicolon = self.token_after(span.end);
+                if self.is_token(semicolon, ";") {
+                    span.end = semicolon.map_or(span.end, |s| s.end);
+                }
+                Kind::ReturnStatement { arguments: self.expressions(self.arena.child(id, "explist"))? }
+            }
+            NodeKind::Assign => Kind::AssignmentStatement {
+                variables: self.expressions(Some(self.child(id, "vars")))?,
+                init: self.expressions(Some(self.child(id, "exps")))?,
+            },
+            NodeKind::FunctionCall | NodeKind::MethodCall =>
+                Kind::CallStatement { expression: Box::new(self.expression(id)?) },
+            NodeKind::Do => Kind::DoStatement { body: self.block(self.child(id, "block"))? },
+            NodeKind::While => Kind::WhileStatement {
+                condition: Box::new(self.expression(self.child(id, "cond"))?),
+                body: self.block(self.child(id, "block"))?,
+            },
+            NodeKind::Repeat => Kind::RepeatStatement {
+                condition: Box::new(self.expression(self.child(id, "cond"))?),
+                body: self.block(self.child(id, "block"))?,
+            },
+            NodeKind::If => Kind::IfStatement { clauses: self.clauses(id)? },
+            NodeKind::NumericFor => Kind::ForNumericStatement {
+                variable: Box::new(self.expression(self.child(id, "var"))?),
+                start: Box::new(self.expression(self.child(id, "start"))?),
+                end: Box::new(self.expression(self.child(id, "limit"))?),
+                step: match self.arena.child(id, "step") {
+                    Some(step) => Some(Box::new(self.expression(step)?)),
+                    None => None,
+                },
+                body: self.block(self.child(id, "block"))?,
+            },
+            NodeKind::GenericFor => Kind::ForGenericStatement {
+                variables: self.names(self.child(id, "names"))?,
+                iterators: self.expressions(Some(self.child(id, "exps")))?,
+                body: self.block(self.child(id, "block"))?,
+            },
+            NodeKind::Local => Kind::LocalStatement {
+                variables: self.names(self.child(id, "names"))?,
+                init: self.expressions(self.arena.child(id, "values"))?,
+            },
+            NodeKind::FunctionStat => {
+                let identifier = self.function_name(self.child(id, "name"))?;
+                self.function(id, Some(identifier), false)?
+            }
+            NodeKind::NamedFunction => {
+                let identifier = self.expression(self.child(id, "name"))?;
+                self.function(id, Some(identifier), true)?
+            }
+            ref kind => return Err(LuaparseError::Unsupported(unsupported(kind))),
+        };
+        Ok(Some(self.node(kind, span)))
+    }
+
+    // The clauses of an `if` and of the `elseif`s stored in its `else`
+    fn clauses(&self, id: NodeId) -> Result<Vec<Node>> {
+        let mut clauses = Vec::new();
+        let mut clause = id;
+        loop {
+            let cond = self.child(clause, "cond");
+            let block = self.child(clause, "block");
+            let then = self.token_after(self.span(cond).end);
+            let end = self.clause_end(block, then.map_or(self.span(cond).end, |t| t.end));
+            let condition = Box::new(self.expression(cond)?);
+            let body = self.block(block)?;
+            let kind = if clause == id {
+                Kind::IfClause { condition, body }
+            } else {
+                Kind::ElseifClause { condition, body }
+            };
+            clauses.push(self.node(kind, Span { start: self.span(clause).start, end }));
+            match self.arena.child(clause, "else") {
+                Some(other) if *self.arena.kind(other) == NodeKind::If => clause = other,
+                Some(block) => {
+                    let keyword = self.token_after(end).unwrap_or(Span { start: end, end });
+                    let end = self.clause_end(block, keyword.end);
+                    clauses.push(self.node(Kind::ElseClause { body: self.block(block)? },
+                                           Span { start: keyword.start, end }));
+                    return Ok(clauses);
+                }
+                None => return Ok(clauses),
+            }
+        }
+    }
+
+    // A clause ends with its last statement, or its keyword when empty,
+    // along with any `;` after them
+    fn clause_end(&self, block: NodeId, keyword_end: usize) -> usize {
+        let mut end = self.spans.get(block).map_or(keyword_end, |s| s.end.max(keyword_end));
+        while let Some(token) = self.token_after(end).filter(|&t| self.is_token(Some(t), ";")) {
+            end = token.end;
+        }
+        end
+    }
+
+    // `id` is a FunctionStat, a NamedFunction or a Function, all of them
+    // have a FunctionBody in `body`
+    fn function(&self, id: NodeId, identifier: Option<Node>, is_local: bool) -> Result<Kind> {
+        let body = self.child(id, "body");
+        if *self.arena.kind(body) != NodeKind::FunctionBody {
+            return Err(LuaparseError::Unsupported(unsupported(self.arena.kind(body))));
+        }
+        let params = self.arena.child(body, "params");
+        let mut parameters = match params.and_then(|p| self.arena.child(p, "names")) {
+            Some(names) => self.names(names)?,
+            None => Vec::new(),
+        };
+        if let Some(&NodeKind::ParameterList(true)) = params.map(|p| self.arena.kind(p)) {
+            // The vararg has no node, it is the `...` before the `)`
+            let open = self.tokens.partition_point(|t| t.start < self.span(id).start);
+            let vararg = self.tokens[open..].iter().cloned()
+                .find(|&t| self.is_token(Some(t), "...") || self.is_token(Some(t), ")"))
+                .unwrap_or(Span { start: 0, end: 0 });
+            parameters.push(self.node(Kind::VarargLiteral {
+                value: Value::String("...".to_string()),
+                raw: "...".to_string(),
+            }, vararg));
+        }
+        Ok(Kind::FunctionDeclaration {
+            identifier: identifier.map(Box::new),
+            is_local,
+            parameters,
+            body: self.block(self.child(body, "block"))?,
+        })
+    }
+
+    // `a.b.c:d` is read as members of members, each spanning from `a`
+    fn function_name(&self, id: NodeId) -> Result<Node> {
+        let name = self.child(id, "name");
+        let start = self.span(name).start;
+        let mut base = self.expression(name)?;
+        for (field, child) in self.arena[id].children.iter().skip(1) {
+            let indexer = if *field == "method" { ":" } else { "." };
+            let identifier = Box::new(self.expression(*child)?);
+            base = self.node(Kind::MemberExpression { indexer, identifier, base: Box::new(base) },
+                             Span { start, end: self.span(*child).end });
+        }
+        Ok(base)
+    }
+
+    fn names(&self, id: NodeId) -> Result<Vec<Node>> {
+        if let NodeKind::NameList(ref attribs) = *self.arena.kind(id) {
+            if attribs.iter().any(|a| a.is_some()) {
+                return Err(LuaparseError::Unsupported("local attributes"));
+            }
+        }
+        self.list(Some(id)).into_iter().map(|name| self.expression(name)).collect()
+    }
+
+    fn expressions(&self, id: Option<NodeId>) -> Result<Vec<Node>> {
+        self.list(id).into_iter().map(|e| self.expression(e)).collect()
+    }
+
+    fn expression(&self, id: NodeId) -> Result<Node> {
+        let span = self.span(id);
+        let raw = || self.text(span);
+        let kind = match *self.arena.kind(id) {
+            NodeKind::Name(ref name) => Kind::Identifier { name: name.clone() },
+            NodeKind::Nil => Kind::NilLiteral { value: Value::Null, raw: raw() },
+            NodeKind::Bool(b) => Kind::BooleanLiteral { value: Value::Bool(b), raw: raw() },
+            NodeKind::VarArg => Kind::VarargLiteral { value: Value::String("...".to_string()), raw: raw() },
+            NodeKind::String(ref s) => Kind::StringLiteral { value: Value::String(String::from_utf8_lossy(s).into_owned()), raw: raw() },
+            NodeKind::Integer(i) => Kind::NumericLiteral { value: Value::Number(i.into()), raw: raw() },
+            NodeKind::Float(f) => Kind::NumericLiteral { value: number(f), raw: raw() },
+            NodeKind::PrefixExp | NodeKind::Paren => {
+                let mut inner = self.expression(self.arena.children(id).next().expect("PrefixExp has an expression"))?;
+                if parenthesized(&self.arena, id) || *self.arena.kind(id) == NodeKind::Paren {
+                    inner.in_parens = true;
+                }
+                return Ok(inner);
+            }
+            NodeKind::Var => return self.expression(self.child(id, "name")),
+            NodeKind::VarListAccess => Kind::MemberExpression {
+                indexer: ".",
+                identifier: Box::new(self.expression(self.child(id, "name"))?),
+                base: Box::new(self.expression(self.child(id, "prefix"))?),
+            },
+            NodeKind::VarPrefixed => Kind::IndexExpression {
+                base: Box::new(self.expression(self.child(id, "prefix"))?),
+                index: Box::new(self.expression(self.child(id, "index"))?),
+            },
+            NodeKind::FunctionCall => {
+                let prefix = self.child(id, "prefix");
+                let base = self.expression(prefix)?;
+                self.call(base, self.span(prefix).end, self.arena.child(id, "args"))?
+            }
+            NodeKind::MethodCall => {
+                let prefix = self.child(id, "prefix");
+                let name = self.child(id, "name");
+                let member = Kind::MemberExpression {
+                    indexer: ":",
+                    identifier: Box::new(self.expression(name)?),
+                    base: Box::new(self.expression(prefix)?),
+                };
+                let base = self.node(member, Span { start: span.start, end: self.span(name).end });
+                self.call(base, self.span(name).end, self.arena.child(id, "args"))?
+            }
+            NodeKind::Function => self.function(id, None, false)?,
+            NodeKind::TableConstructor => {
+                let mut fields = Vec::new();
+                for field in self.list(self.arena.child(id, "fields")) {
+                    fields.push(self.field(field)?);
+                }
+                Kind::TableConstructorExpression { fields }
+            }
+            NodeKind::Not => self.unary("not", id)?,
+            NodeKind::Len => self.unary("#", id)?,
+            NodeKind::UMin => self.unary("-", id)?,
+            NodeKind::BinNot => self.unary("~", id)?,
+            ref kind => match kind.binop() {
+                Some(op) => {
+                    let operator = op.to_string();
+                    let left = Box::new(self.expression(self.child(id, "left"))?);
+                    let right = Box::new(self.expression(self.child(id, "right"))?);
+                    if operator == "and" || operator == "or" {
+                        Kind::LogicalExpression { operator, left, right }
+                    } else {
+                        Kind::BinaryExpression { operator, left, right }
+                    }
+                }
+                None => return Err(LuaparseError::Unsupported(unsupported(kind))),
+            },
+        };
+        Ok(self.node(kind, span))
+    }
+
+    fn unary(&self, operator: &'static str, id: NodeId) -> Result<Kind> {
+        Ok(Kind::UnaryExpression { operator, argument: Box::new(self.expression(self.child(id, "operand"))?) })
+    }
+
+    // The arguments of `f(a)`, `f {a}` and `f "a"` are all an ExpList,
+    // the token after the function tells them apart
+    fn call(&self, base: Node, base_end: usize, args: Option<NodeId>) -> Result<Kind> {
+        let base = Box::new(base);
+        let next = self.token_after(base_end);
+        let argument = match self.list(args).first() {
+            Some(&first) if !self.is_token(next, "(") => first,
+            _ => return Ok(Kind::CallExpression { base, arguments: self.expressions(args)? }),
+        };
+        let argument = Box::new(self.expression(argument)?);
+        Ok(if self.is_token(next, "{") {
+            Kind::TableCallExpression { base, arguments: argument }
+        } else {
+            Kind::StringCallExpression { base, argument }
+        })
+    }
+
+    fn field(&self, id: NodeId) -> Result<Node> {
+        let kind = match *self.arena.kind(id) {
+            NodeKind::FieldSingle => Kind::TableValue { value: Box::new(self.expression(self.child(id, "value"))?) },
+            _ => {
+                let key = self.child(id, "key");
+                let value = Box::new(self.expression(self.child(id, "value"))?);
+                if let NodeKind::Name(_) = *self.arena.kind(key) {
+                    Kind::TableKeyString { key: Box::new(self.expression(key)?), value }
+                } else {
+                    Kind::TableKey { key: Box::new(self.expression(key)?), value }
+                }
+            }
+        };
+        Ok(self.node(kind, self.span(id)))
+    }
+}
+
+// A JavaScript number, which JSON.stringify writes without a fraction
+// when it is whole and as null when it is not finite
+fn number(value: f64) -> Value {
+    if value.fract() == 0.0 && value.abs() < 9007199254740992.0 {
+        Value::Number((value as i64).into())
+    } else {
+        Number::from_f64(value).map_or(Value::Null, Value::Number)
+    }
+}
+
+fn unsupported(kind: &NodeKind) -> &'static str {
+    match *kind {
+        #[cfg(feature="luau")]
+        NodeKind::Typed | NodeKind::TypedFunctionBody => "Luau type annotations",
+        #[cfg(feature="luau")]
+        NodeKind::TypeAlias(_) => "Luau type aliases",
+        #[cfg(feature="luau")]
+        NodeKind::CompoundAssign(_) => "Luau compound assignments",
+        #[cfg(feature="luau")]
+        NodeKind::Continue => "Luau `continue`",
+        #[cfg(feature="luau")]
+        NodeKind::IfExp => "Luau if expressions",
+        _ => "this node",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{LuaVersion, ParserConfig};
+    use parse_block_with;
+    use serde_json::json;
+
+    fn luaparse(source: &str, config: &LuaparseConfig) -> Value {
+        let parser = ParserConfig { version: LuaVersion::Lua54, ..ParserConfig::default() };
+        let block = parse_block_with(source.as_bytes(), &parser).unwrap();
+        serde_json::from_str(&to_json(&block, source.as_bytes(), config).unwrap()).unwrap()
+    }
+
+    fn ast(source: &str) -> Value {
+        luaparse(source, &LuaparseConfig::default())
+    }
+
+    #[test]
+    fn statements() {
+        let chunk = ast("local a, b = 1; x.y = nil ::l:: goto l do return end");
+        assert_eq!(chunk, json!({"type": "Chunk", "body": [
+            {"type": "LocalStatement",
+             "variables": [{"type": "Identifier", "name": "a"}, {"type": "Identifier", "name": "b"}],
+             "init": [{"type": "NumericLiteral", "value": 1, "raw": "1"}]},
+            {"type": "AssignmentStatement",
+             "variables": [{"type": "MemberExpression", "indexer": ".",
+                            "identifier": {"type": "Identifier", "name": "y"},
+                            "base": {"type": "Identifier", "name": "x"}}],
+             "init": [{"type": "NilLiteral", "value": null, "raw": "nil"}]},
+            {"type": "LabelStatement", "label": {"type": "Identifier", "name": "l"}},
+            {"type": "GotoStatement", "label": {"type": "Identifier", "name": "l"}},
+            {"type": "DoStatement", "body": [{"type": "ReturnStatement", "arguments": []}]},
+        ], "comments": []}));
+    }
+
+    #[test]
+    fn control() {
+        let chunk = ast("if a then elseif b then break else end for i = 1, 2 do end for k in p do end");
+        let body = &chunk["body"];
+        assert_eq!(body[0]["clauses"].as_array().unwrap().iter().map(|c| &c["type"]).collect::<Vec<_>>(),
+                   vec!["IfClause", "ElseifClause", "ElseClause"]);
+        assert_eq!(body[0]["clauses"][1]["body"], json!([{"type": "BreakStatement"}]));
+        assert_eq!(body[1]["type"], "ForNumericStatement");
+        assert_eq!(body[1]["step"], Value::Null);
+        assert_eq!(body[2]["iterators"][0]["name"], "p");
+        let chunk = ast("while true do end repeat until false");
+        assert_eq!(chunk["body"][0]["condition"]["raw"], "true");
+        assert_eq!(chunk["body"][1]["type"], "RepeatStatement");
+    }
+
+    #[test]
+    fn functions() {
+        let chunk = ast("function a.b:c(x, ...) end local function f() end g = function(...) end");
+        let declaration = &chunk["body"][0];
+        assert_eq!(declaration["isLocal"], false);
+        assert_eq!(declaration["identifier"]["indexer"], ":");
+        assert_eq!(declaration["identifier"]["base"]["indexer"], ".");
+        assert_eq!(declaration["parameters"], json!([
+            {"type": "Identifier", "name": "x"},
+            {"type": "VarargLiteral", "value": "...", "raw": "..."},
+        ]));
+        assert_eq!(chunk["body"][1]["isLocal"], true);
+        assert_eq!(chunk["body"][1]["identifier"]["name"], "f");
+        assert_eq!(chunk["body"][2]["init"][0]["identifier"], Value::Null);
+        assert_eq!(chunk["body"][2]["init"][0]["parameters"][0]["type"], "VarargLiteral");
+    }
+
+    #[test]
+    fn calls() {
+        let chunk = ast("f(1) f 's' f {} o:m(2) o:m {}");
+        let calls: Vec<_> = chunk["body"].as_array().unwrap().iter()
+            .map(|s| (s["type"].clone(), s["expression"]["type"].clone()))
+            .collect();
+        assert_eq!(calls, vec![
+            (json!("CallStatement"), json!("CallExpression")),
+            (json!("CallStatement"), json!("StringCallExpression")),
+            (json!("CallStatement"), json!("TableCallExpression")),
+            (json!("CallStatement"), json!("CallExpression")),
+            (json!("CallStatement"), json!("TableCallExpression")),
+        ]);
+        assert_eq!(chunk["body"][1]["expression"]["argument"]["raw"], "'s'");
+        assert_eq!(chunk["body"][3]["expression"]["base"], json!({
+            "type": "MemberExpression", "indexer": ":",
+            "identifier": {"type": "Identifier", "name": "m"},
+            "base": {"type": "Identifier", "name": "o"},
+        }));
+    }
+
+    #[test]
+    fn expressions() {
+        let chunk = ast("x = {1, a = 2, [3] = 4} y = not (a or b) .. t[1] + -0x10 * 1.0 z = 'a\\tb'");
+        assert_eq!(chunk["body"][0]["init"][0]["fields"].as_array().unwrap().iter()
+                       .map(|f| &f["type"]).collect::<Vec<_>>(),
+                   vec!["TableValue", "TableKeyString", "TableKey"]);
+        let concat = &chunk["body"][1]["init"][0];
+        assert_eq!(concat["operator"], "..");
+        assert_eq!(concat["left"]["operator"], "not");
+        assert_eq!(concat["left"]["argument"]["type"], "LogicalExpression");
+        assert_eq!(concat["left"]["argument"]["inParens"], true);
+        assert_eq!(concat["right"]["left"]["type"], "IndexExpression");
+        assert_eq!(concat["right"]["right"]["left"], json!({
+            "type": "UnaryExpression", "operator": "-",
+            "argument": {"type": "NumericLiteral", "value": 16, "raw": "0x10"},
+        }));
+        assert_eq!(concat["right"]["right"]["right"]["value"], 1);
+        assert_eq!(chunk["body"][2]["init"][0], json!({"type": "StringLiteral", "value": "a\tb", "raw": "'a\\tb'"}));
+    }
+
+    #[test]
+    fn locations() {
+        let source = "local s = 'é'\r\n-- c\nif x then ; elseif y then f() ; else end";
+        let config = LuaparseConfig { locations: true, ranges: true };
+        let chunk = luaparse(source, &config);
+        let string = &chunk["body"][0]["init"][0];
+        assert_eq!(string["loc"], json!({"start": {"line": 1, "column": 10}, "end": {"line": 1, "column": 13}}));
+        assert_eq!(string["range"], json!([10, 13]));
+        assert_eq!(chunk["comments"], json!([{
+            "type": "Comment", "value": " c", "raw": "-- c",
+            "loc": {"start": {"line": 2, "column": 0}, "end": {"line": 2, "column": 4}},
+            "range": [15, 19],
+        }]));
+        let clauses = &chunk["body"][1]["clauses"];
+        let ranges: Vec<_> = clauses.as_array().unwrap().iter().map(|c| c["range"].clone()).collect();
+        assert_eq!(ranges, vec![json!([20, 31]), json!([32, 51]), json!([52, 56])]);
+        assert_eq!(chunk["body"][1]["range"], json!([20, 60]));
+        assert_eq!(chunk["range"], json!([0, 60]));
+        assert_eq!(chunk["body"][1]["loc"]["start"], json!({"line": 3, "column": 0}));
+    }
+
+    #[test]
+    fn key_order() {
+        let source = b"return (a)";
+        let block = parse_block_with(source, &ParserConfig::default()).unwrap();
+        let config = LuaparseConfig { locations: false, ranges: true };
+        assert_eq!(to_json(&block, source, &config).unwrap(),
+                   r#"{"type":"Chunk","body":[{"type":"ReturnStatement","arguments":[{"type":"Identifier","name":"a","range":[8,9],"inParens":true}],"range":[0,10]}],"range":[0,10],"comments":[]}"#);
+    }
+
+    #[test]
+    fn unsupported() {
+        let block = parse_block_with(b"local x <const> = 1",
+                                     &ParserConfig { version: LuaVersion::Lua54, ..ParserConfig::default() }).unwrap();
+        assert_eq!(to_json(&block, b"", &LuaparseConfig::default()),
+                   Err(LuaparseError::Unsupported("local attributes")));
+        let exp = ::parse_string(&b"1"[..]).unwrap();
+        assert_eq!(to_json(&exp, b"1", &LuaparseConfig::default()), Err(LuaparseError::NotABlock));
+    }
+}
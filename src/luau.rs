@@ -0,0 +1,369 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Luau syntax, enabled by the `luau` feature
+//!
+//! Luau is the Lua 5.1 dialect used by Roblox. These parsers are hooked into
+//! the standard grammar at a few points: declared names, function bodies,
+//! statements and simple expressions. Without the feature those hooks never
+//! match.
+
+use std::str;
+
+use nom::multispace;
+
+use ast::ASTNode;
+use ast::ASTNode::*;
+use exp::parse_exp;
+use name::{parse_name, parse_valid_name};
+use op::BinOp;
+use string::parse_string;
+use var::parse_var;
+
+// Types
+
+// Every recursive type goes back through parse_type or parse_return_type, so
+// this is where their nesting is limited
+named!(pub parse_type<ASTNode>, nested!(map_opt!(do_parse!(
+           first: parse_optional_type
+        >> rest: many0!(complete!(pair!(ws!(alt!(tag!("|") | tag!("&"))), parse_optional_type)))
+        >> ((first, rest))),
+    |(first, rest)| fold_type(first, rest))));
+
+// Luau rejects mixing unions and intersections without parentheses
+fn fold_type(first: ASTNode, rest: Vec<(&[u8], ASTNode)>) -> Option<ASTNode> {
+    let union = match rest.first() {
+        Some(&(op, _)) => op == b"|",
+        None => return Some(first),
+    };
+    if rest.iter().any(|&(op, _)| (op == b"|") != union) {
+        return None;
+    }
+    let mut types = vec![first];
+    types.extend(rest.into_iter().map(|(_, t)| t));
+    Some(if union { TypeUnion(types) } else { TypeIntersection(types) })
+}
+
+named!(parse_optional_type<ASTNode>, do_parse!(
+           t: parse_simple_type
+        >> optional: many0!(complete!(ws!(tag!("?"))))
+        >> (optional.into_iter().fold(t, |acc, _| astb!(TypeOptional, acc)))));
+
+named!(parse_simple_type<ASTNode>, alt!(
+    map!(keyword!("nil"), |_| TypeName("nil".into(), vec![])) |
+    map!(keyword!("true"), |_| Bool(true)) |
+    map!(keyword!("false"), |_| Bool(false)) |
+    parse_string |
+    parse_typeof |
+    parse_table_type |
+    parse_paren_type |
+    parse_type_reference
+));
+
+named!(parse_typeof<ASTNode>, do_parse!(
+           keyword!("typeof")
+        >> e: delimited!(ws!(tag!("(")), parse_exp, ws!(tag!(")")))
+        >> (astb!(TypeOf, e))));
+
+named!(parse_type_reference<ASTNode>, do_parse!(
+           name: map_res!(recognize!(pair!(
+                   parse_valid_name,
+                   opt!(complete!(pair!(tag!("."), parse_valid_name))))),
+               str::from_utf8)
+        >> args: opt!(complete!(delimited!(
+                   ws!(tag!("<")),
+                   separated_nonempty_list!(ws!(tag!(",")), parse_type),
+                   ws!(tag!(">")))))
+        >> (TypeName(name.into(), args.unwrap_or_default()))));
+
+named!(parse_table_type<ASTNode>, map!(delimited!(
+            ws!(tag!("{")),
+            alt!(
+                complete!(terminated!(
+                    separated_nonempty_list!(parse_table_type_separator, parse_table_type_field),
+                    opt!(complete!(parse_table_type_separator)))) |
+                map!(parse_type, |t| vec![astb!(TypeIndexer, TypeName("number".into(), vec![]), t)]) |
+                value!(vec![])),
+            ws!(tag!("}"))),
+ASTNode::TypeTable));
+
+named!(parse_table_type_separator, ws!(alt!(tag!(",") | tag!(";"))));
+
+named!(parse_table_type_field<ASTNode>, alt!(
+    do_parse!(
+           key: delimited!(tag!("["), ws!(parse_type), tag!("]"))
+        >> ws!(tag!(":"))
+        >> value: parse_type
+        >> (astb!(TypeIndexer, key, value))) |
+    do_parse!(
+           name: parse_name
+        >> ws!(tag!(":"))
+        >> t: parse_type
+        >> (astb!(TypeField, name, t)))
+));
+
+named!(parse_type_params<Vec<ASTNode>>, delimited!(
+            ws!(tag!("(")),
+            separated_list!(ws!(tag!(",")), parse_type_param),
+            ws!(tag!(")"))));
+
+named!(parse_type_param<ASTNode>, alt!(
+    map!(preceded!(tag!("..."), ws!(parse_type)), |t| astb!(TypeVariadic, t)) |
+    do_parse!(
+           name: parse_name
+        >> ws!(tag!(":"))
+        >> t: parse_type
+        >> (astb!(Typed, name, t))) |
+    parse_type
+));
+
+// A parenthesised type, or the parameters of a function type
+named!(parse_paren_type<ASTNode>, map_opt!(pair!(
+            parse_type_params,
+            opt!(complete!(preceded!(ws!(tag!("->")), parse_return_type)))),
+    |(mut params, returns): (Vec<ASTNode>, _)| match returns {
+        Some(returns) => Some(ast!(TypeFunction, params, Box::new(returns))),
+        None if params.len() == 1 => match params.pop() {
+            Some(Typed(_, _)) | Some(TypeVariadic(_)) => None,
+            t => t,
+        },
+        None => None,
+    }));
+
+// A parenthesised list is a type pack, unless it holds the parameters of a
+// function type
+named!(pub parse_return_type<ASTNode>, nested!(switch!(peek!(take!(1)),
+    b"(" => do_parse!(
+               params: parse_type_params
+            >> returns: opt!(complete!(preceded!(ws!(tag!("->")), parse_return_type)))
+            >> (match returns {
+                Some(returns) => ast!(TypeFunction, params, Box::new(returns)),
+                None => ast!(TypePack, params),
+            })) |
+    _ => call!(parse_type))));
+
+// Annotations
+
+named!(pub parse_typed_name<ASTNode>, do_parse!(
+           name: parse_name
+        >> t: opt!(complete!(preceded!(ws!(tag!(":")), parse_type)))
+        >> (match t {
+            Some(t) => astb!(Typed, name, t),
+            None => name,
+        })));
+
+named!(pub parse_return_annotation<ASTNode>, preceded!(ws!(tag!(":")), parse_return_type));
+
+// Statements
+
+named!(pub parse_luau_statement<ASTNode>, alt!(
+        parse_type_alias |
+        complete!(parse_compound_assign) |
+        parse_continue
+));
+
+named!(parse_type_alias<ASTNode>, do_parse!(
+           export: opt!(complete!(terminated!(keyword!("export"), multispace)))
+        >> keyword!("type")
+        >> name: ws!(parse_name)
+        >> generics: opt!(complete!(delimited!(
+                       tag!("<"),
+                       separated_nonempty_list!(ws!(tag!(",")), ws!(parse_name)),
+                       tag!(">"))))
+        >> ws!(tag!("="))
+        >> t: parse_type
+        >> (TypeAlias(export.is_some(), Box::new(name), generics.unwrap_or_default(), Box::new(t)))));
+
+// Unlike the other keywords, continue is still a valid name, so it is only
+// a statement when what follows cannot make it a call or an assignment
+named!(parse_continue<ASTNode>, map!(
+        terminated!(keyword!("continue"), not!(complete!(ws!(one_of!("(.[:=,\"'{"))))),
+        |_| Continue));
+
+named!(parse_compound_assign<ASTNode>, do_parse!(
+           var: parse_var
+        >> op: ws!(compound_op)
+        >> e: parse_exp
+        >> (CompoundAssign(op, Box::new(var), Box::new(e)))));
+
+// Operators that are a prefix of another operator have to come after it
+named!(compound_op<BinOp>, alt!(
+    tag!("+=")  => { |_| BinOp::Add } |
+    tag!("-=")  => { |_| BinOp::Sub } |
+    tag!("*=")  => { |_| BinOp::Mul } |
+    tag!("//=") => { |_| BinOp::FDiv } |
+    tag!("/=")  => { |_| BinOp::Div } |
+    tag!("%=")  => { |_| BinOp::Mod } |
+    tag!("^=")  => { |_| BinOp::Exp } |
+    tag!("..=") => { |_| BinOp::Concat }
+));
+
+// Expressions
+
+named!(pub parse_if_exp<ASTNode>, do_parse!(
+           keyword!("if")
+        >> cond: ws!(parse_exp)
+        >> keyword!("then")
+        >> then: ws!(parse_exp)
+        >> elseifs: many0!(complete!(do_parse!(
+                       keyword!("elseif")
+                    >> cond: ws!(parse_exp)
+                    >> keyword!("then")
+                    >> then: ws!(parse_exp)
+                    >> ((cond, then)))))
+        >> keyword!("else")
+        >> other: ws!(parse_exp)
+        >> (fold_if_exp(cond, then, elseifs, other))));
+
+fn fold_if_exp(cond: ASTNode, then: ASTNode, elseifs: Vec<(ASTNode, ASTNode)>, other: ASTNode) -> ASTNode {
+    let other = elseifs.into_iter().rev().fold(other, |acc, (cond, then)| astb!(IfExp, cond, then, acc));
+    astb!(IfExp, cond, then, other)
+}
+
+#[cfg(test)]
+mod tests {
+    use ast::ASTNode;
+    use ast::ASTNode::*;
+    use op::BinOp;
+    use function::parse_block;
+
+    fn name(n: &str) -> ASTNode {
+        ast!(Name, n.into())
+    }
+
+    fn type_name(n: &str) -> ASTNode {
+        ast!(TypeName, n.into(), vec![])
+    }
+
+    fn var(n: &str) -> ASTNode {
+        astb!(PrefixExp, astb!(Var, name(n)))
+    }
+
+    ast_test!(parse_type_1, parse_type, "number", type_name("number"));
+    ast_test!(parse_type_2, parse_type, "Map<string, {number}>",
+              ast!(TypeName, "Map".into(), vec![
+                type_name("string"),
+                ast!(TypeTable, vec![astb!(TypeIndexer, type_name("number"), type_name("number"))]),
+              ]));
+    ast_test!(parse_type_3, parse_type, "string? | nil",
+              ast!(TypeUnion, vec![astb!(TypeOptional, type_name("string")), type_name("nil")]));
+    ast_test!(parse_type_4, parse_type, "A & M.B",
+              ast!(TypeIntersection, vec![type_name("A"), type_name("M.B")]));
+    ast_panic_test!(parse_type_5, parse_type, "A & B | C");
+    ast_test!(parse_type_6, parse_type, "(x: number, ...string) -> (boolean, string)",
+              ast!(TypeFunction, vec![
+                astb!(Typed, name("x"), type_name("number")),
+                astb!(TypeVariadic, type_name("string")),
+              ], Box::new(ast!(TypePack, vec![type_name("boolean"), type_name("string")]))));
+    ast_test!(parse_type_7, parse_type, "{ x: number, [string]: boolean; }",
+              ast!(TypeTable, vec![
+                astb!(TypeField, name("x"), type_name("number")),
+                astb!(TypeIndexer, type_name("string"), type_name("boolean")),
+              ]));
+    ast_test!(parse_type_8, parse_type, "(\"a\" | true)?",
+              astb!(TypeOptional, ast!(TypeUnion, vec![ast!(String, "a".into()), ast!(Bool, true)])));
+    ast_test!(parse_type_9, parse_type, "typeof(a)", astb!(TypeOf, var("a")));
+    ast_test!(parse_type_10, parse_type, "{}", ast!(TypeTable, vec![]));
+
+    ast_test!(parse_typed_name_1, parse_typed_name, "a : number",
+              astb!(Typed, name("a"), type_name("number")));
+    ast_test!(parse_typed_name_2, parse_typed_name, "a", name("a"));
+
+    ast_test!(parse_type_alias_1, parse_luau_statement, "export type Pair<T> = {T}",
+              ast!(TypeAlias, true, Box::new(name("Pair")), vec![name("T")], Box::new(
+                ast!(TypeTable, vec![astb!(TypeIndexer, type_name("number"), type_name("T"))]))));
+    ast_test!(parse_type_alias_2, parse_luau_statement, "type Id = number",
+              ast!(TypeAlias, false, Box::new(name("Id")), vec![], Box::new(type_name("number"))));
+
+    ast_test!(parse_compound_assign_1, parse_luau_statement, "a += 1",
+              ast!(CompoundAssign, BinOp::Add, Box::new(astb!(Var, name("a"))), Box::new(ast!(Integer, 1))));
+    ast_test!(parse_compound_assign_2, parse_luau_statement, "a.b ..= \"c\"",
+              ast!(CompoundAssign, BinOp::Concat,
+                   Box::new(astb!(VarListAccess, var("a"), name("b"))),
+                   Box::new(ast!(String, "c".into()))));
+    ast_test!(parse_compound_assign_3, parse_luau_statement, "a //= 2",
+              ast!(CompoundAssign, BinOp::FDiv, Box::new(astb!(Var, name("a"))), Box::new(ast!(Integer, 2))));
+
+    ast_test!(parse_continue_1, parse_luau_statement, "continue", ast!(Continue));
+    ast_test!(parse_continue_2, parse_luau_statement, "continue += 1",
+              ast!(CompoundAssign, BinOp::Add, Box::new(astb!(Var, name("continue"))), Box::new(ast!(Integer, 1))));
+    ast_test!(parse_continue_3, parse_luau_statement, "continue -- next\n", ast!(Continue));
+    ast_panic_test!(parse_continue_4, parse_luau_statement, "continue()");
+    ast_panic_test!(parse_continue_5, parse_luau_statement, "continue.x = 1");
+    ast_panic_test!(parse_continue_6, parse_luau_statement, "continue 'a'");
+
+    ast_test!(parse_if_exp_1, parse_if_exp, "if a then 1 elseif b then 2 else 3",
+              astb!(IfExp, var("a"), ast!(Integer, 1),
+                    astb!(IfExp, var("b"), ast!(Integer, 2), ast!(Integer, 3))));
+    ast_panic_test!(parse_if_exp_2, parse_if_exp, "if a then 1");
+
+    #[test]
+    fn pathological_type_nesting() {
+        use config::{too_deep, with_config, ParserConfig};
+        use super::parse_type;
+
+        for open in &["(", "{", "{ [", "() -> ", "Array<", "{ x: "] {
+            let input = open.repeat(10000);
+            with_config(&ParserConfig::default(), || {
+                parse_type(input.as_bytes());
+                assert!(too_deep(), "{}", open);
+            });
+        }
+    }
+
+    #[test]
+    fn luau_block() {
+        let input = "local x: number, y = 1, 2 type T = string continue";
+        let block = parse_block(input.as_bytes()).unwrap();
+        assert!(block.0.is_empty());
+        assert_eq!(block.1, ast!(Block, vec![
+            astb!(Local, ast!(NameList, vec![
+                (astb!(Typed, name("x"), type_name("number")), None),
+                (name("y"), None),
+            ]), Some(ast!(ExpList, vec![ast!(Integer, 1), ast!(Integer, 2)]))),
+            ast!(TypeAlias, false, Box::new(name("T")), vec![], Box::new(type_name("string"))),
+            ast!(Continue),
+        ], Box::new(None)));
+    }
+
+    #[test]
+    fn continue_as_name() {
+        let input = "continue() continue.x = 1 continue[1], continue = 2, 3 continue:m{} continue";
+        let block = parse_block(input.as_bytes()).unwrap();
+        assert!(block.0.is_empty());
+        assert_eq!(block.1, ast!(Block, vec![
+            astb!(FunctionCall, var("continue"), None),
+            astb!(Assign,
+                  ast!(VarList, vec![astb!(VarListAccess, var("continue"), name("x"))]),
+                  ast!(ExpList, vec![ast!(Integer, 1)])),
+            astb!(Assign,
+                  ast!(VarList, vec![astb!(VarPrefixed, var("continue"), ast!(Integer, 1)), astb!(Var, name("continue"))]),
+                  ast!(ExpList, vec![ast!(Integer, 2), ast!(Integer, 3)])),
+            astb!(MethodCall, var("continue"), name("m"),
+                  Some(ast!(ExpList, vec![ast!(TableConstructor, Box::new(None))]))),
+            ast!(Continue),
+        ], Box::new(None)));
+    }
+
+    #[test]
+    fn luau_function() {
+        use exp::parse_exp;
+
+        let f = parse_exp(b"function (a: number, b): string return if a then b else \"\" end").unwrap().1;
+        assert_eq!(f, astb!(Function, ast!(TypedFunctionBody,
+            Box::new(Some(ast!(ParameterList, Box::new(Some(ast!(NameList, vec![
+                (astb!(Typed, name("a"), type_name("number")), None),
+                (name("b"), None),
+            ]))), false))),
+            Box::new(type_name("string")),
+            Box::new(ast!(Block, vec![], Box::new(Some(astb!(RetStat, Some(ast!(ExpList, vec![
+                astb!(IfExp, var("a"), var("b"), ast!(String, "".into()))
+            ])))))))
+        )));
+    }
+}
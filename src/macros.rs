@@ -66,3 +66,53 @@ macro_rules! ast {
     };
 }
 
+
+/// Runs the wrapped parser one level of nesting deeper, failing once the
+/// configured `max_depth` is reached. See `config::DepthGuard`.
+macro_rules! nested (
+    ($i:expr, $submac:ident!( $($args:tt)* )) => ({
+        match ::config::DepthGuard::enter() {
+            Some(_guard) => $submac!($i, $($args)*),
+            None => ::nom::IResult::Error(error_code!(::nom::ErrorKind::Custom(0))),
+        }
+    });
+    ($i:expr, $f:expr) => (
+        nested!($i, call!($f))
+    );
+);
+
+/// Succeeds without consuming input if `$feature`, introduced in `$version`,
+/// is supported by the configured Lua version. See `config::require`.
+macro_rules! requires (
+    ($i:expr, $feature:expr, $version:expr) => ({
+        if ::config::require($feature, $version, $i) {
+            ::nom::IResult::Done($i, ())
+        } else {
+            ::nom::IResult::Error(error_code!(::nom::ErrorKind::Custom(0)))
+        }
+    });
+);
+
+/// Always fails, stands in for the parsers of disabled features
+macro_rules! fail (
+    ($i:expr,) => ({
+        let _ = $i;
+        ::nom::IResult::Error(error_code!(::nom::ErrorKind::Custom(0)))
+    });
+);
+
+/// nom's `ws!`, skipping comments as well as whitespace between tokens
+macro_rules! ws (
+    ($i:expr, $($args:tt)*) => ({
+        use ::space::parse_space;
+        sep!($i, parse_space, $($args)*)
+    });
+);
+
+/// Matches the keyword `$keyword`, unless it is only the start of a longer
+/// name such as `localize`
+macro_rules! keyword (
+    ($i:expr, $keyword:expr) => (
+        terminated!($i, tag!($keyword), not!(complete!(alt!(tag!("_") | call!(::nom::alpha) | call!(::nom::digit)))))
+    );
+);
@@ -0,0 +1,445 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The `nom-lua` command, which parses Lua files and shows what the parser
+//! makes of them
+//!
+//! ```text
+//! nom-lua check src/**/*.lua
+//! echo 'return 1 + 2' | nom-lua ast -
+//! nom-lua dot -o ast.dot main.lua
+//! ```
+//!
+//! A file may be a glob pattern, for shells that do not expand them, or
+//! `-` for stdin. The exit status is 1 when any file fails and 2 when the
+//! arguments are wrong.
+
+extern crate nom_lua;
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::process;
+
+use nom_lua::arena::Arena;
+use nom_lua::span::{self, Span};
+use nom_lua::validate::validate;
+use nom_lua::{parse_block_with, printer, sexp, ASTNode, Error, LuaVersion, ParserConfig};
+
+// The lines of the usage, the ones of features left out of the build are
+// not shown
+const USAGE: &[(Option<&str>, &str)] = &[
+    (None, "usage: nom-lua <command> [options] <file>...\n\ncommands:"),
+    (None, "    check     report syntax errors and misplaced goto, labels and break"),
+    (None, "    ast       print the tree as an S-expression"),
+    (Some("graphviz"), "    dot       write the tree as a Graphviz graph"),
+    (None, "    tokens    list the tokens with their line and column"),
+    (None, "    fmt       print the files formatted\n\noptions:"),
+    (None, "    --lua <version>      accept Lua 5.1, 5.2, 5.3 (the default) or 5.4"),
+    (Some("serde"), "    --json               ast: print the tree as JSON"),
+    (Some("serde"), "    --spans              ast: with --json, add the span of each node"),
+    (Some("serde"), "    --luaparse           ast: print the JSON of the luaparse npm package"),
+    (Some("graphviz"), "    --cluster-functions  dot: box every function body"),
+    (Some("graphviz"), "    -o <file>            dot: write to <file> instead of stdout"),
+    (None, "    -w, --write          fmt: rewrite the files instead of printing them"),
+    (None, "    --check              fmt: list the files that are not formatted"),
+    (None, "\nA file may be a glob pattern such as 'src/**/*.lua', or - for stdin."),
+];
+
+fn has_feature(feature: &str) -> bool {
+    (feature == "graphviz" && cfg!(feature="graphviz")) || (feature == "serde" && cfg!(feature="serde"))
+}
+
+fn usage_text() -> String {
+    let lines: Vec<&str> = USAGE.iter()
+        .filter(|&&(feature, _)| feature.is_none_or(has_feature))
+        .map(|&(_, line)| line)
+        .collect();
+    lines.join("\n")
+}
+
+// Exits with a usage error when `feature` was left out of the build
+fn need_feature(what: &str, feature: &str) {
+    if !has_feature(feature) {
+        usage(&format!("{} needs nom-lua built with the {} feature", what, feature));
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Command {
+    Check,
+    Ast,
+    Dot,
+    Tokens,
+    Fmt,
+}
+
+struct Options {
+    command: Command,
+    version: LuaVersion,
+    json: bool,
+    spans: bool,
+    luaparse: bool,
+    cluster_functions: bool,
+    output: Option<String>,
+    write: bool,
+    check: bool,
+    files: Vec<String>,
+}
+
+fn usage(message: &str) -> ! {
+    eprintln!("nom-lua: {}\n\n{}", message, usage_text());
+    process::exit(2);
+}
+
+fn parse_args(args: Vec<String>) -> Options {
+    let mut args = args.into_iter();
+    let command = match args.next().as_ref().map(|s| &s[..]) {
+        Some("check") => Command::Check,
+        Some("ast") => Command::Ast,
+        Some("dot") => {
+            need_feature("dot", "graphviz");
+            Command::Dot
+        }
+        Some("tokens") => Command::Tokens,
+        Some("fmt") => Command::Fmt,
+        Some("-h") | Some("--help") => {
+            println!("{}", usage_text());
+            process::exit(0);
+        }
+        Some(other) => usage(&format!("unknown command {}", other)),
+        None => usage("no command given"),
+    };
+    let mut options = Options {
+        command,
+        version: LuaVersion::Lua53,
+        json: false,
+        spans: false,
+        luaparse: false,
+        cluster_functions: false,
+        output: None,
+        write: false,
+        check: false,
+        files: Vec::new(),
+    };
+    while let Some(arg) = args.next() {
+        if let "--json" | "--spans" | "--luaparse" = &arg[..] {
+            need_feature(&arg, "serde");
+        }
+        let allowed = match &arg[..] {
+            "--lua" => {
+                options.version = match args.next().as_ref().map(|s| &s[..]) {
+                    Some("5.1") => LuaVersion::Lua51,
+                    Some("5.2") => LuaVersion::Lua52,
+                    Some("5.3") => LuaVersion::Lua53,
+                    Some("5.4") => LuaVersion::Lua54,
+                    _ => usage("--lua takes 5.1, 5.2, 5.3 or 5.4"),
+                };
+                true
+            }
+            "--json" => { options.json = true; command == Command::Ast }
+            "--spans" => { options.spans = true; command == Command::Ast }
+            "--luaparse" => { options.luaparse = true; command == Command::Ast }
+            "--cluster-functions" => { options.cluster_functions = true; command == Command::Dot }
+            "-o" => {
+                options.output = Some(args.next().unwrap_or_else(|| usage("-o takes a file")));
+                command == Command::Dot
+            }
+            "-w" | "--write" => { options.write = true; command == Command::Fmt }
+            "--check" => { options.check = true; command == Command::Fmt }
+            "-" => { options.files.push(arg.clone()); true }
+            _ if arg.starts_with('-') => usage(&format!("unknown option {}", arg)),
+            _ => { options.files.push(arg.clone()); true }
+        };
+        if !allowed {
+            usage(&format!("{} does not apply to this command", arg));
+        }
+    }
+    if options.files.is_empty() {
+        usage("no files given, use - for stdin");
+    }
+    options
+}
+
+// Whether `name` matches `pattern`, where `*` is any run of characters and
+// `?` any single one
+fn matches(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((&b'*', rest)) => (0..=name.len()).any(|i| matches(rest, &name[i..])),
+        Some((&b'?', rest)) => !name.is_empty() && matches(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && matches(rest, &name[1..]),
+    }
+}
+
+fn is_pattern(part: &str) -> bool {
+    part.contains(['*', '?'])
+}
+
+// The entries of `dir` that are not hidden, unless `hidden`
+fn entries(dir: &PathBuf, hidden: bool) -> Vec<PathBuf> {
+    let listed = if dir.as_os_str().is_empty() { fs::read_dir(".") } else { fs::read_dir(dir) };
+    let mut paths: Vec<_> = match listed {
+        Ok(entries) => entries.filter_map(|e| e.ok())
+            .filter(|e| hidden || !e.file_name().to_string_lossy().starts_with('.'))
+            .map(|e| dir.join(e.file_name()))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    paths.sort();
+    paths
+}
+
+// `dir` and every directory below it, for `**`
+fn descendants(dir: &PathBuf) -> Vec<PathBuf> {
+    let mut dirs = vec![dir.clone()];
+    for entry in entries(dir, false) {
+        if entry.is_dir() {
+            dirs.extend(descendants(&entry));
+        }
+    }
+    dirs
+}
+
+// The files a glob pattern names, one part of the path at a time
+fn expand(pattern: &str) -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::new()];
+    for (i, part) in pattern.split('/').enumerate() {
+        paths = if part == "**" {
+            paths.iter().flat_map(descendants).collect()
+        } else if is_pattern(part) {
+            paths.iter()
+                .flat_map(|dir| entries(dir, part.starts_with('.')))
+                .filter(|path| path.file_name().is_some_and(|name| {
+                    matches(part.as_bytes(), name.to_string_lossy().as_bytes())
+                }))
+                .collect()
+        } else if i == 0 && part.is_empty() {
+            vec![PathBuf::from("/")]
+        } else {
+            paths.iter().map(|dir| dir.join(part)).collect()
+        };
+    }
+    paths.retain(|path| path.is_file());
+    paths.dedup();
+    paths
+}
+
+/// A file to work on, `name` is what diagnostics call it
+struct Input {
+    name: String,
+    path: Option<PathBuf>,
+    source: Vec<u8>,
+}
+
+impl Input {
+    // Where parsing starts, `lua` skips a first line starting with `#`
+    fn start(&self) -> usize {
+        if self.source.first() == Some(&b'#') {
+            self.source.iter().position(|&b| b == b'\n').unwrap_or(self.source.len())
+        } else {
+            0
+        }
+    }
+
+    // The source with the skipped line blanked out, so that offsets, lines
+    // and columns are those of the file
+    fn code(&self) -> Vec<u8> {
+        let mut code = self.source.clone();
+        for byte in &mut code[..self.start()] {
+            *byte = b' ';
+        }
+        code
+    }
+
+    fn at(&self, offset: usize) -> String {
+        let (line, column) = Span { start: offset, end: offset }.line_column(&self.source);
+        format!("{}:{}:{}", self.name, line, column)
+    }
+
+    fn parse(&self, options: &Options) -> Result<ASTNode, String> {
+        let config = ParserConfig { version: options.version, ..ParserConfig::default() };
+        parse_block_with(&self.code(), &config).map_err(|e| match e {
+            Error::Syntax(offset) => format!("{}: syntax error", self.at(offset)),
+            Error::Invalid(offset, message) => format!("{}: {}", self.at(offset), message),
+            e => format!("{}: {}", self.name, e),
+        })
+    }
+}
+
+fn read(file: &str) -> Result<Vec<Input>, String> {
+    if file == "-" {
+        let mut source = Vec::new();
+        io::stdin().read_to_end(&mut source).map_err(|e| format!("cannot read stdin: {}", e))?;
+        return Ok(vec![Input { name: "stdin".to_string(), path: None, source }]);
+    }
+    let paths = if is_pattern(file) { expand(file) } else { vec![PathBuf::from(file)] };
+    if paths.is_empty() {
+        return Err(format!("no files match {}", file));
+    }
+    paths.into_iter().map(|path| {
+        let source = fs::read(&path).map_err(|e| format!("cannot open {}: {}", path.display(), e))?;
+        Ok(Input { name: path.display().to_string(), path: Some(path), source })
+    }).collect()
+}
+
+fn check(input: &Input, options: &Options) -> Result<(), String> {
+    let ast = input.parse(options)?;
+    let errors = validate(&input.code(), &Arena::from_ast(&ast), options.version);
+    if errors.is_empty() {
+        return Ok(());
+    }
+    Err(errors.iter().map(|e| match e.span {
+        Some(span) => format!("{}: {}", input.at(span.start), e),
+        None => format!("{}: {}", input.name, e),
+    }).collect::<Vec<_>>().join("\n"))
+}
+
+#[cfg(feature="serde")]
+fn json(ast: &ASTNode, input: &Input, options: &Options) -> Result<String, String> {
+    use nom_lua::json;
+    use nom_lua::luaparse::{self, LuaparseConfig};
+    if options.luaparse {
+        let config = LuaparseConfig { locations: true, ranges: true };
+        luaparse::to_json(ast, &input.code(), &config).map_err(|e| format!("{}: {}", input.name, e))
+    } else if options.spans {
+        Ok(json::to_json_with_spans(ast, &input.code()))
+    } else {
+        Ok(json::to_json(ast))
+    }
+}
+
+#[cfg(not(feature="serde"))]
+fn json(_: &ASTNode, _: &Input, _: &Options) -> Result<String, String> {
+    unreachable!("parse_args rejects --json without the serde feature")
+}
+
+fn ast(input: &Input, options: &Options, out: &mut dyn Write) -> Result<(), String> {
+    let ast = input.parse(options)?;
+    let text = if options.json || options.luaparse {
+        json(&ast, input, options)? + "\n"
+    } else {
+        sexp::print(&ast)
+    };
+    out.write_all(text.as_bytes()).map_err(|e| e.to_string())
+}
+
+#[cfg(feature="graphviz")]
+fn dot(input: &Input, options: &Options, mut out: &mut dyn Write) -> Result<(), String> {
+    use nom_lua::graphviz::{render, DotConfig};
+    let ast = input.parse(options)?;
+    let config = DotConfig { cluster_functions: options.cluster_functions };
+    match options.output {
+        Some(ref path) => fs::File::create(path)
+            .and_then(|mut file| render(&ast, &config, &mut file))
+            .map_err(|e| format!("cannot write {}: {}", path, e)),
+        None => render(&ast, &config, &mut out).map_err(|e| e.to_string()),
+    }
+}
+
+#[cfg(not(feature="graphviz"))]
+fn dot(_: &Input, _: &Options, _: &mut dyn Write) -> Result<(), String> {
+    unreachable!("parse_args rejects dot without the graphviz feature")
+}
+
+fn tokens(input: &Input, out: &mut dyn Write) -> Result<(), String> {
+    for token in span::tokens(&input.source) {
+        let text = String::from_utf8_lossy(&input.source[token.span.start..token.span.end])
+            .replace('\n', "\\n").replace('\r', "\\r").replace('\t', "\\t");
+        writeln!(out, "{}: {} {}", input.at(token.span.start), token.kind, text).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn fmt(input: &Input, options: &Options, out: &mut dyn Write) -> Result<(), String> {
+    let ast = input.parse(options)?;
+    let code = input.code();
+    let printed = printer::print_with_comments(&ast, &code).ok_or_else(|| {
+        let comment = span::comments(&code)[0];
+        format!("{}: comments would be lost, not formatting", input.at(comment.start))
+    })?;
+    let mut formatted = input.source[..input.start()].to_vec();
+    if !formatted.is_empty() {
+        formatted.push(b'\n');
+    }
+    formatted.extend(printed.into_bytes());
+    if options.check {
+        if formatted != input.source {
+            return Err(format!("{}: not formatted", input.name));
+        }
+        return Ok(());
+    }
+    match input.path {
+        Some(ref path) if options.write => {
+            if formatted != input.source {
+                fs::write(path, &formatted).map_err(|e| format!("cannot write {}: {}", input.name, e))?;
+            }
+            Ok(())
+        }
+        _ => out.write_all(&formatted).map_err(|e| e.to_string()),
+    }
+}
+
+fn main() {
+    let options = parse_args(env::args().skip(1).collect());
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut failed = false;
+    let mut inputs = Vec::new();
+    for file in &options.files {
+        match read(file) {
+            Ok(found) => inputs.extend(found),
+            Err(e) => {
+                eprintln!("nom-lua: {}", e);
+                failed = true;
+            }
+        }
+    }
+    // Counted once patterns are expanded, a second file would overwrite the
+    // output of the first
+    if options.output.is_some() && inputs.len() > 1 {
+        usage("-o takes a single file");
+    }
+    for input in &inputs {
+        let result = match options.command {
+            Command::Check => check(input, &options),
+            Command::Ast => ast(input, &options, &mut out),
+            Command::Dot => dot(input, &options, &mut out),
+            Command::Tokens => tokens(input, &mut out),
+            Command::Fmt => fmt(input, &options, &mut out),
+        };
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            failed = true;
+        }
+    }
+    let _ = out.flush();
+    process::exit(if failed { 1 } else { 0 });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        assert!(matches(b"*.lua", b"a.lua"));
+        assert!(matches(b"*.lua", b".lua"));
+        assert!(matches(b"a?c*", b"abc"));
+        assert!(!matches(b"a?c", b"ac"));
+        assert!(!matches(b"*.lua", b"a.luac"));
+    }
+
+    #[test]
+    fn blanked_first_line() {
+        let input = Input { name: "x".into(), path: None, source: b"#!/bin/lua\nreturn".to_vec() };
+        assert_eq!(input.code(), b"          \nreturn");
+        assert_eq!(input.at(11), "x:2:1");
+    }
+}
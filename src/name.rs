@@ -6,22 +6,31 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use ast::ASTNode;
-use nom::{alpha, digit};
+use ast::{ASTNode, Attrib};
+use nom::{alpha, digit, IResult};
 use std::str;
 use std::str::FromStr;
+use config::{reject, version, LuaVersion};
+// A name being declared, which may have a type with the luau feature
+#[cfg(feature="luau")]
+use luau::parse_typed_name as parse_binding_name;
+#[cfg(not(feature="luau"))]
+use self::parse_name as parse_binding_name;
 
-named!(recognize_keyword, alt!(
+// A keyword must not be followed by another character of a name, otherwise
+// it is only the start of a name like `done` or `format`. Keywords that are
+// a prefix of another keyword have to come after it
+named!(recognize_keyword, terminated!(alt!(
      tag!("and") |
      tag!("break") |
      tag!("do") |
-     tag!("else") |
      tag!("elseif") |
+     tag!("else") |
      tag!("end") |
      tag!("false") |
      tag!("for") |
      tag!("function") |
-     tag!("goto") |
+     recognize_goto |
      tag!("if") |
      tag!("in") |
      tag!("local") |
@@ -34,7 +43,14 @@ named!(recognize_keyword, alt!(
      tag!("true") |
      tag!("until") |
      tag!("while")
-));
+), not!(complete!(alt!(tag!("_") | alpha | digit)))));
+
+// goto is a plain name before Lua 5.2
+named!(recognize_goto, map_opt!(tag!("goto"), |k| if version() >= LuaVersion::Lua52 {
+    Some(k)
+} else {
+    None
+}));
 
 named!(pub parse_name<ASTNode>, map!(parse_valid_name,  ASTNode::Name));
 
@@ -46,7 +62,7 @@ named!(pub parse_valid_name<String>, map_res!(map_res!(do_parse!(
             str::from_utf8), FromStr::from_str));
 
 named!(pub parse_label<ASTNode>, map!(delimited!(
-            tag!("::"),
+            terminated!(tag!("::"), requires!("labels", LuaVersion::Lua52)),
             ws!(parse_valid_name),
             tag!("::")),
 ASTNode::Label));
@@ -54,38 +70,125 @@ ASTNode::Label));
 
 named!(pub parse_namelist<ASTNode>, map!(
             map!(do_parse!(
-                   a: parse_name
-                >> b: many0!(preceded!(ws!(tag!(",")), parse_name))
+                   a: parse_binding_name
+                >> b: many0!(preceded!(ws!(tag!(",")), parse_binding_name))
                 >> ((a,b))
             ), |(a, mut b): (_, Vec < ASTNode >) | { b.insert(0, a); b }),
-ASTNode::NameList));
+|names: Vec<ASTNode>| ASTNode::NameList(names.into_iter().map(|n| (n, None)).collect())));
+
+named!(parse_attrib_name<String>, delimited!(
+            terminated!(tag!("<"), requires!("local attributes", LuaVersion::Lua54)),
+            ws!(parse_valid_name),
+            tag!(">")));
+
+fn parse_attrib(input: &[u8]) -> IResult<&[u8], Attrib> {
+    match parse_attrib_name(input) {
+        IResult::Done(rest, name) => match name.as_str() {
+            "const" => IResult::Done(rest, Attrib::Const),
+            "close" => IResult::Done(rest, Attrib::Close),
+            _ => reject(format!("unknown attribute '{}'", name), input),
+        },
+        IResult::Error(e) => IResult::Error(e),
+        IResult::Incomplete(needed) => IResult::Incomplete(needed),
+    }
+}
+
+named!(parse_attname<(ASTNode, Option<Attrib>)>,
+       pair!(parse_binding_name, opt!(complete!(ws!(parse_attrib)))));
+
+named!(parse_attnames<Vec<(ASTNode, Option<Attrib>)>>, map!(
+            pair!(parse_attname, many0!(complete!(preceded!(ws!(tag!(",")), parse_attname)))),
+            |(a, mut b): (_, Vec<_>)| { b.insert(0, a); b }));
+
+// Only one variable of a declaration may be closed
+pub fn parse_attnamelist(input: &[u8]) -> IResult<&[u8], ASTNode> {
+    match parse_attnames(input) {
+        IResult::Done(rest, names) => {
+            if names.iter().filter(|&&(_, attrib)| attrib == Some(Attrib::Close)).count() > 1 {
+                reject("multiple to-be-closed variables in local list".to_string(), input)
+            } else {
+                IResult::Done(rest, ASTNode::NameList(names))
+            }
+        }
+        IResult::Error(e) => IResult::Error(e),
+        IResult::Incomplete(needed) => IResult::Incomplete(needed),
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use ast::ASTNode::*;
+    use ast::{ASTNode, Attrib};
+    use config::{with_config, LuaVersion, ParserConfig};
+    use super::parse_attnamelist;
 
     ast_test!(parse_valid_name_1, parse_valid_name, "il", "il".to_string());
     ast_test!(parse_valid_name_2, parse_valid_name, "_il3", "_il3".to_string());
     ast_panic_test!(parse_valid_name_3, parse_valid_name, "3lc_");
     ast_panic_test!(parse_valid_name_4, parse_valid_name, "not");
+    ast_test!(parse_valid_name_5, parse_valid_name, "done", "done".to_string());
+    ast_test!(parse_valid_name_6, parse_valid_name, "nil_", "nil_".to_string());
+    ast_panic_test!(parse_valid_name_7, parse_valid_name, "end ");
+    ast_panic_test!(parse_valid_name_8, parse_valid_name, "elseif");
+    ast_test!(parse_valid_name_9, parse_valid_name, "elseif_", "elseif_".to_string());
 
     ast_test!(parse_label_1, parse_label, "::il::", ast!(Label, "il".into()));
     ast_test!(parse_label_2, parse_label, ":: z ::", ast!(Label, "z".into()));
 
     ast_test!(parse_namelist_1, parse_namelist, "name1", ast!(NameList, vec![
-        ast!(Name, "name1".into()),
+        (ast!(Name, "name1".into()), None),
     ]));
     ast_test!(parse_namelist_2, parse_namelist, "name1 , name2", ast!(NameList, vec![
-        ast!(Name, "name1".into()),
-        ast!(Name, "name2".into()),
+        (ast!(Name, "name1".into()), None),
+        (ast!(Name, "name2".into()), None),
     ]));
     ast_test!(parse_namelist_3, parse_namelist, "name1 , name2, name3", ast!(NameList, vec![
-        ast!(Name, "name1".into()),
-        ast!(Name, "name2".into()),
-        ast!(Name, "name3".into()),
+        (ast!(Name, "name1".into()), None),
+        (ast!(Name, "name2".into()), None),
+        (ast!(Name, "name3".into()), None),
     ]));
     ast_test!(parse_namelist_4, parse_namelist, "a,b", ast!(NameList, vec![
-        ast!(Name, "a".into()),
-        ast!(Name, "b".into()),
+        (ast!(Name, "a".into()), None),
+        (ast!(Name, "b".into()), None),
     ]));
+
+    fn parse_lua54(input: &str) -> Option<ASTNode> {
+        let config = ParserConfig { version: LuaVersion::Lua54, ..ParserConfig::default() };
+        with_config(&config, || parse_attnamelist(input.as_bytes()).to_result().ok())
+    }
+
+    #[test]
+    fn parse_attnamelist_1() {
+        assert_eq!(parse_lua54("x <const>, y"), Some(ast!(NameList, vec![
+            (ast!(Name, "x".into()), Some(Attrib::Const)),
+            (ast!(Name, "y".into()), None),
+        ])));
+    }
+
+    #[test]
+    fn parse_attnamelist_2() {
+        assert_eq!(parse_lua54("f< close >,g <const>"), Some(ast!(NameList, vec![
+            (ast!(Name, "f".into()), Some(Attrib::Close)),
+            (ast!(Name, "g".into()), Some(Attrib::Const)),
+        ])));
+    }
+
+    #[test]
+    fn parse_attnamelist_3() {
+        assert_eq!(parse_lua54("f <close>, g <close>"), None);
+    }
+
+    #[test]
+    fn attrib_errors() {
+        use error::Error;
+        use parse_block_with;
+
+        let config = ParserConfig { version: LuaVersion::Lua54, ..ParserConfig::default() };
+        assert_eq!(parse_block_with(b"local x <foo> = 1", &config),
+                   Err(Error::Invalid(8, "unknown attribute 'foo'".to_string())));
+        assert_eq!(parse_block_with(b"x = 1\nlocal f <close>, g <close> = a, b", &config),
+                   Err(Error::Invalid(12, "multiple to-be-closed variables in local list".to_string())));
+        // A syntax error before the attribute is reported first
+        assert_eq!(parse_block_with(b"x = = 1\nlocal x <foo> = 1", &config), Err(Error::Syntax(0)));
+    }
 }
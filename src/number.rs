@@ -11,9 +11,8 @@ use std::str::FromStr;
 use ast::ASTNode;
 use ast::ASTNode::*;
 
-use super::nom::{digit, hex_digit};
+use super::nom::{digit, hex_digit, ErrorKind, IResult};
 //TODO: LOCALE dependent decimal point!
-//TODO: Hex numbers
 
 named!(parse_int_overflow<ASTNode>, map!(
            map_res!(map_res!(digit, str::from_utf8), FromStr::from_str),
@@ -23,13 +22,15 @@ named!(parse_int<ASTNode>, map!(
            map_res!(map_res!(digit, str::from_utf8), FromStr::from_str),
            ASTNode::Integer));
 
+// Hexadecimal integers wrap around modulo 2^64 instead of overflowing to a
+// float, `0xffffffffffffffff` is -1
 named!(parse_hex_int<ASTNode>,
            do_parse!(
                preceded!(tag!("0"), alt!(tag!("x") | tag!("X"))) >>
-               hex: map_res!(
-                     map_res!(hex_digit, str::from_utf8),
-                    |h| i64::from_str_radix(h, 16))
-               >> (ast!(Integer, hex))));
+               hex: map!(hex_digit, |h: &[u8]| h.iter().fold(0u64, |n, &c| {
+                   n.wrapping_mul(16).wrapping_add(u64::from((c as char).to_digit(16).unwrap()))
+               }))
+               >> (ast!(Integer, hex as i64))));
 
 named!(parse_float_exp, recognize!(do_parse!(
                alt!(tag!("e") | tag!("E"))
@@ -39,30 +40,96 @@ named!(parse_float_exp, recognize!(do_parse!(
 
 named!(parse_float<ASTNode>,
        do_parse!(
-              float: map_res!( map_res!( recognize!( do_parse!(
+              // Digits without a decimal point are only a float with an exponent
+              float: map_res!( map_res!( alt!(
+                      recognize!( do_parse!(
                          alt!(
                           delimited!(digit, tag!("."), opt!(complete!(digit))) |
-                          delimited!(opt!(digit), tag!("."), digit) |
-                          digit)
+                          delimited!(opt!(digit), tag!("."), digit))
                       >> opt!(complete!(parse_float_exp))
-                      >> ())),
+                      >> ())) |
+                      recognize!(pair!(digit, parse_float_exp))),
                   str::from_utf8), FromStr::from_str)
            >> (ast!(Float, float)))
       );
 
 
-//named!(parse_hex_float<ASTNode>, map_res!(apply!(parse_hexf64, false), |_| ASTNode::Float(0.0f)));
+/// Parses a hexadecimal float such as `0x1.8p3`, which has a fraction, a
+/// binary exponent or both
+fn parse_hex_float(input: &[u8]) -> IResult<&[u8], ASTNode> {
+    let error = IResult::Error(error_code!(ErrorKind::HexDigit));
+    if !(input.starts_with(b"0x") || input.starts_with(b"0X")) {
+        return error;
+    }
+    let mut i = 2;
+    let mut mantissa = 0f64;
+    let mut exponent = 0i64;
+    let (mut digits, mut point) = (0, false);
+    while let Some(&c) = input.get(i) {
+        match (c as char).to_digit(16) {
+            Some(d) => {
+                mantissa = mantissa * 16.0 + f64::from(d);
+                digits += 1;
+                if point {
+                    exponent -= 4;
+                }
+            }
+            None if c == b'.' && !point => point = true,
+            None => break,
+        }
+        i += 1;
+    }
+    if digits == 0 {
+        return error;
+    }
+    let mut binary = false;
+    if let Some(&b'p') | Some(&b'P') = input.get(i) {
+        let sign = match input.get(i + 1) {
+            Some(&b'-') => -1,
+            _ => 1,
+        };
+        let start = i + 1 + input[i + 1..].starts_with(b"+") as usize + (sign < 0) as usize;
+        let end = start + input[start..].iter().take_while(|c| c.is_ascii_digit()).count();
+        if end == start {
+            return error;
+        }
+        // An exponent too large to read is too large to matter
+        let value = str::from_utf8(&input[start..end]).unwrap().parse().unwrap_or(100_000);
+        exponent += sign * value;
+        binary = true;
+        i = end;
+    }
+    if !point && !binary {
+        return error;
+    }
+    IResult::Done(&input[i..], ast!(Float, scale(mantissa, exponent.clamp(-100_000, 100_000) as i32)))
+}
+
+// `x * 2^exponent`, in steps so that no power of two underflows or
+// overflows on its own, `0x1p-1074` is the smallest subnormal
+fn scale(mut x: f64, mut exponent: i32) -> f64 {
+    while exponent > 1000 && x.is_finite() && x != 0.0 {
+        x *= 2f64.powi(1000);
+        exponent -= 1000;
+    }
+    while exponent < -1000 && x != 0.0 {
+        x *= 2f64.powi(-1000);
+        exponent += 1000;
+    }
+    x * 2f64.powi(exponent)
+}
 
 
-named!(pub parse_number<ASTNode>, dbg_dmp!(alt!(
-            //complete!(parse_hex_float) |
+named!(pub parse_number<ASTNode>, alt!(
+            parse_hex_float |
             complete!(parse_hex_int) |
             complete!(parse_float) |
             parse_int |
             parse_int_overflow
-)));
+));
 
 #[cfg(test)]
+#[allow(clippy::approx_constant)]
 mod tests {
     //The tests panic because the macro calls unwrap, otherwise they should fail gracefully
 
@@ -86,6 +153,24 @@ mod tests {
     // preceding +/- are separate ASTNodes
     ast_panic_test!(parse_hex_7, parse_hex_int, "-0x20");
     ast_panic_test!(parse_hex_8, parse_hex_int, "+0x20");
+    ast_test!(parse_hex_9, parse_hex_int, "0xffffffffffffffff", ast!(Integer, -1));
+    ast_test!(parse_hex_10, parse_hex_int, "0x8000000000000000", ast!(Integer, i64::MIN));
+    ast_test!(parse_hex_11, parse_hex_int, "0x10000000000000001", ast!(Integer, 1));
+
+    ast_test!(parse_hex_float_1, parse_hex_float, "0x1p4", ast!(Float, 16.0));
+    ast_test!(parse_hex_float_2, parse_hex_float, "0x1.8", ast!(Float, 1.5));
+    ast_test!(parse_hex_float_3, parse_hex_float, "0X.8P-1", ast!(Float, 0.25));
+    ast_test!(parse_hex_float_4, parse_hex_float, "0xA.", ast!(Float, 10.0));
+    ast_test!(parse_hex_float_5, parse_hex_float, "0x1P+2", ast!(Float, 4.0));
+    ast_test!(parse_hex_float_6, parse_hex_float, "0x1p99999999999999999999", ast!(Float, f64::INFINITY));
+    ast_panic_test!(parse_hex_float_7, parse_hex_float, "0x10");
+    ast_panic_test!(parse_hex_float_8, parse_hex_float, "0x.p1");
+    ast_panic_test!(parse_hex_float_9, parse_hex_float, "0x1p");
+    ast_test!(parse_hex_float_10, parse_hex_float, "0x1p-1074", ast!(Float, f64::from_bits(1)));
+    ast_test!(parse_hex_float_11, parse_hex_float, "0x.1p-1070", ast!(Float, f64::from_bits(1)));
+    ast_test!(parse_hex_float_12, parse_hex_float, "0x1p-1022", ast!(Float, f64::MIN_POSITIVE));
+    ast_test!(parse_hex_float_13, parse_hex_float, "0x1p-1076", ast!(Float, 0.0));
+    ast_test!(parse_hex_float_14, parse_hex_float, "0x1p1023", ast!(Float, 2f64.powi(1023)));
 
 
     ast_test!(parse_float_1, parse_float, "3.0", ast!(Float, 3.0));
@@ -109,6 +194,10 @@ mod tests {
     ast_test!(parse_number_2, parse_number, "20.0", ast!(Float, 20.0));
     ast_test!(parse_number_3, parse_number, "0x20", ast!(Integer, 0x20));
     ast_test!(parse_number_4, parse_number, "1000000000000000000000000", ast!(Float, 1e+24));
+    ast_test!(parse_number_5, parse_number, "1 ", ast!(Integer, 1));
+    ast_test!(parse_number_6, parse_number, "1e2 ", ast!(Float, 100.0));
+    ast_test!(parse_number_7, parse_number, "0x.1", ast!(Float, 0.0625));
+    ast_test!(parse_number_8, parse_number, "0xA", ast!(Integer, 10));
     //ast_panic_test!(parse_number_5, parse_number, "10f");
 
     quickcheck! {
@@ -117,7 +206,7 @@ mod tests {
             use nom::IResult;
             let formatted = format!("{}", x);
             let parsed = super::parse_int(formatted.as_bytes());
-            if let IResult::Done(a, b) = parsed {
+            if let IResult::Done(_, b) = parsed {
                 return b == ast!(Integer, x as i64);
             }
             false
@@ -13,40 +13,61 @@
 //mod logic_ops;
 //mod concat_ops;
 
+use std::fmt;
+use std::fmt::{Display, Formatter};
 use std::str;
 
+use nom::{ErrorKind, IResult};
+
 use ast::ASTNode;
 use ast::ASTNode::*;
-use super::exp::parse_exp;
-use super::number::parse_number;
+use super::exp::parse_simple_exp;
+use config::{check_depth, require, LuaVersion};
 
-//named!(pub parse_op<ASTNode>, dbg_dmp!(alt!(parse_exponent | parse_unop | parse_binop)));
-// for some reason we have to use the map
-named!(pub parse_op<ASTNode>, map!(parse_exponent, |a|a));
+// op ::= unop {binop unop}, the operators are folded by their precedence.
+// The chain is parsed in a loop, so a long flat expression such as a sum of
+// many terms does not count against `max_depth`
+named!(pub parse_op<ASTNode>, do_parse!(
+           left: parse_unop
+        >> right: many0!(complete!(pair!(binop, parse_unop)))
+        >> (fold_binop(left, right))));
 
+// Each unary operator nests its operand one level deeper
 named!(parse_unop<ASTNode>, do_parse!(
-           unop: many0!(unop)
-        >> right: parse_binop
+           unop: map_opt!(many0!(complete!(unop)), |u: Vec<UnOp>| if check_depth(u.len()) {
+               Some(u)
+           } else {
+               None
+           })
+        >> right: parse_exponent
         >> (fold_unop(unop, right))));
 
-named!(parse_binop<ASTNode>, do_parse!(
-           left: parse_atom
-        >> right: many0!(do_parse!(bop: binop >> right: parse_atom >> (bop, right)))
-        >> (fold_binop(left, right))));
-
+// ^ binds tighter than the unary operators on its left but not on its right,
+// -2 ^ -2 is -(2 ^ (-2)), and is right associative
 named!(parse_exponent<ASTNode>, do_parse!(
-           left: parse_unop
-        >> right: many0!(do_parse!(xp: exponent >> right: parse_unop >> (xp, right)))
-        >> (fold_binop(left, right))));
+           left: parse_atom
+        >> right: opt!(complete!(preceded!(exponent, nested!(parse_unop))))
+        >> (match right {
+            Some(right) => astb!(Exp, left, right),
+            None => left,
+        })));
 
-named!(parse_atom<ASTNode>, alt!(parse_number | delimited!(tag!("("), ws!(parse_exp), tag!(")"))));
+named!(parse_atom<ASTNode>, call!(parse_simple_exp));
 
 named!(exponent<BinOp>, map!(ws!(tag!("^")), |_| BinOp::Exp));
 
-named!(binop<BinOp>, alt!(
+fn binop(input: &[u8]) -> IResult<&[u8], BinOp> {
+    match binop_token(input) {
+        IResult::Done(_, op) if !op.is_supported(input) => IResult::Error(error_code!(ErrorKind::Custom(0))),
+        other => other,
+    }
+}
+
+// Operators that are a prefix of another operator have to come after it
+named!(binop_token<BinOp>, alt!(
     ws!(tag!("*"))   => { |_| BinOp::Mul } |
-    ws!(tag!("/"))   => { |_| BinOp::Div } |
     ws!(tag!("//"))  => { |_| BinOp::FDiv } |
+    ws!(tag!("/"))   => { |_| BinOp::Div } |
     ws!(tag!("%"))   => { |_| BinOp::Mod } |
     ws!(tag!("+"))   => { |_| BinOp::Add } |
     ws!(tag!("-"))   => { |_| BinOp::Sub } |
@@ -54,19 +75,20 @@ named!(binop<BinOp>, alt!(
     ws!(tag!("<<"))  => { |_| BinOp::Lsh } |
     ws!(tag!(">>"))  => { |_| BinOp::Rsh } |
     ws!(tag!("&"))   => { |_| BinOp::BitAnd } |
+    ws!(tag!("~="))  => { |_| BinOp::Ne } |
     ws!(tag!("~"))   => { |_| BinOp::BitXor } |
     ws!(tag!("|"))   => { |_| BinOp::BitOr } |
-    ws!(tag!("<"))   => { |_| BinOp::Lt } |
-    ws!(tag!(">"))   => { |_| BinOp::Gt } |
     ws!(tag!("<="))  => { |_| BinOp::Le } |
     ws!(tag!(">="))  => { |_| BinOp::Ge } |
-    ws!(tag!("~="))  => { |_| BinOp::Ne } |
+    ws!(tag!("<"))   => { |_| BinOp::Lt } |
+    ws!(tag!(">"))   => { |_| BinOp::Gt } |
     ws!(tag!("=="))  => { |_| BinOp::Eq } |
-    ws!(tag!("and")) => { |_| BinOp::And } |
-    ws!(tag!("or"))  => { |_| BinOp::Or }
+    ws!(keyword!("and")) => { |_| BinOp::And } |
+    ws!(keyword!("or"))  => { |_| BinOp::Or }
 ));
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
 pub enum BinOp {
     Exp,
     Mul,
@@ -91,10 +113,86 @@ pub enum BinOp {
     Or,
 }
 
+impl Display for BinOp {
+    fn fmt(&self, format: &mut Formatter) -> fmt::Result {
+        let token = match *self {
+            BinOp::Exp => "^",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::FDiv => "//",
+            BinOp::Mod => "%",
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Concat => "..",
+            BinOp::Lsh => "<<",
+            BinOp::Rsh => ">>",
+            BinOp::BitAnd => "&",
+            BinOp::BitXor => "~",
+            BinOp::BitOr => "|",
+            BinOp::Lt => "<",
+            BinOp::Gt => ">",
+            BinOp::Le => "<=",
+            BinOp::Ge => ">=",
+            BinOp::Ne => "~=",
+            BinOp::Eq => "==",
+            BinOp::And => "and",
+            BinOp::Or => "or",
+        };
+        write!(format, "{}", token)
+    }
+}
+
+impl BinOp {
+    /// How tightly the operator binds, higher binds tighter
+    pub fn precedence(self) -> u8 {
+        match self {
+            BinOp::Or => 1,
+            BinOp::And => 2,
+            BinOp::Lt |
+            BinOp::Gt |
+            BinOp::Le |
+            BinOp::Ge |
+            BinOp::Ne |
+            BinOp::Eq => 3,
+            BinOp::BitOr => 4,
+            BinOp::BitXor => 5,
+            BinOp::BitAnd => 6,
+            BinOp::Lsh |
+            BinOp::Rsh => 7,
+            BinOp::Concat => 8,
+            BinOp::Add |
+            BinOp::Sub => 9,
+            BinOp::Mul |
+            BinOp::Div |
+            BinOp::FDiv |
+            BinOp::Mod => 10,
+            // The unary operators are 11
+            BinOp::Exp => 12,
+        }
+    }
+
+    pub fn is_right_associative(self) -> bool {
+        self == BinOp::Concat || self == BinOp::Exp
+    }
+
+    /// Whether the configured Lua version has this operator, found at `input`
+    fn is_supported(&self, input: &[u8]) -> bool {
+        match *self {
+            BinOp::FDiv => require("integer division", LuaVersion::Lua53, input),
+            BinOp::Lsh |
+            BinOp::Rsh |
+            BinOp::BitAnd |
+            BinOp::BitXor |
+            BinOp::BitOr => require("bitwise operators", LuaVersion::Lua53, input),
+            _ => true,
+        }
+    }
+}
+
 
 fn fold_unop(unop: Vec<UnOp>, initial: ASTNode) -> ASTNode {
-    unop.into_iter().fold(initial, |acc, op| {
-        println!("Proc unop: {:?}", op);
+    // The operator closest to the operand is applied first
+    unop.into_iter().rev().fold(initial, |acc, op| {
         match op {
             UnOp::BinNot => astb!(BinNot, acc),
             UnOp::Not => astb!(Not, acc),
@@ -104,42 +202,73 @@ fn fold_unop(unop: Vec<UnOp>, initial: ASTNode) -> ASTNode {
     })
 }
 
+pub(crate) fn make_binop(op: BinOp, left: ASTNode, right: ASTNode) -> ASTNode {
+    match op {
+        BinOp::Exp => astb!(Exp, left, right),
+        BinOp::Mul => astb!(Mul, left, right),
+        BinOp::Div => astb!(Div, left, right),
+        BinOp::FDiv => astb!(FDiv, left, right),
+        BinOp::Mod => astb!(Mod, left, right),
+        BinOp::Add => astb!(Add, left, right),
+        BinOp::Sub => astb!(Sub, left, right),
+        BinOp::Concat => astb!(Concat, left, right),
+        BinOp::Lsh => astb!(Lsh, left, right),
+        BinOp::Rsh => astb!(Rsh, left, right),
+        BinOp::BitAnd => astb!(BitAnd, left, right),
+        BinOp::BitXor => astb!(BitXor, left, right),
+        BinOp::BitOr => astb!(BitOr, left, right),
+        BinOp::Lt => astb!(Lt, left, right),
+        BinOp::Gt => astb!(Gt, left, right),
+        BinOp::Le => astb!(Le, left, right),
+        BinOp::Ge => astb!(Ge, left, right),
+        BinOp::Ne => astb!(Ne, left, right),
+        BinOp::Eq => astb!(Eq, left, right),
+        BinOp::And => astb!(And, left, right),
+        BinOp::Or => astb!(Or, left, right),
+    }
+}
+
+// Shunting-yard, so that long chains of operators do not recurse
 fn fold_binop(left: ASTNode, remainder: Vec<(BinOp, ASTNode)>) -> ASTNode {
-    remainder.into_iter().fold(left, |acc, pair| {
-        let (op, right) = pair;
-        println!("Proc binop: {:?}", op);
-        match op {
-            //TODO: This is right Associative, we need to invert this operation
-            BinOp::Exp => astb!(Exp, acc, right),
-            BinOp::Mul => astb!(Mul, acc, right),
-            BinOp::Div => astb!(Div, acc, right),
-            BinOp::FDiv => astb!(FDiv, acc, right),
-            BinOp::Mod => astb!(Mod, acc, right),
-            BinOp::Add => astb!(Add, acc, right),
-            BinOp::Sub => astb!(Sub, acc, right),
-            //TODO: This is right Associative, we need to invert this operation
-            BinOp::Concat => astb!(Concat, acc, right),
-            BinOp::Lsh => astb!(Lsh, acc, right),
-            BinOp::Rsh => astb!(Rsh, acc, right),
-            BinOp::BitAnd => astb!(BitAnd, acc, right),
-            BinOp::BitXor => astb!(BitXor, acc, right),
-            BinOp::BitOr => astb!(BitOr, acc, right),
-            BinOp::Lt => astb!(Lt, acc, right),
-            BinOp::Gt => astb!(Gt, acc, right),
-            BinOp::Le => astb!(Le, acc, right),
-            BinOp::Ge => astb!(Ge, acc, right),
-            BinOp::Ne => astb!(Ne, acc, right),
-            BinOp::Eq => astb!(Eq, acc, right),
-            BinOp::And => astb!(And, acc, right),
-            BinOp::Or => astb!(Or, acc, right),
+    fn reduce(operands: &mut Vec<ASTNode>, op: BinOp) {
+        let right = operands.pop().unwrap();
+        let left = operands.pop().unwrap();
+        operands.push(make_binop(op, left, right));
+    }
+
+    let mut operands = vec![left];
+    let mut operators: Vec<BinOp> = Vec::new();
+    for (op, right) in remainder {
+        while let Some(&top) = operators.last() {
+            if top.precedence() > op.precedence() ||
+               (top.precedence() == op.precedence() && !op.is_right_associative()) {
+                operators.pop();
+                reduce(&mut operands, top);
+            } else {
+                break;
+            }
         }
-    })
+        operators.push(op);
+        operands.push(right);
+    }
+    while let Some(op) = operators.pop() {
+        reduce(&mut operands, op);
+    }
+    operands.pop().unwrap()
 }
 
 
+pub fn unop(input: &[u8]) -> IResult<&[u8], UnOp> {
+    match unop_token(input) {
+        IResult::Done(_, UnOp::BinNot) if !require("bitwise operators", LuaVersion::Lua53, input) =>
+            IResult::Error(error_code!(ErrorKind::Custom(0))),
+        other => other,
+    }
+}
+
 // TODO: Change to be just preceded by whitespace
-named!(pub unop<UnOp>, alt!(
-    ws!(tag!("not"))  => { |_| UnOp::Not } |
+named!(unop_token<UnOp>, alt!(
+    ws!(keyword!("not")) => { |_| UnOp::Not } |
     ws!(tag!("#"))    => { |_| UnOp::Len } |
     ws!(tag!("-"))    => { |_| UnOp::UMin } |
     ws!(tag!("~"))    => { |_| UnOp::BinNot }
@@ -152,3 +281,43 @@ pub enum UnOp {
     UMin,
     BinNot,
 }
+
+#[cfg(test)]
+mod tests {
+    use ast::ASTNode;
+    use ast::ASTNode::*;
+
+    ast_test!(parse_op_fdiv, parse_op, "0x4 // 0x2",
+              astb!(FDiv, ast!(Integer, 4), ast!(Integer, 2)));
+    ast_test!(parse_op_le, parse_op, "0x4 <= 0x2",
+              astb!(Le, ast!(Integer, 4), ast!(Integer, 2)));
+    ast_test!(parse_op_ge, parse_op, "0x4 >= 0x2",
+              astb!(Ge, ast!(Integer, 4), ast!(Integer, 2)));
+    ast_test!(parse_op_ne, parse_op, "0x4 ~= 0x2",
+              astb!(Ne, ast!(Integer, 4), ast!(Integer, 2)));
+
+    fn int(i: i64) -> ASTNode {
+        ast!(Integer, i)
+    }
+
+    ast_test!(parse_op_precedence_1, parse_op, "1 + 2 * 3",
+              astb!(Add, int(1), astb!(Mul, int(2), int(3))));
+    ast_test!(parse_op_precedence_2, parse_op, "1 * 2 + 3 == 4 and 5 or 6",
+              astb!(Or, astb!(And, astb!(Eq, astb!(Add, astb!(Mul, int(1), int(2)), int(3)), int(4)), int(5)), int(6)));
+    ast_test!(parse_op_precedence_3, parse_op, "1 - 2 - 3",
+              astb!(Sub, astb!(Sub, int(1), int(2)), int(3)));
+    ast_test!(parse_op_precedence_4, parse_op, "1 .. 2 .. 3",
+              astb!(Concat, int(1), astb!(Concat, int(2), int(3))));
+    ast_test!(parse_op_precedence_5, parse_op, "1 | 2 ~ 3 & 4 << 5",
+              astb!(BitOr, int(1), astb!(BitXor, int(2), astb!(BitAnd, int(3), astb!(Lsh, int(4), int(5))))));
+    ast_test!(parse_op_exp_1, parse_op, "2 ^ 3 ^ 2",
+              astb!(Exp, int(2), astb!(Exp, int(3), int(2))));
+    ast_test!(parse_op_exp_2, parse_op, "-2 ^ -2",
+              astb!(UMin, astb!(Exp, int(2), astb!(UMin, int(2)))));
+    ast_test!(parse_op_unop_1, parse_op, "- not #1",
+              astb!(UMin, astb!(Not, astb!(Len, int(1)))));
+    ast_test!(parse_op_unop_2, parse_op, "-1 + 2",
+              astb!(Add, astb!(UMin, int(1)), int(2)));
+    ast_test!(parse_op_keyword_1, parse_op, "nothing",
+              astb!(PrefixExp, astb!(Var, ast!(Name, "nothing".into()))));
+}
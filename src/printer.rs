@@ -0,0 +1,693 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Prints an AST back as Lua source
+//!
+//! The output parses back to the same tree: operands only get parentheses
+//! where precedence needs them, a parenthesized expression keeps its
+//! `PrefixExp`, and a statement starting with `(` is preceded by `;` when
+//! it would otherwise be read as a call on the previous line, which only
+//! happens in trees the parser does not make. Blocks are indented
+//! by two spaces. The original layout is not kept, and comments only by
+//! `print_with_comments`.
+
+use arena::{Arena, NodeKind};
+use ast::ASTNode;
+use ast::ASTNode::*;
+use op::BinOp;
+use span::{comments, spans, tokens, Span, TokenKind};
+use std::string::String;
+
+const INDENT: &str = "  ";
+
+// Above every operator, the operands that never need parentheses
+const ATOM: u8 = 14;
+const UNARY: u8 = 11;
+
+pub(crate) const KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if",
+    "in", "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+/// Prints `node` as Lua source
+///
+/// A Block is printed as a chunk, one statement per line. Any other
+/// statement or expression is printed on its own.
+pub fn print(node: &ASTNode) -> String {
+    match *node {
+        Block(_, _) => block(node, 0),
+        _ if is_statement(node) => statement(node, 0),
+        _ => other(node, 0),
+    }
+}
+
+/// Prints `block`, parsed from `source`, as `print` does but keeping the
+/// comments of `source`
+///
+/// A comment on a line of its own goes on its own line before the line
+/// holding the name or keyword that follows it. A comment after code goes
+/// at the end of the line holding the name or keyword before it. Comments
+/// keep their order and stay within the lines of the innermost statement
+/// holding them, so when a statement is joined on one line, the comments
+/// inside it go before that line except the last one after code. None
+/// when `source` has comments but not the same names and keywords as the
+/// output, which leaves nowhere to put them.
+pub fn print_with_comments(block: &ASTNode, source: &[u8]) -> Option<String> {
+    let printed = print(block);
+    let comments = comments(source);
+    if comments.is_empty() {
+        return Some(printed);
+    }
+    let words = |source: &[u8]| -> Vec<Span> {
+        tokens(source).into_iter()
+            .filter(|t| t.kind == TokenKind::Keyword || t.kind == TokenKind::Name)
+            .map(|t| t.span)
+            .collect()
+    };
+    let (old, new) = (words(source), words(printed.as_bytes()));
+    if old.len() != new.len() ||
+        old.iter().zip(&new).any(|(a, b)| source[a.start..a.end] != printed.as_bytes()[b.start..b.end]) {
+        return None;
+    }
+    let lines: Vec<&str> = printed.lines().collect();
+    let mut starts = vec![0];
+    starts.extend(printed.bytes().enumerate().filter(|&(_, c)| c == b'\n').map(|(i, _)| i + 1));
+    let line_of = |offset: usize| match starts.binary_search(&offset) {
+        Ok(line) => line,
+        Err(line) => line - 1,
+    };
+    // The statements, where they are in the source and in the output
+    let arena = Arena::from_ast(block);
+    let (old_spans, new_spans) = (spans(source, &arena), spans(printed.as_bytes(), &arena));
+    let statements: Vec<(Span, Span)> = arena.ids()
+        .filter(|&id| arena.parent(id).is_some_and(|p| matches!(*arena.kind(p), NodeKind::Block)))
+        .filter_map(|id| Some((*old_spans.get(id)?, *new_spans.get(id)?)))
+        .collect();
+    // Where each comment goes, before or after a line of the output
+    const BEFORE: bool = false;
+    const AFTER: bool = true;
+    let mut slots = Vec::with_capacity(comments.len());
+    for comment in comments {
+        let text = ::std::str::from_utf8(&source[comment.start..comment.end]).ok()?.trim_end();
+        let line_start = source[..comment.start].iter().rposition(|&c| c == b'\n').map_or(0, |i| i + 1);
+        let own_line = source[line_start..comment.start].iter().all(|c| c.is_ascii_whitespace());
+        let next = old.iter().position(|word| word.start >= comment.end);
+        let previous = next.unwrap_or(old.len()).checked_sub(1);
+        let mut slot = match (own_line, previous) {
+            (false, Some(previous)) => (line_of(new[previous].start), AFTER),
+            _ => (next.map_or(lines.len(), |n| line_of(new[n].start)), BEFORE),
+        };
+        let inside = statements.iter()
+            .filter(|&&(old, _)| old.start < comment.start && comment.end <= old.end)
+            .min_by_key(|&&(old, _)| old.end - old.start);
+        if let Some(&(_, new)) = inside {
+            let (first, last) = (line_of(new.start), line_of(new.end - 1));
+            slot = slot.max((first, BEFORE)).min((last, !own_line));
+        }
+        slots.push((slot, text));
+    }
+    // Going back from the last comment, an earlier comment never goes after
+    // a later one, and only one comment goes after each line
+    let mut limit = (usize::MAX, BEFORE);
+    for &mut (ref mut slot, _) in slots.iter_mut().rev() {
+        *slot = (*slot).min(limit);
+        if *slot == limit && slot.1 == AFTER {
+            slot.1 = BEFORE;
+        }
+        limit = *slot;
+    }
+    // The comments before each line, and after the last one
+    let mut before = vec![Vec::new(); lines.len() + 1];
+    let mut after = vec![None; lines.len()];
+    for ((line, side), text) in slots {
+        if side == AFTER {
+            after[line] = Some(text);
+        } else {
+            before[line].push(text);
+        }
+    }
+    let mut out = String::with_capacity(printed.len() + source.len());
+    for (i, comments) in before.iter().enumerate() {
+        let line = lines.get(i).cloned().unwrap_or("");
+        let code = line.trim_start();
+        let mut indent = line[..line.len() - code.len()].to_string();
+        let first = code.split(|c: char| c != '_' && !c.is_ascii_alphanumeric()).next();
+        if let Some("end") | Some("else") | Some("elseif") | Some("until") = first {
+            indent.push_str(INDENT);
+        }
+        for comment in comments {
+            out.push_str(&indent);
+            out.push_str(comment);
+            out.push('\n');
+        }
+        if i < lines.len() {
+            out.push_str(line);
+            if let Some(comment) = after[i] {
+                out.push(' ');
+                out.push_str(comment);
+            }
+            out.push('\n');
+        }
+    }
+    Some(out)
+}
+
+/// Whether `s` can be used as a name, it is not a keyword
+pub(crate) fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c == '_' || c.is_ascii_alphabetic() => {},
+        _ => return false,
+    }
+    chars.all(|c| c == '_' || c.is_ascii_alphanumeric()) && !KEYWORDS.contains(&s)
+}
+
+fn is_statement(node: &ASTNode) -> bool {
+    match *node {
+        EmptyStatement | Break | Goto(_) | RetStat(_) | Label(_) | Assign(_, _) | Do(_) |
+        While(_, _) | Repeat(_, _) | If(_, _, _) | NumericFor(_, _, _, _, _) |
+        GenericFor(_, _, _) | FunctionStat(_, _) | NamedFunction(_, _) | Local(_, _) |
+        FunctionCall(_, _) | MethodCall(_, _, _) => true,
+        #[cfg(feature="luau")]
+        TypeAlias(_, _, _, _) | CompoundAssign(_, _, _) | Continue => true,
+        _ => false,
+    }
+}
+
+fn pad(indent: usize) -> String {
+    INDENT.repeat(indent)
+}
+
+fn block(node: &ASTNode, indent: usize) -> String {
+    let mut out = String::new();
+    if let Block(ref statements, ref retstat) = *node {
+        let mut previous: Option<(&ASTNode, String)> = None;
+        for s in statements {
+            let text = statement(s, indent);
+            out.push_str(&pad(indent));
+            if let Some((node, ref last)) = previous {
+                if text.starts_with('(') && is_callable_end(node, last) {
+                    out.push(';');
+                }
+            }
+            out.push_str(&text);
+            out.push('\n');
+            previous = Some((s, text));
+        }
+        if let Some(ref retstat) = **retstat {
+            out.push_str(&pad(indent));
+            out.push_str(&statement(retstat, indent));
+            out.push('\n');
+        }
+    }
+    out
+}
+
+// Whether `node`, printed as `text`, ends with an expression that a `(`
+// after it would call, a name, an index or a call
+fn is_callable_end(node: &ASTNode, text: &str) -> bool {
+    match *node {
+        // The names of these are not expressions
+        Local(_, ref exps) if exps.is_none() => return false,
+        Goto(_) => return false,
+        #[cfg(feature="luau")]
+        TypeAlias(_, _, _, _) => return false,
+        _ => {}
+    }
+    match tokens(text.as_bytes()).last() {
+        Some(token) => match token.kind {
+            TokenKind::Name => true,
+            TokenKind::Symbol => matches!(&text[token.span.start..token.span.end], ")" | "]"),
+            _ => false,
+        },
+        None => false,
+    }
+}
+
+/// The lines of a nested block followed by the indentation of its `end`, or
+/// a single space when it is empty
+fn body(node: &ASTNode, indent: usize) -> String {
+    let lines = block(node, indent + 1);
+    if lines.is_empty() {
+        " ".to_string()
+    } else {
+        format!("\n{}{}", lines, pad(indent))
+    }
+}
+
+fn statement(node: &ASTNode, indent: usize) -> String {
+    match *node {
+        EmptyStatement => ";".to_string(),
+        Break => "break".to_string(),
+        Goto(ref name) => format!("goto {}", other(name, indent)),
+        Label(ref name) => format!("::{}::", name),
+        RetStat(ref exps) => match **exps {
+            Some(ref exps) => format!("return {}", other(exps, indent)),
+            None => "return".to_string(),
+        },
+        Assign(ref vars, ref exps) => format!("{} = {}", other(vars, indent), other(exps, indent)),
+        Do(ref b) => format!("do{}end", body(b, indent)),
+        While(ref cond, ref b) => format!("while {} do{}end", exp(cond, 0, indent), body(b, indent)),
+        Repeat(ref b, ref cond) => format!("repeat{}until {}", body(b, indent), exp(cond, 0, indent)),
+        If(_, _, ref first_other) => {
+            // With several clauses, each starts a line even when it is empty
+            let body = |b: &ASTNode| match body(b, indent) {
+                ref empty if empty == " " && first_other.is_some() => format!("\n{}", pad(indent)),
+                lines => lines,
+            };
+            let mut out = "if ".to_string();
+            let mut node = node;
+            loop {
+                match *node {
+                    If(ref cond, ref b, ref other) => {
+                        out.push_str(&format!("{} then{}", exp(cond, 0, indent), body(b)));
+                        match **other {
+                            Some(ref e @ If(_, _, _)) => {
+                                out.push_str("elseif ");
+                                node = e;
+                            }
+                            Some(ref b) => {
+                                out.push_str(&format!("else{}", body(b)));
+                                break;
+                            }
+                            None => break,
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            out + "end"
+        }
+        NumericFor(ref name, ref start, ref limit, ref step, ref b) => {
+            let step = match **step {
+                Some(ref step) => format!(", {}", exp(step, 0, indent)),
+                None => String::new(),
+            };
+            format!("for {} = {}, {}{} do{}end", other(name, indent), exp(start, 0, indent),
+                    exp(limit, 0, indent), step, body(b, indent))
+        }
+        GenericFor(ref names, ref exps, ref b) =>
+            format!("for {} in {} do{}end", other(names, indent), other(exps, indent), body(b, indent)),
+        FunctionStat(ref name, ref f) =>
+            format!("function {}{}", other(name, indent), function_body(f, indent)),
+        NamedFunction(ref name, ref f) =>
+            format!("local function {}{}", other(name, indent), function_body(f, indent)),
+        Local(ref names, ref exps) => match **exps {
+            Some(ref exps) => format!("local {} = {}", other(names, indent), other(exps, indent)),
+            None => format!("local {}", other(names, indent)),
+        },
+        #[cfg(feature="luau")]
+        TypeAlias(export, ref name, ref generics, ref t) => {
+            let generics = if generics.is_empty() {
+                String::new()
+            } else {
+                format!("<{}>", list(generics, indent))
+            };
+            format!("{}type {}{} = {}", if export { "export " } else { "" }, other(name, indent),
+                    generics, ty(t, 0, indent))
+        }
+        #[cfg(feature="luau")]
+        CompoundAssign(op, ref var, ref e) => format!("{} {}= {}", other(var, indent), op, exp(e, 0, indent)),
+        #[cfg(feature="luau")]
+        Continue => "continue".to_string(),
+        _ => exp(node, 0, indent),
+    }
+}
+
+/// Prints the parameters and the block of a function, from the `(`
+fn function_body(node: &ASTNode, indent: usize) -> String {
+    match *node {
+        FunctionBody(ref params, ref b) => format!("({}){}end", parameters(params, indent), body(b, indent)),
+        #[cfg(feature="luau")]
+        TypedFunctionBody(ref params, ref returns, ref b) =>
+            format!("({}): {}{}end", parameters(params, indent), return_type(returns, indent),
+                    body(b, indent)),
+        _ => other(node, indent),
+    }
+}
+
+fn parameters(params: &Option<ASTNode>, indent: usize) -> String {
+    match *params {
+        Some(ref params) => other(params, indent),
+        None => String::new(),
+    }
+}
+
+fn list(nodes: &[ASTNode], indent: usize) -> String {
+    nodes.iter().map(|n| other(n, indent)).collect::<Vec<_>>().join(", ")
+}
+
+/// Prints the nodes that are neither statements nor expressions, such as
+/// lists, and falls back to `exp` for the rest
+fn other(node: &ASTNode, indent: usize) -> String {
+    match *node {
+        Name(ref name) => name.clone(),
+        Block(_, _) => block(node, indent),
+        ExpList(ref exps) => exps.iter().map(|e| exp(e, 0, indent)).collect::<Vec<_>>().join(", "),
+        VarList(ref vars) => list(vars, indent),
+        NameList(ref names) => names.iter().map(|(name, attrib)| match *attrib {
+            Some(ref attrib) => format!("{} {}", other(name, indent), attrib),
+            None => other(name, indent),
+        }).collect::<Vec<_>>().join(", "),
+        FieldList(ref fields) => list(fields, indent),
+        ParameterList(ref names, vararg) => match (&**names, vararg) {
+            (Some(names), true) => format!("{}, ...", other(names, indent)),
+            (Some(names), false) => other(names, indent),
+            (&None, true) => "...".to_string(),
+            (&None, false) => String::new(),
+        },
+        FieldSingle(ref e) => exp(e, 0, indent),
+        FieldAssign(ref key, ref value) => match **key {
+            Name(ref name) if is_name(name) => format!("{} = {}", name, exp(value, 0, indent)),
+            Name(ref name) => format!("[{}] = {}", quote(name.as_bytes()), exp(value, 0, indent)),
+            _ => format!("[{}] = {}", exp(key, 0, indent), exp(value, 0, indent)),
+        },
+        FunctionName(ref name, ref fields, ref method) => {
+            let mut out = other(name, indent);
+            for field in fields.iter().flat_map(|f| f.iter()) {
+                out.push('.');
+                out.push_str(&other(field, indent));
+            }
+            if let Some(ref method) = *method {
+                out.push(':');
+                out.push_str(&other(method, indent));
+            }
+            out
+        }
+        FunctionBody(_, _) => function_body(node, indent),
+        #[cfg(feature="luau")]
+        TypedFunctionBody(_, _, _) => function_body(node, indent),
+        #[cfg(feature="luau")]
+        Typed(ref name, ref t) => format!("{}: {}", other(name, indent), ty(t, 0, indent)),
+        #[cfg(feature="luau")]
+        TypeName(_, _) | TypeOptional(_) | TypeUnion(_) | TypeIntersection(_) | TypeFunction(_, _) |
+        TypePack(_) | TypeTable(_) | TypeField(_, _) | TypeIndexer(_, _) | TypeOf(_) |
+        TypeVariadic(_) => ty(node, 0, indent),
+        _ if is_statement(node) => statement(node, indent),
+        _ => exp(node, 0, indent),
+    }
+}
+
+/// Prints an expression, in parentheses if it binds less tightly than `min`
+fn exp(node: &ASTNode, min: u8, indent: usize) -> String {
+    let (text, precedence) = exp_precedence(node, indent);
+    if precedence < min {
+        format!("({})", text)
+    } else {
+        text
+    }
+}
+
+fn exp_precedence(node: &ASTNode, indent: usize) -> (String, u8) {
+    if let Some((op, a, b)) = node.as_binop() {
+        let p = op.precedence();
+        let (left, right) = match op {
+            // The left operand of ^ is parsed as an atom
+            BinOp::Exp => (ATOM - 1, UNARY),
+            _ if op.is_right_associative() => (p + 1, p),
+            _ => (p, p + 1),
+        };
+        return (format!("{} {} {}", exp(a, left, indent), op, exp(b, right, indent)), p);
+    }
+    match *node {
+        Nil => ("nil".to_string(), ATOM),
+        Bool(b) => (b.to_string(), ATOM),
+        VarArg => ("...".to_string(), ATOM),
+        Integer(i) if i == i64::MIN => (format!("({} - 1)", i + 1), ATOM),
+        Integer(i) => (i.to_string(), if i < 0 { UNARY } else { ATOM }),
+        Float(f) => float(f),
+        ASTNode::String(ref s) => (quote(s), ATOM),
+        Not(ref e) => (format!("not {}", exp(e, UNARY, indent)), UNARY),
+        Len(ref e) => (unary("#", e, indent), UNARY),
+        UMin(ref e) => (unary("-", e, indent), UNARY),
+        BinNot(ref e) => (unary("~", e, indent), UNARY),
+        Paren(ref e) => (format!("({})", exp(e, 0, indent)), ATOM),
+        PrefixExp(ref e) => (prefix(e, indent), ATOM),
+        FunctionCall(ref f, ref args) => (format!("{}({})", head(f, indent), arguments(args, indent)), ATOM),
+        MethodCall(ref o, ref name, ref args) =>
+            (format!("{}:{}({})", head(o, indent), other(name, indent), arguments(args, indent)), ATOM),
+        Var(ref name) => (other(name, indent), ATOM),
+        VarListAccess(ref pe, ref name) => match **name {
+            Name(ref s) if !is_name(s) => (format!("{}[{}]", head(pe, indent), quote(s.as_bytes())), ATOM),
+            _ => (format!("{}.{}", head(pe, indent), other(name, indent)), ATOM),
+        },
+        VarPrefixed(ref pe, ref key) => (format!("{}[{}]", head(pe, indent), exp(key, 0, indent)), ATOM),
+        TableConstructor(ref fields) => match **fields {
+            Some(ref fields) => (format!("{{{}}}", other(fields, indent)), ATOM),
+            None => ("{}".to_string(), ATOM),
+        },
+        Function(ref f) => (format!("function{}", function_body(f, indent)), ATOM),
+        #[cfg(feature="luau")]
+        IfExp(_, _, _) => {
+            let mut out = "if ".to_string();
+            let mut node = node;
+            while let IfExp(ref cond, ref then, ref other) = *node {
+                out.push_str(&format!("{} then {} ", exp(cond, 0, indent), exp(then, 0, indent)));
+                match **other {
+                    IfExp(_, _, _) => out.push_str("elseif "),
+                    _ => out.push_str(&format!("else {}", exp(other, 0, indent))),
+                }
+                node = other;
+            }
+            (out, 0)
+        }
+        _ => (other(node, indent), ATOM),
+    }
+}
+
+fn unary(op: &str, e: &ASTNode, indent: usize) -> String {
+    let operand = exp(e, UNARY, indent);
+    // - -x, since --x starts a comment
+    if operand.starts_with('-') || (op == "~" && operand.starts_with('=')) {
+        format!("{} {}", op, operand)
+    } else {
+        format!("{}{}", op, operand)
+    }
+}
+
+fn float(f: f64) -> (String, u8) {
+    if f.is_nan() {
+        ("(0 / 0)".to_string(), ATOM)
+    } else if f.is_infinite() {
+        (if f > 0.0 { "1e999" } else { "-1e999" }.to_string(), if f > 0.0 { ATOM } else { UNARY })
+    } else {
+        (format!("{:?}", f), if f.is_sign_negative() { UNARY } else { ATOM })
+    }
+}
+
+/// The contents of a PrefixExp, parenthesized unless it is a variable or a
+/// call
+fn prefix(e: &ASTNode, indent: usize) -> String {
+    match *e {
+        Var(_) | VarListAccess(_, _) | VarPrefixed(_, _) | FunctionCall(_, _) | MethodCall(_, _, _) =>
+            exp(e, 0, indent),
+        _ => format!("({})", exp(e, 0, indent)),
+    }
+}
+
+/// The prefix of a call or of an index, which must be a prefixexp
+fn head(e: &ASTNode, indent: usize) -> String {
+    match *e {
+        PrefixExp(_) | Paren(_) | Var(_) | VarListAccess(_, _) | VarPrefixed(_, _) |
+        FunctionCall(_, _) | MethodCall(_, _, _) => exp(e, 0, indent),
+        _ => format!("({})", exp(e, 0, indent)),
+    }
+}
+
+fn arguments(args: &Option<ASTNode>, indent: usize) -> String {
+    match *args {
+        Some(ref args) => other(args, indent),
+        None => String::new(),
+    }
+}
+
+/// Quotes a string, escaping quotes, backslashes and control characters
+pub(crate) fn quote(s: &[u8]) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for chunk in s.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                c if (c as u32) < 0x20 || c as u32 == 0x7f => out.push_str(&format!("\\{:03}", c as u32)),
+                c => out.push(c),
+            }
+        }
+        // Bytes that are not UTF-8 are escaped, the output stays text
+        for b in chunk.invalid() {
+            out.push_str(&format!("\\{:03}", b));
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(feature="luau")]
+fn return_type(t: &ASTNode, indent: usize) -> String {
+    match *t {
+        TypePack(ref types) => format!("({})", types.iter().map(|t| ty(t, 0, indent)).collect::<Vec<_>>().join(", ")),
+        _ => ty(t, 0, indent),
+    }
+}
+
+/// Prints a type, in parentheses if it binds less tightly than `min`: a
+/// function type is 0, a union or an intersection 1 and `T?` 2
+#[cfg(feature="luau")]
+fn ty(node: &ASTNode, min: u8, indent: usize) -> String {
+    let types = |types: &[ASTNode], min| types.iter().map(|t| ty(t, min, indent)).collect::<Vec<_>>();
+    let (text, precedence) = match *node {
+        TypeName(ref name, ref args) if args.is_empty() => (name.clone(), 3),
+        TypeName(ref name, ref args) => (format!("{}<{}>", name, types(args, 0).join(", ")), 3),
+        TypeOptional(ref t) => (format!("{}?", ty(t, 2, indent)), 2),
+        TypeUnion(ref ts) => (types(ts, 2).join(" | "), 1),
+        TypeIntersection(ref ts) => (types(ts, 2).join(" & "), 1),
+        TypeFunction(ref params, ref returns) =>
+            (format!("({}) -> {}", types(params, 0).join(", "), return_type(returns, indent)), 0),
+        TypePack(_) => (return_type(node, indent), 3),
+        TypeTable(ref fields) => (format!("{{{}}}", types(fields, 0).join(", ")), 3),
+        TypeField(ref name, ref t) => (format!("{}: {}", other(name, indent), ty(t, 0, indent)), 3),
+        TypeIndexer(ref key, ref value) => (format!("[{}]: {}", ty(key, 0, indent), ty(value, 0, indent)), 3),
+        TypeOf(ref e) => (format!("typeof({})", exp(e, 0, indent)), 3),
+        TypeVariadic(ref t) => (format!("...{}", ty(t, 2, indent)), 3),
+        Typed(ref name, ref t) => (format!("{}: {}", other(name, indent), ty(t, 0, indent)), 3),
+        _ => (exp(node, ATOM, indent), 3),
+    };
+    if precedence < min {
+        format!("({})", text)
+    } else {
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {parse_block_with, LuaVersion, ParserConfig};
+
+    fn round_trip(source: &str) -> String {
+        let config = ParserConfig { version: LuaVersion::Lua54, ..ParserConfig::default() };
+        let block = parse_block_with(source.as_bytes(), &config).unwrap();
+        let printed = print(&block);
+        let again = parse_block_with(printed.as_bytes(), &config)
+            .unwrap_or_else(|e| panic!("{}: {}", e, printed));
+        assert_eq!(block, again, "{}", printed);
+        printed
+    }
+
+    #[test]
+    fn statements() {
+        assert_eq!(round_trip("local a, b = 1, 2 a, b = b, a ; do local c end"),
+                   "local a, b = 1, 2\na, b = b, a\n;\ndo\n  local c\nend\n");
+        assert_eq!(round_trip("if a then b() elseif c then d() else e() end"),
+                   "if a then\n  b()\nelseif c then\n  d()\nelse\n  e()\nend\n");
+        assert_eq!(round_trip("if a then elseif b then c() else end if d then end"),
+                   "if a then\nelseif b then\n  c()\nelse\nend\nif d then end\n");
+        assert_eq!(round_trip("do if a then elseif b then end end"),
+                   "do\n  if a then\n  elseif b then\n  end\nend\n");
+        assert_eq!(round_trip("while x do end repeat x = x - 1 until x < 0"),
+                   "while x do end\nrepeat\n  x = x - 1\nuntil x < 0\n");
+        assert_eq!(round_trip("for i = 1, 10, 2 do end for k, v in pairs(t) do break end"),
+                   "for i = 1, 10, 2 do end\nfor k, v in pairs(t) do\n  break\nend\n");
+        assert_eq!(round_trip("::top:: goto top return 1, ..."), "::top::\ngoto top\nreturn 1, ...\n");
+        round_trip("function a.b.c:d(x, ...) return self end local function f() end");
+        round_trip("local x <const>, y <close> = 1");
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(round_trip("x = (1 + 2) * 3 - (4 - 5) - 6 .. 7 .. (8 .. 9)"),
+                   "x = (1 + 2) * 3 - (4 - 5) - 6 .. 7 .. (8 .. 9)\n");
+        assert_eq!(round_trip("x = 2 ^ 3 ^ 2, (2 ^ 3) ^ 2, -2 ^ 2, (-2) ^ 2, 2 ^ -2"),
+                   "x = 2 ^ 3 ^ 2, (2 ^ 3) ^ 2, -2 ^ 2, (-2) ^ 2, 2 ^ -2\n");
+        assert_eq!(round_trip("x = - -a, not not a, a - -1, -(a + b), #t[1], ~ ~a"),
+                   "x = - -a, not not a, a - -1, -(a + b), #t[1], ~~a\n");
+        assert_eq!(round_trip("x = a or b and c, (a or b) and c, a < b == c, a | b ~ c & d << e"),
+                   "x = a or b and c, (a or b) and c, a < b == c, a | b ~ c & d << e\n");
+    }
+
+    #[test]
+    fn prefixes() {
+        assert_eq!(round_trip("x = (a), (f()), (a).b, (\"x\"):rep(2), a.b[c]:m(1)(\"s\")({})"),
+                   "x = (a), (f()), (a).b, (\"x\"):rep(2), a.b[c]:m(1)(\"s\")({})\n");
+        assert_eq!(round_trip("a = 1 ;(f)() g = 2 ;(g)()"), "a = 1\n;\n(f)()\ng = 2\n;\n(g)()\n");
+        // Only an expression that can be called needs the `;`
+        assert_eq!(round_trip("a = 1 (f)() b = {} (g)() local c (h)() return"),
+                   "a = 1\n(f)()\nb = {}\n(g)()\nlocal c\n(h)()\nreturn\n");
+        let call = astb!(FunctionCall, astb!(PrefixExp, astb!(PrefixExp, astb!(Var, ast!(Name, "f".into())))), None);
+        assert_eq!(print(&ast!(Block, vec![call.clone(), call], Box::new(None))), "(f)()\n;(f)()\n");
+        assert_eq!(round_trip("t = {1, [2] = 3, x = 4, {}}"), "t = {1, [2] = 3, x = 4, {}}\n");
+        assert_eq!(round_trip("f = function(a, ...) local function g() end return g end"),
+                   "f = function(a, ...)\n  local function g() end\n  return g\nend\n");
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(round_trip("x = 1.5, 1e300, 1e-7, 0.1, 1e999, 9223372036854775807"),
+                   "x = 1.5, 1e300, 1e-7, 0.1, 1e999, 9223372036854775807\n");
+        assert_eq!(round_trip(r#"x = "a\"b\\c\n\0\1x\127é", 'q'"#),
+                   "x = \"a\\\"b\\\\c\\n\\000\\001x\\127é\", \"q\"\n");
+        // Bytes that are not UTF-8 keep their value
+        assert_eq!(round_trip(r#"x = "\128\255é\xc3""#), "x = \"\\128\\255é\\195\"\n");
+        assert_eq!(quote(&[0xC3, 0xA9, 0xFF]), "\"é\\255\"");
+    }
+
+    #[test]
+    fn synthesized() {
+        // Trees the parser never makes, from folding or decompiling
+        let minus = ASTNode::Integer(-5);
+        assert_eq!(print(&astb!(Exp, minus.clone(), ASTNode::Integer(2))), "(-5) ^ 2");
+        assert_eq!(print(&astb!(UMin, minus)), "- -5");
+        assert_eq!(print(&ASTNode::Integer(i64::MIN)), "(-9223372036854775807 - 1)");
+        assert_eq!(print(&ASTNode::Float(f64::NAN)), "(0 / 0)");
+        assert_eq!(print(&ASTNode::Float(-f64::INFINITY)), "-1e999");
+        assert_eq!(print(&astb!(VarListAccess, astb!(Var, ast!(Name, "t".into())), ast!(Name, "end".into()))),
+                   "t[\"end\"]");
+        assert!(is_name("_a1") && !is_name("1a") && !is_name("goto") && !is_name(""));
+    }
+
+    fn with_comments(source: &str) -> String {
+        let block = parse_block_with(source.as_bytes(), &ParserConfig::default()).unwrap();
+        let printed = print_with_comments(&block, source.as_bytes()).unwrap();
+        let again = parse_block_with(printed.as_bytes(), &ParserConfig::default()).unwrap();
+        assert_eq!(print_with_comments(&again, printed.as_bytes()).as_ref(), Some(&printed));
+        printed
+    }
+
+    #[test]
+    fn comments() {
+        assert_eq!(with_comments("-- top\nlocal  x=1 -- one  \nreturn x\n-- bottom"),
+                   "-- top\nlocal x = 1 -- one\nreturn x\n-- bottom\n");
+        assert_eq!(with_comments("while x do\n  -- a\n  f()\n  --[[ b\n c ]]\nend --[=[d]=]"),
+                   "while x do\n  -- a\n  f()\n  --[[ b\n c ]]\nend --[=[d]=]\n");
+        assert_eq!(with_comments("if a then f() -- yes\nelse\n-- no\ng() end"),
+                   "if a then\n  f() -- yes\nelse\n  -- no\n  g()\nend\n");
+        assert_eq!(with_comments("local t = {\n  1, -- one\n  2, -- two\n}\nreturn t"),
+                   "-- one\nlocal t = {1, 2} -- two\nreturn t\n");
+        assert_eq!(with_comments("local t = {\n  -- a\n  1 -- b\n} -- c\nreturn t -- d"),
+                   "-- a\n-- b\nlocal t = {1} -- c\nreturn t -- d\n");
+        assert_eq!(with_comments("f(function ()\n  -- a\n  g() -- b\nend, -- c\n1)\nh()"),
+                   "f(function()\n  -- a\n  g() -- b\nend, 1) -- c\nh()\n");
+        assert_eq!(with_comments("; -- c\n"), ";\n-- c\n");
+        assert_eq!(with_comments("-- only"), "-- only\n");
+        assert_eq!(with_comments("x = 1\n"), "x = 1\n");
+    }
+
+    #[cfg(feature="luau")]
+    #[test]
+    fn luau() {
+        round_trip("local x: number? = if a then b elseif c then d else e");
+        round_trip("export type Map<K, V> = {[K]: V, n: number} type F = ((number, ...string) -> (boolean, string))?");
+        round_trip("type U = (A | B) & C type T = typeof(x) | \"a\" | true");
+        round_trip("function f(a: string, b): (number, string) x += 1 s ..= \"!\" continue end");
+        round_trip("x = (if a then b else c) + 1");
+    }
+}
@@ -0,0 +1,551 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Scope resolution
+//!
+//! Works out, for every name used as a variable, whether it refers to a
+//! local of the enclosing function, to an upvalue captured from an outer
+//! function, or to a global. Lua scoping rules apply: the values of a
+//! `local` statement are evaluated before its names come into scope, a
+//! `local function` is visible in its own body, the condition of
+//! `repeat ... until` sees the locals of the loop body and the variables of
+//! a `for` loop are only visible inside it.
+//!
+//! As in Lua 5.2 and later, a free name is a field of `_ENV`. When a local
+//! named `_ENV` is in scope the name is bound to that local instead of being
+//! a global. A free use of `_ENV` itself is not a global either: it is the
+//! upvalue every chunk is given, which holds the globals.
+
+use std::collections::BTreeSet;
+
+use arena::{Arena, NodeId, NodeKind, SideTable};
+use ast::Attrib;
+
+/// Identifies a scope of a `Resolution`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ScopeId(usize);
+
+/// Identifies a declaration of a `Resolution`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DeclId(usize);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScopeKind {
+    /// The chunk or the body of a function, holds the parameters
+    Function,
+    /// A block, or a `for` loop holding its variables
+    Block,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scope {
+    pub kind: ScopeKind,
+    pub parent: Option<ScopeId>,
+    /// The node that opens the scope: the root, a function body, a block or a
+    /// `for` statement
+    pub node: NodeId,
+    /// The declarations of the scope, in source order
+    pub declarations: Vec<DeclId>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeclKind {
+    Local,
+    LocalFunction,
+    Parameter,
+    ForVariable,
+    /// The implicit `self` of a method, declared at its function body
+    SelfParameter,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Declaration {
+    pub name: String,
+    pub kind: DeclKind,
+    /// The `Name` node that declares the variable, the function body for
+    /// `self`
+    pub node: NodeId,
+    pub scope: ScopeId,
+    pub attrib: Option<Attrib>,
+    /// The names bound to this declaration, in source order
+    pub uses: Vec<NodeId>,
+}
+
+/// What a name used as a variable refers to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Binding {
+    /// A local of the same function
+    Local(DeclId),
+    /// A local of an enclosing function
+    Upvalue(DeclId),
+    /// A field of the `_ENV` local declared here
+    Env(DeclId),
+    /// `_ENV` with no local `_ENV` in scope, the implicit upvalue of the
+    /// chunk
+    ChunkEnv,
+    Global,
+}
+
+/// The scopes and bindings of an `Arena`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Resolution {
+    scopes: Vec<Scope>,
+    declarations: Vec<Declaration>,
+    bindings: SideTable<Binding>,
+    globals: BTreeSet<String>,
+}
+
+impl Resolution {
+    /// The outermost scope, opened by the root of the arena
+    pub fn root(&self) -> ScopeId {
+        ScopeId(0)
+    }
+
+    pub fn scope(&self, id: ScopeId) -> &Scope {
+        &self.scopes[id.0]
+    }
+
+    pub fn scopes(&self) -> &[Scope] {
+        &self.scopes
+    }
+
+    /// The function scope that `id` belongs to, which is `id` itself for
+    /// the scope of a function
+    pub fn function(&self, mut id: ScopeId) -> ScopeId {
+        loop {
+            let scope = &self.scopes[id.0];
+            match (scope.kind, scope.parent) {
+                (ScopeKind::Block, Some(parent)) => id = parent,
+                _ => return id,
+            }
+        }
+    }
+
+    pub fn declaration(&self, id: DeclId) -> &Declaration {
+        &self.declarations[id.0]
+    }
+
+    pub fn declarations(&self) -> &[Declaration] {
+        &self.declarations
+    }
+
+    /// The binding of a `Name` node used as a variable
+    pub fn binding(&self, name: NodeId) -> Option<Binding> {
+        self.bindings.get(name).cloned()
+    }
+
+    /// The binding of every `Name` node used as a variable
+    pub fn bindings(&self) -> &SideTable<Binding> {
+        &self.bindings
+    }
+
+    /// The globals the chunk reads or assigns
+    pub fn globals(&self) -> &BTreeSet<String> {
+        &self.globals
+    }
+}
+
+struct Resolver<'a> {
+    arena: &'a Arena,
+    resolution: Resolution,
+    current: ScopeId,
+}
+
+/// Resolves the names of the chunk at the root of `arena`
+pub fn resolve(arena: &Arena) -> Resolution {
+    let mut resolver = Resolver {
+        arena,
+        resolution: Resolution {
+            scopes: Vec::new(),
+            declarations: Vec::new(),
+            bindings: SideTable::new(),
+            globals: BTreeSet::new(),
+        },
+        current: ScopeId(0),
+    };
+    let root = arena.root();
+    resolver.open(ScopeKind::Function, root);
+    if *arena.kind(root) == NodeKind::Block {
+        resolver.statements(root);
+    } else {
+        resolver.walk(root);
+    }
+    resolver.resolution
+}
+
+impl<'a> Resolver<'a> {
+    fn open(&mut self, kind: ScopeKind, node: NodeId) -> ScopeId {
+        let id = ScopeId(self.resolution.scopes.len());
+        let parent = if id.0 == 0 { None } else { Some(self.current) };
+        self.resolution.scopes.push(Scope { kind, parent, node, declarations: Vec::new() });
+        self.current = id;
+        id
+    }
+
+    fn close(&mut self, id: ScopeId) {
+        self.current = self.resolution.scopes[id.0].parent.unwrap_or(id);
+    }
+
+    fn lookup(&self, name: &str) -> Option<DeclId> {
+        let mut scope = Some(self.current);
+        while let Some(id) = scope {
+            let scope_ref = &self.resolution.scopes[id.0];
+            let found = scope_ref.declarations.iter().rev()
+                .find(|d| self.resolution.declarations[d.0].name == name);
+            if let Some(&d) = found {
+                return Some(d);
+            }
+            scope = scope_ref.parent;
+        }
+        None
+    }
+
+    fn declare(&mut self, node: NodeId, kind: DeclKind, attrib: Option<Attrib>) {
+        let name = match *self.arena.kind(node) {
+            NodeKind::Name(ref name) => name.clone(),
+            // `self` of a method
+            _ => "self".to_string(),
+        };
+        let id = DeclId(self.resolution.declarations.len());
+        self.resolution.declarations.push(Declaration {
+            name,
+            kind,
+            node,
+            scope: self.current,
+            attrib,
+            uses: Vec::new(),
+        });
+        self.resolution.scopes[self.current.0].declarations.push(id);
+    }
+
+    fn use_name(&mut self, node: NodeId) {
+        let name = match *self.arena.kind(node) {
+            NodeKind::Name(ref name) => name.clone(),
+            _ => return,
+        };
+        let (binding, decl) = match self.lookup(&name) {
+            Some(d) => {
+                let scope = self.resolution.declarations[d.0].scope;
+                if self.resolution.function(scope) == self.resolution.function(self.current) {
+                    (Binding::Local(d), Some(d))
+                } else {
+                    (Binding::Upvalue(d), Some(d))
+                }
+            }
+            None if name == "_ENV" => (Binding::ChunkEnv, None),
+            None => match self.lookup("_ENV") {
+                Some(env) => (Binding::Env(env), Some(env)),
+                None => {
+                    self.resolution.globals.insert(name);
+                    (Binding::Global, None)
+                }
+            },
+        };
+        if let Some(d) = decl {
+            self.resolution.declarations[d.0].uses.push(node);
+        }
+        self.resolution.bindings.insert(node, binding);
+    }
+
+    // Declares a name that may carry a Luau type annotation
+    fn declare_typed(&mut self, node: NodeId, kind: DeclKind, attrib: Option<Attrib>) {
+        match self.arena.child(node, "name") {
+            Some(name) => {
+                self.walk_children(node, &["type"]);
+                self.declare(name, kind, attrib);
+            }
+            None => self.declare(node, kind, attrib),
+        }
+    }
+
+    fn declare_list(&mut self, list: NodeId, kind: DeclKind) {
+        let attribs = match *self.arena.kind(list) {
+            NodeKind::NameList(ref attribs) => attribs.clone(),
+            _ => Vec::new(),
+        };
+        let items: Vec<_> = self.arena.children(list).collect();
+        for (i, item) in items.into_iter().enumerate() {
+            self.declare_typed(item, kind, attribs.get(i).cloned().unwrap_or(None));
+        }
+    }
+
+    fn walk_children(&mut self, node: NodeId, fields: &[&str]) {
+        let children: Vec<_> = self.arena[node].children.iter()
+            .filter(|&&(field, _)| fields.contains(&field))
+            .map(|&(_, id)| id)
+            .collect();
+        for child in children {
+            self.walk(child);
+        }
+    }
+
+    fn statements(&mut self, block: NodeId) {
+        let children: Vec<_> = self.arena.children(block).collect();
+        for child in children {
+            self.walk(child);
+        }
+    }
+
+    fn block(&mut self, block: NodeId) {
+        let scope = self.open(ScopeKind::Block, block);
+        self.statements(block);
+        self.close(scope);
+    }
+
+    // A function body, with the implicit `self` of a method
+    fn function_body(&mut self, body: NodeId, method: bool) {
+        let scope = self.open(ScopeKind::Function, body);
+        if method {
+            self.declare(body, DeclKind::SelfParameter, None);
+        }
+        if let Some(params) = self.arena.child(body, "params") {
+            if let Some(names) = self.arena.child(params, "names") {
+                self.declare_list(names, DeclKind::Parameter);
+            }
+        }
+        self.walk_children(body, &["returns"]);
+        if let Some(block) = self.arena.child(body, "block") {
+            self.statements(block);
+        }
+        self.close(scope);
+    }
+
+    fn walk(&mut self, node: NodeId) {
+        let arena = self.arena;
+        let child = |field| arena.child(node, field);
+        match *arena.kind(node) {
+            NodeKind::Var => if let Some(name) = child("name") {
+                self.use_name(name);
+            },
+            NodeKind::Local => {
+                self.walk_children(node, &["values"]);
+                if let Some(names) = child("names") {
+                    self.declare_list(names, DeclKind::Local);
+                }
+            }
+            NodeKind::NamedFunction => {
+                if let Some(name) = child("name") {
+                    self.declare(name, DeclKind::LocalFunction, None);
+                }
+                self.walk_children(node, &["body"]);
+            }
+            NodeKind::FunctionStat => {
+                let name = child("name");
+                let method = name.is_some_and(|n| arena.child(n, "method").is_some());
+                if let Some(base) = name.and_then(|n| arena.child(n, "name")) {
+                    self.use_name(base);
+                }
+                if let Some(body) = child("body") {
+                    self.function_body(body, method);
+                }
+            }
+            NodeKind::FunctionBody => self.function_body(node, false),
+            #[cfg(feature="luau")]
+            NodeKind::TypedFunctionBody => self.function_body(node, false),
+            NodeKind::Do | NodeKind::While => {
+                self.walk_children(node, &["cond"]);
+                if let Some(block) = child("block") {
+                    self.block(block);
+                }
+            }
+            NodeKind::Repeat => {
+                // The condition sees the locals of the body
+                if let Some(block) = child("block") {
+                    let scope = self.open(ScopeKind::Block, block);
+                    self.statements(block);
+                    self.walk_children(node, &["cond"]);
+                    self.close(scope);
+                }
+            }
+            NodeKind::If => {
+                self.walk_children(node, &["cond"]);
+                if let Some(block) = child("block") {
+                    self.block(block);
+                }
+                match child("else") {
+                    Some(e) if *arena.kind(e) == NodeKind::Block => self.block(e),
+                    Some(e) => self.walk(e),
+                    None => (),
+                }
+            }
+            NodeKind::NumericFor => {
+                self.walk_children(node, &["start", "limit", "step"]);
+                let scope = self.open(ScopeKind::Block, node);
+                if let Some(var) = child("var") {
+                    self.declare_typed(var, DeclKind::ForVariable, None);
+                }
+                if let Some(block) = child("block") {
+                    self.statements(block);
+                }
+                self.close(scope);
+            }
+            NodeKind::GenericFor => {
+                self.walk_children(node, &["exps"]);
+                let scope = self.open(ScopeKind::Block, node);
+                if let Some(names) = child("names") {
+                    self.declare_list(names, DeclKind::ForVariable);
+                }
+                if let Some(block) = child("block") {
+                    self.statements(block);
+                }
+                self.close(scope);
+            }
+            NodeKind::Block => self.block(node),
+            // Field names, method names, labels and type names are not
+            // variables, every other child is walked
+            _ => {
+                let children: Vec<_> = arena.children(node).collect();
+                for c in children {
+                    self.walk(c);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{with_config, LuaVersion, ParserConfig};
+    use function::parse_block;
+    use nom::IResult;
+
+    fn arena(input: &str) -> Arena {
+        let config = ParserConfig { version: LuaVersion::Lua54, ..ParserConfig::default() };
+        match with_config(&config, || parse_block(input.as_bytes())) {
+            IResult::Done(rest, block) => {
+                assert!(rest.is_empty(), "left over: {:?}", ::std::str::from_utf8(rest));
+                Arena::from_ast(&block)
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    // The bindings of the uses of `name`, in source order
+    fn uses(input: &str, name: &str) -> Vec<Binding> {
+        let arena = arena(input);
+        let resolution = resolve(&arena);
+        resolution.bindings().iter()
+            .filter(|&(id, _)| *arena.kind(id) == NodeKind::Name(name.into()))
+            .map(|(_, &b)| b)
+            .collect()
+    }
+
+    fn globals(input: &str) -> Vec<String> {
+        resolve(&arena(input)).globals().iter().cloned().collect()
+    }
+
+    #[test]
+    fn locals_upvalues_and_globals() {
+        let input = "local x = 1 local function f() return x, y end return x";
+        let arena = arena(input);
+        let r = resolve(&arena);
+        assert_eq!(r.declarations().len(), 2);
+        let x = DeclId(0);
+        assert_eq!(r.declaration(x).name, "x");
+        assert_eq!(r.declaration(x).kind, DeclKind::Local);
+        assert_eq!(uses(input, "x"), vec![Binding::Upvalue(x), Binding::Local(x)]);
+        assert_eq!(uses(input, "y"), vec![Binding::Global]);
+        assert_eq!(r.declaration(x).uses.len(), 2);
+        assert_eq!(globals(input), vec!["y"]);
+    }
+
+    #[test]
+    fn local_values_are_evaluated_first() {
+        let input = "local x = 1 do local x = x + 1 end";
+        assert_eq!(uses(input, "x"), vec![Binding::Local(DeclId(0))]);
+        assert_eq!(globals("local print = print print(1)"), vec!["print"]);
+    }
+
+    #[test]
+    fn local_function_is_recursive() {
+        let input = "local function f(n) return f(n - 1) end local g = function() return g end";
+        assert_eq!(uses(input, "f"), vec![Binding::Upvalue(DeclId(0))]);
+        assert_eq!(uses(input, "n"), vec![Binding::Local(DeclId(1))]);
+        assert_eq!(globals(input), vec!["g"]);
+    }
+
+    #[test]
+    fn blocks_end_scopes() {
+        let input = "do local a end if c then local b else local b end while b do end return a";
+        assert_eq!(globals(input), vec!["a", "b", "c"]);
+        let r = resolve(&arena(input));
+        assert_eq!(r.scopes().len(), 5);
+        assert_eq!(r.scope(r.root()).kind, ScopeKind::Function);
+        assert!(r.scopes()[1..].iter().all(|s| s.parent == Some(r.root())));
+    }
+
+    #[test]
+    fn for_variables() {
+        let input = "for i = i, 10 do print(i) end for k, v in pairs(t) do k = v end return k";
+        assert_eq!(globals(input), vec!["i", "k", "pairs", "print", "t"]);
+        assert_eq!(uses(input, "v"), vec![Binding::Local(DeclId(2))]);
+        let r = resolve(&arena(input));
+        assert!(r.declarations().iter().all(|d| d.kind == DeclKind::ForVariable));
+    }
+
+    #[test]
+    fn repeat_condition_sees_the_body() {
+        assert_eq!(globals("repeat local done = true until done"), Vec::<String>::new());
+        assert_eq!(globals("repeat do local done = true end until done"), vec!["done"]);
+    }
+
+    #[test]
+    fn methods_have_self() {
+        let input = "function a.b:c(x) return self, x end";
+        assert_eq!(globals(input), vec!["a"]);
+        let r = resolve(&arena(input));
+        assert_eq!(r.declaration(DeclId(0)).name, "self");
+        assert_eq!(r.declaration(DeclId(0)).kind, DeclKind::SelfParameter);
+        assert_eq!(r.declaration(DeclId(1)).kind, DeclKind::Parameter);
+        assert_eq!(globals("function f() return self end"), vec!["f", "self"]);
+    }
+
+    #[test]
+    fn fields_are_not_variables() {
+        let input = "local t = {a = b, [c] = 1} t.d = t:e(f) goto g ::g::";
+        assert_eq!(globals(input), vec!["b", "c", "f"]);
+    }
+
+    #[test]
+    fn shadowing() {
+        let input = "local x local x = x return x";
+        assert_eq!(uses(input, "x"), vec![Binding::Local(DeclId(0)), Binding::Local(DeclId(1))]);
+    }
+
+    #[test]
+    fn attributes_are_kept() {
+        let r = resolve(&arena("local a <const>, b <close> = 1"));
+        assert_eq!(r.declaration(DeclId(0)).attrib, Some(Attrib::Const));
+        assert_eq!(r.declaration(DeclId(1)).attrib, Some(Attrib::Close));
+    }
+
+    #[test]
+    fn env_local_hides_globals() {
+        let input = "local _ENV = {print = print} print(x) return _ENV";
+        assert_eq!(globals(input), vec!["print"]);
+        assert_eq!(uses(input, "x"), vec![Binding::Env(DeclId(0))]);
+    }
+
+    #[test]
+    fn chunk_env() {
+        let input = "_ENV.x = 1 local function f() return _ENV end return f, y";
+        assert_eq!(globals(input), vec!["y"]);
+        assert_eq!(uses(input, "_ENV"), vec![Binding::ChunkEnv, Binding::ChunkEnv]);
+        assert_eq!(uses("local _ENV = _ENV return _ENV", "_ENV"), vec![Binding::ChunkEnv, Binding::Local(DeclId(0))]);
+    }
+
+    #[cfg(feature="luau")]
+    #[test]
+    fn luau_typed_names() {
+        let input = "local x: number = 1 local function f(a: typeof(x)): number return a + y end";
+        assert_eq!(globals(input), vec!["y"]);
+        assert_eq!(uses(input, "x"), vec![Binding::Upvalue(DeclId(0))]);
+        assert_eq!(uses(input, "a"), vec![Binding::Local(DeclId(2))]);
+    }
+}
@@ -0,0 +1,893 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Writes ASTs as S-expressions and reads them back
+//!
+//! Every node is a list headed by the name of its `ASTNode` variant,
+//! followed by its fields in order:
+//!
+//! ```text
+//! (Block
+//!   [(Local (NameList (Name x) <const>) (ExpList (Integer 1)))
+//!    (FunctionCall (PrefixExp (Var (Name print))) (ExpList (String "a\n")))]
+//!   _)
+//! ```
+//!
+//! - an absent optional field is `_`
+//! - a list field is in brackets, unless it is the only field of its node
+//!   as in `ExpList`, whose items follow the head directly
+//! - `Name`, `Label` and `TypeName` are bare words when they are valid
+//!   identifiers and quoted otherwise, `String` is always quoted, with the
+//!   escapes `\"`, `\\`, `\n`, `\r`, `\t`, `\u{..}` and `\xNN` for a byte
+//!   that is not UTF-8
+//! - `Float` always has a `.`, an exponent or is `inf`, `-inf` or `NaN`
+//! - attributes follow their name in a NameList, as `<const>` and `<close>`
+//! - flags are `true` or `false`, operators are written as in Lua
+//!
+//! `print` breaks lists that do not fit in 80 columns, putting each field
+//! on its own line, so the output is the same for equal trees. `read` also
+//! takes `;` comments and any layout.
+
+use std::error;
+use std::fmt::{self, Display, Formatter, Write};
+use std::str::FromStr;
+
+use ast::{ASTNode, Attrib};
+use op::BinOp;
+
+const WIDTH: usize = 80;
+
+/// The deepest nesting of lists and vectors `read` takes
+///
+/// Reading recurses once per level, as in `json::MAX_DEPTH`.
+pub const MAX_DEPTH: usize = 512;
+
+/// An error of `read`, at a byte offset in the text
+#[derive(Clone, Debug, PartialEq)]
+pub struct SexpError {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl Display for SexpError {
+    fn fmt(&self, format: &mut Formatter) -> fmt::Result {
+        write!(format, "offset {}: {}", self.offset, self.message)
+    }
+}
+
+impl error::Error for SexpError {}
+
+type Result<T> = ::std::result::Result<T, SexpError>;
+
+// A field of a node as it is printed
+enum Item<'a> {
+    Node(&'a ASTNode),
+    Absent,
+    Vector(Vec<Item<'a>>),
+    Atom(String),
+}
+
+fn node(n: &ASTNode) -> Item<'_> {
+    Item::Node(n)
+}
+
+fn opt(n: &Option<ASTNode>) -> Item<'_> {
+    n.as_ref().map_or(Item::Absent, Item::Node)
+}
+
+fn vector(list: &[ASTNode]) -> Item<'_> {
+    Item::Vector(list.iter().map(Item::Node).collect())
+}
+
+fn atom<T: ToString>(value: T) -> Item<'static> {
+    Item::Atom(value.to_string())
+}
+
+fn text(value: &str) -> Item<'static> {
+    let bare = value.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') &&
+        value.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    Item::Atom(if bare { value.to_string() } else { quote(value.as_bytes()) })
+}
+
+fn float(value: f64) -> String {
+    // Debug is the shortest text that reads back to the same float, and
+    // keeps a `.0` on whole numbers
+    format!("{:?}", value)
+}
+
+/// Quotes `value` as an S-expression string
+pub fn quote(value: &[u8]) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for chunk in value.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                c if c.is_control() => {
+                    let _ = write!(out, "\\u{{{:x}}}", c as u32);
+                }
+                c => out.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            let _ = write!(out, "\\x{:02x}", b);
+        }
+    }
+    out.push('"');
+    out
+}
+
+// The head and the fields of a node
+fn items(n: &ASTNode) -> (&'static str, Vec<Item<'_>>) {
+    use ast::ASTNode::*;
+
+    match *n {
+        Integer(a) => ("Integer", vec![atom(a)]),
+        Float(a) => ("Float", vec![Item::Atom(float(a))]),
+        Bool(a) => ("Bool", vec![atom(a)]),
+        String(ref a) => ("String", vec![Item::Atom(quote(a))]),
+        Label(ref a) => ("Label", vec![text(a)]),
+        Name(ref a) => ("Name", vec![text(a)]),
+        Paren(ref a) => ("Paren", vec![node(a)]),
+        Block(ref stats, ref ret) => ("Block", vec![vector(stats), opt(ret)]),
+        EmptyStatement => ("EmptyStatement", vec![]),
+        Break => ("Break", vec![]),
+        Goto(ref a) => ("Goto", vec![node(a)]),
+        RetStat(ref a) => ("RetStat", vec![opt(a)]),
+        Assign(ref a, ref b) => ("Assign", vec![node(a), node(b)]),
+        Do(ref a) => ("Do", vec![node(a)]),
+        While(ref a, ref b) => ("While", vec![node(a), node(b)]),
+        Repeat(ref a, ref b) => ("Repeat", vec![node(a), node(b)]),
+        If(ref a, ref b, ref c) => ("If", vec![node(a), node(b), opt(c)]),
+        NumericFor(ref a, ref b, ref c, ref d, ref e) =>
+            ("NumericFor", vec![node(a), node(b), node(c), opt(d), node(e)]),
+        GenericFor(ref a, ref b, ref c) => ("GenericFor", vec![node(a), node(b), node(c)]),
+        FunctionStat(ref a, ref b) => ("FunctionStat", vec![node(a), node(b)]),
+        BinNot(ref a) => ("BinNot", vec![node(a)]),
+        Not(ref a) => ("Not", vec![node(a)]),
+        Len(ref a) => ("Len", vec![node(a)]),
+        UMin(ref a) => ("UMin", vec![node(a)]),
+        PrefixExp(ref a) => ("PrefixExp", vec![node(a)]),
+        FunctionCall(ref a, ref b) => ("FunctionCall", vec![node(a), opt(b)]),
+        MethodCall(ref a, ref b, ref c) => ("MethodCall", vec![node(a), node(b), opt(c)]),
+        Nil => ("Nil", vec![]),
+        VarArg => ("VarArg", vec![]),
+        TableConstructor(ref a) => ("TableConstructor", vec![opt(a)]),
+        Function(ref a) => ("Function", vec![node(a)]),
+        FunctionBody(ref a, ref b) => ("FunctionBody", vec![opt(a), node(b)]),
+        FunctionName(ref a, ref b, ref c) => ("FunctionName", vec![
+            node(a),
+            b.as_ref().map_or(Item::Absent, |b| vector(b)),
+            c.as_ref().map_or(Item::Absent, |c| node(c)),
+        ]),
+        NamedFunction(ref a, ref b) => ("NamedFunction", vec![node(a), node(b)]),
+        ExpList(ref a) => ("ExpList", a.iter().map(node).collect()),
+        VarList(ref a) => ("VarList", a.iter().map(node).collect()),
+        NameList(ref a) => {
+            let mut items = Vec::new();
+            for &(ref name, attrib) in a {
+                items.push(node(name));
+                if let Some(attrib) = attrib {
+                    items.push(atom(attrib));
+                }
+            }
+            ("NameList", items)
+        }
+        FieldList(ref a) => ("FieldList", a.iter().map(node).collect()),
+        ParameterList(ref a, va) => ("ParameterList", vec![opt(a), atom(va)]),
+        FieldSingle(ref a) => ("FieldSingle", vec![node(a)]),
+        FieldAssign(ref a, ref b) => ("FieldAssign", vec![node(a), node(b)]),
+        Local(ref a, ref b) => ("Local", vec![node(a), opt(b)]),
+        Var(ref a) => ("Var", vec![node(a)]),
+        VarPrefixed(ref a, ref b) => ("VarPrefixed", vec![node(a), node(b)]),
+        VarListAccess(ref a, ref b) => ("VarListAccess", vec![node(a), node(b)]),
+        #[cfg(feature="luau")]
+        Typed(ref a, ref b) => ("Typed", vec![node(a), node(b)]),
+        #[cfg(feature="luau")]
+        TypedFunctionBody(ref a, ref b, ref c) => ("TypedFunctionBody", vec![opt(a), node(b), node(c)]),
+        #[cfg(feature="luau")]
+        TypeAlias(export, ref a, ref b, ref c) => ("TypeAlias", vec![atom(export), node(a), vector(b), node(c)]),
+        #[cfg(feature="luau")]
+        CompoundAssign(op, ref a, ref b) => ("CompoundAssign", vec![atom(op), node(a), node(b)]),
+        #[cfg(feature="luau")]
+        Continue => ("Continue", vec![]),
+        #[cfg(feature="luau")]
+        IfExp(ref a, ref b, ref c) => ("IfExp", vec![node(a), node(b), node(c)]),
+        #[cfg(feature="luau")]
+        TypeName(ref a, ref b) => ("TypeName", vec![text(a), vector(b)]),
+        #[cfg(feature="luau")]
+        TypeOptional(ref a) => ("TypeOptional", vec![node(a)]),
+        #[cfg(feature="luau")]
+        TypeUnion(ref a) => ("TypeUnion", a.iter().map(node).collect()),
+        #[cfg(feature="luau")]
+        TypeIntersection(ref a) => ("TypeIntersection", a.iter().map(node).collect()),
+        #[cfg(feature="luau")]
+        TypeFunction(ref a, ref b) => ("TypeFunction", vec![vector(a), node(b)]),
+        #[cfg(feature="luau")]
+        TypePack(ref a) => ("TypePack", a.iter().map(node).collect()),
+        #[cfg(feature="luau")]
+        TypeTable(ref a) => ("TypeTable", a.iter().map(node).collect()),
+        #[cfg(feature="luau")]
+        TypeField(ref a, ref b) => ("TypeField", vec![node(a), node(b)]),
+        #[cfg(feature="luau")]
+        TypeIndexer(ref a, ref b) => ("TypeIndexer", vec![node(a), node(b)]),
+        #[cfg(feature="luau")]
+        TypeOf(ref a) => ("TypeOf", vec![node(a)]),
+        #[cfg(feature="luau")]
+        TypeVariadic(ref a) => ("TypeVariadic", vec![node(a)]),
+        ref binop => {
+            let (op, a, b) = binop.as_binop().expect("every other node is a binary operation");
+            (binop_name(op), vec![node(a), node(b)])
+        }
+    }
+}
+
+fn binop_name(op: BinOp) -> &'static str {
+    match op {
+        BinOp::Exp => "Exp",
+        BinOp::Mul => "Mul",
+        BinOp::Div => "Div",
+        BinOp::FDiv => "FDiv",
+        BinOp::Mod => "Mod",
+        BinOp::Add => "Add",
+        BinOp::Sub => "Sub",
+        BinOp::Concat => "Concat",
+        BinOp::Lsh => "Lsh",
+        BinOp::Rsh => "Rsh",
+        BinOp::BitAnd => "BitAnd",
+        BinOp::BitXor => "BitXor",
+        BinOp::BitOr => "BitOr",
+        BinOp::Lt => "Lt",
+        BinOp::Gt => "Gt",
+        BinOp::Le => "Le",
+        BinOp::Ge => "Ge",
+        BinOp::Ne => "Ne",
+        BinOp::Eq => "Eq",
+        BinOp::And => "And",
+        BinOp::Or => "Or",
+    }
+}
+
+const BINOPS: [BinOp; 21] = [
+    BinOp::Exp, BinOp::Mul, BinOp::Div, BinOp::FDiv, BinOp::Mod, BinOp::Add, BinOp::Sub,
+    BinOp::Concat, BinOp::Lsh, BinOp::Rsh, BinOp::BitAnd, BinOp::BitXor, BinOp::BitOr,
+    BinOp::Lt, BinOp::Gt, BinOp::Le, BinOp::Ge, BinOp::Ne, BinOp::Eq, BinOp::And, BinOp::Or,
+];
+
+/// Writes `ast` on a single line
+pub fn print_flat(ast: &ASTNode) -> String {
+    let mut out = String::new();
+    flat(&Item::Node(ast), &mut out, usize::MAX);
+    out
+}
+
+/// Writes `ast` in its canonical layout, lists that do not fit in 80
+/// columns have each field on a line of its own
+pub fn print(ast: &ASTNode) -> String {
+    let mut out = String::new();
+    pretty(&Item::Node(ast), 0, &mut out);
+    out.push('\n');
+    out
+}
+
+// Writes `item` on one line, giving up once `out` is longer than `limit`
+fn flat(item: &Item, out: &mut String, limit: usize) -> bool {
+    match *item {
+        Item::Node(n) => {
+            let (head, items) = items(n);
+            out.push('(');
+            out.push_str(head);
+            for item in &items {
+                out.push(' ');
+                if !flat(item, out, limit) {
+                    return false;
+                }
+            }
+            out.push(')');
+        }
+        Item::Vector(ref items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                if !flat(item, out, limit) {
+                    return false;
+                }
+            }
+            out.push(']');
+        }
+        Item::Absent => out.push('_'),
+        Item::Atom(ref a) => out.push_str(a),
+    }
+    out.len() <= limit
+}
+
+// Writes `item` starting at column `indent`
+fn pretty(item: &Item, indent: usize, out: &mut String) {
+    let start = out.len();
+    if flat(item, out, start + WIDTH.saturating_sub(indent)) {
+        return;
+    }
+    out.truncate(start);
+    match *item {
+        Item::Node(n) => {
+            let (head, items) = items(n);
+            out.push('(');
+            out.push_str(head);
+            for item in &items {
+                out.push('\n');
+                out.extend(::std::iter::repeat_n(' ', indent + 2));
+                pretty(item, indent + 2, out);
+            }
+            out.push(')');
+        }
+        Item::Vector(ref items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push('\n');
+                    out.extend(::std::iter::repeat_n(' ', indent + 1));
+                }
+                pretty(item, indent + 1, out);
+            }
+            out.push(']');
+        }
+        Item::Absent | Item::Atom(_) => {
+            flat(item, out, usize::MAX);
+        }
+    }
+}
+
+// The syntax of the text, before it is read as nodes
+#[derive(Debug)]
+enum Sexp {
+    List(Vec<Sexp>),
+    Vector(Vec<Sexp>),
+    Atom(String),
+    Str(Vec<u8>),
+}
+
+struct Reader<'a> {
+    text: &'a str,
+    offset: usize,
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error<T>(&self, offset: usize, message: String) -> Result<T> {
+        Err(SexpError { offset, message })
+    }
+
+    fn skip(&mut self) {
+        loop {
+            let rest = &self.text[self.offset..];
+            let trimmed = rest.trim_start();
+            self.offset += rest.len() - trimmed.len();
+            if trimmed.starts_with(';') {
+                self.offset += trimmed.find('\n').unwrap_or(trimmed.len());
+            } else {
+                return;
+            }
+        }
+    }
+
+    fn sexp(&mut self) -> Result<(usize, Sexp)> {
+        self.skip();
+        let start = self.offset;
+        let c = match self.text[start..].chars().next() {
+            Some(c) => c,
+            None => return self.error(start, "unexpected end of input".to_string()),
+        };
+        match c {
+            '(' | '[' => {
+                let close = if c == '(' { ')' } else { ']' };
+                if self.depth == MAX_DEPTH {
+                    return self.error(start, format!("nested deeper than {} levels", MAX_DEPTH));
+                }
+                self.depth += 1;
+                self.offset += 1;
+                let mut items = Vec::new();
+                loop {
+                    self.skip();
+                    match self.text[self.offset..].chars().next() {
+                        Some(c) if c == close => break,
+                        Some(')') | Some(']') => return self.error(self.offset, format!("expected `{}`", close)),
+                        _ => items.push(self.sexp()?),
+                    }
+                }
+                self.offset += 1;
+                self.depth -= 1;
+                let items = items.into_iter().map(|(_, s)| s).collect();
+                Ok((start, if c == '(' { Sexp::List(items) } else { Sexp::Vector(items) }))
+            }
+            ')' | ']' => self.error(start, format!("unexpected `{}`", c)),
+            '"' => self.string().map(|s| (start, Sexp::Str(s))),
+            _ => {
+                let len = self.text[start..]
+                    .find(|c: char| c.is_whitespace() || "()[]\";".contains(c))
+                    .unwrap_or(self.text.len() - start);
+                self.offset += len;
+                Ok((start, Sexp::Atom(self.text[start..start + len].to_string())))
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>> {
+        let start = self.offset;
+        let mut out = Vec::new();
+        let mut chars = self.text[start + 1..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.offset = start + 1 + i + 1;
+                    return Ok(out);
+                }
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => out.push(b'\n'),
+                    Some((_, 'r')) => out.push(b'\r'),
+                    Some((_, 't')) => out.push(b'\t'),
+                    Some((_, '"')) => out.push(b'"'),
+                    Some((_, '\\')) => out.push(b'\\'),
+                    Some((j, 'x')) => {
+                        let hex = self.text.get(start + 1 + j + 1..start + 1 + j + 3);
+                        match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                            Some(b) => {
+                                out.push(b);
+                                chars.next();
+                                chars.next();
+                            }
+                            None => return self.error(start + 1 + j, "invalid \\x escape".to_string()),
+                        }
+                    }
+                    Some((j, 'u')) => {
+                        let rest = &self.text[start + 1 + j + 1..];
+                        let code = rest.strip_prefix('{')
+                            .and_then(|r| r.find('}').map(|end| &r[..end]))
+                            .and_then(|hex| u32::from_str_radix(hex, 16).ok().map(|c| (hex.len(), c)));
+                        match code.and_then(|(len, c)| ::std::char::from_u32(c).map(|c| (len, c))) {
+                            Some((len, c)) => {
+                                out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                                for _ in 0..len + 2 {
+                                    chars.next();
+                                }
+                            }
+                            None => return self.error(start + 1 + j, "invalid \\u escape".to_string()),
+                        }
+                    }
+                    _ => return self.error(start + 1 + i, "invalid escape".to_string()),
+                },
+                c => out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+        self.error(start, "unterminated string".to_string())
+    }
+}
+
+// The fields of a node being read
+struct Fields {
+    head: &'static str,
+    offset: usize,
+    items: ::std::vec::IntoIter<Sexp>,
+}
+
+impl Fields {
+    fn error<T>(&self, message: String) -> Result<T> {
+        Err(SexpError { offset: self.offset, message: format!("{}: {}", self.head, message) })
+    }
+
+    fn next(&mut self, what: &str) -> Result<Sexp> {
+        match self.items.next() {
+            Some(item) => Ok(item),
+            None => self.error(format!("missing {}", what)),
+        }
+    }
+
+    fn node(&mut self) -> Result<ASTNode> {
+        let item = self.next("a node")?;
+        self.to_node(item)
+    }
+
+    fn to_node(&self, item: Sexp) -> Result<ASTNode> {
+        match item {
+            Sexp::List(items) => convert(self.offset, items),
+            other => self.error(format!("expected a node, found {:?}", other)),
+        }
+    }
+
+    fn boxed(&mut self) -> Result<Box<ASTNode>> {
+        self.node().map(Box::new)
+    }
+
+    fn opt(&mut self) -> Result<Box<Option<ASTNode>>> {
+        match self.next("a node or `_`")? {
+            Sexp::Atom(ref a) if a == "_" => Ok(Box::new(None)),
+            item => self.to_node(item).map(|n| Box::new(Some(n))),
+        }
+    }
+
+    // A loop rather than `collect`, whose adapters take a lot of stack per
+    // level of a deep tree in debug builds
+    fn to_nodes(&self, items: Vec<Sexp>) -> Result<Vec<ASTNode>> {
+        let mut nodes = Vec::with_capacity(items.len());
+        for item in items {
+            nodes.push(self.to_node(item)?);
+        }
+        Ok(nodes)
+    }
+
+    fn vector(&mut self) -> Result<Vec<ASTNode>> {
+        match self.next("a list")? {
+            Sexp::Vector(items) => self.to_nodes(items),
+            other => self.error(format!("expected a list in brackets, found {:?}", other)),
+        }
+    }
+
+    fn rest(&mut self) -> Result<Vec<ASTNode>> {
+        let items: Vec<_> = self.items.by_ref().collect();
+        self.to_nodes(items)
+    }
+
+    fn atom(&mut self, what: &str) -> Result<String> {
+        match self.next(what)? {
+            Sexp::Atom(a) => Ok(a),
+            other => self.error(format!("expected {}, found {:?}", what, other)),
+        }
+    }
+
+    fn parse<T: FromStr>(&mut self, what: &str) -> Result<T> {
+        let atom = self.atom(what)?;
+        match atom.parse() {
+            Ok(value) => Ok(value),
+            Err(_) => self.error(format!("expected {}, found {}", what, atom)),
+        }
+    }
+
+    // A name, bare or quoted
+    fn text(&mut self) -> Result<String> {
+        match self.next("a name")? {
+            Sexp::Atom(a) => Ok(a),
+            Sexp::Str(s) => match ::std::string::String::from_utf8(s) {
+                Ok(a) => Ok(a),
+                Err(_) => self.error("expected a name, found bytes that are not UTF-8".to_string()),
+            },
+            other => self.error(format!("expected a name, found {:?}", other)),
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>> {
+        match self.next("a string")? {
+            Sexp::Str(s) => Ok(s),
+            other => self.error(format!("expected a quoted string, found {:?}", other)),
+        }
+    }
+
+    #[cfg(feature="luau")]
+    fn binop(&mut self) -> Result<BinOp> {
+        let atom = self.atom("an operator")?;
+        match BINOPS.iter().find(|op| op.to_string() == atom) {
+            Some(&op) => Ok(op),
+            None => self.error(format!("unknown operator {}", atom)),
+        }
+    }
+
+    fn name_list(&mut self) -> Result<Vec<(ASTNode, Option<Attrib>)>> {
+        let mut names: Vec<(ASTNode, Option<Attrib>)> = Vec::new();
+        while let Some(item) = self.items.next() {
+            let attrib = match item {
+                Sexp::Atom(ref a) if a == "<const>" => Attrib::Const,
+                Sexp::Atom(ref a) if a == "<close>" => Attrib::Close,
+                item => {
+                    let name = self.to_node(item)?;
+                    names.push((name, None));
+                    continue;
+                }
+            };
+            match names.last_mut() {
+                Some(&mut (_, ref mut slot @ None)) => *slot = Some(attrib),
+                _ => return self.error(format!("{} does not follow a name", attrib)),
+            }
+        }
+        Ok(names)
+    }
+
+    fn end(mut self, node: ASTNode) -> Result<ASTNode> {
+        match self.items.next() {
+            Some(extra) => self.error(format!("unexpected {:?}", extra)),
+            None => Ok(node),
+        }
+    }
+}
+
+const HEADS: &[&str] = &[
+    "Integer", "Float", "Bool", "String", "Label", "Name", "Paren", "Block", "EmptyStatement",
+    "Break", "Goto", "RetStat", "Assign", "Do", "While", "Repeat", "If", "NumericFor",
+    "GenericFor", "FunctionStat", "BinNot", "Not", "Len", "UMin", "PrefixExp", "FunctionCall",
+    "MethodCall", "Nil", "VarArg", "TableConstructor", "Function", "FunctionBody",
+    "FunctionName", "NamedFunction", "ExpList", "VarList", "NameList", "FieldList",
+    "ParameterList", "FieldSingle", "FieldAssign", "Local", "Var", "VarPrefixed", "VarListAccess",
+    #[cfg(feature="luau")] "Typed",
+    #[cfg(feature="luau")] "TypedFunctionBody",
+    #[cfg(feature="luau")] "TypeAlias",
+    #[cfg(feature="luau")] "CompoundAssign",
+    #[cfg(feature="luau")] "Continue",
+    #[cfg(feature="luau")] "IfExp",
+    #[cfg(feature="luau")] "TypeName",
+    #[cfg(feature="luau")] "TypeOptional",
+    #[cfg(feature="luau")] "TypeUnion",
+    #[cfg(feature="luau")] "TypeIntersection",
+    #[cfg(feature="luau")] "TypeFunction",
+    #[cfg(feature="luau")] "TypePack",
+    #[cfg(feature="luau")] "TypeTable",
+    #[cfg(feature="luau")] "TypeField",
+    #[cfg(feature="luau")] "TypeIndexer",
+    #[cfg(feature="luau")] "TypeOf",
+    #[cfg(feature="luau")] "TypeVariadic",
+];
+
+// The head of a node, and its operator when it is a binary operation
+fn head(offset: usize, items: &mut ::std::vec::IntoIter<Sexp>) -> Result<(&'static str, Option<BinOp>)> {
+    let head = match items.next() {
+        Some(Sexp::Atom(head)) => head,
+        _ => return Err(SexpError { offset, message: "expected a node name".to_string() }),
+    };
+    let binop = BINOPS.iter().cloned().find(|&op| binop_name(op) == head);
+    match HEADS.iter().find(|&&h| h == head) {
+        Some(&head) => Ok((head, binop)),
+        None => match binop {
+            Some(op) => Ok((binop_name(op), binop)),
+            None => Err(SexpError { offset, message: format!("unknown node {}", head) }),
+        },
+    }
+}
+
+fn convert(offset: usize, items: Vec<Sexp>) -> Result<ASTNode> {
+    let mut items = items.into_iter();
+    let (head, binop) = head(offset, &mut items)?;
+    let mut f = Fields { head, offset, items };
+    let node = match binop {
+        Some(op) => {
+            let (a, b) = (f.node()?, f.node()?);
+            ::op::make_binop(op, a, b)
+        }
+        None => builder(head)(&mut f)?,
+    };
+    f.end(node)
+}
+
+type Builder = fn(&mut Fields) -> Result<ASTNode>;
+
+// Each node is built by its own small function, so that reading a deep tree
+// takes little stack per level
+fn builder(head: &str) -> Builder {
+    use ast::ASTNode::*;
+
+    match head {
+        "Integer" => |f| Ok(Integer(f.parse("an integer")?)),
+        "Float" => |f| Ok(Float(f.parse("a float")?)),
+        "Bool" => |f| Ok(Bool(f.parse("true or false")?)),
+        "String" => |f| Ok(String(f.string()?)),
+        "Label" => |f| Ok(Label(f.text()?)),
+        "Name" => |f| Ok(Name(f.text()?)),
+        "Paren" => |f| Ok(Paren(f.boxed()?)),
+        "Block" => |f| Ok(Block(f.vector()?, f.opt()?)),
+        "EmptyStatement" => |_| Ok(EmptyStatement),
+        "Break" => |_| Ok(Break),
+        "Goto" => |f| Ok(Goto(f.boxed()?)),
+        "RetStat" => |f| Ok(RetStat(f.opt()?)),
+        "Assign" => |f| Ok(Assign(f.boxed()?, f.boxed()?)),
+        "Do" => |f| Ok(Do(f.boxed()?)),
+        "While" => |f| Ok(While(f.boxed()?, f.boxed()?)),
+        "Repeat" => |f| Ok(Repeat(f.boxed()?, f.boxed()?)),
+        "If" => |f| Ok(If(f.boxed()?, f.boxed()?, f.opt()?)),
+        "NumericFor" => |f| Ok(NumericFor(f.boxed()?, f.boxed()?, f.boxed()?, f.opt()?, f.boxed()?)),
+        "GenericFor" => |f| Ok(GenericFor(f.boxed()?, f.boxed()?, f.boxed()?)),
+        "FunctionStat" => |f| Ok(FunctionStat(f.boxed()?, f.boxed()?)),
+        "BinNot" => |f| Ok(BinNot(f.boxed()?)),
+        "Not" => |f| Ok(Not(f.boxed()?)),
+        "Len" => |f| Ok(Len(f.boxed()?)),
+        "UMin" => |f| Ok(UMin(f.boxed()?)),
+        "PrefixExp" => |f| Ok(PrefixExp(f.boxed()?)),
+        "FunctionCall" => |f| Ok(FunctionCall(f.boxed()?, f.opt()?)),
+        "MethodCall" => |f| Ok(MethodCall(f.boxed()?, f.boxed()?, f.opt()?)),
+        "Nil" => |_| Ok(Nil),
+        "VarArg" => |_| Ok(VarArg),
+        "TableConstructor" => |f| Ok(TableConstructor(f.opt()?)),
+        "Function" => |f| Ok(Function(f.boxed()?)),
+        "FunctionBody" => |f| Ok(FunctionBody(f.opt()?, f.boxed()?)),
+        "FunctionName" => |f| {
+            let name = f.boxed()?;
+            let fields = match f.next("a list or `_`")? {
+                Sexp::Atom(ref a) if a == "_" => None,
+                Sexp::Vector(items) => Some(f.to_nodes(items)?),
+                other => return f.error(format!("expected a list in brackets or `_`, found {:?}", other)),
+            };
+            Ok(FunctionName(name, fields, (*f.opt()?).map(Box::new)))
+        },
+        "NamedFunction" => |f| Ok(NamedFunction(f.boxed()?, f.boxed()?)),
+        "ExpList" => |f| Ok(ExpList(f.rest()?)),
+        "VarList" => |f| Ok(VarList(f.rest()?)),
+        "NameList" => |f| Ok(NameList(f.name_list()?)),
+        "FieldList" => |f| Ok(FieldList(f.rest()?)),
+        "ParameterList" => |f| Ok(ParameterList(f.opt()?, f.parse("true or false")?)),
+        "FieldSingle" => |f| Ok(FieldSingle(f.boxed()?)),
+        "FieldAssign" => |f| Ok(FieldAssign(f.boxed()?, f.boxed()?)),
+        "Local" => |f| Ok(Local(f.boxed()?, f.opt()?)),
+        "Var" => |f| Ok(Var(f.boxed()?)),
+        "VarPrefixed" => |f| Ok(VarPrefixed(f.boxed()?, f.boxed()?)),
+        "VarListAccess" => |f| Ok(VarListAccess(f.boxed()?, f.boxed()?)),
+        #[cfg(feature="luau")]
+        "Typed" => |f| Ok(Typed(f.boxed()?, f.boxed()?)),
+        #[cfg(feature="luau")]
+        "TypedFunctionBody" => |f| Ok(TypedFunctionBody(f.opt()?, f.boxed()?, f.boxed()?)),
+        #[cfg(feature="luau")]
+        "TypeAlias" => |f| Ok(TypeAlias(f.parse("true or false")?, f.boxed()?, f.vector()?, f.boxed()?)),
+        #[cfg(feature="luau")]
+        "CompoundAssign" => |f| Ok(CompoundAssign(f.binop()?, f.boxed()?, f.boxed()?)),
+        #[cfg(feature="luau")]
+        "Continue" => |_| Ok(Continue),
+        #[cfg(feature="luau")]
+        "IfExp" => |f| Ok(IfExp(f.boxed()?, f.boxed()?, f.boxed()?)),
+        #[cfg(feature="luau")]
+        "TypeName" => |f| Ok(TypeName(f.text()?, f.vector()?)),
+        #[cfg(feature="luau")]
+        "TypeOptional" => |f| Ok(TypeOptional(f.boxed()?)),
+        #[cfg(feature="luau")]
+        "TypeUnion" => |f| Ok(TypeUnion(f.rest()?)),
+        #[cfg(feature="luau")]
+        "TypeIntersection" => |f| Ok(TypeIntersection(f.rest()?)),
+        #[cfg(feature="luau")]
+        "TypeFunction" => |f| Ok(TypeFunction(f.vector()?, f.boxed()?)),
+        #[cfg(feature="luau")]
+        "TypePack" => |f| Ok(TypePack(f.rest()?)),
+        #[cfg(feature="luau")]
+        "TypeTable" => |f| Ok(TypeTable(f.rest()?)),
+        #[cfg(feature="luau")]
+        "TypeField" => |f| Ok(TypeField(f.boxed()?, f.boxed()?)),
+        #[cfg(feature="luau")]
+        "TypeIndexer" => |f| Ok(TypeIndexer(f.boxed()?, f.boxed()?)),
+        #[cfg(feature="luau")]
+        "TypeOf" => |f| Ok(TypeOf(f.boxed()?)),
+        #[cfg(feature="luau")]
+        "TypeVariadic" => |f| Ok(TypeVariadic(f.boxed()?)),
+        _ => unreachable!("the other heads are binary operations"),
+    }
+}
+
+/// Reads a tree written by `print` or `print_flat`
+pub fn read(text: &str) -> Result<ASTNode> {
+    let mut reader = Reader { text, offset: 0, depth: 0 };
+    let (offset, sexp) = reader.sexp()?;
+    reader.skip();
+    if reader.offset < text.len() {
+        return reader.error(reader.offset, "unexpected text after the node".to_string());
+    }
+    match sexp {
+        Sexp::List(items) => convert(offset, items),
+        _ => reader.error(offset, "expected a node".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast::ASTNode::*;
+    use config::{LuaVersion, ParserConfig};
+    use parse_block_with;
+
+    fn parse(source: &str) -> ASTNode {
+        let config = ParserConfig { version: LuaVersion::Lua54, ..ParserConfig::default() };
+        parse_block_with(source.as_bytes(), &config).unwrap()
+    }
+
+    #[test]
+    fn flat_nodes() {
+        let ast = parse("local x <const>, y = 1.0, 'a\"\\n' t.f = function(...) end");
+        assert_eq!(print_flat(&ast), "(Block [\
+            (Local (NameList (Name x) <const> (Name y)) (ExpList (Float 1.0) (String \"a\\\"\\n\"))) \
+            (Assign (VarList (VarListAccess (PrefixExp (Var (Name t))) (Name f))) \
+            (ExpList (Function (FunctionBody (ParameterList _ true) (Block [] _)))))] _)");
+        assert_eq!(print_flat(&ast!(Name, "not a name".into())), "(Name \"not a name\")");
+        assert_eq!(print_flat(&String(vec![b'a', 0xFF])), "(String \"a\\xff\")");
+        let name = FunctionName(Box::new(ast!(Name, "a".into())), Some(vec![]), None);
+        assert_eq!(print_flat(&name), "(FunctionName (Name a) [] _)");
+    }
+
+    #[test]
+    fn layout() {
+        let ast = parse("if a then print('a long string that does not fit', 1 + 2) else return end");
+        assert_eq!(print(&ast), "\
+(Block
+  [(If
+     (PrefixExp (Var (Name a)))
+     (Block
+       [(FunctionCall
+          (PrefixExp (Var (Name print)))
+          (ExpList
+            (String \"a long string that does not fit\")
+            (Add (Integer 1) (Integer 2))))]
+       _)
+     (Block [] (RetStat _)))]
+  _)
+");
+        assert_eq!(print(&ast!(Nil)), "(Nil)\n");
+    }
+
+    #[test]
+    fn round_trips() {
+        let sources = [
+            "local a <close> = f(x):m{1, [2] = 3; k = ...} goto l ::l::",
+            "function a.b.c:d(e, ...) return -e ^ 2 // #t, not (a or b) and c ~= ~d end",
+            "for i = 1, 10, 2 do break end for k, v in pairs(t) do ; end",
+            "while a[1] do repeat x = 1e300 * 0.1 until x >= 1 << 2 | 3 & 4 ~ 5 >> 1 end",
+            "t = {'\\0\\1\\x7f\\u{10FFFF}é', \"\\\\\", 2^63, 0x7fffffffffffffff .. 'z'}",
+            "s = '\\xff\\128é'",
+        ];
+        for source in &sources {
+            let ast = parse(source);
+            assert_eq!(read(&print(&ast)).unwrap(), ast, "{}", print(&ast));
+            assert_eq!(read(&print_flat(&ast)).unwrap(), ast);
+        }
+        for &f in &[f64::INFINITY, f64::NEG_INFINITY, -0.0, 1e-320] {
+            let float = read(&print_flat(&Float(f))).unwrap();
+            assert_eq!(float, Float(f));
+        }
+        match read("(Float NaN)").unwrap() {
+            Float(f) => assert!(f.is_nan()),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[cfg(feature="luau")]
+    #[test]
+    fn luau() {
+        let ast = parse("export type T<U> = {x: number?, [string]: (U, ...any) -> ()} | typeof(a) type W = V & X \
+                         x += 1 local f: (number) -> () = function(a: number): string \
+                         return if a then b else c end continue");
+        assert_eq!(read(&print(&ast)).unwrap(), ast, "{}", print(&ast));
+        assert!(print_flat(&ast).contains("(CompoundAssign + (Var (Name x)) (Integer 1))"));
+    }
+
+    #[test]
+    fn comments_and_layout_are_ignored() {
+        let text = "; a comment\n(Block\n[ (Break) ; another\n] (RetStat _)) ; end";
+        assert_eq!(read(text).unwrap(), ast!(Block, vec![Break], Box::new(Some(astb!(RetStat, None)))));
+        assert_eq!(read("(Name \"x\")").unwrap(), ast!(Name, "x".into()));
+    }
+
+    #[test]
+    fn errors() {
+        let error = |text| read(text).unwrap_err();
+        assert_eq!(error("(Nope)"), SexpError { offset: 0, message: "unknown node Nope".into() });
+        assert_eq!(error("(Integer x)").message, "Integer: expected an integer, found x");
+        assert_eq!(error("(Block [] _ _)").message, "Block: unexpected Atom(\"_\")");
+        assert_eq!(error("(Block [])").message, "Block: missing a node or `_`");
+        assert_eq!(error("(Block [)").offset, 8);
+        assert_eq!(error("(String x)").message, "String: expected a quoted string, found Atom(\"x\")");
+        assert_eq!(error("(String \"\\q\")").offset, 9);
+        assert_eq!(error("(Nil) (Nil)").message, "unexpected text after the node");
+        assert_eq!(error("(NameList <const>)").message, "NameList: <const> does not follow a name");
+        assert_eq!(error("(Add (Nil)").message, "unexpected end of input");
+        let deep = |depth| format!("{}(Nil){}", "(Paren ".repeat(depth - 1), ")".repeat(depth - 1));
+        assert!(read(&deep(MAX_DEPTH)).is_ok());
+        assert_eq!(read(&deep(MAX_DEPTH + 1)).unwrap_err(),
+                   SexpError { offset: 7 * MAX_DEPTH, message: "nested deeper than 512 levels".into() });
+        assert_eq!(read(&"[".repeat(100000)).unwrap_err().message, "nested deeper than 512 levels");
+    }
+
+    #[test]
+    fn debug() {
+        let ast = parse("x = 1");
+        assert_eq!(format!("{:?}", ast), print_flat(&ast));
+        assert_eq!(format!("{:#?}", ast), print(&ast).trim_end());
+    }
+}
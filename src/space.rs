@@ -0,0 +1,82 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Whitespace and comments, which separate tokens
+//!
+//! `ws!` is redefined in `macros` to skip them with `parse_space` instead of
+//! nom's `sp`, which only knows about whitespace.
+
+use nom::{ErrorKind, IResult};
+
+use string::{long_bracket_level, parse_long_bracket};
+
+fn is_space(c: u8) -> bool {
+    c == b' ' || (b'\x09'..=b'\x0D').contains(&c)
+}
+
+/// Skips whitespace and comments. A comment is `--` up to the end of the
+/// line, or `--` followed by a long bracket such as `--[[ ... ]]`, which
+/// fails when it is not closed.
+pub fn parse_space(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    let mut i = 0;
+    while i < input.len() {
+        if is_space(input[i]) {
+            i += 1;
+        } else if input[i..].starts_with(b"--") {
+            i += 2;
+            let rest = &input[i..];
+            if long_bracket_level(rest).is_some() {
+                match parse_long_bracket(rest) {
+                    IResult::Done(after, _) => i = input.len() - after.len(),
+                    _ => return IResult::Error(error_code!(ErrorKind::Custom(0))),
+                }
+            } else {
+                i += rest.iter().position(|&c| c == b'\n' || c == b'\r').unwrap_or(rest.len());
+            }
+        } else {
+            break;
+        }
+    }
+    IResult::Done(&input[i..], &input[..i])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rest(input: &str) -> &str {
+        match parse_space(input.as_bytes()) {
+            IResult::Done(rest, _) => ::std::str::from_utf8(rest).unwrap(),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn whitespace() {
+        assert_eq!(rest(" \t\r\n\x0b\x0cx"), "x");
+        assert_eq!(rest("x "), "x ");
+        assert_eq!(rest(""), "");
+        assert_eq!(rest("- -x"), "- -x");
+    }
+
+    #[test]
+    fn comments() {
+        assert_eq!(rest("-- a\n  x"), "x");
+        assert_eq!(rest("-- a"), "");
+        assert_eq!(rest("--[[ a\n b ]] x"), "x");
+        assert_eq!(rest("--[==[ ]] ]==]x"), "x");
+        assert_eq!(rest("--[= a\nx"), "x");
+        assert_eq!(rest("-- a\n-- b\r\n--[[c]]x"), "x");
+    }
+
+    #[test]
+    fn unfinished_long_comment() {
+        assert!(parse_space(b"--[[ a").is_err());
+        assert!(parse_space(b"--[==[ a ]]").is_err());
+    }
+}
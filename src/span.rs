@@ -0,0 +1,540 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Source positions of AST nodes
+//!
+//! The AST does not store positions. They are recovered by splitting the
+//! source into tokens and walking the arena in source order, matching the
+//! tokens each node starts or ends with (names, literals and keywords such
+//! as `local` or `end`). A node spans from its first matched token to the
+//! last token matched by it or its children, so the spans of nodes without
+//! any token, like an empty block, are missing.
+//!
+//! The split itself is public as `tokens`, for tools that work on tokens.
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use arena::{Arena, NodeId, NodeKind, SideTable};
+use printer::KEYWORDS;
+
+/// A range of bytes in the source, `end` is exclusive
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature="serde", derive(Serialize, Deserialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// The smallest span covering both spans
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+
+    /// The line and column of the start of the span, both starting at 1
+    pub fn line_column(self, source: &[u8]) -> (usize, usize) {
+        let before = &source[..self.start.min(source.len())];
+        let line = before.iter().filter(|&&c| c == b'\n').count() + 1;
+        let column = before.iter().rev().take_while(|&&c| c != b'\n').count() + 1;
+        (line, column)
+    }
+}
+
+/// What a token of `tokens` is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    Keyword,
+    Name,
+    Number,
+    /// A quoted or long string
+    String,
+    /// An operator or punctuation, such as `..` or `(`
+    Symbol,
+}
+
+impl Display for TokenKind {
+    fn fmt(&self, format: &mut Formatter) -> fmt::Result {
+        let name = match *self {
+            TokenKind::Keyword => "keyword",
+            TokenKind::Name => "name",
+            TokenKind::Number => "number",
+            TokenKind::String => "string",
+            TokenKind::Symbol => "symbol",
+        };
+        write!(format, "{}", name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+// The length of the long bracket opening at `i`, as in `[==[`
+pub(crate) fn long_bracket(source: &[u8], i: usize) -> Option<usize> {
+    if source.get(i) != Some(&b'[') {
+        return None;
+    }
+    let level = source[i + 1..].iter().take_while(|&&c| c == b'=').count();
+    match source.get(i + 1 + level) {
+        Some(&b'[') => Some(level),
+        _ => None,
+    }
+}
+
+// The end of the long string or comment whose body starts at `i`
+fn long_end(source: &[u8], i: usize, level: usize) -> usize {
+    let mut close = vec![b']'];
+    close.extend(::std::iter::repeat_n(b'=', level));
+    close.push(b']');
+    source[i..].windows(close.len())
+        .position(|w| w == &close[..])
+        .map_or(source.len(), |p| i + p + close.len())
+}
+
+/// Splits `source` into tokens, leaving out whitespace and comments
+///
+/// This only finds where tokens start and end, a malformed number or an
+/// unfinished string is still a token.
+pub fn tokens(source: &[u8]) -> Vec<Token> {
+    lex(source).0
+}
+
+/// The spans of the comments of `source`, in order
+pub fn comments(source: &[u8]) -> Vec<Span> {
+    lex(source).1
+}
+
+/// The spans of the tokens and of the comments of `source`, in order
+#[cfg(feature="serde")]
+pub(crate) fn token_spans(source: &[u8]) -> (Vec<Span>, Vec<Span>) {
+    let (tokens, comments) = lex(source);
+    (tokens.iter().map(|t| t.span).collect(), comments)
+}
+
+fn lex(source: &[u8]) -> (Vec<Token>, Vec<Span>) {
+    let mut tokens = Vec::new();
+    let mut comments = Vec::new();
+    let mut i = 0;
+    // A first line starting with `#` is skipped by `lua`
+    if source.first() == Some(&b'#') {
+        i = source.iter().position(|&c| c == b'\n').unwrap_or(source.len());
+    }
+    while i < source.len() {
+        let c = source[i];
+        let start = i;
+        let kind = match c {
+            _ if c.is_ascii_whitespace() => {
+                i += 1;
+                continue;
+            }
+            b'-' if source.get(i + 1) == Some(&b'-') => {
+                i = match long_bracket(source, i + 2) {
+                    Some(level) => long_end(source, i + level + 4, level),
+                    None => source[i..].iter().position(|&c| c == b'\n').map_or(source.len(), |p| i + p),
+                };
+                comments.push(Span { start, end: i });
+                continue;
+            }
+            b'[' if long_bracket(source, i).is_some() => {
+                let level = long_bracket(source, i).unwrap_or(0);
+                i = long_end(source, i + level + 2, level);
+                TokenKind::String
+            }
+            b'\'' | b'"' => {
+                i += 1;
+                while i < source.len() && source[i] != c && source[i] != b'\n' {
+                    i += if source[i] == b'\\' { 2 } else { 1 };
+                }
+                i = (i + 1).min(source.len());
+                TokenKind::String
+            }
+            b'0'..=b'9' => {
+                i = number_end(source, i);
+                TokenKind::Number
+            }
+            b'.' if source.get(i + 1).is_some_and(u8::is_ascii_digit) => {
+                i = number_end(source, i);
+                TokenKind::Number
+            }
+            b'a'..=b'z' | b'A'..=b'Z' | b'_' => {
+                while i < source.len() && (source[i].is_ascii_alphanumeric() || source[i] == b'_') {
+                    i += 1;
+                }
+                if KEYWORDS.iter().any(|k| k.as_bytes() == &source[start..i]) {
+                    TokenKind::Keyword
+                } else {
+                    TokenKind::Name
+                }
+            }
+            b'.' if source[i..].starts_with(b"...") => {
+                i += 3;
+                TokenKind::Symbol
+            }
+            b'=' | b'~' | b'<' | b'>' if source.get(i + 1) == Some(&b'=') => {
+                i += 2;
+                TokenKind::Symbol
+            }
+            b'.' | b':' | b'/' | b'<' | b'>' if source.get(i + 1) == Some(&c) => {
+                i += 2;
+                TokenKind::Symbol
+            }
+            _ => {
+                i += 1;
+                TokenKind::Symbol
+            }
+        };
+        tokens.push(Token { kind, span: Span { start, end: i } });
+    }
+    (tokens, comments)
+}
+
+// Whether the long string or comment whose bracket opens at `i` is closed
+fn long_closed(source: &[u8], i: usize) -> bool {
+    let level = long_bracket(source, i).unwrap_or(0);
+    let mut close = vec![b']'];
+    close.extend(::std::iter::repeat_n(b'=', level));
+    close.push(b']');
+    source[i + level + 2..].windows(close.len()).any(|w| w == &close[..])
+}
+
+/// Whether `source` stops inside a block, a bracket, a long string or a
+/// long comment, or right after an operator, so that more lines could still
+/// make it a chunk. An interactive reader keeps reading such input, as
+/// `lua` does.
+pub fn is_incomplete(source: &[u8]) -> bool {
+    let (tokens, comments) = lex(source);
+    // An unclosed long string or comment runs to the end of the source
+    let last = tokens.last().filter(|t| t.kind == TokenKind::String).map(|t| t.span.start);
+    if last.is_some_and(|start| source[start] == b'[' && !long_closed(source, start)) {
+        return true;
+    }
+    if let Some(comment) = comments.last() {
+        if long_bracket(source, comment.start + 2).is_some() && !long_closed(source, comment.start + 2) {
+            return true;
+        }
+    }
+    let mut depth = 0;
+    for token in tokens.iter().filter(|t| t.kind != TokenKind::String) {
+        match &source[token.span.start..token.span.end] {
+            b"do" | b"if" | b"function" | b"repeat" | b"(" | b"{" | b"[" => depth += 1,
+            b"end" | b"until" | b")" | b"}" | b"]" => depth -= 1,
+            _ => {}
+        }
+    }
+    let dangling = tokens.last().is_some_and(|t| t.kind != TokenKind::String && matches!(
+        &source[t.span.start..t.span.end],
+        b"=" | b"," | b"." | b":" | b".." | b"+" | b"-" | b"*" | b"/" | b"//" | b"%" | b"^" |
+        b"==" | b"~=" | b"<" | b"<=" | b">" | b">=" | b"&" | b"|" | b"~" | b"<<" | b">>" |
+        b"#" | b"and" | b"or" | b"not" | b"local" | b"in"
+    ));
+    depth > 0 || dangling
+}
+
+fn number_end(source: &[u8], mut i: usize) -> usize {
+    let hex = source[i..].starts_with(b"0x") || source[i..].starts_with(b"0X");
+    while i < source.len() {
+        let c = source[i];
+        let exponent = if hex { b"pP" } else { b"eE" };
+        if exponent.contains(&c) && matches!(source.get(i + 1), Some(&b'+') | Some(&b'-')) {
+            i += 2;
+        } else if c.is_ascii_alphanumeric() || c == b'_' || (c == b'.' && source.get(i + 1) != Some(&b'.')) {
+            i += 1;
+        } else {
+            break;
+        }
+    }
+    i
+}
+
+/// Whether the PrefixExp `id` is an expression in parentheses, the parser
+/// keeps them as a PrefixExp around anything but a variable or a call
+pub(crate) fn parenthesized(arena: &Arena, id: NodeId) -> bool {
+    *arena.kind(id) == NodeKind::PrefixExp && arena.child(id, "exp").is_some_and(|e| !matches!(
+        *arena.kind(e),
+        NodeKind::Var | NodeKind::VarPrefixed | NodeKind::VarListAccess |
+        NodeKind::FunctionCall | NodeKind::MethodCall
+    ))
+}
+
+struct Matcher<'a> {
+    source: &'a [u8],
+    arena: &'a Arena,
+    tokens: Vec<Token>,
+    next: usize,
+    spans: SideTable<Span>,
+}
+
+impl<'a> Matcher<'a> {
+    // Consumes the next token accepted by `accept`, skipping the others
+    fn find<F: Fn(&Token, &[u8]) -> bool>(&mut self, accept: F) -> Option<Span> {
+        let source = self.source;
+        let found = self.tokens[self.next..].iter()
+            .position(|t| accept(t, &source[t.span.start..t.span.end]))?;
+        self.next += found + 1;
+        Some(self.tokens[self.next - 1].span)
+    }
+
+    fn text(&mut self, text: &str) -> Option<Span> {
+        self.find(|t, s| t.kind != TokenKind::String && s == text.as_bytes())
+    }
+
+    fn kind(&mut self, kind: TokenKind) -> Option<Span> {
+        self.find(|t, _| t.kind == kind)
+    }
+
+    // Whether `id` is the `else` child of a node of the same kind, which
+    // is how `elseif` is stored
+    fn is_elseif(&self, id: NodeId) -> bool {
+        self.arena.parent(id)
+            .is_some_and(|p| self.arena.kind(p) == self.arena.kind(id) && self.arena.child(p, "else") == Some(id))
+    }
+
+    fn walk(&mut self, id: NodeId) -> Option<Span> {
+        let arena = self.arena;
+        let kind = arena.kind(id);
+        let elseif = matches!(*kind, NodeKind::If) && self.is_elseif(id);
+        #[cfg(feature="luau")]
+        let elseif = elseif || matches!(*kind, NodeKind::IfExp) && self.is_elseif(id);
+        let mut span = match *kind {
+            NodeKind::Name(ref name) => self.text(name),
+            // Closed by the second `::` below
+            NodeKind::Label(ref name) => {
+                let open = self.text("::");
+                let name = self.text(name);
+                open.or(name)
+            }
+            NodeKind::Integer(_) | NodeKind::Float(_) => self.kind(TokenKind::Number),
+            NodeKind::String(_) => self.kind(TokenKind::String),
+            NodeKind::Nil => self.text("nil"),
+            NodeKind::Bool(b) => self.text(if b { "true" } else { "false" }),
+            NodeKind::VarArg => self.text("..."),
+            NodeKind::Break => self.text("break"),
+            #[cfg(feature="luau")]
+            NodeKind::Continue => self.text("continue"),
+            _ if elseif => self.text("elseif"),
+            #[cfg(feature="luau")]
+            NodeKind::IfExp => self.text("if"),
+            NodeKind::If => self.text("if"),
+            NodeKind::Do => self.text("do"),
+            NodeKind::While => self.text("while"),
+            NodeKind::Repeat => self.text("repeat"),
+            NodeKind::NumericFor | NodeKind::GenericFor => self.text("for"),
+            NodeKind::Function | NodeKind::FunctionStat => self.text("function"),
+            NodeKind::Local | NodeKind::NamedFunction => self.text("local"),
+            NodeKind::RetStat => self.text("return"),
+            NodeKind::Goto => self.text("goto"),
+            NodeKind::TableConstructor => self.text("{"),
+            NodeKind::Paren => self.text("("),
+            NodeKind::Not => self.text("not"),
+            NodeKind::Len => self.text("#"),
+            NodeKind::UMin => self.text("-"),
+            NodeKind::BinNot => self.text("~"),
+            NodeKind::PrefixExp if parenthesized(arena, id) => self.text("("),
+            NodeKind::FieldAssign => match arena.child(id, "key") {
+                Some(key) if matches!(*arena.kind(key), NodeKind::Name(_)) => None,
+                _ => self.text("["),
+            },
+            _ => None,
+        };
+        let join = |span: Option<Span>, other: Option<Span>| match (span, other) {
+            (Some(a), Some(b)) => Some(a.to(b)),
+            (a, b) => a.or(b),
+        };
+        let children = arena[id].children.clone();
+        // `f "s"` and `f {}` store their argument in an ExpList as well
+        let mut paren_args = true;
+        for (field, child) in children {
+            if field == "right" {
+                if let Some(op) = kind.binop() {
+                    self.text(&op.to_string());
+                }
+            }
+            if field == "args" {
+                paren_args = self.tokens.get(self.next)
+                    .is_some_and(|t| &self.source[t.span.start..t.span.end] == b"(");
+            }
+            if field == "else" && *arena.kind(child) == NodeKind::Block {
+                let keyword = self.text("else");
+                span = join(span, keyword);
+            }
+            let child = self.walk(child);
+            span = join(span, child);
+        }
+        let close = match *kind {
+            NodeKind::Label(_) => self.text("::"),
+            NodeKind::If if !elseif => self.text("end"),
+            NodeKind::Do | NodeKind::While | NodeKind::NumericFor | NodeKind::GenericFor |
+            NodeKind::FunctionBody => self.text("end"),
+            #[cfg(feature="luau")]
+            NodeKind::TypedFunctionBody => self.text("end"),
+            NodeKind::TableConstructor => self.text("}"),
+            NodeKind::Paren => self.text(")"),
+            NodeKind::PrefixExp if parenthesized(arena, id) => self.text(")"),
+            NodeKind::VarPrefixed => self.text("]"),
+            NodeKind::FunctionCall | NodeKind::MethodCall if paren_args => self.text(")"),
+            _ => None,
+        };
+        span = join(span, close);
+        if let Some(span) = span {
+            self.spans.insert(id, span);
+        }
+        span
+    }
+}
+
+/// Finds the spans of the nodes of `arena`, which was parsed from `source`
+pub fn spans(source: &[u8], arena: &Arena) -> SideTable<Span> {
+    let mut matcher = Matcher {
+        source,
+        arena,
+        tokens: tokens(source),
+        next: 0,
+        spans: SideTable::new(),
+    };
+    matcher.walk(arena.root());
+    matcher.spans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{with_config, LuaVersion, ParserConfig};
+    use function::parse_block;
+    use nom::IResult;
+
+    // The source text of every node of `kind`
+    fn texts(source: &str, kind: fn(&NodeKind) -> bool) -> Vec<&str> {
+        let config = ParserConfig { version: LuaVersion::Lua54, ..ParserConfig::default() };
+        let block = match with_config(&config, || parse_block(source.as_bytes())) {
+            IResult::Done(_, block) => block,
+            other => panic!("{:?}", other),
+        };
+        let arena = Arena::from_ast(&block);
+        let spans = spans(source.as_bytes(), &arena);
+        arena.ids()
+            .filter(|&id| kind(arena.kind(id)))
+            .map(|id| spans.get(id).map_or("", |s| &source[s.start..s.end]))
+            .collect()
+    }
+
+    #[test]
+    fn statements() {
+        let source = "local x <const> = 1\nif x then f(x, 'a') elseif y then return else do end end\n";
+        assert_eq!(texts(source, |k| *k == NodeKind::Local), vec!["local x <const> = 1"]);
+        assert_eq!(texts(source, |k| *k == NodeKind::If), vec![
+            "if x then f(x, 'a') elseif y then return else do end end",
+            "elseif y then return else do end",
+        ]);
+        assert_eq!(texts(source, |k| *k == NodeKind::FunctionCall), vec!["f(x, 'a')"]);
+        assert_eq!(texts(source, |k| *k == NodeKind::Do), vec!["do end"]);
+    }
+
+    #[test]
+    fn calls() {
+        let source = "f 'x' g {1} o:m\"s\" h(y)";
+        assert_eq!(texts(source, |k| matches!(*k, NodeKind::FunctionCall | NodeKind::MethodCall)),
+                   vec!["f 'x'", "g {1}", "o:m\"s\"", "h(y)"]);
+    }
+
+    #[test]
+    fn functions_and_tables() {
+        let source = "function t.a:b(...) return {[1] = 0x10, a = '}', ...} end";
+        assert_eq!(texts(source, |k| *k == NodeKind::FunctionStat), vec![source]);
+        assert_eq!(texts(source, |k| *k == NodeKind::TableConstructor),
+                   vec!["{[1] = 0x10, a = '}', ...}"]);
+        assert_eq!(texts(source, |k| *k == NodeKind::FieldAssign), vec!["[1] = 0x10", "a = '}'"]);
+        assert_eq!(texts(source, |k| *k == NodeKind::Name("b".into())), vec!["b"]);
+    }
+
+    #[test]
+    fn strings_are_skipped() {
+        let source = "x = 'y' .. \"z\\\"x\"\ny = 'x'";
+        assert_eq!(texts(source, |k| *k == NodeKind::Name("x".into())), vec!["x"]);
+        assert_eq!(texts(source, |k| *k == NodeKind::Name("y".into())), vec!["y"]);
+        assert_eq!(texts(source, |k| matches!(*k, NodeKind::String(_))),
+                   vec!["'y'", "\"z\\\"x\"", "'x'"]);
+    }
+
+    #[test]
+    fn comments_are_skipped() {
+        let source = b"#!/bin/lua\n-- a\nb --[=[ c ]] ]=] [[d]]";
+        let names: Vec<_> = tokens(source).iter()
+            .map(|t| &source[t.span.start..t.span.end])
+            .collect();
+        assert_eq!(names, vec![&b"b"[..], &b"[[d]]"[..]]);
+    }
+
+    #[test]
+    fn comments() {
+        let source = b"-- a\nb --[=[ c ]] ]=] [[d]]";
+        let (tokens, comments) = lex(source);
+        let tokens: Vec<_> = tokens.iter().map(|t| t.span).collect();
+        assert_eq!(tokens, vec![Span { start: 5, end: 6 }, Span { start: 22, end: 27 }]);
+        let comments: Vec<_> = comments.iter().map(|s| &source[s.start..s.end]).collect();
+        assert_eq!(comments, vec![&b"-- a"[..], &b"--[=[ c ]] ]=]"[..]]);
+    }
+
+    #[test]
+    fn token_kinds() {
+        let source = b"local x = y..0x1p4 [[s]]";
+        let kinds: Vec<_> = tokens(source).iter().map(|t| t.kind.to_string()).collect();
+        assert_eq!(kinds, vec!["keyword", "name", "symbol", "name", "symbol", "number", "string"]);
+        assert_eq!(super::comments(b"a -- b\n"), vec![Span { start: 2, end: 6 }]);
+    }
+
+    #[test]
+    fn incomplete() {
+        for source in &["function f()", "if x then return elseif y then", "t = {1,\n", "f(", "repeat",
+                        "x = [==[a]]", "--[[ a", "x = 1 +", "local", "for i = 1, 2 do if x then end",
+                        "t[", "t[="] {
+            assert!(is_incomplete(source.as_bytes()), "{:?}", source);
+        }
+        for source in &["", "x = 1", "function f() end", "repeat until x", "x = [==[a]]]==]", "end",
+                        "x = 'if'", "--[[ a ]] x = 1", "-- if", "x = '[['", "while x do end"] {
+            assert!(!is_incomplete(source.as_bytes()), "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn parentheses_and_indexing() {
+        let source = "x = (a).b[(c)] + ( d )";
+        let arena = Arena::from_ast(&::parse_block_with(source.as_bytes(), &ParserConfig::default()).unwrap());
+        let spans = spans(source.as_bytes(), &arena);
+        let parens: Vec<_> = arena.ids()
+            .filter(|&id| parenthesized(&arena, id))
+            .map(|id| spans.get(id).map_or("", |s| &source[s.start..s.end]))
+            .collect();
+        assert_eq!(parens, vec!["(a)", "(c)", "( d )"]);
+        assert_eq!(texts(source, |k| *k == NodeKind::VarPrefixed), vec!["(a).b[(c)]"]);
+        assert_eq!(texts(source, |k| *k == NodeKind::Add), vec!["(a).b[(c)] + ( d )"]);
+    }
+
+    #[test]
+    fn operators() {
+        let source = "x = a - -b ~= ~c // not #d";
+        assert_eq!(texts(source, |k| *k == NodeKind::UMin), vec!["-b"]);
+        assert_eq!(texts(source, |k| *k == NodeKind::Sub), vec!["a - -b"]);
+        assert_eq!(texts(source, |k| *k == NodeKind::BinNot), vec!["~c"]);
+        assert_eq!(texts(source, |k| *k == NodeKind::FDiv), vec!["~c // not #d"]);
+        assert_eq!(texts(source, |k| *k == NodeKind::Not), vec!["not #d"]);
+        assert_eq!(texts(source, |k| *k == NodeKind::Ne), vec!["a - -b ~= ~c // not #d"]);
+    }
+
+    #[test]
+    fn line_column() {
+        let span = Span { start: 6, end: 7 };
+        assert_eq!(span.line_column(b"a\nbc\n  d"), (3, 2));
+        assert_eq!(Span { start: 0, end: 1 }.line_column(b"a"), (1, 1));
+    }
+}
@@ -7,35 +7,133 @@
 // except according to those terms.
 
 use ast::ASTNode;
-use name::{parse_label, parse_name};
-//use function::parse_local_function;
-use exp::parse_explist;
+use ast::ASTNode::*;
+use config::LuaVersion;
+use name::{parse_attnamelist, parse_label, parse_name, parse_namelist};
+use function::{parse_block, parse_function_stat, parse_local_function};
+use exp::{parse_exp, parse_explist};
+use var::{parse_suffixed, parse_varlist};
+#[cfg(feature="luau")]
+use luau::parse_luau_statement;
 
-named!(parse_goto<ASTNode>, map!(map!(preceded!(tag!("goto"), ws!(parse_name)), Box::new), ASTNode::Goto));
+#[cfg(not(feature="luau"))]
+named!(parse_luau_statement<ASTNode>, fail!());
+
+named!(parse_goto<ASTNode>, do_parse!(
+           keyword!("goto")
+        >> requires!("goto", LuaVersion::Lua52)
+        >> n: ws!(parse_name)
+        >> (astb!(Goto, n))));
+
+named!(parse_local<ASTNode>, do_parse!(
+           keyword!("local")
+        >> names: ws!(parse_attnamelist)
+        >> values: opt!(complete!(preceded!(ws!(tag!("=")), parse_explist)))
+        >> (astb!(Local, names, values))));
+
+named!(parse_break<ASTNode>, map!(keyword!("break"), |_| Break));
+
+named!(parse_do<ASTNode>, do_parse!(
+           keyword!("do")
+        >> b: ws!(parse_block)
+        >> keyword!("end")
+        >> (astb!(Do, b))));
+
+named!(parse_while<ASTNode>, do_parse!(
+           keyword!("while")
+        >> c: ws!(parse_exp)
+        >> keyword!("do")
+        >> b: ws!(parse_block)
+        >> keyword!("end")
+        >> (astb!(While, c, b))));
+
+named!(parse_repeat<ASTNode>, do_parse!(
+           keyword!("repeat")
+        >> b: ws!(parse_block)
+        >> keyword!("until")
+        >> c: ws!(parse_exp)
+        >> (astb!(Repeat, b, c))));
+
+named!(parse_if<ASTNode>, do_parse!(
+           keyword!("if")
+        >> c: ws!(parse_exp)
+        >> keyword!("then")
+        >> b: ws!(parse_block)
+        >> elseifs: many0!(complete!(do_parse!(
+                       keyword!("elseif")
+                    >> c: ws!(parse_exp)
+                    >> keyword!("then")
+                    >> b: ws!(parse_block)
+                    >> ((c, b)))))
+        >> other: opt!(complete!(preceded!(keyword!("else"), ws!(parse_block))))
+        >> keyword!("end")
+        >> (fold_if(c, b, elseifs, other))));
+
+// elseif is an If in the else branch of the previous one
+fn fold_if(cond: ASTNode, block: ASTNode, elseifs: Vec<(ASTNode, ASTNode)>, other: Option<ASTNode>) -> ASTNode {
+    let other = elseifs.into_iter().rev().fold(other, |acc, (cond, block)| Some(astb!(If, cond, block, acc)));
+    astb!(If, cond, block, other)
+}
+
+named!(parse_numeric_for<ASTNode>, do_parse!(
+           keyword!("for")
+        >> n: ws!(parse_name)
+        >> tag!("=")
+        >> start: ws!(parse_exp)
+        >> tag!(",")
+        >> limit: ws!(parse_exp)
+        >> step: opt!(complete!(preceded!(tag!(","), ws!(parse_exp))))
+        >> keyword!("do")
+        >> b: ws!(parse_block)
+        >> keyword!("end")
+        >> (astb!(NumericFor, n, start, limit, step, b))));
+
+named!(parse_generic_for<ASTNode>, do_parse!(
+           keyword!("for")
+        >> names: ws!(parse_namelist)
+        >> keyword!("in")
+        >> exps: ws!(parse_explist)
+        >> keyword!("do")
+        >> b: ws!(parse_block)
+        >> keyword!("end")
+        >> (astb!(GenericFor, names, exps, b))));
+
+named!(parse_assign<ASTNode>, do_parse!(
+           vars: parse_varlist
+        >> ws!(tag!("="))
+        >> exps: parse_explist
+        >> (astb!(Assign, vars, exps))));
+
+named!(parse_call<ASTNode>, map_opt!(parse_suffixed, |(e, _)| match e {
+    FunctionCall(_, _) | MethodCall(_, _, _) => Some(e),
+    _ => None,
+}));
 
 named!(parse_semicolon, ws!(tag!(";")));
 named!(parse_semicolon_statement<ASTNode>, map!(parse_semicolon, |_| ASTNode::EmptyStatement));
 
 named!(pub parse_statement<ASTNode>, alt!(
         parse_semicolon_statement |
-        //varlist=explist
-        //functioncall
         parse_label |
-        parse_goto
-        //do end (deps block)
-        //while (deps exp, block)
-        //repeat until (deps exp, block)
-        //if (deps exp, block)
-        //for (deps exp, block)
-        //for in (deps exp, explist, block)
-        //function (deps funcname)
-        //parse_local_function
-        //local (deps namelist, explist)
+        parse_break |
+        parse_goto |
+        parse_do |
+        parse_while |
+        parse_repeat |
+        parse_if |
+        parse_numeric_for |
+        parse_generic_for |
+        parse_function_stat |
+        parse_local_function |
+        parse_local |
+        parse_luau_statement |
+        complete!(parse_assign) |
+        parse_call
 ));
 
 named!(pub parse_retstat<ASTNode>, map!(map!(
         delimited!(
-            tag!("return"),
+            keyword!("return"),
             ws!(opt!(complete!(parse_explist))),
             opt!(complete!(tag!(";")))
         ),
@@ -44,6 +142,9 @@ named!(pub parse_retstat<ASTNode>, map!(map!(
 #[cfg(test)]
 mod tests {
     use ast::ASTNode::*;
+    use ast::{ASTNode, Attrib};
+    use config::{with_config, LuaVersion, ParserConfig};
+    use nom::IResult;
 
     ast_valid!(parse_semicolon, parse_semicolon, ";");
 
@@ -52,6 +153,71 @@ mod tests {
     ast_panic_test!(parse_goto_2, parse_goto, "goto 17");
     ast_panic_test!(parse_goto_3, parse_goto, "got 17");
 
+    ast_test!(parse_local_1, parse_local, "local a, b",
+              astb!(Local, ast!(NameList, vec![
+                (ast!(Name, "a".into()), None),
+                (ast!(Name, "b".into()), None),
+              ]), None));
+    ast_test!(parse_local_2, parse_local, "local a = 1, nil",
+              astb!(Local, ast!(NameList, vec![
+                (ast!(Name, "a".into()), None),
+              ]), Some(ast!(ExpList, vec![
+                ast!(Integer, 1),
+                ast!(Nil),
+              ]))));
+    ast_panic_test!(parse_local_3, parse_local, "localize = 1");
+
+    ast_test!(parse_break_1, parse_statement, "break", ast!(Break));
+    ast_panic_test!(parse_break_2, parse_break, "breaking");
+
+    ast_test!(parse_assign_1, parse_statement, "a, b = 1",
+              astb!(Assign,
+                    ast!(VarList, vec![
+                      astb!(Var, ast!(Name, "a".into())),
+                      astb!(Var, ast!(Name, "b".into())),
+                    ]),
+                    ast!(ExpList, vec![ast!(Integer, 1)])));
+    ast_panic_test!(parse_assign_2, parse_assign, "f() = 1");
+
+    ast_test!(parse_call_1, parse_statement, "f()",
+              astb!(FunctionCall, astb!(PrefixExp, astb!(Var, ast!(Name, "f".into()))), None));
+    ast_panic_test!(parse_call_2, parse_call, "a.b");
+
+    ast_test!(parse_do_1, parse_statement, "do ; end",
+              astb!(Do, ast!(Block, vec![ast!(EmptyStatement)], Box::new(None))));
+
+    ast_test!(parse_while_1, parse_statement, "while true do break end",
+              astb!(While, ast!(Bool, true),
+                    ast!(Block, vec![ast!(Break)], Box::new(None))));
+
+    ast_test!(parse_repeat_1, parse_statement, "repeat ; until false",
+              astb!(Repeat, ast!(Block, vec![ast!(EmptyStatement)], Box::new(None)),
+                    ast!(Bool, false)));
+
+    ast_test!(parse_if_1, parse_statement, "if true then elseif false then ; else break end",
+              astb!(If, ast!(Bool, true), ast!(Block, vec![], Box::new(None)),
+                    Some(astb!(If, ast!(Bool, false),
+                               ast!(Block, vec![ast!(EmptyStatement)], Box::new(None)),
+                               Some(ast!(Block, vec![ast!(Break)], Box::new(None)))))));
+    ast_test!(parse_if_2, parse_statement, "if nil then end",
+              astb!(If, ast!(Nil), ast!(Block, vec![], Box::new(None)), None));
+    ast_test!(parse_if_3, parse_statement, "if nil then return elseif nil then end",
+              astb!(If, ast!(Nil), ast!(Block, vec![], Box::new(Some(astb!(RetStat, None)))),
+                    Some(astb!(If, ast!(Nil), ast!(Block, vec![], Box::new(None)), None))));
+
+    ast_test!(parse_numeric_for_1, parse_statement, "for i = 1, 10, 2 do end",
+              astb!(NumericFor, ast!(Name, "i".into()), ast!(Integer, 1), ast!(Integer, 10),
+                    Some(ast!(Integer, 2)), ast!(Block, vec![], Box::new(None))));
+
+    ast_test!(parse_generic_for_1, parse_statement, "for k, v in t do end",
+              astb!(GenericFor,
+                    ast!(NameList, vec![
+                      (ast!(Name, "k".into()), None),
+                      (ast!(Name, "v".into()), None),
+                    ]),
+                    ast!(ExpList, vec![astb!(PrefixExp, astb!(Var, ast!(Name, "t".into())))]),
+                    ast!(Block, vec![], Box::new(None))));
+
     ast_test!(parse_retstat_1, parse_retstat, "return false,true ;",
               astb!(RetStat, Some(ast!(ExpList, vec![
                 ast!(Bool, false),
@@ -65,4 +231,34 @@ mod tests {
 
     ast_test!(parse_retstat_3, parse_retstat, "return",
               astb!(RetStat, None));
+
+    fn parse_lua54(input: &str) -> Option<ASTNode> {
+        let config = ParserConfig { version: LuaVersion::Lua54, ..ParserConfig::default() };
+        with_config(&config, || match super::parse_statement(input.as_bytes()) {
+            IResult::Done(&[], statement) => Some(statement),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn parse_local_attribs() {
+        assert_eq!(parse_lua54("local x <const>, f <close> = 5, nil"),
+                   Some(astb!(Local, ast!(NameList, vec![
+                     (ast!(Name, "x".into()), Some(Attrib::Const)),
+                     (ast!(Name, "f".into()), Some(Attrib::Close)),
+                   ]), Some(ast!(ExpList, vec![
+                     ast!(Integer, 5),
+                     ast!(Nil),
+                   ])))));
+    }
+
+    #[test]
+    fn parse_local_unknown_attrib() {
+        assert_eq!(parse_lua54("local x <mutable> = 5"), None);
+    }
+
+    #[test]
+    fn parse_local_two_close() {
+        assert_eq!(parse_lua54("local f <close>, g <close> = nil, nil"), None);
+    }
 }
@@ -7,87 +7,161 @@
 // except according to those terms.
 
 use ast::ASTNode;
-use nom::{hex_digit, digit};
+use config::LuaVersion;
+use nom::{hex_digit, ErrorKind, IResult};
 use std::{str, char};
 
 named!(pub parse_string<ASTNode>,
-       map!(alt!(/*parse_string_literal |*/ parse_string_short_literal), |s| ASTNode::String(s)));
-
-
-//named!(parse_string_literal<String>, map_res!(raw_string, |s, _| s));
-
-//pub fn raw_string(input: &str) -> IResult<&str, (String, usize)> {
-//    let mut chars = input.char_indices();
-//    let mut n = 0;
-//    while let Some((byte_offset, ch)) = chars.next() {
-//        match ch {
-//            '"' => {
-//                n = byte_offset;
-//                break;
-//            }
-//            '#' => {}
-//            _ => return IResult::Error,
-//        }
-//    }
-//    let mut s = String::new();
-//    for (byte_offset, ch) in chars {
-//        match ch {
-//            '"' if input[byte_offset + 1..].starts_with(&input[..n]) => {
-//                let rest = &input[byte_offset + 1 + n..];
-//                return IResult::Done(rest, (s, n));
-//            }
-//            '\r' => {}
-//            _ => s.push(ch),
-//        }
-//    }
-//    IResult::Error
-//}
-
-// TODO: A short literal string cannot contain unescaped line breaks nor escapes not forming a valid escape sequence.
-// TODO: " ' inside strings are valid
-named!(parse_string_short_literal<String>,
-       delimited!(
-        alt!(tag!("\"") | tag!("'")),
-        fold_many0!(alt!(
-            map!(linebreak, |_| '\n') |
-            parse_byte |
-            parse_unicode |
-            one_of!("\x07\x08\x09\x0A\x0B\x0C\x0D")
-            // Find a way to discard the output from this: preceded!(tag!(r#"\z"#), alt!(sp |
-            // linebreak))
-        ), String::new(), |mut acc: String, item| {
-            acc.push(item);
+       map!(alt!(parse_string_literal | parse_string_short_literal), ASTNode::String));
+
+/// The level of the long bracket that opens `input`, 2 for `[==[`
+pub(crate) fn long_bracket_level(input: &[u8]) -> Option<usize> {
+    if input.first() != Some(&b'[') {
+        return None;
+    }
+    let level = input[1..].iter().take_while(|&&c| c == b'=').count();
+    match input.get(level + 1) {
+        Some(&b'[') => Some(level),
+        _ => None,
+    }
+}
+
+/// Parses a long bracket, `[[ ... ]]` or `[==[ ... ]==]` with any number of
+/// `=`, returning what is between the brackets. A line break right after the
+/// opening bracket is not part of it.
+pub(crate) fn parse_long_bracket(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    let level = match long_bracket_level(input) {
+        Some(level) => level,
+        None => return IResult::Error(error_code!(ErrorKind::Tag)),
+    };
+    let body = &input[level + 2..];
+    let mut close = vec![b']'];
+    close.extend(::std::iter::repeat_n(b'=', level));
+    close.push(b']');
+    let end = match body.windows(close.len()).position(|w| w == &close[..]) {
+        Some(end) => end,
+        None => return IResult::Error(error_code!(ErrorKind::Tag)),
+    };
+    let skip = match body {
+        [b'\r', b'\n', ..] | [b'\n', b'\r', ..] if end >= 2 => 2,
+        [b'\r', ..] | [b'\n', ..] if end >= 1 => 1,
+        _ => 0,
+    };
+    IResult::Done(&body[end + close.len()..], &body[skip..end])
+}
+
+// A long literal string has no escape sequences, and each of its line
+// breaks, `\r\n`, `\n\r`, `\n` or `\r`, is read as `\n`
+named!(parse_string_literal<Vec<u8>>, map!(parse_long_bracket, |body: &[u8]| {
+    let mut bytes = Vec::with_capacity(body.len());
+    let mut i = 0;
+    while i < body.len() {
+        match &body[i..] {
+            [b'\r', b'\n', ..] | [b'\n', b'\r', ..] => { bytes.push(b'\n'); i += 2; }
+            [b'\r', ..] => { bytes.push(b'\n'); i += 1; }
+            [c, ..] => { bytes.push(*c); i += 1; }
+            [] => unreachable!(),
+        }
+    }
+    bytes
+}));
+
+// A short literal string can not contain unescaped line breaks, nor escapes
+// that do not form a valid escape sequence
+named!(parse_string_short_literal<Vec<u8>>, alt!(
+        delimited!(tag!("\""), apply!(parse_short_literal_content, b'"'), tag!("\"")) |
+        delimited!(tag!("'"), apply!(parse_short_literal_content, b'\''), tag!("'"))));
+
+named_args!(parse_short_literal_content(quote: u8)<Vec<u8>>, fold_many0!(alt!(
+            parse_escape |
+            map!(apply!(parse_plain, quote), StringPart::Plain)
+        ), Vec::new(), |mut acc: Vec<u8>, item| {
+            match item {
+                StringPart::Byte(b) => acc.push(b),
+                StringPart::Char(c) => acc.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                StringPart::Skip => {},
+                StringPart::Plain(s) => acc.extend_from_slice(s),
+            }
             acc
-        }),
-        alt!(tag!("\"") | tag!("'"))));
+        }));
+
+enum StringPart<'a> {
+    /// A byte escape, `\xff` or `\255`
+    Byte(u8),
+    /// Any other escape, stored as UTF-8
+    Char(char),
+    /// `\z`, which produces nothing
+    Skip,
+    Plain(&'a [u8]),
+}
 
-named!(parse_byte<char>, alt!(parse_byte_x | parse_byte_d));
+/// Parses the bytes up to the closing quote, the next escape sequence or a
+/// line break, which ends the string unfinished
+fn parse_plain(input: &[u8], quote: u8) -> IResult<&[u8], &[u8]> {
+    let end = input.iter()
+        .position(|&c| c == quote || c == b'\\' || c == b'\n' || c == b'\r')
+        .unwrap_or(input.len());
+    if end == 0 {
+        return IResult::Error(error_code!(ErrorKind::IsNot));
+    }
+    IResult::Done(&input[end..], &input[..end])
+}
 
-named!(parse_byte_x<char>, map!(map_res!(map_res!(
-                preceded!(tag!("\\x"), hex_digit),
-                str::from_utf8),
-            |s| u8::from_str_radix(s, 16)), |i: u8| i as char));
+named!(parse_escape<StringPart<'static>>, alt!(
+            map!(linebreak, |_| StringPart::Char('\n')) |
+            map!(parse_skip_whitespace, |_| StringPart::Skip) |
+            map!(parse_byte, StringPart::Byte) |
+            map!(parse_unicode, StringPart::Char) |
+            map!(parse_simple_escape, StringPart::Char)
+));
+
+named!(parse_simple_escape<char>, map!(preceded!(tag!("\\"), one_of!("abfnrtv\\\"'")), |c| match c {
+    'a' => '\x07',
+    'b' => '\x08',
+    'f' => '\x0C',
+    'n' => '\n',
+    'r' => '\r',
+    't' => '\t',
+    'v' => '\x0B',
+    c => c,
+}));
+
+// \z skips the whitespace that follows it, including line breaks
+named!(parse_skip_whitespace, preceded!(
+            terminated!(tag!("\\z"), requires!("the \\z escape", LuaVersion::Lua52)),
+            take_while!(call!(|c| c == b' ' || (b'\x09'..=b'\x0D').contains(&c)))));
+
+named!(parse_byte<u8>, alt!(parse_byte_x | parse_byte_d));
+
+// Exactly two digits, `\x0a0` is the byte 10 followed by a `0`
+named!(parse_byte_x<u8>, map_res!(
+            preceded!(
+                terminated!(tag!("\\x"), requires!("hexadecimal escapes", LuaVersion::Lua52)),
+                fold_many_m_n!(2, 2, one_of!("0123456789abcdefABCDEF"), String::new(), |mut acc: String, c: char| {
+                    acc.push(c);
+                    acc
+                })),
+            |s: String| u8::from_str_radix(&s, 16)));
 
 named!(linebreak, alt!(tag!("\\\r\n") | tag!("\\\n\r") | tag!("\\\n")));
 
 
-// TODO: if a decimal escape sequence is to be followed by a digit, it must be expressed using exactly three digits
-// Notice, the fold_many_m_n is not actually enforcing bounds here, because digit recognizes
-// more than one character, but I think this implementation might be usefull for the future
-named!(parse_byte_d<char>, map!(map_res!(
-            preceded!(tag!("\\"), fold_many_m_n!(1, 3, digit, String::new(), |mut acc: String, item: &[u8]| {
-                for c in item {
-                    acc.push(*c as char);
-                }
+// At most three digits, `\1270` is the byte 127 followed by a `0`
+named!(parse_byte_d<u8>, map_res!(
+            preceded!(tag!("\\"), fold_many_m_n!(1, 3, one_of!("0123456789"), String::new(), |mut acc: String, c: char| {
+                acc.push(c);
                 acc
             })),
-            |s: String| s.parse::<u8>()), |i: u8| i as char));
+            |s: String| s.parse::<u8>()));
 
 named!(parse_unicode<char>,
        map_opt!(
            map_res!(
                map_res!(
-                   delimited!(tag!("\\u{"), recognize!(hex_digit), tag!("}")),
+                   delimited!(
+                       terminated!(tag!("\\u{"), requires!("unicode escapes", LuaVersion::Lua53)),
+                       recognize!(hex_digit),
+                       tag!("}")),
                    str::from_utf8),
                    |h| u32::from_str_radix(h, 16)),
                    char::from_u32));
@@ -97,34 +171,65 @@ mod tests {
     ast_panic_test!(parse_unicode_1, parse_unicode, r#"\u{}"#);
     ast_test!(parse_unicode_2, parse_unicode, r#"\u{A}"#, char::from_u32(0xA).unwrap());
     ast_test!(parse_unicode_3, parse_unicode, r#"\u{a2}"#, char::from_u32(0xa2).unwrap());
-    ast_test!(parse_unicode_4, parse_unicode, r#"\u{AFf9}"#, char::from_u32(0xAFf9).unwrap());
+    ast_test!(parse_unicode_4, parse_unicode, r#"\u{AFf9}"#, char::from_u32(0xAFF9).unwrap());
     ast_test!(parse_unicode_5, parse_unicode, r#"\u{0000000000000FFFF}"#, char::from_u32(0xFFFF).unwrap());
     ast_test!(parse_unicode_6, parse_unicode, r#"\u{10FFFF}"#, char::from_u32(0x10FFFF).unwrap());
     ast_panic_test!(parse_unicode_7, parse_unicode, r#"\u{110000}"#);
 
 
-    ast_test!(parse_byte_d_1, parse_byte_d, r#"\0"#, '\0');
-    ast_test!(parse_byte_d_2, parse_byte_d, r#"\00"#, '\0');
-    ast_test!(parse_byte_d_3, parse_byte_d, r#"\000"#, '\0');
-    // TODO: This should parse to the rust string r#"\u{0}0"# make this test reflect that
-    ast_test!(parse_byte_d_4, parse_byte_d, r#"\0000"#, '\0');
-    ast_test!(parse_byte_d_5, parse_byte_d, r#"\230"#, '\u{E6}');
+    ast_test!(parse_byte_d_1, parse_byte_d, r#"\0"#, 0);
+    ast_test!(parse_byte_d_2, parse_byte_d, r#"\00"#, 0);
+    ast_test!(parse_byte_d_3, parse_byte_d, r#"\000"#, 0);
+    ast_test!(parse_byte_d_4, parse_byte_d, r#"\0000"#, 0);
+    ast_test!(parse_byte_d_5, parse_byte_d, r#"\230"#, 0xE6);
     ast_panic_test!(parse_byte_d_6, parse_byte_d, r#"\256"#);
 
 
-    ast_test!(parse_byte_x_1, parse_byte_x, r#"\x00"#, '\0');
-    // TODO: This should parse to the rust string "\u{0a}0" make this test reflect that
-    //ast_test!(parse_byte_x_2, parse_byte_x, r#"\x0a0"#, '\x0a');
-    ast_test!(parse_byte_x_3, parse_byte_x, r#"\x23"#, '\u{23}');
-    ast_test!(parse_byte_x_4, parse_byte_x, r#"\x000023"#, '\u{23}');
-    ast_test!(parse_byte_x_5, parse_byte_x, r#"\xFf"#, '\u{FF}');
-
-    ast_test!(parse_string_short_literal_1, parse_string_short_literal, r#""""#, "");
-    ast_test!(parse_string_short_literal_2, parse_string_short_literal, r#"''"#, "");
-    ast_test!(parse_string_short_literal_3, parse_string_short_literal, r#"'\u{1F62A}'"#, "😪");
-    ast_test!(parse_string_short_literal_4, parse_string_short_literal, r#"'\097'"#, "a");
-    ast_test!(parse_string_short_literal_5, parse_string_short_literal, format!("'{}'", "\x07\x08\x09\x0A\x0B\x0C\x0D"), "\x07\x08\x09\x0A\x0B\x0C\x0D");
-    ast_test!(parse_string_short_literal_6, parse_string_short_literal, "'\\\n\r'", "\n");
-    ast_test!(parse_string_short_literal_7, parse_string_short_literal, "'\\\r\n'", "\n");
-    ast_test!(parse_string_short_literal_8, parse_string_short_literal, "'\\\n'", "\n");
+    ast_test!(parse_byte_x_1, parse_byte_x, r#"\x00"#, 0);
+    ast_test!(parse_byte_x_2, parse_byte_x, r#"\x0a0"#, 0x0a);
+    ast_test!(parse_byte_x_3, parse_byte_x, r#"\x23"#, 0x23);
+    ast_panic_test!(parse_byte_x_4, parse_byte_x, r#"\x2""#);
+    ast_test!(parse_byte_x_5, parse_byte_x, r#"\xFf"#, 0xFF);
+
+    ast_test!(parse_string_short_literal_1, parse_string_short_literal, r#""""#, "".as_bytes());
+    ast_test!(parse_string_short_literal_2, parse_string_short_literal, r#"''"#, "".as_bytes());
+    ast_test!(parse_string_short_literal_3, parse_string_short_literal, r#"'\u{1F62A}'"#, "😪".as_bytes());
+    ast_test!(parse_string_short_literal_4, parse_string_short_literal, r#"'\097'"#, "a".as_bytes());
+    ast_test!(parse_string_short_literal_5, parse_string_short_literal, format!("'{}'", "\x07\x08\x09\x0B\x0C"), "\x07\x08\x09\x0B\x0C".as_bytes());
+    ast_test!(parse_string_short_literal_6, parse_string_short_literal, "'\\\n\r'", "\n".as_bytes());
+    ast_test!(parse_string_short_literal_7, parse_string_short_literal, "'\\\r\n'", "\n".as_bytes());
+    ast_test!(parse_string_short_literal_8, parse_string_short_literal, "'\\\n'", "\n".as_bytes());
+    ast_test!(parse_string_short_literal_9, parse_string_short_literal, r#""say 'hi'""#, "say 'hi'".as_bytes());
+    ast_test!(parse_string_short_literal_10, parse_string_short_literal, r#"'a\'b\"c'"#, "a'b\"c".as_bytes());
+    ast_test!(parse_string_short_literal_11, parse_string_short_literal, r#""\a\b\f\n\r\t\v\\""#, "\x07\x08\x0C\n\r\t\x0B\\".as_bytes());
+    ast_test!(parse_string_short_literal_12, parse_string_short_literal, "'a\\z  \n\t b'", "ab".as_bytes());
+    ast_test!(parse_string_short_literal_13, parse_string_short_literal, "'héllo \\x41'", "héllo A".as_bytes());
+    ast_panic_test!(parse_string_short_literal_14, parse_string_short_literal, r#""mismatched'"#);
+    ast_panic_test!(parse_string_short_literal_15, parse_string_short_literal, r#""\q""#);
+    ast_panic_test!(parse_string_short_literal_17, parse_string_short_literal, "\"a\nb\"");
+    ast_panic_test!(parse_string_short_literal_18, parse_string_short_literal, "'a\rb'");
+    ast_test!(parse_string_short_literal_19, parse_string_short_literal, "'a\\\nb'", "a\nb".as_bytes());
+    ast_test!(parse_string_short_literal_16, parse_string_short_literal, r#""\1270""#, "\u{7f}0".as_bytes());
+    ast_test!(parse_string_short_literal_20, parse_string_short_literal, r#""\x000023""#, "\u{0}0023".as_bytes());
+
+    ast_test!(parse_string_1, parse_string, r#""ayy""#, ASTNode::String("ayy".into()));
+    ast_test!(parse_string_3, parse_string, r#""\128\xff\u{ff}""#, ASTNode::String(vec![0x80, 0xFF, 0xC3, 0xBF]));
+
+    #[test]
+    fn raw_bytes() {
+        use super::*;
+
+        // Lua strings are bytes, which need not be UTF-8
+        assert_eq!(parse_string(b"'\xff\x80'").unwrap().1, ASTNode::String(vec![0xFF, 0x80]));
+        assert_eq!(parse_string(b"[[\xff]]").unwrap().1, ASTNode::String(vec![0xFF]));
+    }
+    ast_test!(parse_string_2, parse_string, "[[ayy]]", ASTNode::String("ayy".into()));
+
+    ast_test!(parse_string_literal_1, parse_string_literal, "[[]]", "".as_bytes());
+    ast_test!(parse_string_literal_2, parse_string_literal, "[==[a]]\\n']=]]==]", "a]]\\n']=]".as_bytes());
+    ast_test!(parse_string_literal_3, parse_string_literal, "[[\nfirst\r\nsecond\rthird\n]]", "first\nsecond\nthird\n".as_bytes());
+    ast_test!(parse_string_literal_4, parse_string_literal, "[[\r\n\r\n]]", "\n".as_bytes());
+    ast_panic_test!(parse_string_literal_5, parse_string_literal, "[[a]=]");
+    ast_panic_test!(parse_string_literal_6, parse_string_literal, "[=[a]]");
+    ast_panic_test!(parse_string_literal_7, parse_string_literal, "[ [a]]");
 }
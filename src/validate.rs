@@ -0,0 +1,255 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The checks `luac` makes after parsing
+//!
+//! The grammar accepts any `goto`, label and `break`, but `luac` rejects a
+//! `goto` without a visible label, a label defined twice, a `goto` jumping
+//! forward into the scope of a local and a `break` outside of a loop. The
+//! errors use the messages of the `luac` of the given version.
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use arena::{Arena, NodeId, NodeKind};
+use config::LuaVersion;
+use span::{spans, Span};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValidationError {
+    /// The offending `Goto`, `Label` or `Break` node
+    pub node: NodeId,
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, format: &mut Formatter) -> fmt::Result {
+        write!(format, "{}", self.message)
+    }
+}
+
+fn name(arena: &Arena, id: NodeId) -> &str {
+    match *arena.kind(id) {
+        NodeKind::Name(ref name) | NodeKind::Label(ref name) => name,
+        _ => "",
+    }
+}
+
+fn is_function(kind: &NodeKind) -> bool {
+    match *kind {
+        NodeKind::FunctionBody => true,
+        #[cfg(feature="luau")]
+        NodeKind::TypedFunctionBody => true,
+        _ => false,
+    }
+}
+
+/// Where a `goto` jumps to, as indices in the statements of `block`
+pub(crate) struct Target {
+    pub block: NodeId,
+    /// The index of the label
+    pub label: usize,
+    /// The index of the statement holding the `goto`
+    pub from: usize,
+}
+
+/// Finds the label a `goto` jumps to, in the enclosing blocks of the same
+/// function
+pub(crate) fn target(arena: &Arena, goto: NodeId) -> Option<Target> {
+    let label = name(arena, arena.child(goto, "label")?);
+    let mut from = goto;
+    for id in arena.ancestors(goto) {
+        if is_function(arena.kind(id)) {
+            return None;
+        }
+        if *arena.kind(id) == NodeKind::Block {
+            let stats: Vec<_> = arena.children(id).collect();
+            let found = stats.iter()
+                .position(|&s| matches!(*arena.kind(s), NodeKind::Label(ref l) if l == label));
+            if let Some(position) = found {
+                let index = stats.iter().position(|&s| s == from)?;
+                return Some(Target { block: id, label: position, from: index });
+            }
+        }
+        from = id;
+    }
+    None
+}
+
+/// The `Name` of the first local whose scope a forward jump to `target`
+/// enters. A label followed only by void statements until the end of its
+/// block is out of the scope of the locals before it, except at the end of
+/// a `repeat` body, where `until` still sees every local of the body.
+pub(crate) fn entered_local(arena: &Arena, target: &Target) -> Option<NodeId> {
+    if target.label < target.from {
+        return None;
+    }
+    let stats: Vec<_> = arena.children(target.block).collect();
+    let at_end = stats[target.label + 1..].iter()
+        .all(|&s| matches!(*arena.kind(s), NodeKind::Label(_) | NodeKind::EmptyStatement));
+    let in_repeat = arena.parent(target.block).is_some_and(|p| *arena.kind(p) == NodeKind::Repeat);
+    let start = match (at_end, in_repeat) {
+        (true, false) => return None,
+        (true, true) => 0,
+        (false, _) => target.from + 1,
+    };
+    let local = stats[start..target.label].iter().find_map(|&s| match *arena.kind(s) {
+        NodeKind::Local => arena.child(s, "names").and_then(|n| arena.children(n).next()),
+        NodeKind::NamedFunction => arena.child(s, "name"),
+        _ => None,
+    })?;
+    // Luau names can carry a type
+    Some(arena.child(local, "name").unwrap_or(local))
+}
+
+fn in_loop(arena: &Arena, id: NodeId) -> bool {
+    for parent in arena.ancestors(id) {
+        match *arena.kind(parent) {
+            NodeKind::While | NodeKind::Repeat | NodeKind::NumericFor | NodeKind::GenericFor =>
+                return true,
+            ref kind if is_function(kind) => return false,
+            _ => (),
+        }
+    }
+    false
+}
+
+// Whether the label `earlier` makes `label` a duplicate. Before Lua 5.4
+// only the labels of the same block count, since then the labels of the
+// enclosing blocks of the same function count too.
+fn repeats(arena: &Arena, earlier: NodeId, label: NodeId, version: LuaVersion) -> bool {
+    let block = match arena.parent(earlier) {
+        Some(block) => block,
+        None => return false,
+    };
+    if version < LuaVersion::Lua54 {
+        return arena.parent(label) == Some(block);
+    }
+    arena.ancestors(label)
+        .take_while(|&a| !is_function(arena.kind(a)))
+        .any(|a| a == block)
+}
+
+/// Checks the `goto`, label and `break` statements of the chunk in `arena`,
+/// parsed from `source`. Errors are in source order.
+pub fn validate(source: &[u8], arena: &Arena, version: LuaVersion) -> Vec<ValidationError> {
+    let spans = spans(source, arena);
+    let line = |id: NodeId| spans.get(id).map_or(0, |s| s.line_column(source).0);
+    let mut errors = Vec::new();
+    let mut labels: Vec<NodeId> = Vec::new();
+    for id in arena.ids() {
+        let message = match *arena.kind(id) {
+            NodeKind::Break if !in_loop(arena, id) => match version {
+                LuaVersion::Lua51 => "no loop to break".to_string(),
+                LuaVersion::Lua54 => format!("break outside a loop at line {}", line(id)),
+                _ => format!("<break> at line {} not inside a loop", line(id)),
+            },
+            NodeKind::Goto => {
+                let label = arena.child(id, "label").map_or("", |l| name(arena, l));
+                match target(arena, id) {
+                    None if version == LuaVersion::Lua52 =>
+                        format!("no visible label '{}' for 'goto' at line {}", label, line(id)),
+                    None => format!("no visible label '{}' for <goto> at line {}", label, line(id)),
+                    Some(t) => match entered_local(arena, &t) {
+                        Some(local) => format!("<goto {}> at line {} jumps into the scope of local '{}'",
+                                               label, line(id), name(arena, local)),
+                        None => continue,
+                    },
+                }
+            }
+            NodeKind::Label(ref label) => {
+                let earlier = labels.iter().cloned().find(|&l| {
+                    name(arena, l) == label && repeats(arena, l, id, version)
+                });
+                labels.push(id);
+                match earlier {
+                    Some(l) => format!("label '{}' already defined on line {}", label, line(l)),
+                    None => continue,
+                }
+            }
+            _ => continue,
+        };
+        errors.push(ValidationError { node: id, message, span: spans.get(id).cloned() });
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::ParserConfig;
+    use parse_block_with;
+
+    fn messages(source: &str, version: LuaVersion) -> Vec<String> {
+        let config = ParserConfig { version, ..ParserConfig::default() };
+        let block = parse_block_with(source.as_bytes(), &config).unwrap();
+        validate(source.as_bytes(), &Arena::from_ast(&block), version)
+            .into_iter()
+            .map(|e| e.message)
+            .collect()
+    }
+
+    fn lua53(source: &str) -> Vec<String> {
+        messages(source, LuaVersion::Lua53)
+    }
+
+    #[test]
+    fn valid_jumps() {
+        assert!(lua53("for i = 1, 2 do while x do break end goto continue local y ::continue:: end").is_empty());
+        assert!(lua53("::top:: local x = 1 goto top").is_empty());
+        assert!(lua53("do goto l end ::l:: ; ::m::").is_empty());
+        assert!(lua53("repeat if x then break end until y").is_empty());
+    }
+
+    #[test]
+    fn invisible_labels() {
+        assert_eq!(lua53("goto nowhere"), vec!["no visible label 'nowhere' for <goto> at line 1"]);
+        assert_eq!(lua53("do ::inner:: end\ngoto inner"),
+                   vec!["no visible label 'inner' for <goto> at line 2"]);
+        assert_eq!(lua53("::out::\nlocal f = function() goto out end"),
+                   vec!["no visible label 'out' for <goto> at line 2"]);
+        assert_eq!(messages("goto l", LuaVersion::Lua52),
+                   vec!["no visible label 'l' for 'goto' at line 1"]);
+    }
+
+    #[test]
+    fn duplicate_labels() {
+        assert_eq!(lua53("::a::\n::a::"), vec!["label 'a' already defined on line 1"]);
+        assert!(lua53("::a:: do ::a:: end").is_empty());
+        assert_eq!(messages("::a:: do\n::a:: end", LuaVersion::Lua54),
+                   vec!["label 'a' already defined on line 1"]);
+        assert!(messages("do ::a:: end ::a::", LuaVersion::Lua54).is_empty());
+        assert!(messages("::a:: function f() ::a:: end", LuaVersion::Lua54).is_empty());
+    }
+
+    #[test]
+    fn jumps_into_scope() {
+        assert_eq!(lua53("goto l\nlocal x = 1\n::l:: print(x)"),
+                   vec!["<goto l> at line 1 jumps into the scope of local 'x'"]);
+        assert_eq!(lua53("do\ngoto l end local function f() end ::l:: f()"),
+                   vec!["<goto l> at line 2 jumps into the scope of local 'f'"]);
+        assert_eq!(lua53("goto l local x ::l:: return"),
+                   vec!["<goto l> at line 1 jumps into the scope of local 'x'"]);
+        assert_eq!(lua53("repeat local x = 1 goto c ::c:: until x"),
+                   vec!["<goto c> at line 1 jumps into the scope of local 'x'"]);
+        assert_eq!(lua53("repeat goto c local y = 1 ::c:: ; until y"),
+                   vec!["<goto c> at line 1 jumps into the scope of local 'y'"]);
+        assert!(lua53("repeat do goto c local x = 1 ::c:: end until x").is_empty());
+        assert!(lua53("repeat goto c ::c:: local x = 1 until x").is_empty());
+    }
+
+    #[test]
+    fn breaks_outside_loops() {
+        assert_eq!(lua53("if x then\nbreak end"), vec!["<break> at line 2 not inside a loop"]);
+        assert_eq!(lua53("while x do local f = function() break end end"),
+                   vec!["<break> at line 1 not inside a loop"]);
+        assert_eq!(messages("break", LuaVersion::Lua54), vec!["break outside a loop at line 1"]);
+        assert_eq!(messages("do break end", LuaVersion::Lua51), vec!["no loop to break"]);
+    }
+}
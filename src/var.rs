@@ -8,51 +8,79 @@
 
 use ast::ASTNode;
 use ast::ASTNode::*;
-use exp::{parse_prefixexp, parse_exp};
+use exp::{parse_exp, parse_explist, parse_tableconstructor};
 use name::parse_name;
-use nom::IResult;
+use space::parse_space;
+use string::parse_string;
 
 named!(pub parse_varlist<ASTNode>, map!(
             map!(do_parse!(
                    a: parse_var
-                >> b: many0!(preceded!(ws!(tag!(",")), parse_var))
+                >> b: many0!(complete!(preceded!(ws!(tag!(",")), parse_var)))
                 >> ((a,b))
             ), |(a, mut b): (_, Vec < ASTNode >) | { b.insert(0, a); b }),
 ASTNode::VarList));
 
-named!(pub parse_var<ASTNode>, alt!(
-    map!(do_parse!(
-           name: parse_name
-        >> r: opt!(alt!(complete!(delimited!(ws!(tag!("[")), ws!(parse_exp), tag!("]"))) |
-                        complete!(preceded!(ws!(tag!(".")), parse_name))))
-        >> ((name, r))
-        ), |(name, r)| {
-            if let Some(rest) = r {
-                match rest {
-                    ASTNode::Name(_) => {
-                        return astb!(VarListAccess, astb!(PrefixExp, astb!(Var, name)), rest);
-                    },
-                    _ => {
-                        return astb!(VarPrefixed, astb!(PrefixExp, astb!(Var, name)), rest);
-                    },
-                }
-            }
+// var ::= Name | prefixexp '[' exp ']' | prefixexp '.' Name
+// is left recursive, so both var and prefixexp are parsed as a base followed by
+// any number of suffixes, which are then folded into the nested nodes.
+named!(pub parse_var<ASTNode>, map_opt!(parse_suffixed, |(e, is_var)| if is_var {
+    Some(e)
+} else {
+    None
+}));
 
-            astb!(Var, name)
-        }
-    ) |
-    do_parse!(
-           pe: parse_prefixexp
-           // The ws!'s are this way, to not eat any whitespace
-           // outside of the expression
-        >> e: delimited!(ws!(tag!("[")), ws!(parse_exp), tag!("]"))
-        >> (astb!(VarPrefixed, pe, e))) |
-    do_parse!(
-           pe: parse_prefixexp
-        >> n: preceded!(ws!(tag!(".")), parse_name)
-        >> (astb!(VarListAccess, pe, n)))
+// Parses a prefixexp, returning it and whether it is also a valid var. The
+// suffixes are parsed in a loop and do not count against `max_depth`
+named!(pub parse_suffixed<(ASTNode, bool)>, do_parse!(
+           base: parse_prefix_base
+        >> suffixes: many0!(parse_suffix)
+        >> (fold_suffixes(base, suffixes))));
+
+named!(parse_prefix_base<(ASTNode, bool)>, alt!(
+    map!(parse_name, |n| (astb!(Var, n), true)) |
+    map!(delimited!(tag!("("), ws!(parse_exp), tag!(")")), |e| (e, false))
+));
+
+// The ws!'s are this way, to not eat any whitespace
+// outside of the expression
+named!(parse_suffix<Suffix>, alt!(
+    complete!(delimited!(ws!(tag!("[")), ws!(parse_exp), tag!("]"))) => { Suffix::Index } |
+    complete!(preceded!(ws!(tag!(".")), parse_name)) => { Suffix::Field } |
+    complete!(preceded!(parse_space, parse_args)) => { Suffix::Call } |
+    complete!(preceded!(ws!(tag!(":")), pair!(parse_name, preceded!(parse_space, parse_args)))) =>
+        { |(n, args)| Suffix::Method(n, args) }
 ));
 
+// args ::= '(' [explist] ')' | tableconstructor | LiteralString
+named!(parse_args<Option<ASTNode>>, alt!(
+    delimited!(ws!(tag!("(")), opt!(ws!(parse_explist)), tag!(")")) |
+    map!(parse_tableconstructor, |t| Some(ast!(ExpList, vec![t]))) |
+    map!(parse_string, |s| Some(ast!(ExpList, vec![s])))
+));
+
+enum Suffix {
+    Index(ASTNode),
+    Field(ASTNode),
+    Call(Option<ASTNode>),
+    Method(ASTNode, Option<ASTNode>),
+}
+
+fn fold_suffixes((base, is_var): (ASTNode, bool), suffixes: Vec<Suffix>) -> (ASTNode, bool) {
+    let is_var = match suffixes.last() {
+        Some(&Suffix::Index(_)) | Some(&Suffix::Field(_)) => true,
+        Some(_) => false,
+        None => is_var,
+    };
+    let e = suffixes.into_iter().fold(base, |acc, suffix| match suffix {
+        Suffix::Index(e) => astb!(VarPrefixed, astb!(PrefixExp, acc), e),
+        Suffix::Field(n) => astb!(VarListAccess, astb!(PrefixExp, acc), n),
+        Suffix::Call(args) => astb!(FunctionCall, astb!(PrefixExp, acc), args),
+        Suffix::Method(n, args) => astb!(MethodCall, astb!(PrefixExp, acc), n, args),
+    });
+    (e, is_var)
+}
+
 #[cfg(test)]
 mod tests {
     use ast::ASTNode::*;
@@ -66,6 +94,39 @@ mod tests {
               astb!(VarListAccess,
                     astb!(PrefixExp, astb!(Var, ast!(Name, "ayy".into()))),
                     ast!(Name, "zxc".into())));
+    ast_test!(parse_var_4, parse_var, "a.b[nil]",
+              astb!(VarPrefixed,
+                    astb!(PrefixExp, astb!(VarListAccess,
+                        astb!(PrefixExp, astb!(Var, ast!(Name, "a".into()))),
+                        ast!(Name, "b".into()))),
+                    ast!(Nil)));
+    ast_test!(parse_var_5, parse_var, "(nil).a",
+              astb!(VarListAccess, astb!(PrefixExp, ast!(Nil)), ast!(Name, "a".into())));
+    ast_invalid!(parse_var_6, parse_var, "(a)");
+    ast_invalid!(parse_var_7, parse_var, "a.b()");
+    ast_test!(parse_var_8, parse_var, "a:b().c",
+              astb!(VarListAccess,
+                    astb!(PrefixExp, astb!(MethodCall,
+                        astb!(PrefixExp, astb!(Var, ast!(Name, "a".into()))),
+                        ast!(Name, "b".into()),
+                        None)),
+                    ast!(Name, "c".into())));
+
+    ast_test!(parse_call_1, parse_suffixed, "f(1, nil)",
+              (astb!(FunctionCall,
+                     astb!(PrefixExp, astb!(Var, ast!(Name, "f".into()))),
+                     Some(ast!(ExpList, vec![ast!(Integer, 1), ast!(Nil)]))), false));
+    ast_test!(parse_call_2, parse_suffixed, "f \"s\" {}",
+              (astb!(FunctionCall,
+                     astb!(PrefixExp, astb!(FunctionCall,
+                        astb!(PrefixExp, astb!(Var, ast!(Name, "f".into()))),
+                        Some(ast!(ExpList, vec![ast!(String, "s".into())])))),
+                     Some(ast!(ExpList, vec![ast!(TableConstructor, Box::new(None))]))), false));
+    ast_test!(parse_call_3, parse_suffixed, "o : m ( )",
+              (astb!(MethodCall,
+                     astb!(PrefixExp, astb!(Var, ast!(Name, "o".into()))),
+                     ast!(Name, "m".into()),
+                     None), false));
 
     ast_test!(parse_varlist_1, parse_varlist, "xcz", ast!(VarList, vec![
         astb!(Var, ast!(Name, "xcz".into()))
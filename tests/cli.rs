@@ -0,0 +1,162 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Runs the `nom-lua` binary
+
+extern crate nom_lua;
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use nom_lua::span::comments;
+
+// The exit status, stdout and stderr of `nom-lua args` given `stdin`
+fn run(args: &[&str], stdin: &str) -> (i32, String, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_nom-lua"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped())
+        .spawn().unwrap();
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    (output.status.code().unwrap(),
+     String::from_utf8(output.stdout).unwrap(),
+     String::from_utf8(output.stderr).unwrap())
+}
+
+#[test]
+fn check() {
+    assert_eq!(run(&["check", "-"], "local x = 1\nreturn x\n"), (0, "".into(), "".into()));
+    assert_eq!(run(&["check", "-"], "x = 1\nx = = 2\n"), (1, "".into(), "stdin:2:1: syntax error\n".into()));
+    assert_eq!(run(&["check", "-"], "goto a\n").2, "stdin:1:1: no visible label 'a' for <goto> at line 1\n");
+    assert_eq!(run(&["check", "--lua", "5.1", "-"], "goto a\n").2, "stdin: Lua 5.2 is required for goto\n");
+    assert_eq!(run(&["check", "--lua", "5.4", "-"], "local x <foo> = 1\n").2, "stdin:1:9: unknown attribute 'foo'\n");
+}
+
+#[test]
+fn check_lexing() {
+    let source = "-- a comment\nlocal s = [==[\n]]\n]==] --[[ long\ncomment ]]\nreturn 0x1p-2, 0xA.8, s\n";
+    assert_eq!(run(&["check", "-"], source), (0, "".into(), "".into()));
+}
+
+#[test]
+fn globs() {
+    let (status, _, stderr) = run(&["check", "tests/fixtures/*.lua", "tests/**/missing*.lua"], "");
+    assert_eq!(status, 1);
+    assert_eq!(stderr, "nom-lua: no files match tests/**/missing*.lua\n");
+    let (status, stdout, _) = run(&["ast", "tests/f*/*.lua"], "");
+    assert_eq!(status, 0);
+    let fixtures = std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures")).unwrap()
+        .filter(|e| e.as_ref().unwrap().path().extension().is_some_and(|e| e == "lua"))
+        .count();
+    assert_eq!(stdout.lines().filter(|line| line.starts_with("(Block")).count(), fixtures);
+}
+
+#[test]
+fn ast() {
+    assert_eq!(run(&["ast", "-"], "return 1").1, "(Block [] (RetStat (ExpList (Integer 1))))\n");
+}
+
+#[test]
+fn tokens() {
+    let (status, stdout, _) = run(&["tokens", "-"], "#!/bin/lua\nlocal s = 'a\\n' -- c\n");
+    assert_eq!(status, 0);
+    assert_eq!(stdout, "stdin:2:1: keyword local\nstdin:2:7: name s\nstdin:2:9: symbol =\nstdin:2:11: string 'a\\n'\n");
+}
+
+#[test]
+fn fmt() {
+    assert_eq!(run(&["fmt", "-"], "#!/bin/lua\nlocal  x=1 return x").1, "#!/bin/lua\nlocal x = 1\nreturn x\n");
+    assert_eq!(run(&["fmt", "--check", "-"], "x = 1\n"), (0, "".into(), "".into()));
+    assert_eq!(run(&["fmt", "--check", "-"], "x=1").2, "stdin: not formatted\n");
+    let source = "-- top\nlocal  t = {\n  1, -- one\n}\nif t then\n-- two\nt[1] = 2 end\n";
+    let formatted = "-- top\nlocal t = {1} -- one\nif t then\n  -- two\n  t[1] = 2\nend\n";
+    assert_eq!(run(&["fmt", "-"], source), (0, formatted.into(), "".into()));
+    assert_eq!(run(&["fmt", "--check", "-"], formatted), (0, "".into(), "".into()));
+}
+
+// Every fixture formats to the same tree with the same comments, and once
+// formatted stays as it is
+#[test]
+fn fmt_fixtures() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+    let mut paths: Vec<_> = std::fs::read_dir(dir).unwrap().map(|e| e.unwrap().path()).collect();
+    paths.sort();
+    for path in &paths {
+        let path = path.to_str().unwrap();
+        let source = std::fs::read(path).unwrap();
+        let (status, formatted, stderr) = run(&["fmt", path], "");
+        assert_eq!((status, stderr.as_str()), (0, ""), "{}", path);
+        assert_eq!(run(&["ast", "-"], &formatted).1, run(&["ast", path], "").1, "{}", path);
+        let comments = |source: &[u8]| -> Vec<Vec<u8>> {
+            comments(source).into_iter().map(|c| source[c.start..c.end].trim_ascii_end().to_vec()).collect()
+        };
+        assert_eq!(comments(formatted.as_bytes()), comments(&source), "{}", path);
+        assert_eq!(run(&["fmt", "--check", "-"], &formatted), (0, "".into(), "".into()), "{}", path);
+    }
+}
+
+#[test]
+#[cfg(feature = "graphviz")]
+fn dot() {
+    let (status, stdout, _) = run(&["dot", "-"], "return 1");
+    assert_eq!(status, 0);
+    assert!(stdout.starts_with("digraph"), "{}", stdout);
+}
+
+#[test]
+fn usage() {
+    let (status, _, stderr) = run(&["ast", "--write", "-"], "");
+    assert_eq!(status, 2);
+    assert!(stderr.starts_with("nom-lua: --write does not apply to this command\n"), "{}", stderr);
+    assert_eq!(run(&["check"], "").0, 2);
+    assert_eq!(run(&["lint", "-"], "").0, 2);
+    // Several files once the pattern is expanded, nothing is written
+    let output = concat!(env!("CARGO_TARGET_TMPDIR"), "/cli-usage.lua");
+    let _ = std::fs::remove_file(output);
+    let (status, _, stderr) = run(&["fmt", "-o", output, "tests/fixtures/*.lua"], "");
+    assert_eq!(status, 2);
+    assert!(stderr.starts_with("nom-lua: -o does not apply to this command\n"), "{}", stderr);
+    assert!(!std::path::Path::new(output).exists());
+}
+
+#[test]
+#[cfg(feature = "graphviz")]
+fn dot_output() {
+    // Several files once the pattern is expanded, nothing is written
+    let output = concat!(env!("CARGO_TARGET_TMPDIR"), "/cli-usage.dot");
+    let _ = std::fs::remove_file(output);
+    let (status, _, stderr) = run(&["dot", "-o", output, "tests/fixtures/*.lua"], "");
+    assert_eq!(status, 2);
+    assert!(stderr.starts_with("nom-lua: -o takes a single file\n"), "{}", stderr);
+    assert!(!std::path::Path::new(output).exists());
+}
+
+// Commands of features left out of the build are hidden and rejected
+#[test]
+fn features() {
+    let (_, help, _) = run(&["--help"], "");
+    let (status, _, stderr) = run(&["dot", "-"], "return 1");
+    if cfg!(feature = "graphviz") {
+        assert!(help.contains("    dot "), "{}", help);
+        assert_eq!(status, 0);
+    } else {
+        assert!(!help.contains("dot"), "{}", help);
+        assert_eq!(status, 2);
+        assert!(stderr.starts_with("nom-lua: dot needs nom-lua built with the graphviz feature\n"), "{}", stderr);
+    }
+    let (status, _, stderr) = run(&["ast", "--json", "-"], "return 1");
+    if cfg!(feature = "serde") {
+        assert!(help.contains("--json"), "{}", help);
+        assert_eq!(status, 0);
+    } else {
+        assert!(!help.contains("--json"), "{}", help);
+        assert_eq!(status, 2);
+        assert!(stderr.starts_with("nom-lua: --json needs nom-lua built with the serde feature\n"), "{}", stderr);
+    }
+}
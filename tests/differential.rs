@@ -0,0 +1,168 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Checks that the parser accepts the same programs as `luac -p`
+//!
+//! Programs are the random trees of `nom_lua::arbitrary` written out by
+//! `printer::print`, some with a byte deleted so that both sides also have
+//! to agree on what they reject.
+//! quickcheck shrinks a disagreement to the smallest program it can find.
+//!
+//! The compiler is `$LUAC`, or the first of `luac5.3` and `luac` on the
+//! path that reports version 5.3. Without one `luac_agrees` is skipped.
+
+#![cfg(feature = "quickcheck")]
+
+extern crate nom_lua;
+extern crate quickcheck;
+
+use std::env;
+use std::fmt;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+
+use quickcheck::{Arbitrary, Gen, QuickCheck, TestResult};
+
+use nom_lua::arena::Arena;
+use nom_lua::validate::validate;
+use nom_lua::{parse_block_with, printer, ASTNode, LuaVersion, ParserConfig};
+
+fn luac() -> Option<String> {
+    let candidates = match env::var("LUAC") {
+        Ok(luac) => vec![luac],
+        Err(_) => vec!["luac5.3".to_string(), "luac".to_string()],
+    };
+    candidates.into_iter().find(|luac| {
+        Command::new(luac).arg("-v").output()
+            .map(|out| String::from_utf8_lossy(&out.stdout).contains("Lua 5.3") ||
+                       String::from_utf8_lossy(&out.stderr).contains("Lua 5.3"))
+            .unwrap_or(false)
+    })
+}
+
+fn luac_accepts(luac: &str, source: &[u8]) -> bool {
+    let mut child = Command::new(luac).args(["-p", "-"])
+        .stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::null())
+        .spawn().unwrap();
+    // `luac` may stop reading at the first error
+    let _ = child.stdin.take().unwrap().write_all(source);
+    child.wait().unwrap().success()
+}
+
+// The parser and the checks `luac` makes after parsing
+fn accepts(source: &[u8]) -> bool {
+    let config = ParserConfig { version: LuaVersion::Lua53, max_depth: 200 };
+    match parse_block_with(source, &config) {
+        Ok(ast) => validate(source, &Arena::from_ast(&ast), LuaVersion::Lua53).is_empty(),
+        Err(_) => false,
+    }
+}
+
+// Whether `ast` is printed as a program that parses back to it, and that
+// `luac` accepts
+fn valid(ast: &ASTNode) -> bool {
+    let source = printer::print(ast);
+    let config = ParserConfig { version: LuaVersion::Lua53, max_depth: 200 };
+    parse_block_with(source.as_bytes(), &config).as_ref() == Ok(ast) && accepts(source.as_bytes())
+}
+
+/// A generated program, and the byte to delete from its source if any
+#[derive(Clone)]
+struct Program {
+    ast: ASTNode,
+    cut: Option<usize>,
+}
+
+impl Program {
+    fn source(&self) -> Vec<u8> {
+        let mut source = printer::print(&self.ast).into_bytes();
+        if let Some(cut) = self.cut {
+            if !source.is_empty() {
+                source.remove(cut % source.len());
+            }
+        }
+        source
+    }
+}
+
+// quickcheck shows failing programs as their source
+impl fmt::Debug for Program {
+    fn fmt(&self, format: &mut fmt::Formatter) -> fmt::Result {
+        write!(format, "{:?}", String::from_utf8_lossy(&self.source()))
+    }
+}
+
+impl Arbitrary for Program {
+    fn arbitrary<G: Gen>(g: &mut G) -> Program {
+        let ast = ASTNode::arbitrary(g);
+        let cut = if g.gen() { Some(g.gen()) } else { None };
+        Program { ast, cut }
+    }
+
+    fn shrink(&self) -> Box<dyn Iterator<Item = Program>> {
+        let cut = self.cut;
+        // the smaller trees are still valid, so are the programs before the cut
+        let smaller = self.ast.shrink().map(move |ast| Program { ast, cut });
+        match cut {
+            Some(_) => Box::new(Some(Program { ast: self.ast.clone(), cut: None }).into_iter().chain(smaller)),
+            None => Box::new(smaller),
+        }
+    }
+}
+
+#[test]
+fn generated_programs_parse() {
+    fn property(program: Program) -> TestResult {
+        if program.cut.is_some() {
+            return TestResult::discard();
+        }
+        TestResult::from_bool(valid(&program.ast))
+    }
+    QuickCheck::new().quickcheck(property as fn(Program) -> TestResult);
+}
+
+#[test]
+fn shrinking_keeps_programs_valid() {
+    let ast = parse_block_with(b"while x do if y then break end f(1 + 2, ...) end return {a = 1}",
+                               &ParserConfig::default()).unwrap();
+    let smaller = Program { ast, cut: None }.shrink().collect::<Vec<_>>();
+    assert!(!smaller.is_empty());
+    for program in smaller {
+        assert!(valid(&program.ast), "{}", printer::print(&program.ast));
+    }
+}
+
+// A cut can join two minus signs into a comment, which both sides have to
+// read as one, as in `f(a --b)`
+#[test]
+fn cuts_make_comments() {
+    let ast = parse_block_with(b"f(a - -b) x = a - -b", &ParserConfig::default()).unwrap();
+    let source = printer::print(&ast);
+    let call = source.find("- -").unwrap() + 1;
+    let assign = source.rfind("- -").unwrap() + 1;
+    assert!(!accepts(&Program { ast: ast.clone(), cut: Some(call) }.source()));
+    assert!(accepts(&Program { ast, cut: Some(assign) }.source()));
+}
+
+static LUAC: OnceLock<Option<String>> = OnceLock::new();
+
+fn luac_agrees_on(program: Program) -> bool {
+    let luac = LUAC.get_or_init(luac).as_ref().unwrap();
+    let source = program.source();
+    accepts(&source) == luac_accepts(luac, &source)
+}
+
+#[test]
+fn luac_agrees() {
+    if LUAC.get_or_init(luac).is_none() {
+        println!("skipped: no luac 5.3, set LUAC to one");
+        return;
+    }
+    QuickCheck::new().tests(500).quickcheck(luac_agrees_on as fn(Program) -> bool);
+}
@@ -0,0 +1,8 @@
+#!/bin/sh
+# Downloads the official Lua 5.3.4 test suite into tests/lua-5.3-tests, where
+# tests/lua53.rs looks for it
+set -e
+cd "$(dirname "$0")"
+rm -rf lua-5.3-tests
+mkdir lua-5.3-tests
+curl -fsSL https://www.lua.org/tests/lua-5.3.4-tests.tar.gz | tar -xz --strip-components=1 -C lua-5.3-tests
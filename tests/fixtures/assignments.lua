@@ -0,0 +1,13 @@
+local a, b, c = 1, 2.5, "three"
+local d
+a, b = b, a
+x, y, z = a, b
+local t = {}
+t.field = true
+t["key with spaces"] = nil
+t[1], t[2] = 'first', "second"
+;
+local n = #t + -a * 2 ^ 3 // 1 % 5
+local bits = ~n & 0xFF | 1 << 4 ~ n >> 2
+local s = "tab\tand \"quotes\"" .. 'esc\\' .. "\x41\u{48}\65"
+return a, b, c, d, n, bits, s
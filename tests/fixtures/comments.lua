@@ -0,0 +1,24 @@
+-- Comments in every place fmt has to keep them, and strings that are
+-- not UTF-8
+local names = { -- the table
+  "caf", -- Latin-1
+  '\255\128', -- escapes
+  -- before the last
+  [[raw ]],
+}
+
+--[[ A long comment
+     over two lines ]]
+local function greet(name) -- trailing
+  -- inside the body
+  if name then
+    print("hello " .. name) -- call
+  else
+    -- nothing to say
+    return
+  end
+  return name --[==[ long ]==] .. "!"
+end
+
+for i = 1, #names do greet(names[i]) end -- loop
+-- the end
@@ -0,0 +1,29 @@
+local total = 0
+for i = 1, 10 do
+  if i % 2 == 0 then
+    total = total + i
+  elseif i == 7 then
+    break
+  else
+    total = total - 1
+  end
+end
+for i = 10, 1, -3 do
+  total = total + i
+end
+local n = 0
+while n < 5 and total > 0 do
+  n = n + 1
+end
+repeat
+  local done = n >= 10
+  n = n + 2
+until done
+do
+  local scoped = n
+  total = total + scoped
+end
+for k, v in pairs({a = 1, b = 2}) do
+  total = total + v
+end
+return total, n
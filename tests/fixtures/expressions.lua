@@ -0,0 +1,12 @@
+local a, b = 3, 4
+local c = (a + b) * (a - b) / 2
+local cmp = a < b, a <= b, a > b, a >= b, a == b, a ~= b
+local logic = not a or b and (a or nil)
+local cat = "a" .. "b" .. "c" .. 1 .. 2.0
+local neg = - -a
+local pow = -a ^ 2 ^ -1
+local len = #"string" + #{1, 2}
+local call = (print)
+local index = ("x"):rep(3)
+local paren = (f())
+return c, cmp, logic, cat, neg, pow, len, call, index, paren
@@ -0,0 +1,26 @@
+local function fib(n)
+  if n < 2 then
+    return n
+  end
+  return fib(n - 1) + fib(n - 2)
+end
+function counter(start)
+  local count = start or 0
+  return function(step)
+    count = count + (step or 1)
+    return count
+  end
+end
+local M = {}
+M.util = {}
+function M.util.add(a, b) return a + b end
+function M:method(...)
+  local args = {...}
+  return self, select("#", ...), args
+end
+local varargs = function(...) return ... end
+print(fib(10), counter(5)(2), M.util.add(1, 2))
+M:method(1, nil, 3)
+print "no parens"
+print { 1, 2 }
+return varargs(1, 2, 3)
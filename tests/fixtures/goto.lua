@@ -0,0 +1,16 @@
+local i = 1
+::top::
+if i < 3 then
+  i = i + 1
+  goto top
+end
+for j = 1, 3 do
+  for k = 1, 3 do
+    if k == j then
+      goto continue
+    end
+    i = i + k
+    ::continue::
+  end
+end
+return i
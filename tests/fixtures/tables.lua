@@ -0,0 +1,13 @@
+local empty = {}
+local list = {1, 2, 3; 4, 5}
+local record = {name = "lua", version = 5.3, ["not a name"] = true}
+local nested = {
+  point = {x = 0, y = 0},
+  [1 + 1] = "computed",
+  list,
+  {},
+}
+local mixed = {f = function(self) return self end, "item", n = -1}
+nested.point.x = nested.point.y + 1
+nested["point"]["y"] = #list
+return empty, record.name, nested[2], mixed:f().n
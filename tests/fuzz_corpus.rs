@@ -0,0 +1,68 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Runs the fuzz corpus through the same checks as the fuzz targets, so
+//! regressions show up without a nightly toolchain or cargo-fuzz
+
+extern crate nom_lua;
+
+use std::fs;
+use std::path::Path;
+
+use nom_lua::{parse_block_with, printer, ASTNode, IResult, ParserConfig};
+
+fn corpus(target: &str) -> Vec<Vec<u8>> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus").join(target);
+    let inputs: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| fs::read(e.unwrap().path()).unwrap()).collect();
+    assert!(!inputs.is_empty(), "the corpus for {} is empty", target);
+    inputs
+}
+
+fn check_corpus<F>(target: &str, parser: F)
+    where F: Fn(&[u8]) -> IResult<&[u8], ASTNode>
+{
+    for data in corpus(target) {
+        if let IResult::Done(rest, _) = parser(&data) {
+            assert!(rest.len() < data.len(),
+                    "{} parsed {:?} without consuming any input", target, data);
+        }
+    }
+}
+
+#[test]
+fn corpus_parse_chunk() {
+    check_corpus("parse_chunk", nom_lua::parse_chunk);
+}
+
+#[test]
+fn corpus_parse_number() {
+    check_corpus("parse_number", nom_lua::number::parse_number);
+}
+
+#[test]
+fn corpus_parse_string() {
+    check_corpus("parse_string", nom_lua::string::parse_string);
+}
+
+#[test]
+fn corpus_parse_name() {
+    check_corpus("parse_name", nom_lua::name::parse_name);
+}
+
+#[test]
+fn corpus_round_trip() {
+    let config = ParserConfig::default();
+    for data in corpus("round_trip").iter().chain(&corpus("parse_chunk")) {
+        if let Ok(ast) = parse_block_with(data, &config) {
+            let printed = printer::print(&ast);
+            let reparsed = parse_block_with(printed.as_bytes(), &config)
+                .unwrap_or_else(|e| panic!("{} does not parse: {}", printed, e));
+            assert_eq!(reparsed, ast, "{}", printed);
+        }
+    }
+}
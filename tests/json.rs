@@ -0,0 +1,53 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Checks that every tree of the fixtures and of the fuzz corpus reads back
+//! from its JSON document unchanged
+
+#![cfg(feature = "serde")]
+
+extern crate nom_lua;
+
+use std::fs;
+use std::path::Path;
+
+use nom_lua::arena::Arena;
+use nom_lua::json::{from_document, from_json, to_json, to_json_with_spans};
+use nom_lua::{parse_block_with, ParserConfig};
+
+fn sources(dir: &str) -> Vec<Vec<u8>> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(dir);
+    let mut paths: Vec<_> = fs::read_dir(dir).unwrap().map(|e| e.unwrap().path()).collect();
+    paths.sort();
+    paths.iter().map(|path| fs::read(path).unwrap()).collect()
+}
+
+#[test]
+fn fixtures() {
+    let sources = sources("tests/fixtures");
+    assert!(!sources.is_empty());
+    for source in sources {
+        let ast = parse_block_with(&source, &ParserConfig::default()).unwrap();
+        assert_eq!(from_json(&to_json(&ast)).unwrap(), ast);
+
+        let document = from_document(&to_json_with_spans(&ast, &source)).unwrap();
+        assert_eq!(document.ast, ast);
+        let spans = document.spans.unwrap();
+        assert_eq!(spans.len(), Arena::from_ast(&ast).len());
+        assert!(spans[0].is_some());
+    }
+}
+
+#[test]
+fn corpus() {
+    for source in sources("fuzz/corpus/parse_chunk") {
+        if let Ok(ast) = parse_block_with(&source, &ParserConfig::default()) {
+            assert_eq!(from_json(&to_json(&ast)).unwrap(), ast, "{:?}", source);
+        }
+    }
+}
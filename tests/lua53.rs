@@ -0,0 +1,209 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Checks the parser against what `lua5.3` accepts
+//!
+//! `suite` parses every file of the official Lua 5.3.4 test suite, from
+//! <https://www.lua.org/tests/>, unpacked into `tests/lua-5.3-tests` or the
+//! directory in `LUA_TESTS`. `tests/fetch-lua-tests.sh` downloads it, the CI
+//! builds do so before testing. It prints a table of the files with where
+//! each failing one stops, and fails when a file outside `KNOWN_FAILURES`
+//! does not parse or a file in it does. Without the suite it fails on CI,
+//! where `CI` is set, and only reports that it is missing elsewhere.
+//!
+//! `valid` and `invalid` check that snippets `luac5.3 -p` accepts or rejects
+//! are accepted or rejected too.
+
+extern crate nom_lua;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use nom_lua::config::LuaVersion;
+use nom_lua::{parse_block_with, Error, ParserConfig};
+
+fn config() -> ParserConfig {
+    // LUAI_MAXCCALLS, the nesting lua5.3 allows
+    ParserConfig { version: LuaVersion::Lua53, max_depth: 200 }
+}
+
+// Parses on a thread with room for the nesting of `config`
+fn parse(source: Vec<u8>) -> Result<(), Error> {
+    thread::Builder::new()
+        .stack_size(256 << 20)
+        .spawn(move || parse_block_with(skip_comment(&source), &config()).map(|_| ()))
+        .unwrap()
+        .join()
+        .unwrap()
+}
+
+// `lua` skips a first line starting with `#`, it keeps the newline so that
+// line numbers still match
+fn skip_comment(source: &[u8]) -> &[u8] {
+    if source.first() == Some(&b'#') {
+        &source[source.iter().position(|&b| b == b'\n').unwrap_or(source.len())..]
+    } else {
+        source
+    }
+}
+
+fn suite_dir() -> PathBuf {
+    match env::var_os("LUA_TESTS") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/lua-5.3-tests"),
+    }
+}
+
+// The line and column of byte `offset`, both from 1
+fn position(source: &[u8], offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.iter().filter(|&&b| b == b'\n').count() + 1;
+    let column = before.iter().rev().take_while(|&&b| b != b'\n').count() + 1;
+    (line, column)
+}
+
+// The files of the suite that are expected not to parse, with the reason
+const KNOWN_FAILURES: &[(&str, &str)] = &[];
+
+#[test]
+fn suite() {
+    let dir = suite_dir();
+    let mut paths: Vec<_> = match fs::read_dir(&dir) {
+        Ok(entries) => entries.map(|e| e.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|e| e == "lua"))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    if paths.is_empty() {
+        let missing = format!("no Lua 5.3 test suite in {}, run tests/fetch-lua-tests.sh or set LUA_TESTS",
+                              dir.display());
+        assert!(env::var_os("CI").is_none(), "{}", missing);
+        println!("{}", missing);
+        return;
+    }
+    paths.sort();
+
+    let mut table = String::new();
+    let mut unexpected = Vec::new();
+    for path in &paths {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let source = fs::read(path).unwrap();
+        let result = match parse(source.clone()) {
+            Ok(()) => "ok".to_string(),
+            Err(Error::Syntax(offset)) => {
+                let (line, column) = position(skip_comment(&source), offset);
+                format!("FAILED at {}:{}", line, column)
+            }
+            Err(e) => format!("FAILED, {}", e),
+        };
+        let known = KNOWN_FAILURES.iter().find(|&&(file, _)| file == name);
+        match (result == "ok", known) {
+            (true, Some(_)) => unexpected.push(format!("{} parses, remove it from KNOWN_FAILURES", name)),
+            (false, None) => unexpected.push(format!("{} {}", name, result)),
+            _ => {}
+        }
+        let reason = known.map_or(String::new(), |&(_, reason)| format!(" (known: {})", reason));
+        table.push_str(&format!("{:<16} {}{}\n", name, result, reason));
+    }
+    println!("{}", table);
+    assert!(unexpected.is_empty(), "{}\n\n{}", unexpected.join("\n"), table);
+}
+
+// `luac5.3 -p` accepts each of these
+const VALID: &[&str] = &[
+    "-- a comment",
+    "x = 1 -- a comment\ny = 2",
+    "x = 1 --[[ a long\ncomment ]] + 2",
+    "--[==[ ]] ]==] x = 1",
+    "x = - -1",
+    "x = [[a long\nstring]]",
+    "x = [==[ ]] ]=] ]==]",
+    "f[[a]]",
+    "x = t[ [[a]] ]",
+    "x = 0x1p4 + 0x.8 + 0XA.8P-1",
+    "x = 0xffffffffffffffff, 0x8000000000000000, 0x7fffffffffffffff",
+    "x = 0x1p-1074, 0x1p1023",
+    "x = '\\x41\\x4a2'",
+];
+
+#[test]
+fn valid() {
+    let rejected: Vec<_> = VALID.iter().filter(|s| parse(s.as_bytes().to_vec()).is_err()).collect();
+    assert!(rejected.is_empty(), "rejected valid Lua 5.3: {:?}", rejected);
+}
+
+// Each of these is a syntax error for `luac5.3 -p`
+const INVALID: &[&str] = &[
+    "x =",
+    "x = 1 +",
+    "x = not",
+    "x = a..",
+    "x = 1 == == 2",
+    "x = 3 = 4",
+    "a = 1 2",
+    "local 1 = 2",
+    "local a, = 1",
+    "local x <const> = 1",
+    "local function a.b() end",
+    "a.b:c = 1",
+    "a.1 = 2",
+    "f() = 1",
+    "(a) = 1",
+    "nil = 1",
+    "break = 1",
+    "a:b",
+    "x = a.b:c.d",
+    "f(,)",
+    "x = {,}",
+    "local t = {[1] 2}",
+    "x = ... ...",
+    "return return",
+    "return 1; x = 2",
+    "do end end",
+    "if x then",
+    "elseif",
+    "while do end",
+    "repeat until",
+    "for i = 1 do end",
+    "for a, b = 1, 2 do end",
+    "function (x) end",
+    "function f(a, ..., b) end",
+    "x = function f() end",
+    "goto",
+    "::a",
+    "x = 0x",
+    "x = 1e",
+    "x = 08x",
+    "x = \"abc",
+    "x = 'a\nb'",
+    "x = [[a",
+    "x = [=[a]]",
+    "--[[ a",
+    "x = 0x1p",
+    "x = 0x.p1",
+    "x = '\\x4'",
+    "x = '\\xg0'",
+    "x = '\\q'",
+    "x = '\\300'",
+    "x = '\\u{110000}'",
+];
+
+#[test]
+fn invalid() {
+    let accepted: Vec<_> = INVALID.iter().filter(|s| parse(s.as_bytes().to_vec()).is_ok()).collect();
+    assert!(accepted.is_empty(), "accepted invalid Lua 5.3: {:?}", accepted);
+}
+
+#[test]
+fn first_line_comment() {
+    assert_eq!(skip_comment(b"#!/usr/bin/lua\nreturn 1"), b"\nreturn 1");
+    assert!(parse(b"#!/usr/bin/lua\nreturn 1".to_vec()).is_ok());
+    assert_eq!(position(b"a\nbc", 3), (2, 2));
+}
@@ -0,0 +1,80 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Runs programs with the reference `lua` 5.3, once from source and once
+//! compiled by `bytecode::compile`, and compares what they print
+//!
+//! The binary is `$LUA`, or the first of `lua5.3` and `lua` on the path
+//! that reports version 5.3. Without one the programs are only compiled.
+
+extern crate nom_lua;
+
+use std::env;
+use std::fs;
+use std::process::Command;
+
+use nom_lua::bytecode::compile;
+use nom_lua::{parse_block_with, LuaVersion, ParserConfig};
+
+const PROGRAMS: &[&str] = &[
+    "print(1 + 2, 7 // 2, 7 / 2, 2 ^ 10, 7 % -3, 1 << 62, 'a' .. 1 .. 2.5)",
+    "local t = {} for i = 1, 100 do t[i] = i * i end print(#t, t[50], t[51], t[100])",
+    "local function fib(n) if n < 2 then return n end return fib(n - 1) + fib(n - 2) end print(fib(20))",
+    "local fs = {} for i = 1, 3 do fs[i] = function() return i end end print(fs[1](), fs[2](), fs[3]())",
+    "local function f(...) return select('#', ...), ... end print(f(1, nil, 3))",
+    "local o = {n = 1} function o:get(x) return self.n + x end print(o:get(2))",
+    "for k, v in ipairs({'a', 'b'}) do print(k, v) end",
+    "local i = 0 ::top:: i = i + 1 if i < 3 then goto top end print(i)",
+    "local n = 0 repeat local m = n n = n + 1 until m >= 3 print(n)",
+    "local a, b = 1, 2 a, b = b, a print(a, b, a < b and 'lt' or 'ge')",
+    "print((pcall(function() local x = nil return x.y end)))",
+    "for x = 1, 2, 0.5 do io.write(x, ' ') end print()",
+];
+
+fn lua() -> Option<String> {
+    let candidates = match env::var("LUA") {
+        Ok(lua) => vec![lua],
+        Err(_) => vec!["lua5.3".to_string(), "lua".to_string()],
+    };
+    candidates.into_iter().find(|lua| {
+        Command::new(lua).arg("-v").output()
+            .map(|out| String::from_utf8_lossy(&out.stdout).contains("Lua 5.3") ||
+                       String::from_utf8_lossy(&out.stderr).contains("Lua 5.3"))
+            .unwrap_or(false)
+    })
+}
+
+fn run(lua: &str, path: &::std::path::Path) -> String {
+    let out = Command::new(lua).arg(path).output().unwrap();
+    String::from_utf8_lossy(&out.stdout).into_owned() + &String::from_utf8_lossy(&out.stderr)
+}
+
+#[test]
+fn compiled_chunks_run_like_their_source() {
+    let lua = lua();
+    let config = ParserConfig { version: LuaVersion::Lua53, ..ParserConfig::default() };
+    let dir = env::temp_dir();
+    for (n, source) in PROGRAMS.iter().enumerate() {
+        let block = parse_block_with(source.as_bytes(), &config).unwrap();
+        let proto = compile(&block, source.as_bytes(), "=test").unwrap();
+        let lua = match lua {
+            Some(ref lua) => lua,
+            None => continue,
+        };
+        let source_path = dir.join(format!("nom-lua-{}-{}.lua", std::process::id(), n));
+        fs::write(&source_path, source).unwrap();
+        for &strip in &[false, true] {
+            let chunk_path = source_path.with_extension("luac");
+            fs::write(&chunk_path, proto.dump(strip)).unwrap();
+            let expected = run(lua, &source_path);
+            assert_eq!(run(lua, &chunk_path), expected, "{}", source);
+            fs::remove_file(&chunk_path).unwrap();
+        }
+        fs::remove_file(&source_path).unwrap();
+    }
+}
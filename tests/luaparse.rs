@@ -0,0 +1,75 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Compares the output of `luaparse::to_json` on the fixtures with the AST
+//! of the luaparse npm package
+//!
+//! The package is looked up by `node` from `$LUAPARSE`, or from the usual
+//! module paths. Without it the fixtures are only exported.
+
+#![cfg(feature = "serde")]
+
+extern crate nom_lua;
+extern crate serde_json;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use nom_lua::luaparse::{to_json, LuaparseConfig};
+use nom_lua::{parse_block_with, ParserConfig};
+use serde_json::Value;
+
+// Strings are decoded byte by byte, so that `value` is the same for any
+// version of luaparse
+const SCRIPT: &str = "
+const luaparse = require(process.env.LUAPARSE || 'luaparse');
+const source = require('fs').readFileSync(process.argv[1], 'utf8');
+const options = { locations: true, ranges: true, luaVersion: '5.3', encodingMode: 'x-user-defined' };
+console.log(JSON.stringify(luaparse.parse(source, options)));
+";
+
+fn node_luaparse() -> Option<()> {
+    let found = Command::new("node")
+        .args(["-e", "require(process.env.LUAPARSE || 'luaparse')"])
+        .output()
+        .ok()?;
+    if found.status.success() { Some(()) } else { None }
+}
+
+fn fixtures() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut paths: Vec<_> = fs::read_dir(dir).unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "lua"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+#[test]
+fn fixtures_match_luaparse() {
+    let luaparse = node_luaparse();
+    let config = LuaparseConfig { locations: true, ranges: true };
+    for path in fixtures() {
+        let source = fs::read(&path).unwrap();
+        let block = parse_block_with(&source, &ParserConfig::default()).unwrap();
+        let ours: Value = serde_json::from_str(&to_json(&block, &source, &config).unwrap()).unwrap();
+        if luaparse.is_none() {
+            continue;
+        }
+        let out = Command::new("node").args(["-e", SCRIPT]).arg(&path).output().unwrap();
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        let theirs: Value = serde_json::from_slice(&out.stdout).unwrap();
+        assert_eq!(ours, theirs, "{}", path.display());
+    }
+    if env::var_os("LUAPARSE").is_some() {
+        assert!(luaparse.is_some(), "$LUAPARSE is set but node can not load it");
+    }
+}
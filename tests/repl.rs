@@ -0,0 +1,70 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Runs the `repl` example on piped input
+//!
+//! `cargo test` builds the examples next to the test binaries, in
+//! `target/<profile>/examples`. When only this test is built, as with
+//! `cargo test --test repl`, the example is built here.
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+// What the `repl` example prints for `stdin`
+fn run(stdin: &str) -> String {
+    run_with_history("repl-history", stdin)
+}
+
+// What the `repl` example prints for `stdin`, with its history in the file
+// `name` of the test directory
+fn run_with_history(name: &str, stdin: &str) -> String {
+    let deps = env::current_exe().unwrap().parent().unwrap().to_path_buf();
+    let repl = deps.parent().unwrap().join("examples").join(format!("repl{}", env::consts::EXE_SUFFIX));
+    if !repl.exists() {
+        let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+        let built = Command::new(cargo).args(["build", "--example", "repl"])
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .status().unwrap();
+        assert!(built.success());
+    }
+    let history = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let mut child = Command::new(&repl)
+        .env("NOM_LUA_HISTORY", history)
+        .stdin(Stdio::piped()).stdout(Stdio::piped())
+        .spawn().unwrap_or_else(|e| panic!("cannot run {}: {}", repl.display(), e));
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn continued_lines() {
+    assert_eq!(run("x = [[a\nb]]\n"), "(Block [(Assign (VarList (Var (Name x))) (ExpList (String \"a\\nb\")))] _)\n");
+    // One tree, printed over several lines
+    assert_eq!(run("if x then\ny = 1\nend\n").lines().filter(|line| line.starts_with("(Block")).count(), 1);
+    assert_eq!(run("return 1 +\n2\n"), run("return 1 + 2\n"));
+}
+
+#[test]
+fn eval_keeps_globals() {
+    assert_eq!(run(":eval\nx = 1\nprint(x)\nx + 1\n"), "1\n2\n");
+    // Earlier prints are not shown again
+    assert_eq!(run(":eval\nprint('a')\nprint('b')\n"), "a\nb\n");
+}
+
+#[test]
+fn history_recall() {
+    let _ = fs::remove_file(Path::new(env!("CARGO_TARGET_TMPDIR")).join("repl-recall"));
+    let ast = "(Block [] (RetStat (ExpList (Integer 1))))\n";
+    assert_eq!(run_with_history("repl-recall", "return 1\n:history 1\n"), format!("{}return 1\n{}", ast, ast));
+    assert_eq!(run_with_history("repl-recall", ":history 9\n"), "no input 9 in the history\n");
+}
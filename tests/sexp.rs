@@ -0,0 +1,47 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Checks that every tree of the fixtures and of the fuzz corpus reads back
+//! from its S-expression unchanged
+
+extern crate nom_lua;
+
+use std::fs;
+use std::path::Path;
+
+use nom_lua::sexp::{print, print_flat, read};
+use nom_lua::{parse_block_with, ParserConfig};
+
+fn sources(dir: &str) -> Vec<Vec<u8>> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(dir);
+    let mut paths: Vec<_> = fs::read_dir(dir).unwrap().map(|e| e.unwrap().path()).collect();
+    paths.sort();
+    paths.iter().map(|path| fs::read(path).unwrap()).collect()
+}
+
+#[test]
+fn fixtures() {
+    let sources = sources("tests/fixtures");
+    assert!(!sources.is_empty());
+    for source in sources {
+        let ast = parse_block_with(&source, &ParserConfig::default()).unwrap();
+        let text = print(&ast);
+        assert_eq!(read(&text).unwrap(), ast);
+        assert_eq!(print(&read(&text).unwrap()), text);
+        assert_eq!(read(&print_flat(&ast)).unwrap(), ast);
+    }
+}
+
+#[test]
+fn corpus() {
+    for source in sources("fuzz/corpus/parse_chunk") {
+        if let Ok(ast) = parse_block_with(&source, &ParserConfig::default()) {
+            assert_eq!(read(&print(&ast)).unwrap(), ast, "{:?}", source);
+        }
+    }
+}
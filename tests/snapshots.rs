@@ -0,0 +1,104 @@
+// Copyright 2017 The nom-lua project developers
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Compares the tree of each `tests/fixtures/*.lua` with its snapshot in
+//! `tests/snapshots/*.ast`, written by `sexp::print`
+//!
+//! Run with `BLESS=1` to write the snapshots of the current parser instead,
+//! grammar changes then show up in the diff of the snapshots.
+
+extern crate nom_lua;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use nom_lua::sexp;
+use nom_lua::{parse_block_with, ParserConfig};
+
+fn bless() -> bool {
+    env::var_os("BLESS").is_some_and(|value| value != "0" && !value.is_empty())
+}
+
+fn files(dir: &Path, extension: &str) -> Vec<PathBuf> {
+    let mut paths: Vec<_> = fs::read_dir(dir).unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == extension))
+        .collect();
+    paths.sort();
+    paths
+}
+
+// The first line where `expected` and `actual` differ, with both versions
+fn difference(expected: &str, actual: &str) -> String {
+    let (mut expected, mut actual) = (expected.lines(), actual.lines());
+    for line in 1.. {
+        match (expected.next(), actual.next()) {
+            (Some(a), Some(b)) if a == b => {}
+            (None, None) => break,
+            (a, b) => return format!("line {}:\n-{}\n+{}", line, a.unwrap_or(""), b.unwrap_or("")),
+        }
+    }
+    "only in the trailing newline".to_string()
+}
+
+#[test]
+fn snapshots() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let snapshots = root.join("snapshots");
+    let bless = bless();
+    let mut failures = Vec::new();
+
+    let fixtures = files(&root.join("fixtures"), "lua");
+    assert!(!fixtures.is_empty());
+    for fixture in &fixtures {
+        let name = fixture.file_stem().unwrap().to_str().unwrap();
+        let source = fs::read(fixture).unwrap();
+        let actual = match parse_block_with(&source, &ParserConfig::default()) {
+            Ok(ast) => sexp::print(&ast),
+            Err(e) => {
+                failures.push(format!("{}.lua: does not parse: {:?}", name, e));
+                continue;
+            }
+        };
+        let path = snapshots.join(format!("{}.ast", name));
+        if bless {
+            fs::write(&path, &actual).unwrap();
+            continue;
+        }
+        match fs::read_to_string(&path) {
+            Ok(ref expected) if *expected == actual => {}
+            Ok(expected) => failures.push(format!("{}.ast differs at {}", name, difference(&expected, &actual))),
+            Err(_) => failures.push(format!("{}.ast is missing", name)),
+        }
+    }
+
+    // Snapshots of fixtures that were removed or renamed
+    for path in files(&snapshots, "ast") {
+        let name = path.file_stem().unwrap().to_str().unwrap();
+        if !fixtures.iter().any(|fixture| fixture.file_stem().unwrap() == name) {
+            if bless {
+                fs::remove_file(&path).unwrap();
+            } else {
+                failures.push(format!("{}.ast has no fixture", name));
+            }
+        }
+    }
+
+    assert!(failures.is_empty(), "{}\n\nrun with BLESS=1 to update the snapshots", failures.join("\n\n"));
+}
+
+#[test]
+fn snapshots_read_back() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    for path in files(&root.join("snapshots"), "ast") {
+        let text = fs::read_to_string(&path).unwrap();
+        let ast = sexp::read(&text).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        assert_eq!(sexp::print(&ast), text, "{}", path.display());
+    }
+}